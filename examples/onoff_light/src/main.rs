@@ -16,10 +16,15 @@
  */
 
 mod dev_att;
+use std::sync::Arc;
+
 use matter::core::{self, CommissioningData};
 use matter::data_model::cluster_basic_information::BasicInfoConfig;
 use matter::data_model::device_types::device_type_add_on_off_light;
+use matter::pairing::{PairingCodes, PairingOptions};
+use matter::persist::DirKvStorage;
 use matter::secure_channel::spake2p::VerifierData;
+use matter::transport::network::TransportConfig;
 
 fn main() {
     env_logger::init();
@@ -40,8 +45,16 @@ fn main() {
         device_name: "OnOff Light".to_string(),
     };
     let dev_att = Box::new(dev_att::HardCodedDevAtt::new());
+    let storage = Arc::new(DirKvStorage::new("/tmp/matter_psm").unwrap());
+
+    // Listen on the Matter port, on all the interfaces
+    let transport = TransportConfig::default();
+
+    let pairing_codes =
+        PairingCodes::new(&dev_info, &comm_data, &PairingOptions::default()).unwrap();
+    pairing_codes.print().unwrap();
 
-    let mut matter = core::Matter::new(dev_info, dev_att, comm_data).unwrap();
+    let mut matter = core::Matter::new(dev_info, dev_att, comm_data, storage, transport).unwrap();
     let dm = matter.get_data_model();
     {
         let mut node = dm.node.write().unwrap();
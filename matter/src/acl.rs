@@ -17,7 +17,7 @@
 
 use std::{
     fmt::Display,
-    sync::{Arc, Mutex, MutexGuard, RwLock},
+    sync::{Arc, RwLock},
 };
 
 use crate::{
@@ -25,12 +25,14 @@ use crate::{
     error::Error,
     fabric,
     interaction_model::messages::GenericPath,
-    sys::Psm,
-    tlv::{FromTLV, TLVElement, TLVList, TLVWriter, TagType, ToTLV},
+    persist::{
+        record::{self, Persist},
+        KvStorage,
+    },
+    tlv::{get_root_node, FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
     transport::session::MAX_CAT_IDS_PER_NOC,
-    utils::writebuf::WriteBuf,
 };
-use log::error;
+use log::{error, info};
 use num_derive::FromPrimitive;
 
 // Matter Minimum Requirements
@@ -366,29 +368,53 @@ struct AclMgrInner {
     entries: AclEntries,
 }
 
-const ACL_KV_ENTRY: &str = "acl";
-const ACL_KV_MAX_SIZE: usize = 300;
-impl AclMgrInner {
-    pub fn store(&self, psm: &MutexGuard<Psm>) -> Result<(), Error> {
-        let mut acl_tlvs = [0u8; ACL_KV_MAX_SIZE];
-        let mut wb = WriteBuf::new(&mut acl_tlvs, ACL_KV_MAX_SIZE);
-        let mut tw = TLVWriter::new(&mut wb);
-        self.entries.to_tlv(&mut tw, TagType::Anonymous)?;
-        psm.set_kv_slice(ACL_KV_ENTRY, wb.as_slice())
-    }
+const ACL_KV_ENTRY: &str = "acls";
+// Older releases stored the entries without a record, under a different key
+const ACL_KV_LEGACY_ENTRY: &str = "acl";
 
-    pub fn load(psm: &MutexGuard<Psm>) -> Result<Self, Error> {
-        let mut acl_tlvs = Vec::new();
-        psm.get_kv_slice(ACL_KV_ENTRY, &mut acl_tlvs)?;
-        let root = TLVList::new(&acl_tlvs)
-            .iter()
-            .next()
-            .ok_or(Error::Invalid)?;
+impl Persist for AclMgrInner {
+    const VERSION: u16 = 1;
 
+    fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+        self.entries.to_tlv(tw, TagType::Anonymous)
+    }
+
+    fn decode(payload: &TLVElement) -> Result<Self, Error> {
         Ok(Self {
-            entries: AclEntries::from_tlv(&root)?,
+            entries: AclEntries::from_tlv(payload)?,
         })
     }
+}
+
+impl AclMgrInner {
+    pub fn store(&self, psm: &dyn KvStorage) -> Result<(), Error> {
+        record::store(psm, ACL_KV_ENTRY, self)
+    }
+
+    pub fn load(psm: &dyn KvStorage) -> Result<Option<Self>, Error> {
+        if let Some(inner) = record::load(psm, ACL_KV_ENTRY)? {
+            return Ok(Some(inner));
+        }
+
+        let mut acl_tlvs = Vec::new();
+        match psm.get_kv_slice(ACL_KV_LEGACY_ENTRY, &mut acl_tlvs) {
+            Ok(_) => (),
+            Err(Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        info!("Migrating the ACLs from the legacy key");
+        let inner = match get_root_node(&acl_tlvs).and_then(|root| Self::decode(&root)) {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("The legacy ACLs are corrupt: {:?}", e);
+                record::quarantine(psm, ACL_KV_LEGACY_ENTRY, &acl_tlvs)?;
+                return Ok(None);
+            }
+        };
+        inner.store(psm)?;
+        psm.remove_kv(ACL_KV_LEGACY_ENTRY)?;
+        Ok(Some(inner))
+    }
 
     /// Traverse fabric specific entries to find the index
     ///
@@ -416,38 +442,25 @@ impl AclMgrInner {
 
 pub struct AclMgr {
     inner: RwLock<AclMgrInner>,
-    // The Option<> is solely because test execution is faster
-    // Doing this here adds the least overhead during ACL verification
-    psm: Option<Arc<Mutex<Psm>>>,
+    psm: Arc<dyn KvStorage>,
 }
 
 impl AclMgr {
-    pub fn new() -> Result<Self, Error> {
-        AclMgr::new_with(true)
-    }
-
-    pub fn new_with(psm_support: bool) -> Result<Self, Error> {
+    /// Create the ACL Manager, with the ACLs that are in the storage
+    pub fn new(psm: Arc<dyn KvStorage>) -> Result<Self, Error> {
         const INIT: Option<AclEntry> = None;
-        let mut psm = None;
 
-        let inner = if !psm_support {
-            AclMgrInner {
+        // Don't run, and risk overwriting the ACLs of a newer release, or the ones that
+        // the storage failed to return
+        let inner = match AclMgrInner::load(psm.as_ref()) {
+            Ok(Some(inner)) => inner,
+            Ok(None) => AclMgrInner {
                 entries: [INIT; MAX_ACL_ENTRIES],
+            },
+            Err(e) => {
+                error!("Couldn't load the ACLs: {:?}", e);
+                return Err(e);
             }
-        } else {
-            let psm_handle = Psm::get()?;
-            let inner = {
-                let psm_lock = psm_handle.lock().unwrap();
-                AclMgrInner::load(&psm_lock)
-            };
-
-            psm = Some(psm_handle);
-            inner.unwrap_or({
-                // Error loading from PSM
-                AclMgrInner {
-                    entries: [INIT; MAX_ACL_ENTRIES],
-                }
-            })
         };
         Ok(Self {
             inner: RwLock::new(inner),
@@ -460,12 +473,9 @@ impl AclMgr {
         for i in 0..MAX_ACL_ENTRIES {
             inner.entries[i] = None;
         }
-        if let Some(psm) = self.psm.as_ref() {
-            let psm = psm.lock().unwrap();
-            let _ = inner.store(&psm).map_err(|e| {
-                error!("Error in storing ACLs {}", e);
-            });
-        }
+        let _ = inner.store(self.psm.as_ref()).map_err(|e| {
+            error!("Error in storing ACLs {}", e);
+        });
     }
 
     pub fn add(&self, entry: AclEntry) -> Result<(), Error> {
@@ -486,12 +496,7 @@ impl AclMgr {
             .ok_or(Error::NoSpace)?;
         inner.entries[index] = Some(entry);
 
-        if let Some(psm) = self.psm.as_ref() {
-            let psm = psm.lock().unwrap();
-            inner.store(&psm)
-        } else {
-            Ok(())
-        }
+        inner.store(self.psm.as_ref())
     }
 
     // Since the entries are fabric-scoped, the index is only for entries with the matching fabric index
@@ -500,12 +505,7 @@ impl AclMgr {
         let old = inner.for_index_in_fabric(index, fab_idx)?;
         *old = Some(new);
 
-        if let Some(psm) = self.psm.as_ref() {
-            let psm = psm.lock().unwrap();
-            inner.store(&psm)
-        } else {
-            Ok(())
-        }
+        inner.store(self.psm.as_ref())
     }
 
     pub fn delete(&self, index: u8, fab_idx: u8) -> Result<(), Error> {
@@ -513,12 +513,7 @@ impl AclMgr {
         let old = inner.for_index_in_fabric(index, fab_idx)?;
         *old = None;
 
-        if let Some(psm) = self.psm.as_ref() {
-            let psm = psm.lock().unwrap();
-            inner.store(&psm)
-        } else {
-            Ok(())
-        }
+        inner.store(self.psm.as_ref())
     }
 
     pub fn delete_for_fabric(&self, fab_idx: u8) -> Result<(), Error> {
@@ -533,12 +528,7 @@ impl AclMgr {
             }
         }
 
-        if let Some(psm) = self.psm.as_ref() {
-            let psm = psm.lock().unwrap();
-            inner.store(&psm)
-        } else {
-            Ok(())
-        }
+        inner.store(self.psm.as_ref())
     }
 
     pub fn for_each_acl<T>(&self, mut f: T) -> Result<(), Error>
@@ -589,15 +579,21 @@ mod tests {
     use crate::{
         acl::{gen_noc_cat, AccessorSubjects},
         data_model::objects::{Access, Privilege},
+        error::Error,
         interaction_model::messages::GenericPath,
+        persist::{
+            record::{self, Persist},
+            KvStorage, MemKvStorage,
+        },
+        tlv::{TLVElement, TLVWriter, TagType},
     };
     use std::sync::Arc;
 
-    use super::{AccessReq, Accessor, AclEntry, AclMgr, AuthMode, Target};
+    use super::{AccessReq, Accessor, AclEntry, AclMgr, AuthMode, Target, ACL_KV_ENTRY};
 
     #[test]
     fn test_basic_empty_subject_target() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
         let accessor = Accessor::new(2, AccessorSubjects::new(112233), AuthMode::Case, am.clone());
         let path = GenericPath::new(Some(1), Some(1234), None);
@@ -625,7 +621,7 @@ mod tests {
 
     #[test]
     fn test_subject() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
         let accessor = Accessor::new(2, AccessorSubjects::new(112233), AuthMode::Case, am.clone());
         let path = GenericPath::new(Some(1), Some(1234), None);
@@ -647,7 +643,7 @@ mod tests {
 
     #[test]
     fn test_cat() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
 
         let allow_cat = 0xABCD;
@@ -685,7 +681,7 @@ mod tests {
 
     #[test]
     fn test_cat_version() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
 
         let allow_cat = 0xABCD;
@@ -717,7 +713,7 @@ mod tests {
 
     #[test]
     fn test_target() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
         let accessor = Accessor::new(2, AccessorSubjects::new(112233), AuthMode::Case, am.clone());
         let path = GenericPath::new(Some(1), Some(1234), None);
@@ -778,7 +774,7 @@ mod tests {
 
     #[test]
     fn test_privilege() {
-        let am = Arc::new(AclMgr::new_with(false).unwrap());
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         am.erase_all();
 
         let accessor = Accessor::new(2, AccessorSubjects::new(112233), AuthMode::Case, am.clone());
@@ -816,4 +812,82 @@ mod tests {
         req.set_target_perms(Access::RWVA);
         assert_eq!(req.allow(), true);
     }
+
+    #[test]
+    fn test_persist() {
+        let storage = Arc::new(MemKvStorage::new());
+        let am = Arc::new(AclMgr::new(storage.clone()).unwrap());
+        let mut new = AclEntry::new(2, Privilege::VIEW, AuthMode::Case);
+        new.add_subject(112233).unwrap();
+        am.add(new).unwrap();
+
+        // A new ACL Manager on the same storage has the entry
+        let am = Arc::new(AclMgr::new(storage).unwrap());
+        let mut count = 0;
+        am.for_each_acl(|e| {
+            assert_eq!(e.fab_idx, Some(2));
+            count += 1;
+        })
+        .unwrap();
+        assert_eq!(count, 1);
+
+        // While a new ACL Manager on another storage doesn't
+        let am = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
+        let mut count = 0;
+        am.for_each_acl(|_| count += 1).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let storage = Arc::new(MemKvStorage::new());
+        storage.set_kv_slice("acls", &[0x15, 0x24, 0x00]).unwrap();
+
+        // Starts with no entries, and the bad record is moved out of the way
+        let am = Arc::new(AclMgr::new(storage.clone()).unwrap());
+        let mut count = 0;
+        am.for_each_acl(|_| count += 1).unwrap();
+        assert_eq!(count, 0);
+        let mut val = Vec::new();
+        assert_eq!(storage.get_kv_slice("acls.corrupt", &mut val), Ok(3));
+
+        // And so is a bad legacy blob
+        storage.set_kv_slice("acl", &[0x15, 0x24, 0x00]).unwrap();
+        AclMgr::new(storage.clone()).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(storage.get_kv_slice("acl", &mut val), Err(Error::NotFound));
+        assert_eq!(storage.get_kv_slice("acl.corrupt", &mut val), Ok(3));
+    }
+
+    // A record from a release that is newer than us
+    struct NewerRecord;
+
+    impl Persist for NewerRecord {
+        const VERSION: u16 = u16::MAX;
+
+        fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+            tw.u8(TagType::Anonymous, 0)
+        }
+
+        fn decode(_payload: &TLVElement) -> Result<Self, Error> {
+            Ok(NewerRecord)
+        }
+    }
+
+    #[test]
+    fn test_newer_record() {
+        let storage = Arc::new(MemKvStorage::new());
+        record::store(storage.as_ref(), ACL_KV_ENTRY, &NewerRecord).unwrap();
+        let mut newer = Vec::new();
+        storage.get_kv_slice(ACL_KV_ENTRY, &mut newer).unwrap();
+
+        // We refuse to start, and leave the record alone
+        assert_eq!(
+            AclMgr::new(storage.clone()).err(),
+            Some(Error::UnsupportedVersion)
+        );
+        let mut val = Vec::new();
+        storage.get_kv_slice(ACL_KV_ENTRY, &mut val).unwrap();
+        assert_eq!(val, newer);
+    }
 }
@@ -0,0 +1,218 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use crate::error::Error;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::convert::TryFrom;
+
+pub const TAG_BOOL: u8 = 0x01;
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STR: u8 = 0x03;
+pub const TAG_OCTET_STR: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STR: u8 = 0x0c;
+pub const TAG_PRINTABLE_STR: u8 = 0x13;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQ: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+/// A reader of the DER encoding, for the subset that the certificates use: the tags are a
+/// single byte, and the lengths are at most 2 bytes
+#[derive(Debug, Clone)]
+pub struct ASN1Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ASN1Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.buf.len()
+    }
+
+    pub fn peek_tag(&self) -> Option<u8> {
+        self.buf.get(self.offset).copied()
+    }
+
+    /// Read the next element, returning its tag and its value
+    pub fn read(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let (tag, start, end) = self.next_bounds()?;
+        self.offset = end;
+        Ok((tag, &self.buf[start..end]))
+    }
+
+    /// Read the next element, which has to be of the given tag, as it is encoded: with its
+    /// tag and length
+    pub fn read_raw(&mut self, expected: u8) -> Result<&'a [u8], Error> {
+        let start = self.offset;
+        self.read_expect(expected)?;
+        Ok(&self.buf[start..self.offset])
+    }
+
+    /// Read the value of the next element, which has to be of the given tag
+    pub fn read_expect(&mut self, expected: u8) -> Result<&'a [u8], Error> {
+        let (tag, value) = self.read()?;
+        if tag == expected {
+            Ok(value)
+        } else {
+            Err(Error::InvalidData)
+        }
+    }
+
+    /// Read the next element, if it is of the given tag
+    pub fn read_optional(&mut self, expected: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek_tag() == Some(expected) {
+            self.read_expect(expected).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Read the next element, which has to be a compound of the given tag, and return a
+    /// reader for its contents
+    pub fn enter(&mut self, expected: u8) -> Result<ASN1Reader<'a>, Error> {
+        self.read_expect(expected).map(ASN1Reader::new)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.read_expect(TAG_BOOL)? {
+            [0] => Ok(false),
+            [_] => Ok(true),
+            _ => Err(Error::InvalidData),
+        }
+    }
+
+    /// Read a non-negative integer that fits in a u64
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let value = self.read_expect(TAG_INTEGER)?;
+        let value = match value {
+            [0, rest @ ..] => rest,
+            _ => value,
+        };
+        if value.len() > 8 || matches!(value.first(), Some(b) if b & 0x80 != 0) {
+            return Err(Error::InvalidData);
+        }
+        Ok(value.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Read a bit string that has no unused bits
+    pub fn bitstr(&mut self) -> Result<&'a [u8], Error> {
+        match self.read_expect(TAG_BIT_STR)? {
+            [0, rest @ ..] => Ok(rest),
+            _ => Err(Error::InvalidData),
+        }
+    }
+
+    /// Read a UTCTime or a GeneralizedTime, as the seconds since the Matter epoch
+    pub fn time(&mut self) -> Result<u32, Error> {
+        let (tag, value) = self.read()?;
+        let value = std::str::from_utf8(value).map_err(|_| Error::Utf8Fail)?;
+        let value = match tag {
+            TAG_UTC_TIME => {
+                // The two digit years from 50 onwards are of the previous century
+                let century = if value.get(..2) >= Some("50") {
+                    "19"
+                } else {
+                    "20"
+                };
+                format!("{}{}", century, value)
+            }
+            TAG_GENERALIZED_TIME => value.to_owned(),
+            _ => return Err(Error::InvalidData),
+        };
+        if value == "99991231235959Z" {
+            // This is the 'no well-defined expiration date'
+            return Ok(0);
+        }
+        let time = NaiveDateTime::parse_from_str(&value, "%Y%m%d%H%M%SZ")
+            .map_err(|_| Error::InvalidTime)?;
+        let matter_epoch = NaiveDate::from_ymd_opt(2000, 1, 1)
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .ok_or(Error::InvalidTime)?;
+        u32::try_from((time - matter_epoch).num_seconds()).map_err(|_| Error::InvalidTime)
+    }
+
+    fn next_bounds(&self) -> Result<(u8, usize, usize), Error> {
+        let mut offset = self.offset;
+        let tag = *self.buf.get(offset).ok_or(Error::InvalidData)?;
+        offset += 1;
+        let first = *self.buf.get(offset).ok_or(Error::InvalidData)?;
+        offset += 1;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let len_bytes = (first & 0x7f) as usize;
+            if len_bytes == 0 || len_bytes > 2 {
+                return Err(Error::InvalidData);
+            }
+            let bytes = self
+                .buf
+                .get(offset..offset + len_bytes)
+                .ok_or(Error::InvalidData)?;
+            offset += len_bytes;
+            bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize)
+        };
+        if offset + len > self.buf.len() {
+            return Err(Error::InvalidData);
+        }
+        Ok((tag, offset, offset + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        // A sequence of an integer and an octet string with a 2-byte length
+        let mut der = vec![
+            0x30, 0x82, 0x01, 0x06, 0x02, 0x01, 0x05, 0x04, 0x82, 0x00, 0xff,
+        ];
+        der.extend_from_slice(&[0xaa; 0xff]);
+        let mut r = ASN1Reader::new(&der);
+        let mut seq = r.enter(TAG_SEQ).unwrap();
+        assert!(r.is_empty());
+        assert_eq!(seq.u64(), Ok(5));
+        assert_eq!(seq.read_optional(TAG_BOOL), Ok(None));
+        assert_eq!(seq.read_expect(TAG_OCTET_STR).unwrap().len(), 0xff);
+        assert!(seq.is_empty());
+        assert_eq!(seq.read(), Err(Error::InvalidData));
+
+        // The length runs past the end
+        assert_eq!(
+            ASN1Reader::new(&[0x04, 0x03, 0x01]).read(),
+            Err(Error::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_time() {
+        let mut r = ASN1Reader::new(b"\x17\x0d210101000000Z\x18\x0f20500101000000Z");
+        assert_eq!(r.time(), Ok(662_774_400));
+        assert_eq!(r.time(), Ok(1_577_923_200));
+        let mut r = ASN1Reader::new(b"\x18\x0f99991231235959Z");
+        assert_eq!(r.time(), Ok(0));
+        // Before the Matter epoch
+        let mut r = ASN1Reader::new(b"\x17\x0d991231235959Z");
+        assert_eq!(r.time(), Err(Error::InvalidTime));
+    }
+}
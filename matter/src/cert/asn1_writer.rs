@@ -155,6 +155,13 @@ impl<'a> ASN1Writer<'a> {
         self.depth[self.current_depth - 1] - RESERVE_LEN_BYTES
     }
 
+    /// Append an element that is already encoded
+    pub fn raw(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.append_with(data.len(), |t| {
+            t.buf[t.offset..t.offset + data.len()].copy_from_slice(data)
+        })
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         &self.buf[..self.offset]
     }
@@ -286,4 +293,8 @@ impl<'a> CertConsumer for ASN1Writer<'a> {
             self.write_str(0x17, time_str.as_bytes())
         }
     }
+
+    fn no_expiry_time(&mut self, _tag: &str) -> Result<(), Error> {
+        self.write_str(0x18, b"99991231235959Z")
+    }
 }
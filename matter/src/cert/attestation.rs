@@ -0,0 +1,577 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The device attestation: the chain of certificates from the DAC of a device up to a PAA,
+//! and the Certification Declaration of the device's product
+
+use log::error;
+
+use super::{
+    asn1_reader::{ASN1Reader, TAG_OCTET_STR, TAG_OID, TAG_SEQ, TAG_SET},
+    ASN1Writer, Cert, CertConsumer, CertTime, MAX_DER_SIGNATURE_SIZE, OID_ECDSA_WITH_SHA256,
+};
+use crate::{
+    crypto::{CryptoKeyPair, KeyPair, EC_SIGNATURE_LEN_BYTES},
+    error::Error,
+    tlv::{self, FromTLV, OctetStr, TLVArray, TLVElement, TLVWriter, TagType, ToTLV, UtfStr},
+    utils::writebuf::WriteBuf,
+};
+
+const OID_PKCS7_DATA: [u8; 9] = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x01];
+const OID_PKCS7_SIGNED_DATA: [u8; 9] = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+const OID_SHA256: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+// The version of the SignedData and the SignerInfo, with the signer identified by its
+// subject key id
+const CMS_VERSION: u8 = 3;
+
+/// The vendor and product ids that a device attests to with its DAC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttestedIds {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// Verify the chain of a device's DAC through its PAI, up to the PAA if it is given, and
+/// return the ids that the DAC attests to
+///
+/// Besides the checks of the [CertVerifier](super::CertVerifier), the ids have to be
+/// consistent along the chain: the PAI is of the DAC's vendor, and of its product if the PAI
+/// is of a single product, while a PAA of a single vendor is of the PAI's vendor.
+pub fn verify_dac_chain(
+    dac: &Cert,
+    pai: &Cert,
+    paa: Option<&Cert>,
+    time: CertTime,
+) -> Result<AttestedIds, Error> {
+    let ids = match (dac.get_vendor_id(), dac.get_product_id()) {
+        (Some(vendor_id), Some(product_id)) => AttestedIds {
+            vendor_id,
+            product_id,
+        },
+        _ => {
+            error!("The DAC has no vendor id or product id");
+            return Err(Error::InvalidAttestation);
+        }
+    };
+    if pai.get_vendor_id() != Some(ids.vendor_id) {
+        error!(
+            "The PAI is of the vendor {:x?}, instead of {:x}",
+            pai.get_vendor_id(),
+            ids.vendor_id
+        );
+        return Err(Error::InvalidAttestation);
+    }
+    if matches!(pai.get_product_id(), Some(pid) if pid != ids.product_id) {
+        error!(
+            "The PAI is of the product {:x?}, instead of {:x}",
+            pai.get_product_id(),
+            ids.product_id
+        );
+        return Err(Error::InvalidAttestation);
+    }
+
+    let verifier = dac.verify_attestation_start(time).add_cert(pai)?;
+    if let Some(paa) = paa {
+        if matches!(paa.get_vendor_id(), Some(vid) if vid != ids.vendor_id) {
+            error!(
+                "The PAA is of the vendor {:x?}, instead of {:x}",
+                paa.get_vendor_id(),
+                ids.vendor_id
+            );
+            return Err(Error::InvalidAttestation);
+        }
+        verifier.add_cert(paa)?.finalise()?;
+    }
+    Ok(ids)
+}
+
+// The Certification Declaration of a product, that the device presents during attestation
+#[derive(FromTLV, ToTLV, Debug)]
+#[tlvargs(lifetime = "'a")]
+pub struct CertDeclaration<'a> {
+    pub format_version: u16,
+    pub vendor_id: u16,
+    pub product_ids: TLVArray<'a, u16>,
+    pub device_type_id: u32,
+    pub certificate_id: UtfStr<'a>,
+    pub security_level: u8,
+    pub security_information: u16,
+    pub version_number: u16,
+    pub certification_type: u8,
+    /// The ids in the DAC, if these aren't the ones of the declaration, like for a product
+    /// that is built on one of another vendor
+    pub dac_origin_vendor_id: Option<u16>,
+    pub dac_origin_product_id: Option<u16>,
+    /// The subject key ids of the only PAAs that the DACs may chain up to
+    pub authorized_paa_list: Option<TLVArray<'a, OctetStr<'a>>>,
+}
+
+impl<'a> CertDeclaration<'a> {
+    /// Check that the declaration certifies the device with the ids from its DAC, which
+    /// chains up to the PAA with the given subject key id, if it is known
+    pub fn check(&self, ids: &AttestedIds, paa_key_id: Option<&[u8]>) -> Result<(), Error> {
+        let certified = match (self.dac_origin_vendor_id, self.dac_origin_product_id) {
+            (Some(vendor_id), Some(product_id)) => {
+                ids.vendor_id == vendor_id && ids.product_id == product_id
+            }
+            _ => {
+                ids.vendor_id == self.vendor_id
+                    && self.product_ids.iter().any(|pid| pid == ids.product_id)
+            }
+        };
+        if !certified {
+            error!(
+                "The Certification Declaration doesn't certify the vendor {:x}, product {:x}",
+                ids.vendor_id, ids.product_id
+            );
+            return Err(Error::InvalidAttestation);
+        }
+
+        if let (Some(list), Some(paa_key_id)) = (&self.authorized_paa_list, paa_key_id) {
+            if !list.iter().any(|id| id.0 == paa_key_id) {
+                error!(
+                    "The PAA {:x?} isn't authorized by the Certification Declaration",
+                    paa_key_id
+                );
+                return Err(Error::InvalidAttestation);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Certification Declaration, as the content of a CMS SignedData envelope
+///
+/// The envelope is of a single signer, that is identified by its subject key id, and that
+/// signs the content itself, without any signed attributes.
+pub struct SignedCertDeclaration<'a> {
+    content: &'a [u8],
+    signer_key_id: &'a [u8],
+    signature: Vec<u8>,
+}
+
+impl<'a> SignedCertDeclaration<'a> {
+    pub fn new(der: &'a [u8]) -> Result<Self, Error> {
+        let mut content_info = ASN1Reader::new(der).enter(TAG_SEQ)?;
+        if content_info.read_expect(TAG_OID)? != OID_PKCS7_SIGNED_DATA {
+            error!("The Certification Declaration isn't of the signed data type");
+            return Err(Error::InvalidData);
+        }
+        let mut signed_data = content_info.enter(0xA0)?.enter(TAG_SEQ)?;
+        if signed_data.u64()? != CMS_VERSION as u64 {
+            return Err(Error::InvalidData);
+        }
+        Self::decode_digest_algo(&mut signed_data.enter(TAG_SET)?)?;
+        let mut encap_content = signed_data.enter(TAG_SEQ)?;
+        if encap_content.read_expect(TAG_OID)? != OID_PKCS7_DATA {
+            return Err(Error::InvalidData);
+        }
+        let content = encap_content.enter(0xA0)?.read_expect(TAG_OCTET_STR)?;
+
+        let mut signer_info = signed_data.enter(TAG_SET)?.enter(TAG_SEQ)?;
+        if signer_info.u64()? != CMS_VERSION as u64 {
+            return Err(Error::InvalidData);
+        }
+        let signer_key_id = signer_info.read_expect(0x80)?;
+        Self::decode_digest_algo(&mut signer_info)?;
+        Cert::decode_sign_algo(&mut signer_info)?;
+        let signature = Cert::decode_signature(signer_info.read_expect(TAG_OCTET_STR)?)?;
+        Ok(Self {
+            content,
+            signer_key_id,
+            signature,
+        })
+    }
+
+    /// Encode the declaration in an envelope, signed by the given key, that has the given
+    /// subject key id. Returns the length of the envelope in the buffer.
+    pub fn encode(
+        cd: &CertDeclaration,
+        signer_key_id: &[u8],
+        signer_key: &KeyPair,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut content = [0u8; MAX_CERT_DECLARATION_LEN];
+        let mut wb = WriteBuf::new(&mut content, MAX_CERT_DECLARATION_LEN);
+        let mut tw = TLVWriter::new(&mut wb);
+        cd.to_tlv(&mut tw, TagType::Anonymous)?;
+        let content = wb.as_borrow_slice();
+
+        let mut signature = [0u8; EC_SIGNATURE_LEN_BYTES];
+        signer_key.sign_msg(content, &mut signature)?;
+        let mut der_signature = [0u8; MAX_DER_SIGNATURE_SIZE];
+        let len = Cert::encode_signature(&signature, &mut der_signature)?;
+
+        let mut w = ASN1Writer::new(buf);
+        w.start_seq("")?;
+        w.oid("", &OID_PKCS7_SIGNED_DATA)?;
+        w.start_ctx("", 0)?;
+        w.start_seq("")?;
+        w.integer("", &[CMS_VERSION])?;
+        w.start_set("")?;
+        Self::encode_digest_algo(&mut w)?;
+        w.end_set()?;
+        w.start_seq("")?;
+        w.oid("", &OID_PKCS7_DATA)?;
+        w.start_ctx("", 0)?;
+        w.ostr("", content)?;
+        w.end_ctx()?;
+        w.end_seq()?;
+        w.start_set("")?;
+        w.start_seq("")?;
+        w.integer("", &[CMS_VERSION])?;
+        w.ctx("", 0, signer_key_id)?;
+        Self::encode_digest_algo(&mut w)?;
+        w.start_seq("")?;
+        w.oid("", &OID_ECDSA_WITH_SHA256)?;
+        w.end_seq()?;
+        w.ostr("", &der_signature[..len])?;
+        w.end_seq()?;
+        w.end_set()?;
+        w.end_seq()?;
+        w.end_ctx()?;
+        w.end_seq()?;
+        Ok(w.as_slice().len())
+    }
+
+    fn decode_digest_algo(r: &mut ASN1Reader) -> Result<(), Error> {
+        if r.enter(TAG_SEQ)?.read_expect(TAG_OID)? != OID_SHA256 {
+            error!("Only the SHA256 digests are supported");
+            return Err(Error::Invalid);
+        }
+        Ok(())
+    }
+
+    fn encode_digest_algo(w: &mut ASN1Writer) -> Result<(), Error> {
+        w.start_seq("")?;
+        w.oid("", &OID_SHA256)?;
+        w.end_seq()
+    }
+
+    /// The subject key id of the signer
+    pub fn get_signer_key_id(&self) -> &[u8] {
+        self.signer_key_id
+    }
+
+    /// Verify the signature, with the public key of the signer
+    pub fn verify(&self, signer_pubkey: &[u8]) -> Result<(), Error> {
+        let result =
+            KeyPair::new_from_public(signer_pubkey)?.verify_msg(self.content, &self.signature);
+        if result.is_err() {
+            error!("Error in signature verification of the Certification Declaration");
+        }
+        result
+    }
+
+    /// The declaration, which is only to be trusted once the signature is verified
+    pub fn get_cert_declaration(&self) -> Result<CertDeclaration<'a>, Error> {
+        CertDeclaration::from_tlv(&tlv::get_root_node_struct(self.content)?)
+    }
+}
+
+// The TLV encoded declaration, without the envelope
+const MAX_CERT_DECLARATION_LEN: usize = 512;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cert::CertSubject, crypto::EC_POINT_LEN_BYTES};
+
+    // 2021-01-01 to 2031-01-01
+    const NOT_BEFORE: u32 = 662774400;
+    const NOT_AFTER: u32 = 978134400;
+    const NOW: CertTime = CertTime::Utc(NOT_BEFORE + 1000);
+
+    struct Issued {
+        cert: Cert,
+        key: KeyPair,
+    }
+
+    fn issue(subject: CertSubject, issuer: Option<&Issued>, not_after: u32) -> Issued {
+        let key = KeyPair::new().unwrap();
+        let mut pubkey = [0u8; EC_POINT_LEN_BYTES];
+        let len = key.get_public_key(&mut pubkey).unwrap();
+        let (issuer_cert, issuer_key) = match issuer {
+            Some(issuer) => (Some(&issuer.cert), &issuer.key),
+            None => (None, &key),
+        };
+        let cert = Cert::issue(
+            &subject,
+            &pubkey[..len],
+            issuer_cert,
+            issuer_key,
+            NOT_BEFORE,
+            not_after,
+        )
+        .unwrap();
+        Issued { cert, key }
+    }
+
+    fn paa(vendor_id: Option<u16>) -> Issued {
+        issue(CertSubject::Paa { vendor_id }, None, 0)
+    }
+
+    fn pai(paa: &Issued, vendor_id: u16, product_id: Option<u16>) -> Issued {
+        let subject = CertSubject::Pai {
+            vendor_id,
+            product_id,
+        };
+        issue(subject, Some(paa), 0)
+    }
+
+    fn dac(pai: &Issued, vendor_id: u16, product_id: u16) -> Issued {
+        let subject = CertSubject::Dac {
+            vendor_id,
+            product_id,
+        };
+        issue(subject, Some(pai), NOT_AFTER)
+    }
+
+    #[test]
+    fn test_dac_chain() {
+        let paa = paa(None);
+        let pai = pai(&paa, 0xFFF1, None);
+        let dac = dac(&pai, 0xFFF1, 0x8000);
+        let ids = AttestedIds {
+            vendor_id: 0xFFF1,
+            product_id: 0x8000,
+        };
+        assert_eq!(
+            verify_dac_chain(&dac.cert, &pai.cert, Some(&paa.cert), NOW),
+            Ok(ids)
+        );
+        // Without the PAA, only the DAC and the PAI are checked
+        assert_eq!(verify_dac_chain(&dac.cert, &pai.cert, None, NOW), Ok(ids));
+        assert_eq!(
+            verify_dac_chain(
+                &dac.cert,
+                &pai.cert,
+                Some(&paa.cert),
+                CertTime::Utc(NOT_AFTER + 1)
+            ),
+            Err(Error::CertExpired)
+        );
+
+        // The certificates have to be in their place in the chain
+        assert_eq!(
+            verify_dac_chain(&pai.cert, &dac.cert, Some(&paa.cert), NOW),
+            Err(Error::InvalidAttestation)
+        );
+        // A DAC that signs another one is still not a PAI
+        let pai_dac = issue(
+            CertSubject::Dac {
+                vendor_id: 0xFFF1,
+                product_id: 0x8000,
+            },
+            Some(&dac),
+            NOT_AFTER,
+        );
+        assert_eq!(
+            verify_dac_chain(&pai_dac.cert, &dac.cert, None, NOW),
+            Err(Error::InvalidCertUsage)
+        );
+        // Some other PAA
+        assert_eq!(
+            verify_dac_chain(&dac.cert, &pai.cert, Some(&self::paa(None).cert), NOW),
+            Err(Error::InvalidAuthKey)
+        );
+    }
+
+    // Certificate policies, with any policy
+    const CERT_POLICIES_EXT: [u8; 19] = [
+        0x30, 0x11, 0x06, 0x03, 0x55, 0x1D, 0x20, 0x04, 0x0A, 0x30, 0x08, 0x30, 0x06, 0x06, 0x04,
+        0x55, 0x1D, 0x20, 0x00,
+    ];
+    const CRITICAL_CERT_POLICIES_EXT: [u8; 22] = [
+        0x30, 0x14, 0x06, 0x03, 0x55, 0x1D, 0x20, 0x01, 0x01, 0xFF, 0x04, 0x0A, 0x30, 0x08, 0x30,
+        0x06, 0x06, 0x04, 0x55, 0x1D, 0x20, 0x00,
+    ];
+
+    // The DER certificate of the DAC with another extension after its own, signed by the PAI
+    fn dac_with_extension(dac: &Issued, pai: &Issued, extension: &[u8]) -> Vec<u8> {
+        let mut asn1 = [0u8; 1000];
+        let len = dac.cert.as_asn1(&mut asn1).unwrap();
+        let mut fields = ASN1Reader::new(&asn1[..len]).enter(TAG_SEQ).unwrap();
+        let mut tbs = [0u8; 1000];
+        let mut w = ASN1Writer::new(&mut tbs);
+        w.start_seq("").unwrap();
+        while let Some(tag) = fields.peek_tag().filter(|tag| *tag != 0xA3) {
+            w.raw(fields.read_raw(tag).unwrap()).unwrap();
+        }
+        let mut extensions = fields.enter(0xA3).unwrap().enter(TAG_SEQ).unwrap();
+        w.start_ctx("", 3).unwrap();
+        w.start_seq("").unwrap();
+        while !extensions.is_empty() {
+            w.raw(extensions.read_raw(TAG_SEQ).unwrap()).unwrap();
+        }
+        w.raw(extension).unwrap();
+        w.end_seq().unwrap();
+        w.end_ctx().unwrap();
+        w.end_seq().unwrap();
+        let tbs = w.as_slice().to_vec();
+
+        let mut signature = [0u8; EC_SIGNATURE_LEN_BYTES];
+        pai.key.sign_msg(&tbs, &mut signature).unwrap();
+        let mut der_signature = [0u8; MAX_DER_SIGNATURE_SIZE];
+        let signature_len = Cert::encode_signature(&signature, &mut der_signature).unwrap();
+        let mut der = [0u8; 1000];
+        let mut w = ASN1Writer::new(&mut der);
+        w.start_seq("").unwrap();
+        w.raw(&tbs).unwrap();
+        w.start_seq("").unwrap();
+        w.oid("", &OID_ECDSA_WITH_SHA256).unwrap();
+        w.end_seq().unwrap();
+        w.bitstr("", false, &der_signature[..signature_len])
+            .unwrap();
+        w.end_seq().unwrap();
+        w.as_slice().to_vec()
+    }
+
+    #[test]
+    fn test_dac_chain_x509_extensions() {
+        let paa = paa(None);
+        let pai = pai(&paa, 0xFFF1, None);
+        let dac = dac(&pai, 0xFFF1, 0x8000);
+
+        // The unsupported non-critical extension is skipped, the signature is verified over
+        // the DER certificate that has it
+        let der = dac_with_extension(&dac, &pai, &CERT_POLICIES_EXT);
+        let x509_dac = Cert::from_x509(&der).unwrap();
+        assert!(verify_dac_chain(&x509_dac, &pai.cert, Some(&paa.cert), NOW).is_ok());
+        let mut buf = [0u8; 1000];
+        let len = x509_dac.as_x509(&mut buf).unwrap();
+        assert_eq!(&buf[..len], der.as_slice());
+
+        // The unsupported critical extension isn't
+        let der = dac_with_extension(&dac, &pai, &CRITICAL_CERT_POLICIES_EXT);
+        assert_eq!(Cert::from_x509(&der).map(|_| ()), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn test_dac_chain_ids() {
+        let paa = paa(Some(0xFFF1));
+        let pai = pai(&paa, 0xFFF1, Some(0x8000));
+        let dac = dac(&pai, 0xFFF1, 0x8000);
+        assert!(verify_dac_chain(&dac.cert, &pai.cert, Some(&paa.cert), NOW).is_ok());
+
+        // A DAC of another product than its PAI
+        let other_product = self::dac(&pai, 0xFFF1, 0x8001);
+        assert_eq!(
+            verify_dac_chain(&other_product.cert, &pai.cert, Some(&paa.cert), NOW),
+            Err(Error::InvalidAttestation)
+        );
+        // A DAC of another vendor than its PAI
+        let other_vendor = self::dac(&pai, 0xFFF2, 0x8000);
+        assert_eq!(
+            verify_dac_chain(&other_vendor.cert, &pai.cert, Some(&paa.cert), NOW),
+            Err(Error::InvalidAttestation)
+        );
+        // A PAI of another vendor than its PAA
+        let other_pai = self::pai(&paa, 0xFFF2, None);
+        let dac = self::dac(&other_pai, 0xFFF2, 0x8000);
+        assert_eq!(
+            verify_dac_chain(&dac.cert, &other_pai.cert, Some(&paa.cert), NOW),
+            Err(Error::InvalidAttestation)
+        );
+    }
+
+    fn cert_declaration<'a>(product_ids: &'a [u16]) -> CertDeclaration<'a> {
+        CertDeclaration {
+            format_version: 1,
+            vendor_id: 0xFFF1,
+            product_ids: TLVArray::new(product_ids),
+            device_type_id: 0x16,
+            certificate_id: UtfStr::new(b"ZIG20142ZB330003-24"),
+            security_level: 0,
+            security_information: 0,
+            version_number: 1,
+            certification_type: 0,
+            dac_origin_vendor_id: None,
+            dac_origin_product_id: None,
+            authorized_paa_list: None,
+        }
+    }
+
+    #[test]
+    fn test_signed_cert_declaration() {
+        let signer = paa(None);
+        let key_id = signer.cert.get_subject_key_id().unwrap();
+        let mut buf = [0u8; 600];
+        let len = SignedCertDeclaration::encode(
+            &cert_declaration(&[0x8000, 0x8001]),
+            key_id,
+            &signer.key,
+            &mut buf,
+        )
+        .unwrap();
+
+        let signed = SignedCertDeclaration::new(&buf[..len]).unwrap();
+        assert_eq!(signed.get_signer_key_id(), key_id);
+        assert_eq!(signed.verify(signer.cert.get_pubkey()), Ok(()));
+        assert!(signed.verify(paa(None).cert.get_pubkey()).is_err());
+        let cd = signed.get_cert_declaration().unwrap();
+        assert_eq!(cd.vendor_id, 0xFFF1);
+        assert_eq!(cd.product_ids.iter().collect::<Vec<_>>(), [0x8000, 0x8001]);
+
+        // The signature is over the whole declaration
+        let at = buf[..len]
+            .windows(2)
+            .position(|w| w == [0x01, 0x80])
+            .unwrap();
+        buf[at] = 0x02;
+        let signed = SignedCertDeclaration::new(&buf[..len]).unwrap();
+        assert!(signed.verify(signer.cert.get_pubkey()).is_err());
+    }
+
+    #[test]
+    fn test_cert_declaration_check() {
+        let ids = AttestedIds {
+            vendor_id: 0xFFF1,
+            product_id: 0x8001,
+        };
+        let mut cd = cert_declaration(&[0x8000, 0x8001]);
+        assert_eq!(cd.check(&ids, None), Ok(()));
+        let other = AttestedIds {
+            product_id: 0x8002,
+            ..ids
+        };
+        assert_eq!(cd.check(&other, None), Err(Error::InvalidAttestation));
+        let other = AttestedIds {
+            vendor_id: 0xFFF2,
+            ..ids
+        };
+        assert_eq!(cd.check(&other, None), Err(Error::InvalidAttestation));
+
+        // The DAC is of the origin, rather than of the declaration's vendor and products
+        cd.dac_origin_vendor_id = Some(0xFFF2);
+        cd.dac_origin_product_id = Some(0x8002);
+        let origin = AttestedIds {
+            vendor_id: 0xFFF2,
+            product_id: 0x8002,
+        };
+        assert_eq!(cd.check(&origin, None), Ok(()));
+        assert_eq!(cd.check(&ids, None), Err(Error::InvalidAttestation));
+
+        let paa_ids = [OctetStr::new(&[1, 2, 3])];
+        cd.authorized_paa_list = Some(TLVArray::new(&paa_ids));
+        assert_eq!(cd.check(&origin, Some(&[1, 2, 3])), Ok(()));
+        assert_eq!(
+            cd.check(&origin, Some(&[4, 5, 6])),
+            Err(Error::InvalidAttestation)
+        );
+    }
+}
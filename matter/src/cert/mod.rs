@@ -15,17 +15,22 @@
  *    limitations under the License.
  */
 
-use std::fmt;
+use std::{convert::TryFrom, fmt};
 
 use crate::{
-    crypto::{CryptoKeyPair, KeyPair},
+    crypto::{CryptoKeyPair, KeyPair, Sha256, EC_SIGNATURE_LEN_BYTES, SHA256_HASH_LEN_BYTES},
     error::Error,
     tlv::{self, FromTLV, TLVArrayOwned, TLVElement, TLVWriter, TagType, ToTLV},
     utils::writebuf::WriteBuf,
 };
-use log::error;
+use log::{error, info};
 use num_derive::FromPrimitive;
+use rand::RngCore;
 
+use self::asn1_reader::{
+    ASN1Reader, TAG_BIT_STR, TAG_BOOL, TAG_INTEGER, TAG_OCTET_STR, TAG_OID, TAG_PRINTABLE_STR,
+    TAG_SEQ, TAG_SET, TAG_UTF8_STR,
+};
 pub use self::asn1_writer::ASN1Writer;
 use self::printer::CertPrinter;
 
@@ -136,25 +141,40 @@ fn encode_key_usage(key_usage: u16, w: &mut dyn CertConsumer) -> Result<(), Erro
     Ok(())
 }
 
+fn decode_key_usage(r: &mut ASN1Reader) -> Result<u16, Error> {
+    // The first byte is the number of unused bits, these are zeroes anyway
+    match r.read_expect(TAG_BIT_STR)? {
+        [_, first] => Ok(reverse_byte(*first) as u16),
+        [_, first, second] => Ok(reverse_byte(*first) as u16 | (reverse_byte(*second) as u16) << 8),
+        _ => Err(Error::InvalidData),
+    }
+}
+
+const OID_SERVER_AUTH: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+const OID_CLIENT_AUTH: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02];
+const OID_CODE_SIGN: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x03];
+const OID_EMAIL_PROT: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x04];
+const OID_TIMESTAMP: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x08];
+const OID_OCSP_SIGN: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+// The values of the extended key usages in the Matter certificate
+const EXT_KEY_USAGE_SERVER_AUTH: u8 = 1;
+const EXT_KEY_USAGE_CLIENT_AUTH: u8 = 2;
+// The index in this is the extended key usage's value in the Matter certificate
+const EXT_KEY_USAGE_ENCODING: [(&str, &[u8; 8]); 7] = [
+    ("", &[0; 8]),
+    ("ServerAuth", &OID_SERVER_AUTH),
+    ("ClientAuth", &OID_CLIENT_AUTH),
+    ("CodeSign", &OID_CODE_SIGN),
+    ("EmailProtection", &OID_EMAIL_PROT),
+    ("Timestamp", &OID_TIMESTAMP),
+    ("OCSPSign", &OID_OCSP_SIGN),
+];
+
 fn encode_extended_key_usage(
     list: &TLVArrayOwned<u8>,
     w: &mut dyn CertConsumer,
 ) -> Result<(), Error> {
-    const OID_SERVER_AUTH: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
-    const OID_CLIENT_AUTH: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02];
-    const OID_CODE_SIGN: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x03];
-    const OID_EMAIL_PROT: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x04];
-    const OID_TIMESTAMP: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x08];
-    const OID_OCSP_SIGN: [u8; 8] = [0x2B, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
-    let encoding = [
-        ("", &[0; 8]),
-        ("ServerAuth", &OID_SERVER_AUTH),
-        ("ClientAuth", &OID_CLIENT_AUTH),
-        ("CodeSign", &OID_CODE_SIGN),
-        ("EmailProtection", &OID_EMAIL_PROT),
-        ("Timestamp", &OID_TIMESTAMP),
-        ("OCSPSign", &OID_OCSP_SIGN),
-    ];
+    let encoding = EXT_KEY_USAGE_ENCODING;
 
     w.start_seq("")?;
     for t in list.iter() {
@@ -169,6 +189,24 @@ fn encode_extended_key_usage(
     Ok(())
 }
 
+fn decode_extended_key_usage(r: &mut ASN1Reader) -> Result<TLVArrayOwned<u8>, Error> {
+    let mut seq = r.enter(TAG_SEQ)?;
+    let mut list = Vec::new();
+    while !seq.is_empty() {
+        let oid = seq.read_expect(TAG_OID)?;
+        let t = EXT_KEY_USAGE_ENCODING
+            .iter()
+            .skip(1)
+            .position(|(_, o)| *o == oid)
+            .ok_or_else(|| {
+                error!("Unsupported extended key usage {:x?}", oid);
+                Error::Invalid
+            })?;
+        list.push(t as u8 + 1);
+    }
+    Ok(TLVArrayOwned::new(list))
+}
+
 #[derive(FromTLV, ToTLV, Default)]
 #[tlvargs(start = 1)]
 struct BasicConstraints {
@@ -188,6 +226,21 @@ impl BasicConstraints {
         }
         w.end_seq()
     }
+
+    fn decode(r: &mut ASN1Reader) -> Result<Self, Error> {
+        let mut seq = r.enter(TAG_SEQ)?;
+        let is_ca = if seq.peek_tag() == Some(TAG_BOOL) {
+            seq.bool()?
+        } else {
+            false
+        };
+        let path = if seq.is_empty() {
+            None
+        } else {
+            Some(u8::try_from(seq.u64()?).map_err(|_| Error::InvalidData)?)
+        };
+        Ok(Self { is_ca, path })
+    }
 }
 
 fn encode_extension_start(
@@ -209,6 +262,12 @@ fn encode_extension_end(w: &mut dyn CertConsumer) -> Result<(), Error> {
     w.end_seq()
 }
 
+const OID_BASIC_CONSTRAINTS: [u8; 3] = [0x55, 0x1D, 0x13];
+const OID_KEY_USAGE: [u8; 3] = [0x55, 0x1D, 0x0F];
+const OID_EXT_KEY_USAGE: [u8; 3] = [0x55, 0x1D, 0x25];
+const OID_SUBJ_KEY_IDENTIFIER: [u8; 3] = [0x55, 0x1D, 0x0E];
+const OID_AUTH_KEY_ID: [u8; 3] = [0x55, 0x1D, 0x23];
+
 #[derive(FromTLV, ToTLV, Default)]
 #[tlvargs(start = 1, datatype = "list")]
 struct Extensions {
@@ -222,12 +281,6 @@ struct Extensions {
 
 impl Extensions {
     fn encode(&self, w: &mut dyn CertConsumer) -> Result<(), Error> {
-        const OID_BASIC_CONSTRAINTS: [u8; 3] = [0x55, 0x1D, 0x13];
-        const OID_KEY_USAGE: [u8; 3] = [0x55, 0x1D, 0x0F];
-        const OID_EXT_KEY_USAGE: [u8; 3] = [0x55, 0x1D, 0x25];
-        const OID_SUBJ_KEY_IDENTIFIER: [u8; 3] = [0x55, 0x1D, 0x0E];
-        const OID_AUTH_KEY_ID: [u8; 3] = [0x55, 0x1D, 0x23];
-
         w.start_ctx("X509v3 extensions:", 3)?;
         w.start_seq("")?;
         if let Some(t) = &self.basic_const {
@@ -264,6 +317,37 @@ impl Extensions {
         w.end_ctx()?;
         Ok(())
     }
+
+    fn decode(r: &mut ASN1Reader) -> Result<Self, Error> {
+        let mut e = Self::default();
+        let mut seq = r.enter(0xA3)?.enter(TAG_SEQ)?;
+        while !seq.is_empty() {
+            let mut ext = seq.enter(TAG_SEQ)?;
+            let oid = ext.read_expect(TAG_OID)?;
+            // The criticality of the supported extensions is implied in the Matter certificate
+            let critical = matches!(ext.read_optional(TAG_BOOL)?, Some(b) if b != [0]);
+            let mut value = ASN1Reader::new(ext.read_expect(TAG_OCTET_STR)?);
+            if oid == OID_BASIC_CONSTRAINTS {
+                e.basic_const = Some(BasicConstraints::decode(&mut value)?);
+            } else if oid == OID_KEY_USAGE {
+                e.key_usage = Some(decode_key_usage(&mut value)?);
+            } else if oid == OID_EXT_KEY_USAGE {
+                e.ext_key_usage = Some(decode_extended_key_usage(&mut value)?);
+            } else if oid == OID_SUBJ_KEY_IDENTIFIER {
+                e.subj_key_id = Some(value.read_expect(TAG_OCTET_STR)?.to_vec());
+            } else if oid == OID_AUTH_KEY_ID {
+                let mut auth_key_id = value.enter(TAG_SEQ)?;
+                e.auth_key_id = Some(auth_key_id.read_expect(0x80)?.to_vec());
+            } else if critical {
+                error!("Unsupported critical extension {:x?}", oid);
+                return Err(Error::Invalid);
+            } else {
+                // This is only in the DER certificate, that the signature is verified over
+                info!("Skipping unsupported extension {:x?}", oid);
+            }
+        }
+        Ok(e)
+    }
 }
 const MAX_DN_ENTRIES: usize = 5;
 
@@ -291,15 +375,19 @@ enum DnTags {
     RootCaId = 20,
     FabricId = 21,
     NocCat = 22,
+    // The ids of the attestation certificates, which aren't in the Matter certificates
+    VendorId = 23,
+    ProductId = 24,
 }
 
+#[derive(Clone)]
 enum DistNameValue {
     Uint(u64),
     Utf8Str(Vec<u8>),
     PrintableStr(Vec<u8>),
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct DistNames {
     // The order in which the DNs arrive is important, as the signing
     // requires that the ASN1 notation retains the same order
@@ -379,94 +467,111 @@ impl ToTLV for DistNames {
     }
 }
 
+const OID_COMMON_NAME: [u8; 3] = [0x55_u8, 0x04, 0x03];
+const OID_SURNAME: [u8; 3] = [0x55_u8, 0x04, 0x04];
+const OID_SERIAL_NUMBER: [u8; 3] = [0x55_u8, 0x04, 0x05];
+const OID_COUNTRY_NAME: [u8; 3] = [0x55_u8, 0x04, 0x06];
+const OID_LOCALITY_NAME: [u8; 3] = [0x55_u8, 0x04, 0x07];
+const OID_STATE_NAME: [u8; 3] = [0x55_u8, 0x04, 0x08];
+const OID_ORGANIZATION_NAME: [u8; 3] = [0x55_u8, 0x04, 0x0A];
+const OID_ORGANIZATIONAL_UNIT_NAME: [u8; 3] = [0x55_u8, 0x04, 0x0B];
+const OID_TITLE: [u8; 3] = [0x55_u8, 0x04, 0x0C];
+const OID_NAME: [u8; 3] = [0x55_u8, 0x04, 0x29];
+const OID_GIVEN_NAME: [u8; 3] = [0x55_u8, 0x04, 0x2A];
+const OID_INITIALS: [u8; 3] = [0x55_u8, 0x04, 0x2B];
+const OID_GENERATION_QUALIFIER: [u8; 3] = [0x55_u8, 0x04, 0x2C];
+const OID_DN_QUALIFIER: [u8; 3] = [0x55_u8, 0x04, 0x2E];
+const OID_PSEUDONYM: [u8; 3] = [0x55_u8, 0x04, 0x41];
+const OID_DOMAIN_COMPONENT: [u8; 10] = [
+    0x09_u8, 0x92, 0x26, 0x89, 0x93, 0xF2, 0x2C, 0x64, 0x01, 0x19,
+];
+const OID_MATTER_NODE_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x01,
+];
+const OID_MATTER_FW_SIGNING_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x02,
+];
+const OID_MATTER_ICAC_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x03,
+];
+const OID_MATTER_RCAC_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x04,
+];
+const OID_MATTER_FABRIC_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x05,
+];
+const OID_MATTER_CASE_AUTH_TAG: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x06,
+];
+
+const OID_MATTER_VENDOR_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x02, 0x01,
+];
+const OID_MATTER_PRODUCT_ID: [u8; 10] = [
+    0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x02, 0x02,
+];
+
+const DN_ENCODING: [(&str, &[u8], Option<IntToStringLen>); 24] = [
+    ("Common Name:", &OID_COMMON_NAME, None),
+    ("Surname:", &OID_SURNAME, None),
+    ("Serial Number", &OID_SERIAL_NUMBER, None),
+    ("Country Name", &OID_COUNTRY_NAME, None),
+    ("Locality name", &OID_LOCALITY_NAME, None),
+    ("State Name", &OID_STATE_NAME, None),
+    ("Org Name", &OID_ORGANIZATION_NAME, None),
+    ("OU Name", &OID_ORGANIZATIONAL_UNIT_NAME, None),
+    ("Title", &OID_TITLE, None),
+    ("Name", &OID_NAME, None),
+    ("Given Name", &OID_GIVEN_NAME, None),
+    ("Initials", &OID_INITIALS, None),
+    ("Gen Qualifier", &OID_GENERATION_QUALIFIER, None),
+    ("DN Qualifier", &OID_DN_QUALIFIER, None),
+    ("Pseudonym", &OID_PSEUDONYM, None),
+    ("Domain Component", &OID_DOMAIN_COMPONENT, None),
+    (
+        "Chip Node Id:",
+        &OID_MATTER_NODE_ID,
+        Some(IntToStringLen::Len16),
+    ),
+    (
+        "Chip Firmware Signing Id:",
+        &OID_MATTER_FW_SIGNING_ID,
+        Some(IntToStringLen::Len16),
+    ),
+    (
+        "Chip ICA Id:",
+        &OID_MATTER_ICAC_ID,
+        Some(IntToStringLen::Len16),
+    ),
+    (
+        "Chip Root CA Id:",
+        &OID_MATTER_RCAC_ID,
+        Some(IntToStringLen::Len16),
+    ),
+    (
+        "Chip Fabric Id:",
+        &OID_MATTER_FABRIC_ID,
+        Some(IntToStringLen::Len16),
+    ),
+    (
+        "Chip NOC CAT Id:",
+        &OID_MATTER_CASE_AUTH_TAG,
+        Some(IntToStringLen::Len8),
+    ),
+    (
+        "Chip Vendor Id:",
+        &OID_MATTER_VENDOR_ID,
+        Some(IntToStringLen::Len4),
+    ),
+    (
+        "Chip Product Id:",
+        &OID_MATTER_PRODUCT_ID,
+        Some(IntToStringLen::Len4),
+    ),
+];
+
 impl DistNames {
     fn encode(&self, tag: &str, w: &mut dyn CertConsumer) -> Result<(), Error> {
-        const OID_COMMON_NAME: [u8; 3] = [0x55_u8, 0x04, 0x03];
-        const OID_SURNAME: [u8; 3] = [0x55_u8, 0x04, 0x04];
-        const OID_SERIAL_NUMBER: [u8; 3] = [0x55_u8, 0x04, 0x05];
-        const OID_COUNTRY_NAME: [u8; 3] = [0x55_u8, 0x04, 0x06];
-        const OID_LOCALITY_NAME: [u8; 3] = [0x55_u8, 0x04, 0x07];
-        const OID_STATE_NAME: [u8; 3] = [0x55_u8, 0x04, 0x08];
-        const OID_ORGANIZATION_NAME: [u8; 3] = [0x55_u8, 0x04, 0x0A];
-        const OID_ORGANIZATIONAL_UNIT_NAME: [u8; 3] = [0x55_u8, 0x04, 0x0B];
-        const OID_TITLE: [u8; 3] = [0x55_u8, 0x04, 0x0C];
-        const OID_NAME: [u8; 3] = [0x55_u8, 0x04, 0x29];
-        const OID_GIVEN_NAME: [u8; 3] = [0x55_u8, 0x04, 0x2A];
-        const OID_INITIALS: [u8; 3] = [0x55_u8, 0x04, 0x2B];
-        const OID_GENERATION_QUALIFIER: [u8; 3] = [0x55_u8, 0x04, 0x2C];
-        const OID_DN_QUALIFIER: [u8; 3] = [0x55_u8, 0x04, 0x2E];
-        const OID_PSEUDONYM: [u8; 3] = [0x55_u8, 0x04, 0x41];
-        const OID_DOMAIN_COMPONENT: [u8; 10] = [
-            0x09_u8, 0x92, 0x26, 0x89, 0x93, 0xF2, 0x2C, 0x64, 0x01, 0x19,
-        ];
-        const OID_MATTER_NODE_ID: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x01,
-        ];
-        const OID_MATTER_FW_SIGNING_ID: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x02,
-        ];
-        const OID_MATTER_ICAC_ID: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x03,
-        ];
-        const OID_MATTER_RCAC_ID: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x04,
-        ];
-        const OID_MATTER_FABRIC_ID: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x05,
-        ];
-        const OID_MATTER_CASE_AUTH_TAG: [u8; 10] = [
-            0x2B_u8, 0x06, 0x01, 0x04, 0x01, 0x82, 0xA2, 0x7C, 0x01, 0x06,
-        ];
-
-        const DN_ENCODING: [(&str, &[u8], Option<IntToStringLen>); 22] = [
-            ("Common Name:", &OID_COMMON_NAME, None),
-            ("Surname:", &OID_SURNAME, None),
-            ("Serial Number", &OID_SERIAL_NUMBER, None),
-            ("Country Name", &OID_COUNTRY_NAME, None),
-            ("Locality name", &OID_LOCALITY_NAME, None),
-            ("State Name", &OID_STATE_NAME, None),
-            ("Org Name", &OID_ORGANIZATION_NAME, None),
-            ("OU Name", &OID_ORGANIZATIONAL_UNIT_NAME, None),
-            ("Title", &OID_TITLE, None),
-            ("Name", &OID_NAME, None),
-            ("Given Name", &OID_GIVEN_NAME, None),
-            ("Initials", &OID_INITIALS, None),
-            ("Gen Qualifier", &OID_GENERATION_QUALIFIER, None),
-            ("DN Qualifier", &OID_DN_QUALIFIER, None),
-            ("Pseudonym", &OID_PSEUDONYM, None),
-            ("Domain Component", &OID_DOMAIN_COMPONENT, None),
-            (
-                "Chip Node Id:",
-                &OID_MATTER_NODE_ID,
-                Some(IntToStringLen::Len16),
-            ),
-            (
-                "Chip Firmware Signing Id:",
-                &OID_MATTER_FW_SIGNING_ID,
-                Some(IntToStringLen::Len16),
-            ),
-            (
-                "Chip ICA Id:",
-                &OID_MATTER_ICAC_ID,
-                Some(IntToStringLen::Len16),
-            ),
-            (
-                "Chip Root CA Id:",
-                &OID_MATTER_RCAC_ID,
-                Some(IntToStringLen::Len16),
-            ),
-            (
-                "Chip Fabric Id:",
-                &OID_MATTER_FABRIC_ID,
-                Some(IntToStringLen::Len16),
-            ),
-            (
-                "Chip NOC CAT Id:",
-                &OID_MATTER_CASE_AUTH_TAG,
-                Some(IntToStringLen::Len8),
-            ),
-        ];
-
         w.start_seq(tag)?;
         for (id, value) in &self.dn {
             let tag: Option<DnTags> = num::FromPrimitive::from_u8(*id);
@@ -487,6 +592,36 @@ impl DistNames {
         w.end_seq()?;
         Ok(())
     }
+
+    fn decode(r: &mut ASN1Reader) -> Result<Self, Error> {
+        let mut seq = r.enter(TAG_SEQ)?;
+        let mut dn = Vec::with_capacity(MAX_DN_ENTRIES);
+        while !seq.is_empty() {
+            let mut attr = seq.enter(TAG_SET)?.enter(TAG_SEQ)?;
+            let oid = attr.read_expect(TAG_OID)?;
+            let index = match DN_ENCODING.iter().position(|(_, o, _)| *o == oid) {
+                Some(index) => index,
+                None => {
+                    // This is only in the DER certificate, that the signature is verified over
+                    info!("Skipping non Matter DN {:x?}", oid);
+                    continue;
+                }
+            };
+            let (tag, value) = attr.read()?;
+            let value = match (tag, DN_ENCODING[index].2) {
+                (TAG_UTF8_STR, Some(_)) => {
+                    let value = String::from_utf8(value.to_vec())?;
+                    let value = u64::from_str_radix(&value, 16).map_err(|_| Error::InvalidData)?;
+                    DistNameValue::Uint(value)
+                }
+                (TAG_UTF8_STR, None) => DistNameValue::Utf8Str(value.to_vec()),
+                (TAG_PRINTABLE_STR, None) => DistNameValue::PrintableStr(value.to_vec()),
+                _ => return Err(Error::InvalidData),
+            };
+            dn.push((index as u8 + 1, value));
+        }
+        Ok(Self { dn })
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -494,6 +629,7 @@ impl DistNames {
 enum IntToStringLen {
     Len16,
     Len8,
+    Len4,
 }
 
 fn encode_dn_value(
@@ -511,6 +647,7 @@ fn encode_dn_value(
         DistNameValue::Uint(v) => match expected_len {
             Some(IntToStringLen::Len16) => w.utf8str("", format!("{:016X}", v).as_str())?,
             Some(IntToStringLen::Len8) => w.utf8str("", format!("{:08X}", v).as_str())?,
+            Some(IntToStringLen::Len4) => w.utf8str("", format!("{:04X}", v).as_str())?,
             _ => {
                 error!("Invalid encoding");
                 return Err(Error::Invalid);
@@ -543,6 +680,161 @@ pub struct Cert {
     pubkey: Vec<u8>,
     extensions: Extensions,
     signature: Vec<u8>,
+    der_tbs: DerTbs,
+}
+
+/// The signed part of a certificate that was converted from X.509, as it was encoded
+///
+/// The DER certificate may have extensions and DNs that the Matter certificate doesn't, so
+/// it is this part that the signature is verified over. It isn't a part of the Matter
+/// certificate.
+#[derive(Default)]
+struct DerTbs(Option<Vec<u8>>);
+
+impl<'a> FromTLV<'a> for DerTbs {
+    fn from_tlv(_t: &TLVElement<'a>) -> Result<Self, Error> {
+        Ok(Self::default())
+    }
+
+    fn tlv_not_found() -> Result<Self, Error> {
+        Ok(Self::default())
+    }
+}
+
+impl ToTLV for DerTbs {
+    fn to_tlv(&self, _tw: &mut TLVWriter, _tag: TagType) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The subject of a certificate that is issued with [Cert::issue]
+pub enum CertSubject<'a> {
+    /// A Root CA Certificate
+    Rcac { rcac_id: u64, fabric_id: u64 },
+    /// A Node Operational Certificate
+    Noc {
+        node_id: u64,
+        fabric_id: u64,
+        cat_ids: &'a [u32],
+    },
+    /// A Product Attestation Authority Certificate, of a vendor or not
+    Paa { vendor_id: Option<u16> },
+    /// A Product Attestation Intermediate Certificate, of a vendor's products or only one
+    /// of them
+    Pai {
+        vendor_id: u16,
+        product_id: Option<u16>,
+    },
+    /// A Device Attestation Certificate
+    Dac { vendor_id: u16, product_id: u16 },
+}
+
+impl<'a> CertSubject<'a> {
+    fn dist_names(&self) -> DistNames {
+        let mut dn = Vec::with_capacity(MAX_DN_ENTRIES);
+        match self {
+            CertSubject::Rcac { rcac_id, fabric_id } => {
+                dn.push((DnTags::RootCaId as u8, DistNameValue::Uint(*rcac_id)));
+                dn.push((DnTags::FabricId as u8, DistNameValue::Uint(*fabric_id)));
+            }
+            CertSubject::Noc {
+                node_id,
+                fabric_id,
+                cat_ids,
+            } => {
+                dn.push((DnTags::NodeId as u8, DistNameValue::Uint(*node_id)));
+                dn.push((DnTags::FabricId as u8, DistNameValue::Uint(*fabric_id)));
+                for cat_id in cat_ids.iter() {
+                    dn.push((DnTags::NocCat as u8, DistNameValue::Uint(*cat_id as u64)));
+                }
+            }
+            CertSubject::Paa { vendor_id } => {
+                if let Some(vendor_id) = vendor_id {
+                    dn.push((
+                        DnTags::VendorId as u8,
+                        DistNameValue::Uint(*vendor_id as u64),
+                    ));
+                }
+            }
+            CertSubject::Pai {
+                vendor_id,
+                product_id,
+            } => {
+                dn.push((
+                    DnTags::VendorId as u8,
+                    DistNameValue::Uint(*vendor_id as u64),
+                ));
+                if let Some(product_id) = product_id {
+                    dn.push((
+                        DnTags::ProductId as u8,
+                        DistNameValue::Uint(*product_id as u64),
+                    ));
+                }
+            }
+            CertSubject::Dac {
+                vendor_id,
+                product_id,
+            } => {
+                dn.push((
+                    DnTags::VendorId as u8,
+                    DistNameValue::Uint(*vendor_id as u64),
+                ));
+                dn.push((
+                    DnTags::ProductId as u8,
+                    DistNameValue::Uint(*product_id as u64),
+                ));
+            }
+        }
+        DistNames { dn }
+    }
+
+    fn extensions(&self, subj_key_id: Vec<u8>, auth_key_id: Vec<u8>) -> Extensions {
+        match self {
+            CertSubject::Rcac { .. } => Extensions {
+                basic_const: Some(BasicConstraints {
+                    is_ca: true,
+                    path: None,
+                }),
+                key_usage: Some(KEY_USAGE_KEY_CERT_SIGN | KEY_USAGE_CRL_SIGN),
+                subj_key_id: Some(subj_key_id),
+                auth_key_id: Some(auth_key_id),
+                ..Default::default()
+            },
+            CertSubject::Noc { .. } => Extensions {
+                basic_const: Some(BasicConstraints::default()),
+                key_usage: Some(KEY_USAGE_DIGITAL_SIGN),
+                ext_key_usage: Some(TLVArrayOwned::new(vec![
+                    EXT_KEY_USAGE_CLIENT_AUTH,
+                    EXT_KEY_USAGE_SERVER_AUTH,
+                ])),
+                subj_key_id: Some(subj_key_id),
+                auth_key_id: Some(auth_key_id),
+                ..Default::default()
+            },
+            // The PAA issues the PAIs, which issue the DACs
+            CertSubject::Paa { .. } | CertSubject::Pai { .. } => Extensions {
+                basic_const: Some(BasicConstraints {
+                    is_ca: true,
+                    path: Some(if let CertSubject::Paa { .. } = self {
+                        1
+                    } else {
+                        0
+                    }),
+                }),
+                key_usage: Some(KEY_USAGE_KEY_CERT_SIGN | KEY_USAGE_CRL_SIGN),
+                subj_key_id: Some(subj_key_id),
+                auth_key_id: Some(auth_key_id),
+                ..Default::default()
+            },
+            CertSubject::Dac { .. } => Extensions {
+                basic_const: Some(BasicConstraints::default()),
+                key_usage: Some(KEY_USAGE_DIGITAL_SIGN),
+                subj_key_id: Some(subj_key_id),
+                auth_key_id: Some(auth_key_id),
+                ..Default::default()
+            },
+        }
+    }
 }
 
 // TODO: Instead of parsing the TLVs everytime, we should just cache this, but the encoding
@@ -553,6 +845,65 @@ impl Cert {
         Cert::from_tlv(&root)
     }
 
+    /// Issue a certificate to the subject's public key, valid between the given times (in
+    /// seconds since the Matter epoch). The certificate is signed with the issuer's key, and
+    /// is self-signed if there is no issuer certificate.
+    pub fn issue(
+        subject: &CertSubject,
+        pubkey: &[u8],
+        issuer: Option<&Cert>,
+        issuer_key: &KeyPair,
+        not_before: u32,
+        not_after: u32,
+    ) -> Result<Self, Error> {
+        // The serial number is a positive integer, so the top bit has to be clear
+        let mut serial_no = vec![0u8; 8];
+        rand::thread_rng().fill_bytes(&mut serial_no);
+        serial_no[0] = (serial_no[0] & 0x7f) | 0x01;
+
+        let subj_key_id = Cert::key_id(pubkey)?;
+        let (issuer_dn, auth_key_id) = if let Some(issuer) = issuer {
+            (
+                issuer.subject.clone(),
+                issuer.get_subject_key_id()?.to_vec(),
+            )
+        } else {
+            (subject.dist_names(), subj_key_id.clone())
+        };
+
+        let mut cert = Self {
+            serial_no,
+            sign_algo: SignAlgoValue::ECDSAWithSHA256 as u8,
+            issuer: issuer_dn,
+            not_before,
+            not_after,
+            subject: subject.dist_names(),
+            pubkey_algo: PubKeyAlgoValue::EcPubKey as u8,
+            ec_curve_id: EcCurveIdValue::Prime256V1 as u8,
+            pubkey: pubkey.to_vec(),
+            extensions: subject.extensions(subj_key_id, auth_key_id),
+            signature: Vec::new(),
+            der_tbs: DerTbs::default(),
+        };
+
+        let mut asn1 = [0u8; MAX_ASN1_CERT_SIZE];
+        let len = cert.as_asn1(&mut asn1)?;
+        let mut signature = [0u8; EC_SIGNATURE_LEN_BYTES];
+        issuer_key.sign_msg(&asn1[..len], &mut signature)?;
+        cert.signature = signature.to_vec();
+        Ok(cert)
+    }
+
+    /// The key identifier of a public key, this is the first 160 bits of its SHA256 hash
+    fn key_id(pubkey: &[u8]) -> Result<Vec<u8>, Error> {
+        const KEY_ID_LEN: usize = 20;
+        let mut hash = [0u8; SHA256_HASH_LEN_BYTES];
+        let mut sha256 = Sha256::new()?;
+        sha256.update(pubkey)?;
+        sha256.finish(&mut hash)?;
+        Ok(hash[..KEY_ID_LEN].to_vec())
+    }
+
     pub fn get_node_id(&self) -> Result<u64, Error> {
         self.subject.u64(DnTags::NodeId).ok_or(Error::NoNodeId)
     }
@@ -565,6 +916,20 @@ impl Cert {
         self.subject.u64(DnTags::FabricId).ok_or(Error::NoFabricId)
     }
 
+    /// The vendor id of an attestation certificate
+    pub fn get_vendor_id(&self) -> Option<u16> {
+        self.subject
+            .u64(DnTags::VendorId)
+            .and_then(|v| u16::try_from(v).ok())
+    }
+
+    /// The product id of an attestation certificate
+    pub fn get_product_id(&self) -> Option<u16> {
+        self.subject
+            .u64(DnTags::ProductId)
+            .and_then(|v| u16::try_from(v).ok())
+    }
+
     pub fn get_pubkey(&self) -> &[u8] {
         self.pubkey.as_slice()
     }
@@ -590,6 +955,118 @@ impl Cert {
         self.signature.as_slice()
     }
 
+    /// The start of the validity period, in seconds since the Matter epoch
+    pub fn get_not_before(&self) -> u32 {
+        self.not_before
+    }
+
+    /// The end of the validity period, in seconds since the Matter epoch. This is 0 if the
+    /// certificate has no well-defined expiration date.
+    pub fn get_not_after(&self) -> u32 {
+        self.not_after
+    }
+
+    pub fn is_ca(&self) -> bool {
+        matches!(&self.extensions.basic_const, Some(b) if b.is_ca)
+    }
+
+    fn check_validity(&self, time: CertTime) -> Result<(), Error> {
+        let now = match time {
+            CertTime::Utc(now) => {
+                if now < self.not_before {
+                    error!(
+                        "Certificate not valid before {}, now {}",
+                        self.not_before, now
+                    );
+                    return Err(Error::CertNotYetValid);
+                }
+                now
+            }
+            // The current time is likely later than this, so it can't tell if the certificate
+            // is valid yet
+            CertTime::LastKnownGood(now) => now,
+        };
+        if self.not_after != 0 && now > self.not_after {
+            error!(
+                "Certificate not valid after {}, now {}",
+                self.not_after, now
+            );
+            return Err(Error::CertExpired);
+        }
+        Ok(())
+    }
+
+    // An issuer in the chain: a CA that can sign certificates, with at most its path length
+    // of CAs between it and the start of the chain
+    fn check_issuer_usage(&self, cas_below: usize) -> Result<(), Error> {
+        let basic_const = self
+            .extensions
+            .basic_const
+            .as_ref()
+            .ok_or(Error::InvalidCertUsage)?;
+        let key_usage = self.extensions.key_usage.unwrap_or_default();
+        if !basic_const.is_ca || key_usage & KEY_USAGE_KEY_CERT_SIGN == 0 {
+            error!(
+                "Issuer certificate isn't a CA: {:x?}",
+                self.get_subject_key_id()
+            );
+            return Err(Error::InvalidCertUsage);
+        }
+        if matches!(basic_const.path, Some(len) if cas_below > len as usize) {
+            error!(
+                "Path length constraint of {:x?} exceeded",
+                self.get_subject_key_id()
+            );
+            return Err(Error::InvalidCertUsage);
+        }
+        Ok(())
+    }
+
+    // The certificate at the start of the chain: either a CA, or a NOC that is used for
+    // signing, as both the client and the server of CASE
+    fn check_leaf_usage(&self) -> Result<(), Error> {
+        if self.is_ca() {
+            return self.check_issuer_usage(0);
+        }
+        let key_usage = self.extensions.key_usage.unwrap_or_default();
+        let ext_key_usage = self.extensions.ext_key_usage.as_ref();
+        let has_ext_key_usage = |usage: u8| match ext_key_usage {
+            Some(list) => list.iter().any(|u| *u == usage),
+            None => false,
+        };
+        if self.extensions.basic_const.is_none()
+            || key_usage & KEY_USAGE_DIGITAL_SIGN == 0
+            || key_usage & (KEY_USAGE_KEY_CERT_SIGN | KEY_USAGE_CRL_SIGN) != 0
+            || !has_ext_key_usage(EXT_KEY_USAGE_CLIENT_AUTH)
+            || !has_ext_key_usage(EXT_KEY_USAGE_SERVER_AUTH)
+        {
+            error!(
+                "Certificate can't be used as a NOC: {:x?}",
+                self.get_subject_key_id()
+            );
+            return Err(Error::InvalidCertUsage);
+        }
+        Ok(())
+    }
+
+    // The DAC at the start of an attestation chain: a certificate that is only used for
+    // signing
+    fn check_dac_usage(&self) -> Result<(), Error> {
+        let key_usage = self.extensions.key_usage.unwrap_or_default();
+        if self.is_ca()
+            || self.extensions.basic_const.is_none()
+            || key_usage & KEY_USAGE_DIGITAL_SIGN == 0
+            || key_usage & (KEY_USAGE_KEY_CERT_SIGN | KEY_USAGE_CRL_SIGN) != 0
+        {
+            error!(
+                "Certificate can't be used as a DAC: {:x?}",
+                self.get_subject_key_id()
+            );
+            return Err(Error::InvalidCertUsage);
+        }
+        Ok(())
+    }
+
     pub fn as_tlv(&self, buf: &mut [u8]) -> Result<usize, Error> {
         let mut wb = WriteBuf::new(buf, buf.len());
         let mut tw = TLVWriter::new(&mut wb);
@@ -598,13 +1075,147 @@ impl Cert {
     }
 
     pub fn as_asn1(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if let DerTbs(Some(tbs)) = &self.der_tbs {
+            let dst = buf.get_mut(..tbs.len()).ok_or(Error::NoSpace)?;
+            dst.copy_from_slice(tbs);
+            return Ok(tbs.len());
+        }
         let mut w = ASN1Writer::new(buf);
         self.encode(&mut w)?;
         Ok(w.as_slice().len())
     }
 
-    pub fn verify_chain_start(&self) -> CertVerifier {
-        CertVerifier::new(self)
+    /// The certificate in the X.509 DER format. Unlike [Cert::as_asn1], which is only the
+    /// part that is signed, this has the signature too.
+    pub fn as_x509(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut tbs = [0u8; MAX_ASN1_CERT_SIZE];
+        let tbs_len = self.as_asn1(&mut tbs)?;
+        let mut signature = [0u8; MAX_DER_SIGNATURE_SIZE];
+        let signature_len = self.signature_as_asn1(&mut signature)?;
+
+        let mut w = ASN1Writer::new(buf);
+        w.start_seq("")?;
+        w.raw(&tbs[..tbs_len])?;
+        w.start_seq("")?;
+        w.oid("", &OID_ECDSA_WITH_SHA256)?;
+        w.end_seq()?;
+        w.bitstr("", false, &signature[..signature_len])?;
+        w.end_seq()?;
+        Ok(w.as_slice().len())
+    }
+
+    /// Convert an X.509 certificate in the DER format. Only the certificates that can be
+    /// represented as Matter certificates are supported, though the non-critical extensions
+    /// and the DNs that the Matter certificates don't have are skipped.
+    pub fn from_x509(der: &[u8]) -> Result<Self, Error> {
+        let mut cert = ASN1Reader::new(der).enter(TAG_SEQ)?;
+        let der_tbs = cert.read_raw(TAG_SEQ)?;
+        let mut tbs = ASN1Reader::new(der_tbs).enter(TAG_SEQ)?;
+        if tbs.enter(0xA0)?.u64()? != 2 {
+            error!("Only X.509 v3 certificates are supported");
+            return Err(Error::Invalid);
+        }
+        let serial_no = tbs.read_expect(TAG_INTEGER)?.to_vec();
+        let sign_algo = Self::decode_sign_algo(&mut tbs)?;
+        let issuer = DistNames::decode(&mut tbs)?;
+        let mut validity = tbs.enter(TAG_SEQ)?;
+        let not_before = validity.time()?;
+        let not_after = validity.time()?;
+        let subject = DistNames::decode(&mut tbs)?;
+
+        let mut pubkey_info = tbs.enter(TAG_SEQ)?;
+        let mut algo = pubkey_info.enter(TAG_SEQ)?;
+        if algo.read_expect(TAG_OID)? != OID_PUB_KEY_ECPUBKEY
+            || algo.read_expect(TAG_OID)? != OID_EC_TYPE_PRIME256V1
+        {
+            error!("Only the Prime256v1 EC public keys are supported");
+            return Err(Error::Invalid);
+        }
+        let pubkey = pubkey_info.bitstr()?.to_vec();
+        let extensions = Extensions::decode(&mut tbs)?;
+
+        Self::decode_sign_algo(&mut cert)?;
+        let signature = Self::decode_signature(cert.bitstr()?)?;
+
+        Ok(Self {
+            serial_no,
+            sign_algo,
+            issuer,
+            not_before,
+            not_after,
+            subject,
+            pubkey_algo: PubKeyAlgoValue::EcPubKey as u8,
+            ec_curve_id: EcCurveIdValue::Prime256V1 as u8,
+            pubkey,
+            extensions,
+            signature,
+            der_tbs: DerTbs(Some(der_tbs.to_vec())),
+        })
+    }
+
+    fn decode_sign_algo(r: &mut ASN1Reader) -> Result<u8, Error> {
+        if r.enter(TAG_SEQ)?.read_expect(TAG_OID)? != OID_ECDSA_WITH_SHA256 {
+            error!("Only the ECDSA with SHA256 signatures are supported");
+            return Err(Error::Invalid);
+        }
+        Ok(SignAlgoValue::ECDSAWithSHA256 as u8)
+    }
+
+    // The signature in the X.509 certificate is the sequence of the r and s integers, while
+    // the Matter certificate has them as fixed size, big-endian values
+    fn signature_as_asn1(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        Self::encode_signature(&self.signature, buf)
+    }
+
+    fn encode_signature(signature: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+        if signature.len() != EC_SIGNATURE_LEN_BYTES {
+            return Err(Error::Invalid);
+        }
+        let mut w = ASN1Writer::new(buf);
+        w.start_seq("")?;
+        for half in signature.chunks(EC_SIGNATURE_LEN_BYTES / 2) {
+            // The integers are minimally encoded, and positive
+            let start = half.iter().position(|b| *b != 0).unwrap_or(half.len() - 1);
+            let mut int = Vec::with_capacity(half.len() + 1);
+            if half[start] & 0x80 != 0 {
+                int.push(0);
+            }
+            int.extend_from_slice(&half[start..]);
+            w.integer("", &int)?;
+        }
+        w.end_seq()?;
+        Ok(w.as_slice().len())
+    }
+
+    fn decode_signature(der: &[u8]) -> Result<Vec<u8>, Error> {
+        const HALF_LEN: usize = EC_SIGNATURE_LEN_BYTES / 2;
+        let mut seq = ASN1Reader::new(der).enter(TAG_SEQ)?;
+        let mut signature = vec![0u8; EC_SIGNATURE_LEN_BYTES];
+        for half in signature.chunks_mut(HALF_LEN) {
+            let int = seq.read_expect(TAG_INTEGER)?;
+            let start = int.iter().position(|b| *b != 0).unwrap_or(int.len());
+            let int = &int[start..];
+            if int.len() > HALF_LEN {
+                return Err(Error::InvalidData);
+            }
+            half[HALF_LEN - int.len()..].copy_from_slice(int);
+        }
+        Ok(signature)
+    }
+
+    /// Start verifying the chain of certificates from this one up to the root, with the
+    /// validity periods checked against the given time
+    pub fn verify_chain_start(&self, time: CertTime) -> CertVerifier {
+        CertVerifier::new(self, time)
+    }
+
+    /// Start verifying the device attestation chain from this DAC up to the PAA, with the
+    /// validity periods checked against the given time
+    pub fn verify_attestation_start(&self, time: CertTime) -> CertVerifier<'_> {
+        CertVerifier {
+            attestation: true,
+            ..CertVerifier::new(self, time)
+        }
     }
 
     fn encode(&self, w: &mut dyn CertConsumer) -> Result<(), Error> {
@@ -627,7 +1238,11 @@ impl Cert {
 
         w.start_seq("Validity:")?;
         w.utctime("Not Before:", self.not_before)?;
-        w.utctime("Not After:", self.not_after)?;
+        if self.not_after == 0 {
+            w.no_expiry_time("Not After:")?;
+        } else {
+            w.utctime("Not After:", self.not_after)?;
+        }
         w.end_seq()?;
 
         self.subject.encode("Subject:", w)?;
@@ -666,17 +1281,103 @@ impl fmt::Display for Cert {
     }
 }
 
+/// The time that the validity periods of the certificates are checked against
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CertTime {
+    /// The current UTC time from a trusted source, in seconds since the Matter epoch
+    Utc(u32),
+    /// The Last Known Good UTC Time, for when the current time isn't known. The
+    /// certificates that expired before it are rejected, but not the ones that only become
+    /// valid after it.
+    LastKnownGood(u32),
+}
+
+impl CertTime {
+    /// The time from a clock: its current UTC time if it is known, otherwise the Last Known
+    /// Good UTC Time
+    ///
+    /// A current time that is earlier than the Last Known Good UTC Time can't be right, so
+    /// it isn't used either.
+    pub fn from_clock(now: Option<u32>, last_known_good: u32) -> Self {
+        match now {
+            Some(now) if now >= last_known_good => CertTime::Utc(now),
+            _ => CertTime::LastKnownGood(last_known_good),
+        }
+    }
+}
+
+/// Verifies a chain of certificates, one issuer at a time, up to a self-signed root
+///
+/// Besides the signatures, every certificate has to be in its validity period, name its
+/// issuer through the authority key id, and be allowed its place in the chain: the issuers
+/// are CAs within their path length constraints, and the certificate at the start is
+/// either a CA or a NOC, or the DAC of a device attestation chain.
 pub struct CertVerifier<'a> {
     cert: &'a Cert,
+    time: CertTime,
+    // Whether this is a device attestation chain, that starts from a DAC
+    attestation: bool,
+    // Whether the certificate is the one that the chain starts from
+    leaf: bool,
+    // The number of CAs between the start of the chain and the certificate
+    cas_below: usize,
 }
 
 impl<'a> CertVerifier<'a> {
-    pub fn new(cert: &'a Cert) -> Self {
-        Self { cert }
+    pub fn new(cert: &'a Cert, time: CertTime) -> Self {
+        Self {
+            cert,
+            time,
+            attestation: false,
+            leaf: true,
+            cas_below: 0,
+        }
     }
 
     pub fn add_cert(self, parent: &'a Cert) -> Result<CertVerifier<'a>, Error> {
-        if !self.cert.is_authority(parent)? {
+        if self.leaf {
+            self.check_leaf_usage()?;
+        }
+        let cas_below = if self.leaf { 0 } else { self.cas_below + 1 };
+        parent.check_issuer_usage(cas_below)?;
+        self.verify_issued_by(parent)?;
+        Ok(CertVerifier {
+            cert: parent,
+            time: self.time,
+            attestation: self.attestation,
+            leaf: false,
+            cas_below,
+        })
+    }
+
+    pub fn finalise(self) -> Result<(), Error> {
+        // The chain has to end at a root CA, which is self-signed. Unless it is also the
+        // start of the chain, its usage was checked when it was added.
+        let cert = self.cert;
+        if self.leaf {
+            self.check_leaf_usage()?;
+        }
+        if !cert.is_ca() {
+            return Err(Error::InvalidCertUsage);
+        }
+        self.verify_issued_by(cert)
+    }
+
+    fn check_leaf_usage(&self) -> Result<(), Error> {
+        if self.attestation {
+            self.cert.check_dac_usage()
+        } else {
+            self.cert.check_leaf_usage()
+        }
+    }
+
+    fn verify_issued_by(&self, parent: &Cert) -> Result<(), Error> {
+        self.cert.check_validity(self.time)?;
+        // A PAA may leave out the authority key id, as it can only be its own
+        let paa_without_auth_key = self.attestation
+            && std::ptr::eq(self.cert, parent)
+            && self.cert.extensions.auth_key_id.is_none();
+        if !paa_without_auth_key && !self.cert.is_authority(parent)? {
             return Err(Error::InvalidAuthKey);
         }
         let mut asn1 = [0u8; MAX_ASN1_CERT_SIZE];
@@ -690,16 +1391,7 @@ impl<'a> CertVerifier<'a> {
                 self.cert.get_subject_key_id()
             );
             e
-        })?;
-
-        // TODO: other validation checks
-        Ok(CertVerifier::new(parent))
-    }
-
-    pub fn finalise(self) -> Result<(), Error> {
-        let cert = self.cert;
-        self.add_cert(cert)?;
-        Ok(())
+        })
     }
 }
 
@@ -721,21 +1413,33 @@ pub trait CertConsumer {
     fn end_ctx(&mut self) -> Result<(), Error>;
     fn oid(&mut self, tag: &str, oid: &[u8]) -> Result<(), Error>;
     fn utctime(&mut self, tag: &str, epoch: u32) -> Result<(), Error>;
+    /// The time of a certificate that has no well-defined expiration date
+    fn no_expiry_time(&mut self, tag: &str) -> Result<(), Error>;
 }
 
 const MAX_DEPTH: usize = 10;
 const MAX_ASN1_CERT_SIZE: usize = 1000;
+// The sequence of the 2 integers, each of which may have a leading zero, along with the
+// space that the writer reserves for the lengths
+const MAX_DER_SIGNATURE_SIZE: usize = EC_SIGNATURE_LEN_BYTES + 16;
 
+mod asn1_reader;
 mod asn1_writer;
+pub mod attestation;
 mod printer;
 
 #[cfg(test)]
 mod tests {
-    use crate::cert::Cert;
+    use crate::cert::{BasicConstraints, Cert, CertTime};
     use crate::error::Error;
     use crate::tlv::{self, FromTLV, TLVWriter, TagType, ToTLV};
     use crate::utils::writebuf::WriteBuf;
 
+    // The test vectors are valid from 2021-01-01 to 2031-01-01
+    const NOT_BEFORE: u32 = 662774400;
+    const NOT_AFTER: u32 = 978134400;
+    const VALID_TIME: CertTime = CertTime::Utc(NOT_BEFORE + 1000);
+
     #[test]
     fn test_asn1_encode_success() {
         {
@@ -765,7 +1469,7 @@ mod tests {
         let noc = Cert::new(&test_vectors::NOC1_SUCCESS).unwrap();
         let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
         let rca = Cert::new(&test_vectors::RCA1_SUCCESS).unwrap();
-        let a = noc.verify_chain_start();
+        let a = noc.verify_chain_start(VALID_TIME);
         a.add_cert(&icac)
             .unwrap()
             .add_cert(&rca)
@@ -779,7 +1483,7 @@ mod tests {
         // The chain doesn't lead up to a self-signed certificate
         let noc = Cert::new(&test_vectors::NOC1_SUCCESS).unwrap();
         let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
-        let a = noc.verify_chain_start();
+        let a = noc.verify_chain_start(VALID_TIME);
         assert_eq!(
             Err(Error::InvalidAuthKey),
             a.add_cert(&icac).unwrap().finalise()
@@ -790,15 +1494,102 @@ mod tests {
     fn test_auth_key_chain_incorrect() {
         let noc = Cert::new(&test_vectors::NOC1_AUTH_KEY_FAIL).unwrap();
         let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
-        let a = noc.verify_chain_start();
+        let a = noc.verify_chain_start(VALID_TIME);
         assert_eq!(Err(Error::InvalidAuthKey), a.add_cert(&icac).map(|_| ()));
     }
 
+    #[test]
+    fn test_verify_chain_validity() {
+        let noc = Cert::new(&test_vectors::NOC1_SUCCESS).unwrap();
+        let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
+        let rca = Cert::new(&test_vectors::RCA1_SUCCESS).unwrap();
+        let verify = |time| {
+            noc.verify_chain_start(time)
+                .add_cert(&icac)?
+                .add_cert(&rca)?
+                .finalise()
+        };
+        assert_eq!(Ok(()), verify(CertTime::Utc(NOT_AFTER)));
+        assert_eq!(
+            Err(Error::CertExpired),
+            verify(CertTime::Utc(NOT_AFTER + 1))
+        );
+        assert_eq!(
+            Err(Error::CertNotYetValid),
+            verify(CertTime::Utc(NOT_BEFORE - 1))
+        );
+
+        // The Last Known Good UTC Time only tells if the certificates have expired
+        assert_eq!(Ok(()), verify(CertTime::LastKnownGood(NOT_BEFORE - 1)));
+        assert_eq!(
+            Err(Error::CertExpired),
+            verify(CertTime::LastKnownGood(NOT_AFTER + 1))
+        );
+    }
+
+    #[test]
+    fn test_cert_time_from_clock() {
+        assert_eq!(
+            CertTime::from_clock(Some(NOT_BEFORE), NOT_BEFORE),
+            CertTime::Utc(NOT_BEFORE)
+        );
+        // A clock that is behind the Last Known Good UTC Time, or not set, isn't used
+        assert_eq!(
+            CertTime::from_clock(Some(NOT_BEFORE - 1), NOT_BEFORE),
+            CertTime::LastKnownGood(NOT_BEFORE)
+        );
+        assert_eq!(
+            CertTime::from_clock(None, NOT_BEFORE),
+            CertTime::LastKnownGood(NOT_BEFORE)
+        );
+    }
+
+    #[test]
+    fn test_verify_chain_usage() {
+        let noc = Cert::new(&test_vectors::NOC1_SUCCESS).unwrap();
+        let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
+        let mut rca = Cert::new(&test_vectors::RCA1_SUCCESS).unwrap();
+
+        // A chain can start at a CA
+        icac.verify_chain_start(VALID_TIME)
+            .add_cert(&rca)
+            .unwrap()
+            .finalise()
+            .unwrap();
+
+        // A NOC can't issue certificates
+        assert_eq!(
+            Err(Error::InvalidCertUsage),
+            noc.verify_chain_start(VALID_TIME)
+                .add_cert(&noc)
+                .map(|_| ())
+        );
+        // Nor be the root
+        assert_eq!(
+            Err(Error::InvalidCertUsage),
+            noc.verify_chain_start(VALID_TIME).finalise()
+        );
+
+        // The root allows no CAs below it, but the ICAC is one
+        rca.extensions.basic_const = Some(BasicConstraints {
+            is_ca: true,
+            path: Some(0),
+        });
+        assert_eq!(
+            Err(Error::InvalidCertUsage),
+            noc.verify_chain_start(VALID_TIME)
+                .add_cert(&icac)
+                .unwrap()
+                .add_cert(&rca)
+                .map(|_| ())
+        );
+    }
+
     #[test]
     fn test_cert_corrupted() {
         let noc = Cert::new(&test_vectors::NOC1_CORRUPT_CERT).unwrap();
         let icac = Cert::new(&test_vectors::ICAC1_SUCCESS).unwrap();
-        let a = noc.verify_chain_start();
+        let a = noc.verify_chain_start(VALID_TIME);
         assert_eq!(Err(Error::InvalidSignature), a.add_cert(&icac).map(|_| ()));
     }
 
@@ -823,6 +1614,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_x509_conversions() {
+        let test_input: [&[u8]; 4] = [
+            &test_vectors::NOC1_SUCCESS,
+            &test_vectors::ICAC1_SUCCESS,
+            &test_vectors::RCA1_SUCCESS,
+            &test_vectors::CHIP_CERT_TXT_IN_DN,
+        ];
+
+        for input in test_input.iter() {
+            let cert = Cert::new(input).unwrap();
+            let mut der = [0u8; 1024];
+            let der_len = cert.as_x509(&mut der).unwrap();
+
+            // The certificate is the signed part, the algorithm and the signature
+            let mut tbs = [0u8; 1024];
+            let tbs_len = cert.as_asn1(&mut tbs).unwrap();
+            assert_eq!(&der[4..4 + tbs_len], &tbs[..tbs_len]);
+
+            let cert = Cert::from_x509(&der[..der_len]).unwrap();
+            let mut buf = [0u8; 1024];
+            let len = cert.as_tlv(&mut buf).unwrap();
+            assert_eq!(*input, &buf[..len]);
+        }
+
+        // A certificate cut short in its signature
+        let cert = Cert::new(&test_vectors::RCA1_SUCCESS).unwrap();
+        let mut der = [0u8; 1024];
+        let der_len = cert.as_x509(&mut der).unwrap();
+        assert_eq!(
+            Cert::from_x509(&der[..der_len - 1]).map(|_| ()),
+            Err(Error::InvalidData)
+        );
+    }
+
     mod test_vectors {
         // Group 1
         pub const NOC1_SUCCESS: [u8; 247] = [
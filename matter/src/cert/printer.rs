@@ -142,4 +142,9 @@ impl<'a, 'b> CertConsumer for CertPrinter<'a, 'b> {
         let _ = writeln!(self.f, "{} {} {}", SPACE[self.level], tag, dt);
         Ok(())
     }
+
+    fn no_expiry_time(&mut self, tag: &str) -> Result<(), Error> {
+        let _ = writeln!(self.f, "{} {} No Expiry", SPACE[self.level], tag);
+        Ok(())
+    }
 }
@@ -0,0 +1,229 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use rand::RngCore;
+
+use crate::{
+    cert::{Cert, CertSubject},
+    crypto::{self, CryptoKeyPair, KeyPair},
+    error::Error,
+    fabric::Fabric,
+    utils::epoch::{SysUtcTime, UtcTime},
+};
+/// How long the certificates that we issue are valid
+const CERT_VALIDITY_SECS: u32 = 10 * 365 * 24 * 60 * 60;
+/// How far back the certificates that we issue are valid from, so that they are valid
+/// right away for the nodes whose clocks are a little behind ours
+const CERT_BACKDATE_SECS: u32 = 60 * 60;
+const MAX_CERT_TLV_LEN: usize = 400;
+
+/// A certificate authority for a fabric. This holds the fabric's root CA key, and issues
+/// the Node Operational Certificates of the nodes in the fabric.
+pub struct CertAuthority {
+    fabric_id: u64,
+    vendor_id: u16,
+    root_key: KeyPair,
+    root_cert: Cert,
+    ipk: [u8; crypto::SYMM_KEY_LEN_BYTES],
+    utc_time: Box<dyn UtcTime>,
+}
+
+impl CertAuthority {
+    /// Create a new fabric, with a freshly generated root CA and IPK
+    pub fn new(rcac_id: u64, fabric_id: u64, vendor_id: u16) -> Result<Self, Error> {
+        Self::new_with_utc_time(rcac_id, fabric_id, vendor_id, Box::new(SysUtcTime))
+    }
+
+    /// Create a new fabric, like [new](CertAuthority::new), with the validity periods of
+    /// the certificates starting from the time of the given source, instead of the system
+    /// clock
+    pub fn new_with_utc_time(
+        rcac_id: u64,
+        fabric_id: u64,
+        vendor_id: u16,
+        utc_time: Box<dyn UtcTime>,
+    ) -> Result<Self, Error> {
+        let root_key = KeyPair::new()?;
+        let mut pubkey = [0u8; crypto::EC_POINT_LEN_BYTES];
+        let len = root_key.get_public_key(&mut pubkey)?;
+        let (not_before, not_after) = validity(utc_time.as_ref())?;
+        let root_cert = Cert::issue(
+            &CertSubject::Rcac { rcac_id, fabric_id },
+            &pubkey[..len],
+            None,
+            &root_key,
+            not_before,
+            not_after,
+        )?;
+
+        let mut ipk = [0u8; crypto::SYMM_KEY_LEN_BYTES];
+        rand::thread_rng().fill_bytes(&mut ipk);
+        Ok(Self {
+            fabric_id,
+            vendor_id,
+            root_key,
+            root_cert,
+            ipk,
+            utc_time,
+        })
+    }
+
+    pub fn get_fabric_id(&self) -> u64 {
+        self.fabric_id
+    }
+
+    pub fn get_vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    /// The epoch key of the fabric's IPK
+    pub fn get_ipk(&self) -> &[u8] {
+        &self.ipk
+    }
+
+    pub fn get_root_cert(&self) -> &Cert {
+        &self.root_cert
+    }
+
+    /// Issue a NOC for the given node, to the given public key
+    pub fn issue_noc(&self, pubkey: &[u8], node_id: u64, cat_ids: &[u32]) -> Result<Cert, Error> {
+        let (not_before, not_after) = validity(self.utc_time.as_ref())?;
+        self.issue_noc_with_validity(pubkey, node_id, cat_ids, not_before, not_after)
+    }
+
+    /// Issue a NOC for the given node, to the given public key, that is valid for the given
+    /// period, in seconds since the Matter epoch
+    pub fn issue_noc_with_validity(
+        &self,
+        pubkey: &[u8],
+        node_id: u64,
+        cat_ids: &[u32],
+        not_before: u32,
+        not_after: u32,
+    ) -> Result<Cert, Error> {
+        Cert::issue(
+            &CertSubject::Noc {
+                node_id,
+                fabric_id: self.fabric_id,
+                cat_ids,
+            },
+            pubkey,
+            Some(&self.root_cert),
+            &self.root_key,
+            not_before,
+            not_after,
+        )
+    }
+
+    /// Create the fabric as seen by one of its nodes, with a fresh key pair and NOC for the
+    /// node. This is what a controller uses to establish CASE sessions in the fabric.
+    pub fn new_fabric(&self, node_id: u64, cat_ids: &[u32]) -> Result<Fabric, Error> {
+        let key_pair = KeyPair::new()?;
+        let mut pubkey = [0u8; crypto::EC_POINT_LEN_BYTES];
+        let len = key_pair.get_public_key(&mut pubkey)?;
+        let noc = self.issue_noc(&pubkey[..len], node_id, cat_ids)?;
+
+        // The fabric takes its own copy of the root certificate
+        let mut buf = [0u8; MAX_CERT_TLV_LEN];
+        let len = self.root_cert.as_tlv(&mut buf)?;
+        let root_cert = Cert::new(&buf[..len])?;
+        Fabric::new(key_pair, root_cert, None, noc, &self.ipk, self.vendor_id)
+    }
+}
+
+/// The validity period of a certificate issued now, in seconds since the Matter epoch
+fn validity(utc_time: &dyn UtcTime) -> Result<(u32, u32), Error> {
+    let now = utc_time.now().ok_or(Error::SysTimeFail)?;
+    let not_before = now.saturating_sub(CERT_BACKDATE_SECS);
+    Ok((not_before, now.saturating_add(CERT_VALIDITY_SECS)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cert::CertTime;
+    use crate::crypto::{self, CryptoKeyPair, KeyPair};
+    use crate::utils::epoch::UtcTime;
+
+    use super::{CertAuthority, CERT_BACKDATE_SECS, CERT_VALIDITY_SECS};
+
+    struct TestUtcTime(Option<u32>);
+
+    impl UtcTime for TestUtcTime {
+        fn now(&self) -> Option<u32> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_issue_noc() {
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let key_pair = KeyPair::new().unwrap();
+        let mut pubkey = [0u8; crypto::EC_POINT_LEN_BYTES];
+        let len = key_pair.get_public_key(&mut pubkey).unwrap();
+
+        let noc = ca.issue_noc(&pubkey[..len], 0x1234, &[0x10001]).unwrap();
+        assert_eq!(noc.get_node_id().unwrap(), 0x1234);
+        assert_eq!(noc.get_fabric_id().unwrap(), 0xABCD);
+        assert_eq!(noc.get_pubkey(), &pubkey[..len]);
+        let mut cat_ids = [0u32; 2];
+        noc.get_cat_ids(&mut cat_ids);
+        assert_eq!(cat_ids, [0x10001, 0]);
+
+        // The NOC chains up to the root, which is self-signed
+        noc.verify_chain_start(CertTime::Utc(noc.get_not_before()))
+            .add_cert(ca.get_root_cert())
+            .unwrap()
+            .finalise()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_validity() {
+        let now = 700_000_000;
+        let ca = CertAuthority::new_with_utc_time(1, 1, 0xFFF1, Box::new(TestUtcTime(Some(now))))
+            .unwrap();
+        // The certificates are valid from a little before now, for the nodes whose clocks
+        // lag behind
+        let root = ca.get_root_cert();
+        assert_eq!(root.get_not_before(), now - CERT_BACKDATE_SECS);
+        assert_eq!(root.get_not_after(), now + CERT_VALIDITY_SECS);
+        let noc = ca.issue_noc(root.get_pubkey(), 0x1234, &[]).unwrap();
+        assert_eq!(noc.get_not_before(), now - CERT_BACKDATE_SECS);
+        noc.verify_chain_start(CertTime::Utc(now - 60))
+            .add_cert(root)
+            .unwrap()
+            .finalise()
+            .unwrap();
+
+        // Without the time, nothing can be issued
+        assert!(
+            CertAuthority::new_with_utc_time(1, 1, 0xFFF1, Box::new(TestUtcTime(None))).is_err()
+        );
+    }
+
+    #[test]
+    fn test_issued_cert_roundtrip() {
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let mut buf = [0u8; super::MAX_CERT_TLV_LEN];
+        let len = ca.get_root_cert().as_tlv(&mut buf).unwrap();
+        let root = crate::cert::Cert::new(&buf[..len]).unwrap();
+        assert_eq!(root.get_fabric_id().unwrap(), 0xABCD);
+        root.verify_chain_start(CertTime::Utc(root.get_not_before()))
+            .finalise()
+            .unwrap();
+    }
+}
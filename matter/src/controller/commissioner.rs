@@ -0,0 +1,540 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::net::SocketAddr;
+
+use log::{info, warn};
+use rand::RngCore;
+
+use crate::{
+    cert::{
+        attestation::{self, SignedCertDeclaration},
+        Cert, CertTime,
+    },
+    crypto::{self, CryptoKeyPair, KeyPair},
+    data_model::{
+        objects::EncodeValue,
+        sdm::{general_commissioning, noc},
+    },
+    error::Error,
+    fabric::Fabric,
+    interaction_model::{
+        core::IMStatusCode,
+        messages::{
+            ib::{self, CmdData, CmdPath},
+            msg::{self, InvReq},
+        },
+    },
+    secure_channel::case::ResumptionRecord,
+    tlv::{
+        get_root_node_struct, FromTLV, OctetStr, TLVArray, TLVElement, TLVWriter, TagType, ToTLV,
+        UtfStr,
+    },
+};
+
+use super::{ca::CertAuthority, Controller, ImResponse};
+
+/// The fabric index that our side of the CASE sessions is associated with
+const LOCAL_FAB_IDX: u8 = 1;
+/// How long the device's fail-safe is armed for, while we commission it
+const FAILSAFE_EXPIRY_SECS: u16 = 60;
+const NONCE_LEN: usize = 32;
+const MAX_CERT_TLV_LEN: usize = 400;
+const MAX_CSR_PUBKEY_LEN: usize = crypto::EC_POINT_LEN_BYTES;
+
+const CERT_TYPE_DAC: u8 = 1;
+const CERT_TYPE_PAI: u8 = 2;
+
+/// Drives the commissioning of devices into a fabric, for which we are the certificate
+/// authority.
+///
+/// The devices are commissioned over a PASE session, and once these have a NOC, the
+/// commissioning is completed over a CASE session. The [Controller] stays available for
+/// sending further requests over the CASE sessions.
+pub struct Commissioner {
+    controller: Controller,
+    ca: CertAuthority,
+    // Our own view of the fabric, used for establishing CASE sessions
+    fabric: Fabric,
+    node_id: u64,
+    // The trusted Product Attestation Authorities (DER encoded)
+    paa_certs: Vec<Vec<u8>>,
+    // The trusted signers of the Certification Declarations (DER encoded)
+    cd_signing_certs: Vec<Vec<u8>>,
+    // Accept the devices' PAI certificates and Certification Declarations as they are
+    allow_untrusted: bool,
+}
+
+impl Commissioner {
+    /// Create a commissioner, that is the node with the given node id in the CA's fabric
+    pub fn new(controller: Controller, ca: CertAuthority, node_id: u64) -> Result<Self, Error> {
+        let fabric = ca.new_fabric(node_id, &[])?;
+        Ok(Self {
+            controller,
+            ca,
+            fabric,
+            node_id,
+            paa_certs: Vec::new(),
+            cd_signing_certs: Vec::new(),
+            allow_untrusted: false,
+        })
+    }
+
+    /// Trust the given (DER encoded) PAA certificate for device attestation. A device is
+    /// only commissioned if its PAI certificate is signed by one of the trusted PAAs.
+    pub fn add_paa_cert(&mut self, paa_cert: &[u8]) {
+        self.paa_certs.push(paa_cert.to_vec());
+    }
+
+    /// Trust the given (DER encoded) certificate for signing the Certification Declarations.
+    /// A device is only commissioned if its Certification Declaration is signed by one of
+    /// the trusted signers.
+    pub fn add_cd_signing_cert(&mut self, cert: &[u8]) {
+        self.cd_signing_certs.push(cert.to_vec());
+    }
+
+    /// Accept the devices' PAI certificates and Certification Declarations as they are,
+    /// without a trusted PAA or signer. The attestation then only proves that the device
+    /// holds the key of its DAC, which is only good enough for testing, with devices that
+    /// have test certificates.
+    pub fn allow_untrusted_attestation(&mut self) {
+        self.allow_untrusted = true;
+    }
+
+    pub fn controller(&mut self) -> &mut Controller {
+        &mut self.controller
+    }
+
+    pub fn fabric(&self) -> &Fabric {
+        &self.fabric
+    }
+
+    pub fn get_node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    /// Commission the device at the given address into our fabric, as the node with the given
+    /// node id. Returns the local session id of the CASE session with the device.
+    pub fn commission(
+        &mut self,
+        peer: SocketAddr,
+        passcode: u32,
+        node_id: u64,
+    ) -> Result<u16, Error> {
+        self.commission_with(peer, passcode, node_id, |ca, pubkey| {
+            Ok((ca.issue_noc(pubkey, node_id, &[])?, None))
+        })
+    }
+
+    /// Commission the device, like [commission](Commissioner::commission), with the NOC,
+    /// and the ICAC if any, that `issue` issues for the operational public key of the
+    /// device, like a NOC with CASE Authenticated Tags
+    pub fn commission_with<F>(
+        &mut self,
+        peer: SocketAddr,
+        passcode: u32,
+        node_id: u64,
+        issue: F,
+    ) -> Result<u16, Error>
+    where
+        F: FnOnce(&CertAuthority, &[u8]) -> Result<(Cert, Option<Cert>), Error>,
+    {
+        let pase_sess = self.controller.pase(peer, passcode)?;
+
+        self.arm_failsafe(pase_sess)?;
+        self.set_regulatory_config(pase_sess)?;
+        let dac_key = self.attest(pase_sess)?;
+        let noc_pubkey = self.request_csr(pase_sess, &dac_key)?;
+        let (noc, icac) = issue(&self.ca, &noc_pubkey)?;
+        self.add_trusted_root_cert(pase_sess)?;
+        self.add_noc(pase_sess, &noc, icac.as_ref())?;
+
+        let case_sess = self.case(peer, node_id)?;
+        self.commissioning_complete(case_sess)?;
+        info!("Commissioned node {:x} at {}", node_id, peer);
+        Ok(case_sess)
+    }
+
+    /// Establish a CASE session with a node in our fabric. Returns the local session id of
+    /// the new session.
+    pub fn case(&mut self, peer: SocketAddr, node_id: u64) -> Result<u16, Error> {
+        self.controller
+            .case(peer, &self.fabric, LOCAL_FAB_IDX, node_id)
+    }
+
+    /// The record that the next CASE session with the node can be resumed with, if any
+    pub fn get_resumption_record(&self, node_id: u64) -> Option<&ResumptionRecord> {
+        self.controller
+            .get_resumption_record(LOCAL_FAB_IDX, node_id)
+    }
+
+    fn arm_failsafe(&mut self, sess_id: u16) -> Result<(), Error> {
+        let req = ArmFailsafeReq {
+            expiry_len: FAILSAFE_EXPIRY_SECS,
+            bread_crumb: 0,
+        };
+        let resp = self.invoke_cmd(
+            sess_id,
+            general_commissioning::ID,
+            general_commissioning::Commands::ArmFailsafe as u16,
+            &req,
+        )?;
+        let data = cmd_resp_data(
+            &resp,
+            general_commissioning::Commands::ArmFailsafeResp as u16,
+        )?;
+        CommissioningResp::from_tlv(&data)?.check("ArmFailSafe")
+    }
+
+    fn set_regulatory_config(&mut self, sess_id: u16) -> Result<(), Error> {
+        let req = SetRegulatoryConfigReq {
+            new_reg_config: general_commissioning::RegLocationType::IndoorOutdoor as u8,
+            country_code: UtfStr::new(b"XX"),
+            bread_crumb: 0,
+        };
+        let resp = self.invoke_cmd(
+            sess_id,
+            general_commissioning::ID,
+            general_commissioning::Commands::SetRegulatoryConfig as u16,
+            &req,
+        )?;
+        let data = cmd_resp_data(
+            &resp,
+            general_commissioning::Commands::SetRegulatoryConfigResp as u16,
+        )?;
+        CommissioningResp::from_tlv(&data)?.check("SetRegulatoryConfig")
+    }
+
+    /// Fetch and verify the device's attestation information. Returns the public key of the
+    /// device's DAC.
+    fn attest(&mut self, sess_id: u16) -> Result<KeyPair, Error> {
+        let dac = Cert::from_x509(&self.cert_chain(sess_id, CERT_TYPE_DAC)?)?;
+        let pai = Cert::from_x509(&self.cert_chain(sess_id, CERT_TYPE_PAI)?)?;
+        let time = CertTime::from_clock(self.controller.utc_time.now(), 0);
+        let ids = attestation::verify_dac_chain(&dac, &pai, None, time)?;
+        let paa_key_id = if self.allow_untrusted {
+            warn!("Untrusted attestation allowed, accepting the PAI as is");
+            None
+        } else {
+            Some(self.trusted_paa_key_id(&dac, &pai, time)?)
+        };
+        let dac_key = KeyPair::new_from_public(dac.get_pubkey())?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let req = NonceReq {
+            nonce: OctetStr::new(&nonce),
+        };
+        let resp = self.invoke_cmd(sess_id, noc::ID, noc::Commands::AttReq as u16, &req)?;
+        let data = cmd_resp_data(&resp, noc::Commands::AttReqResp as u16)?;
+        let resp = SignedResp::from_tlv(&data)?;
+        self.verify_signed_resp(sess_id, &dac_key, &resp)?;
+
+        let elements = AttestationElements::from_tlv(&get_root_node_struct(resp.elements.0)?)?;
+        if elements.nonce.0 != nonce {
+            warn!("Attestation nonce mismatch");
+            return Err(Error::Invalid);
+        }
+        let cd = SignedCertDeclaration::new(elements.cert_declaration.0)?;
+        if self.allow_untrusted {
+            warn!("Untrusted attestation allowed, accepting the Certification Declaration as is");
+        } else {
+            self.verify_cert_declaration(&cd)?;
+        }
+        cd.get_cert_declaration()?
+            .check(&ids, paa_key_id.as_deref())?;
+        info!(
+            "Device attestation verified, vendor id {:x}, product id {:x}",
+            ids.vendor_id, ids.product_id
+        );
+        Ok(dac_key)
+    }
+
+    /// Find the trusted PAA that the device's chain leads up to, returns its subject key id
+    fn trusted_paa_key_id(&self, dac: &Cert, pai: &Cert, time: CertTime) -> Result<Vec<u8>, Error> {
+        for paa in &self.paa_certs {
+            let paa = Cert::from_x509(paa)?;
+            if attestation::verify_dac_chain(dac, pai, Some(&paa), time).is_ok() {
+                return Ok(paa.get_subject_key_id()?.to_vec());
+            }
+        }
+        warn!("The PAI is not signed by any of the trusted PAAs");
+        Err(Error::InvalidAuthKey)
+    }
+
+    fn verify_cert_declaration(&self, cd: &SignedCertDeclaration) -> Result<(), Error> {
+        for signer in &self.cd_signing_certs {
+            let signer = Cert::from_x509(signer)?;
+            if signer.get_subject_key_id()? == cd.get_signer_key_id() {
+                return cd.verify(signer.get_pubkey());
+            }
+        }
+        warn!("The Certification Declaration is not signed by any of the trusted signers");
+        Err(Error::InvalidAuthKey)
+    }
+
+    fn cert_chain(&mut self, sess_id: u16, cert_type: u8) -> Result<Vec<u8>, Error> {
+        let req = CertChainReq { cert_type };
+        let resp = self.invoke_cmd(sess_id, noc::ID, noc::Commands::CertChainReq as u16, &req)?;
+        let data = cmd_resp_data(&resp, noc::Commands::CertChainResp as u16)?;
+        Ok(CertChainResp::from_tlv(&data)?.cert.0.to_vec())
+    }
+
+    /// Request a CSR from the device, returns the public key for its NOC
+    fn request_csr(&mut self, sess_id: u16, dac_key: &KeyPair) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let req = NonceReq {
+            nonce: OctetStr::new(&nonce),
+        };
+        let resp = self.invoke_cmd(sess_id, noc::ID, noc::Commands::CSRReq as u16, &req)?;
+        let data = cmd_resp_data(&resp, noc::Commands::CSRResp as u16)?;
+        let resp = SignedResp::from_tlv(&data)?;
+        self.verify_signed_resp(sess_id, dac_key, &resp)?;
+
+        let elements = NocsrElements::from_tlv(&get_root_node_struct(resp.elements.0)?)?;
+        if elements.nonce.0 != nonce {
+            warn!("CSR nonce mismatch");
+            return Err(Error::Invalid);
+        }
+        let mut pubkey = [0u8; MAX_CSR_PUBKEY_LEN];
+        let len = crypto::csr_pubkey(elements.csr.0, &mut pubkey)?;
+        Ok(pubkey[..len].to_vec())
+    }
+
+    /// Verify that the elements are signed by the device's DAC, along with the session's
+    /// attestation challenge
+    fn verify_signed_resp(
+        &mut self,
+        sess_id: u16,
+        dac_key: &KeyPair,
+        resp: &SignedResp,
+    ) -> Result<(), Error> {
+        let mut msg = resp.elements.0.to_vec();
+        msg.extend_from_slice(&self.controller.att_challenge(sess_id)?);
+        dac_key.verify_msg(&msg, resp.signature.0)
+    }
+
+    fn add_trusted_root_cert(&mut self, sess_id: u16) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_CERT_TLV_LEN];
+        let len = self.ca.get_root_cert().as_tlv(&mut buf)?;
+        let req = CertReq {
+            cert: OctetStr::new(&buf[..len]),
+        };
+        let resp = self.invoke_cmd(
+            sess_id,
+            noc::ID,
+            noc::Commands::AddTrustedRootCert as u16,
+            &req,
+        )?;
+        cmd_resp_status(&resp)
+    }
+
+    fn add_noc(&mut self, sess_id: u16, noc: &Cert, icac: Option<&Cert>) -> Result<(), Error> {
+        let mut buf = [0u8; MAX_CERT_TLV_LEN];
+        let len = noc.as_tlv(&mut buf)?;
+        let mut icac_buf = [0u8; MAX_CERT_TLV_LEN];
+        let icac_len = match icac {
+            Some(icac) => icac.as_tlv(&mut icac_buf)?,
+            None => 0,
+        };
+        let ipk = self.ca.get_ipk().to_vec();
+        let req = AddNocReq {
+            noc_value: OctetStr::new(&buf[..len]),
+            icac_value: OctetStr::new(&icac_buf[..icac_len]),
+            ipk_value: OctetStr::new(&ipk),
+            case_admin_subject: self.node_id,
+            vendor_id: self.ca.get_vendor_id(),
+        };
+        let resp = self.invoke_cmd(sess_id, noc::ID, noc::Commands::AddNOC as u16, &req)?;
+        let data = cmd_resp_data(&resp, noc::Commands::NOCResp as u16)?;
+        let resp = NocResp::from_tlv(&data)?;
+        if resp.status_code != 0 {
+            warn!("AddNOC failed with status {}", resp.status_code);
+            return Err(Error::Invalid);
+        }
+        Ok(())
+    }
+
+    fn commissioning_complete(&mut self, sess_id: u16) -> Result<(), Error> {
+        let resp = self.invoke_cmd(
+            sess_id,
+            general_commissioning::ID,
+            general_commissioning::Commands::CommissioningComplete as u16,
+            &EmptyReq {},
+        )?;
+        let data = cmd_resp_data(
+            &resp,
+            general_commissioning::Commands::CommissioningCompleteResp as u16,
+        )?;
+        CommissioningResp::from_tlv(&data)?.check("CommissioningComplete")
+    }
+
+    /// Invoke a command on the root endpoint
+    fn invoke_cmd(
+        &mut self,
+        sess_id: u16,
+        cluster: u32,
+        cmd: u16,
+        data: &dyn ToTLV,
+    ) -> Result<ImResponse, Error> {
+        let cmd_data = [CmdData::new(
+            CmdPath::new(Some(0), Some(cluster), Some(cmd)),
+            EncodeValue::Value(data),
+        )];
+        let req = InvReq {
+            suppress_response: Some(false),
+            timed_request: Some(false),
+            inv_requests: Some(TLVArray::new(&cmd_data)),
+        };
+        self.controller.invoke(sess_id, &req)?.ok_or(Error::Invalid)
+    }
+}
+
+/// The first response in an InvokeResponse
+fn first_inv_resp(resp: &ImResponse) -> Result<ib::InvResp<'_>, Error> {
+    msg::InvResp::from_tlv(&resp.root()?)?
+        .inv_responses
+        .and_then(|r| r.iter().next())
+        .ok_or(Error::Invalid)
+}
+
+/// The data of the command response, which is expected to have the given command id
+fn cmd_resp_data(resp: &ImResponse, cmd: u16) -> Result<TLVElement<'_>, Error> {
+    match first_inv_resp(resp)? {
+        ib::InvResp::Cmd(c) if c.path.path.leaf == Some(cmd as u32) => {
+            c.data.unwrap_tlv().ok_or(Error::Invalid)
+        }
+        ib::InvResp::Cmd(c) => {
+            warn!("Unexpected command response {:?}", c.path);
+            Err(Error::Invalid)
+        }
+        ib::InvResp::Status(s) => {
+            warn!("Command {:?} failed: {:?}", s.path, s.status);
+            Err(Error::Invalid)
+        }
+    }
+}
+
+/// Check that the command response is a success status
+fn cmd_resp_status(resp: &ImResponse) -> Result<(), Error> {
+    match first_inv_resp(resp)? {
+        ib::InvResp::Status(s) if s.status.status == IMStatusCode::Sucess => Ok(()),
+        ib::InvResp::Status(s) => {
+            warn!("Command {:?} failed: {:?}", s.path, s.status);
+            Err(Error::Invalid)
+        }
+        ib::InvResp::Cmd(c) => {
+            warn!("Unexpected command response {:?}", c.path);
+            Err(Error::Invalid)
+        }
+    }
+}
+
+#[derive(ToTLV)]
+struct ArmFailsafeReq {
+    expiry_len: u16,
+    bread_crumb: u64,
+}
+
+#[derive(ToTLV)]
+struct SetRegulatoryConfigReq<'a> {
+    new_reg_config: u8,
+    country_code: UtfStr<'a>,
+    bread_crumb: u64,
+}
+
+#[derive(ToTLV)]
+struct EmptyReq {}
+
+#[derive(ToTLV)]
+struct NonceReq<'a> {
+    nonce: OctetStr<'a>,
+}
+
+#[derive(ToTLV)]
+struct CertChainReq {
+    cert_type: u8,
+}
+
+#[derive(ToTLV)]
+struct CertReq<'a> {
+    cert: OctetStr<'a>,
+}
+
+#[derive(ToTLV)]
+struct AddNocReq<'a> {
+    noc_value: OctetStr<'a>,
+    icac_value: OctetStr<'a>,
+    ipk_value: OctetStr<'a>,
+    case_admin_subject: u64,
+    vendor_id: u16,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct CommissioningResp<'a> {
+    error_code: u8,
+    debug_txt: Option<UtfStr<'a>>,
+}
+
+impl<'a> CommissioningResp<'a> {
+    fn check(&self, cmd: &str) -> Result<(), Error> {
+        if self.error_code == 0 {
+            Ok(())
+        } else {
+            warn!(
+                "{} failed with error {}: {:?}",
+                cmd, self.error_code, self.debug_txt
+            );
+            Err(Error::Invalid)
+        }
+    }
+}
+
+// A response with elements that are signed by the device's DAC
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct SignedResp<'a> {
+    elements: OctetStr<'a>,
+    signature: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
+struct AttestationElements<'a> {
+    cert_declaration: OctetStr<'a>,
+    nonce: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
+struct NocsrElements<'a> {
+    csr: OctetStr<'a>,
+    nonce: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct CertChainResp<'a> {
+    cert: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+struct NocResp {
+    status_code: u8,
+}
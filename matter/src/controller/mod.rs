@@ -0,0 +1,593 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The controller role: establishing secure sessions with a device as the initiator, and
+//! sending Interaction Model requests to the device over these sessions.
+//!
+//! The [Controller] is synchronous, each request blocks until its response is received.
+//! The responses are returned as they were received, and can be parsed with the types in
+//! [messages::msg](crate::interaction_model::messages::msg).
+
+pub mod ca;
+pub mod commissioner;
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use heapless::LinearMap;
+use log::{error, info};
+
+use crate::{
+    cert::CertTime,
+    error::Error,
+    fabric::Fabric,
+    interaction_model::{
+        core::{IMStatusCode, OpCode, PROTO_ID_INTERACTION_MODEL},
+        messages::msg::{InvReq, ReadReq, ReportDataMsg, StatusResp, SubscribeReq, WriteReq},
+    },
+    secure_channel::{
+        case::{CaseInitiator, ResumptionRecord, ResumptionStore},
+        common::{self, PROTO_ID_SECURE_CHANNEL},
+        pake::PakeInitiator,
+    },
+    tlv::{get_root_node_struct, FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+    transport::{
+        capture::Capture,
+        exchange::{self, ExchangeMgr, Role},
+        mrp::ReliableMessage,
+        network::{Address, NetworkInterface},
+        session::SessionMgr,
+        udp::UdpListener,
+    },
+    utils::epoch::{SysUtcTime, UtcTime},
+};
+
+/// How long we wait for the peer to respond
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An Interaction Model message received from the device
+pub struct ImResponse {
+    pub opcode: OpCode,
+    pub payload: Vec<u8>,
+}
+
+impl ImResponse {
+    /// The root of the message's TLV, to be parsed with the types in
+    /// [messages::msg](crate::interaction_model::messages::msg)
+    pub fn root(&self) -> Result<TLVElement<'_>, Error> {
+        get_root_node_struct(&self.payload)
+    }
+}
+
+struct RxMsg {
+    exch_id: u16,
+    // Whether the peer initiated this exchange
+    peer_initiated: bool,
+    proto_id: u16,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl RxMsg {
+    fn is_report(&self) -> bool {
+        self.peer_initiated
+            && self.proto_id == PROTO_ID_INTERACTION_MODEL as u16
+            && self.opcode == OpCode::ReportData as u8
+    }
+}
+
+pub struct Controller {
+    exch_mgr: ExchangeMgr,
+    // Subscription reports that were received while we were waiting for something else
+    reports: VecDeque<RxMsg>,
+    // The CASE sessions that we can resume
+    resumption: ResumptionStore,
+    // The time that the certificates of the devices are checked against
+    utc_time: Box<dyn UtcTime>,
+}
+
+impl Controller {
+    /// Create a controller that listens on the given UDP port, a port of 0 picks any free port
+    pub fn new(port: u16) -> Result<Self, Error> {
+        Self::new_with_network(Box::new(UdpListener::new_with_port(port)?))
+    }
+
+    /// Create a controller on the given network interface, like a
+    /// [Loopback](crate::transport::loopback::Loopback) to a device in the same process
+    pub fn new_with_network(network: Box<dyn NetworkInterface>) -> Result<Self, Error> {
+        let mut sess_mgr = SessionMgr::new();
+        sess_mgr.add_network_interface(network)?;
+        Ok(Self {
+            exch_mgr: ExchangeMgr::new(sess_mgr),
+            reports: VecDeque::new(),
+            resumption: ResumptionStore::new(),
+            utc_time: Box::new(SysUtcTime),
+        })
+    }
+
+    /// Set the source of the UTC time that the certificates of the devices are checked
+    /// against, instead of the system clock
+    pub fn set_utc_time(&mut self, utc_time: Box<dyn UtcTime>) {
+        self.utc_time = utc_time;
+    }
+
+    // The time that the certificates of the devices in the fabric are checked against. Our
+    // own NOC was valid when it was issued, so the time has passed its Not Before, which
+    // serves as the Last Known Good UTC Time.
+    fn get_cert_time(&self, fabric: &Fabric) -> CertTime {
+        CertTime::from_clock(self.utc_time.now(), fabric.noc.get_not_before())
+    }
+
+    /// Capture all the messages in plaintext, for debugging
+    pub fn set_capture(&mut self, capture: Box<dyn Capture>) {
+        self.exch_mgr.get_sess_mgr().set_capture(capture);
+    }
+
+    /// Establish a PASE session with the device at the given address, using its passcode.
+    /// Returns the local session id of the new session.
+    pub fn pase(&mut self, peer: SocketAddr, passcode: u32) -> Result<u16, Error> {
+        let peer_addr = Address::Udp(peer);
+        let sess_idx = self.exch_mgr.get_sess_mgr().add(peer_addr, None)?;
+        let result = self.do_pase(peer_addr, passcode);
+        // The unsecured session was only needed for the session establishment
+        self.exch_mgr.get_sess_mgr().remove(sess_idx);
+        result
+    }
+
+    fn do_pase(&mut self, peer_addr: Address, passcode: u32) -> Result<u16, Error> {
+        let (exch_id, local_sessid) = self.initiate_unsecured()?;
+        let mut pake = PakeInitiator::new(passcode, local_sessid);
+
+        let result = (|| {
+            let mut tx = self.exch_mgr.new_tx()?;
+            pake.pbkdfparamreq(&mut tx)?;
+            self.exch_mgr.send(exch_id, tx)?;
+
+            let rx = self.recv_sc(exch_id, common::OpCode::PBKDFParamResponse)?;
+            let mut tx = self.exch_mgr.new_tx()?;
+            pake.pasepake1(&rx, &mut tx)?;
+            self.exch_mgr.send(exch_id, tx)?;
+
+            let rx = self.recv_sc(exch_id, common::OpCode::PASEPake2)?;
+            let mut tx = self.exch_mgr.new_tx()?;
+            pake.pasepake3(&rx, &mut tx)?;
+            self.exch_mgr.send(exch_id, tx)?;
+
+            self.recv_sc(exch_id, common::OpCode::StatusReport)
+        })();
+        self.complete(exch_id);
+        result?;
+
+        let clone_data = pake.get_session_clone_data(peer_addr)?;
+        self.exch_mgr.add_session(&clone_data)?;
+        info!("PASE session established with {}", peer_addr);
+        Ok(local_sessid)
+    }
+
+    /// Establish a CASE session with the node with the given node id, in our fabric. The
+    /// fabric index is the one that the session will be associated with on our side.
+    /// Returns the local session id of the new session.
+    ///
+    /// If we had a session with the node before, we offer to resume it, which spares the
+    /// node the certificate verification and the ECDH.
+    pub fn case(
+        &mut self,
+        peer: SocketAddr,
+        fabric: &Fabric,
+        fab_idx: u8,
+        peer_nodeid: u64,
+    ) -> Result<u16, Error> {
+        let peer_addr = Address::Udp(peer);
+        let sess_idx = self.exch_mgr.get_sess_mgr().add(peer_addr, None)?;
+        let result = self.do_case(peer_addr, fabric, fab_idx, peer_nodeid);
+        // The unsecured session was only needed for the session establishment
+        self.exch_mgr.get_sess_mgr().remove(sess_idx);
+        result
+    }
+
+    fn do_case(
+        &mut self,
+        peer_addr: Address,
+        fabric: &Fabric,
+        fab_idx: u8,
+        peer_nodeid: u64,
+    ) -> Result<u16, Error> {
+        let (exch_id, local_sessid) = self.initiate_unsecured()?;
+        let mut case = CaseInitiator::new(local_sessid, fab_idx, peer_nodeid)?;
+        if let Some(record) = self.resumption.get_by_peer(fab_idx, peer_nodeid) {
+            case.set_resumption(record.clone());
+        }
+
+        let result = (|| {
+            let mut tx = self.exch_mgr.new_tx()?;
+            case.sigma1(fabric, &mut tx)?;
+            self.exch_mgr.send(exch_id, tx)?;
+
+            let (opcode, rx) = self.recv_sc_one_of(
+                exch_id,
+                &[common::OpCode::CASESigma2, common::OpCode::CASESigma2Resume],
+            )?;
+            let mut tx = self.exch_mgr.new_tx()?;
+            if opcode == common::OpCode::CASESigma2Resume as u8 {
+                case.sigma2_resume(&rx, &mut tx)?;
+                return self.exch_mgr.send(exch_id, tx);
+            }
+            case.sigma3(fabric, self.get_cert_time(fabric), &rx, &mut tx)?;
+            self.exch_mgr.send(exch_id, tx)?;
+
+            self.recv_sc(exch_id, common::OpCode::StatusReport)
+                .map(|_| ())
+        })();
+        self.complete(exch_id);
+        if result.is_err() {
+            // Don't offer the same resumption again
+            self.resumption.remove(fab_idx, peer_nodeid);
+        }
+        result?;
+
+        let clone_data = case.get_session_clone_data(fabric, peer_addr)?;
+        self.exch_mgr.add_session(&clone_data)?;
+        if let Some(record) = case.get_resumption_record() {
+            self.resumption.add(record.clone());
+        }
+        if case.is_resumed() {
+            info!("CASE session resumed with {}", peer_addr);
+        } else {
+            info!("CASE session established with {}", peer_addr);
+        }
+        Ok(local_sessid)
+    }
+
+    /// The record that the next CASE session with the node can be resumed with, if any
+    pub fn get_resumption_record(
+        &self,
+        fab_idx: u8,
+        peer_nodeid: u64,
+    ) -> Option<&ResumptionRecord> {
+        self.resumption.get_by_peer(fab_idx, peer_nodeid)
+    }
+
+    /// Close the session with the given local session id, letting the device know with a
+    /// CloseSession
+    pub fn close_session(&mut self, sess_id: u16) -> Result<(), Error> {
+        self.exch_mgr.close_session(sess_id)
+    }
+
+    /// Read attributes and/or events on the session with the given local session id. The
+    /// reports are returned as received, there are multiple of these if the device had to
+    /// send the report in chunks.
+    pub fn read(&mut self, sess_id: u16, req: &ReadReq) -> Result<Vec<ImResponse>, Error> {
+        let exch_id = self.initiate(sess_id)?;
+        let result = self
+            .send_im(exch_id, OpCode::ReadRequest, req)
+            .and_then(|_| self.recv_reports(exch_id));
+        self.complete(exch_id);
+        result
+    }
+
+    /// Write attributes on the session with the given local session id. Returns the
+    /// WriteResponse, unless the request suppresses it.
+    pub fn write(&mut self, sess_id: u16, req: &WriteReq) -> Result<Option<ImResponse>, Error> {
+        let exch_id = self.initiate(sess_id)?;
+        let result = self
+            .send_im(exch_id, OpCode::WriteRequest, req)
+            .and_then(|_| {
+                if req.supress_response == Some(true) {
+                    Ok(None)
+                } else {
+                    self.recv_im(exch_id, OpCode::WriteResponse).map(Some)
+                }
+            });
+        self.complete(exch_id);
+        result
+    }
+
+    /// Invoke commands on the session with the given local session id. Returns the
+    /// InvokeResponse, unless the request suppresses it.
+    pub fn invoke(&mut self, sess_id: u16, req: &InvReq) -> Result<Option<ImResponse>, Error> {
+        let exch_id = self.initiate(sess_id)?;
+        let result = self
+            .send_im(exch_id, OpCode::InvokeRequest, req)
+            .and_then(|_| {
+                if req.suppress_response == Some(true) {
+                    Ok(None)
+                } else {
+                    self.recv_im(exch_id, OpCode::InvokeResponse).map(Some)
+                }
+            });
+        self.complete(exch_id);
+        result
+    }
+
+    /// Subscribe on the session with the given local session id. Returns the priming
+    /// reports, and the SubscribeResponse that confirms the subscription. The subsequent
+    /// reports of the subscription are received with [recv_report](Controller::recv_report).
+    pub fn subscribe(
+        &mut self,
+        sess_id: u16,
+        req: &SubscribeReq,
+    ) -> Result<(Vec<ImResponse>, ImResponse), Error> {
+        let exch_id = self.initiate(sess_id)?;
+        let result = self
+            .send_im(exch_id, OpCode::SubscribeRequest, req)
+            .and_then(|_| {
+                let reports = self.recv_reports(exch_id)?;
+                let resp = self.recv_im(exch_id, OpCode::SubscriptResponse)?;
+                Ok((reports, resp))
+            });
+        self.complete(exch_id);
+        result
+    }
+
+    /// Wait for a report on any of our subscriptions, for up to the given time
+    pub fn recv_report(&mut self, timeout: Duration) -> Result<Option<ImResponse>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(msg) = self.reports.pop_front() {
+                let resp = ImResponse {
+                    opcode: OpCode::ReportData,
+                    payload: msg.payload,
+                };
+                let result = self.ack_report(msg.exch_id, &resp);
+                self.complete(msg.exch_id);
+                return result.map(|_| Some(resp));
+            }
+            if Instant::now() > deadline {
+                return Ok(None);
+            }
+            if let Some(msg) = self.recv_msg()? {
+                self.queue_report(msg);
+            }
+        }
+    }
+
+    /// Receive the reports on this exchange until the last chunk, confirming each report
+    /// that expects a status response
+    fn recv_reports(&mut self, exch_id: u16) -> Result<Vec<ImResponse>, Error> {
+        let mut reports = Vec::new();
+        loop {
+            let resp = self.recv_im(exch_id, OpCode::ReportData)?;
+            let more_chunks = self.ack_report(exch_id, &resp)?;
+            reports.push(resp);
+            if !more_chunks {
+                return Ok(reports);
+            }
+        }
+    }
+
+    /// Send a status response for the report, if the report expects one. Returns whether
+    /// more chunks of the report are to follow.
+    fn ack_report(&mut self, exch_id: u16, resp: &ImResponse) -> Result<bool, Error> {
+        let (more_chunks, suppress_response) = {
+            let report = ReportDataMsg::from_tlv(&resp.root()?)?;
+            (
+                report.more_chunks.unwrap_or(false),
+                report.suppress_response.unwrap_or(false),
+            )
+        };
+        if !suppress_response {
+            let status = StatusResp {
+                status: IMStatusCode::Sucess,
+            };
+            self.send_im(exch_id, OpCode::StatusResponse, &status)?;
+        }
+        Ok(more_chunks)
+    }
+
+    /// The attestation challenge of the session with the given local session id
+    fn att_challenge(&mut self, sess_id: u16) -> Result<Vec<u8>, Error> {
+        let sess = self
+            .exch_mgr
+            .get_sess_mgr()
+            .get_with_id(sess_id)
+            .ok_or(Error::NoSession)?;
+        Ok(sess.get_att_challenge().to_vec())
+    }
+
+    fn initiate(&mut self, sess_id: u16) -> Result<u16, Error> {
+        Ok(self.exch_mgr.initiate(sess_id)?.exch.get_id())
+    }
+
+    /// Initiate an exchange on the unsecured session, and reserve the local session id for
+    /// the secure session that is to be established on it
+    fn initiate_unsecured(&mut self) -> Result<(u16, u16), Error> {
+        let mut exch_ctx = self.exch_mgr.initiate(0)?;
+        let local_sessid = exch_ctx.sess.reserve_new_sess_id();
+        Ok((exch_ctx.exch.get_id(), local_sessid))
+    }
+
+    /// Close out the exchange, once we are done with it
+    fn complete(&mut self, exch_id: u16) {
+        if let Some(exch) = self.exch_mgr.get_with_id(exch_id) {
+            exch.close();
+        }
+        if let Err(e) = self.send_acks() {
+            error!("Error in sending Acks {:?}", e);
+        }
+        self.exch_mgr.purge();
+    }
+
+    fn send_im<T: ToTLV>(&mut self, exch_id: u16, opcode: OpCode, msg: &T) -> Result<(), Error> {
+        let mut tx = self.exch_mgr.new_tx()?;
+        tx.set_proto_id(PROTO_ID_INTERACTION_MODEL as u16);
+        tx.set_proto_opcode(opcode as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        msg.to_tlv(&mut tw, TagType::Anonymous)?;
+        self.exch_mgr.send(exch_id, tx)
+    }
+
+    fn recv_im(&mut self, exch_id: u16, opcode: OpCode) -> Result<ImResponse, Error> {
+        let msg = self.recv_on(exch_id)?;
+        if msg.proto_id != PROTO_ID_INTERACTION_MODEL as u16 {
+            error!("Unexpected protocol {} in response", msg.proto_id);
+            return Err(Error::Invalid);
+        }
+        let resp = ImResponse {
+            opcode: num::FromPrimitive::from_u8(msg.opcode).ok_or(Error::InvalidOpcode)?,
+            payload: msg.payload,
+        };
+        if resp.opcode != opcode {
+            if resp.opcode == OpCode::StatusResponse {
+                let status = StatusResp::from_tlv(&resp.root()?)?;
+                error!("Request failed with status {:?}", status.status);
+            } else {
+                error!("Expected {:?}, received {:?}", opcode, resp.opcode);
+            }
+            return Err(Error::InvalidOpcode);
+        }
+        Ok(resp)
+    }
+
+    /// Receive the secure channel message with the given opcode. A status report that
+    /// reports a failure is always an error.
+    fn recv_sc(&mut self, exch_id: u16, opcode: common::OpCode) -> Result<Vec<u8>, Error> {
+        self.recv_sc_one_of(exch_id, &[opcode])
+            .map(|(_, payload)| payload)
+    }
+
+    /// Receive a secure channel message with any of the given opcodes, along with its opcode.
+    /// A successful status report is only accepted if it is one of them.
+    fn recv_sc_one_of(
+        &mut self,
+        exch_id: u16,
+        opcodes: &[common::OpCode],
+    ) -> Result<(u8, Vec<u8>), Error> {
+        let msg = self.recv_on(exch_id)?;
+        if msg.proto_id != PROTO_ID_SECURE_CHANNEL as u16 {
+            error!("Unexpected protocol {} in response", msg.proto_id);
+            return Err(Error::Invalid);
+        }
+        if msg.opcode == common::OpCode::StatusReport as u8 {
+            // General Code, Protocol Id and Protocol Code
+            if msg.payload.len() < 8 {
+                return Err(Error::TruncatedPacket);
+            }
+            let general_code = LittleEndian::read_u16(&msg.payload[0..2]);
+            let proto_code = LittleEndian::read_u16(&msg.payload[6..8]);
+            if general_code != 0 || proto_code != 0 {
+                error!(
+                    "Session establishment failed, general code: {} protocol code: {}",
+                    general_code, proto_code
+                );
+                return Err(Error::Invalid);
+            }
+        }
+        if !opcodes.iter().any(|o| msg.opcode == *o as u8) {
+            error!("Expected {:?}, received opcode {}", opcodes, msg.opcode);
+            return Err(Error::InvalidOpcode);
+        }
+        Ok((msg.opcode, msg.payload))
+    }
+
+    /// Receive the next message on the given exchange
+    fn recv_on(&mut self, exch_id: u16) -> Result<RxMsg, Error> {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            if Instant::now() > deadline {
+                error!("Timed out waiting for a response on exchange {}", exch_id);
+                return Err(Error::Timeout);
+            }
+            self.retransmit();
+            if !matches!(self.exch_mgr.get_with_id(exch_id), Some(e) if e.is_state_open()) {
+                error!(
+                    "Peer didn't acknowledge our message on exchange {}",
+                    exch_id
+                );
+                return Err(Error::Timeout);
+            }
+            if let Some(msg) = self.recv_msg()? {
+                if msg.exch_id == exch_id {
+                    return Ok(msg);
+                }
+                self.queue_report(msg);
+            }
+        }
+    }
+
+    fn queue_report(&mut self, msg: RxMsg) {
+        if msg.is_report() {
+            self.reports.push_back(msg);
+        } else {
+            info!(
+                "Dropping message with opcode {} on exchange {}",
+                msg.opcode, msg.exch_id
+            );
+        }
+    }
+
+    fn recv_msg(&mut self) -> Result<Option<RxMsg>, Error> {
+        let result = match self.exch_mgr.recv() {
+            Ok(r) => r,
+            // Nothing was received in this poll interval
+            Err(Error::Timeout) => {
+                self.send_acks()?;
+                return Ok(None);
+            }
+            Err(e) => {
+                error!("Error in recv: {:?}", e);
+                return Ok(None);
+            }
+        };
+        let (mut rx, exch_ctx) = if let Some(r) = result {
+            r
+        } else {
+            return Ok(None);
+        };
+
+        let msg = RxMsg {
+            exch_id: exch_ctx.exch.get_id(),
+            peer_initiated: exch_ctx.exch.get_role() == Role::Responder,
+            proto_id: rx.get_proto_id(),
+            opcode: rx.get_proto_opcode(),
+            payload: rx.as_borrow_slice().to_vec(),
+        };
+        if msg.proto_id == PROTO_ID_SECURE_CHANNEL as u16
+            && msg.opcode == common::OpCode::MRPStandAloneAck as u8
+        {
+            // The acknowledgement has already been processed by the exchange
+            return Ok(None);
+        }
+        Ok(Some(msg))
+    }
+
+    /// Retransmit any unacknowledged messages that are due, and close the exchanges on which
+    /// we have given up
+    fn retransmit(&mut self) {
+        let mut failed: LinearMap<u16, u16, { exchange::MAX_EXCHANGES }> = LinearMap::new();
+        self.exch_mgr.retransmit(&mut failed);
+        for exch_id in failed.keys() {
+            if let Some(exch) = self.exch_mgr.get_with_id(*exch_id) {
+                exch.close();
+            }
+        }
+    }
+
+    fn send_acks(&mut self) -> Result<(), Error> {
+        let mut acks_to_send: LinearMap<u16, (), { exchange::MAX_EXCHANGES }> = LinearMap::new();
+        self.exch_mgr.pending_acks(&mut acks_to_send);
+        for exch_id in acks_to_send.keys() {
+            let mut tx = self.exch_mgr.new_tx()?;
+            ReliableMessage::prepare_ack(*exch_id, &mut tx);
+            self.exch_mgr.send(*exch_id, tx)?;
+        }
+        Ok(())
+    }
+}
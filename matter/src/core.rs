@@ -23,11 +23,18 @@ use crate::{
     },
     error::*,
     fabric::FabricMgr,
+    group_keys::GroupMgr,
     interaction_model::InteractionModel,
     mdns::Mdns,
-    pairing::{print_pairing_code_and_qr, DiscoveryCapabilities},
+    persist::KvStorage,
     secure_channel::{core::SecureChannel, pake::PaseMgr, spake2p::VerifierData},
-    transport,
+    transport::{
+        self,
+        capture::Capture,
+        mgr::{SessionControl, StopHandle},
+        network::{NetworkInterface, TransportConfig},
+    },
+    utils::epoch::UtcTime,
 };
 use std::sync::Arc;
 
@@ -44,6 +51,7 @@ pub struct Matter {
     transport_mgr: transport::mgr::Mgr,
     data_model: DataModel,
     fabric_mgr: Arc<FabricMgr>,
+    pase: PaseMgr,
 }
 
 impl Matter {
@@ -53,26 +61,75 @@ impl Matter {
     /// * dev_att: An object that implements the trait [DevAttDataFetcher]. Any Matter device
     /// requires a set of device attestation certificates and keys. It is the responsibility of
     /// this object to return the device attestation details when queried upon.
+    /// * storage: The storage where the device's state, like the fabrics and the ACLs, is
+    /// persisted. See [persist](crate::persist) for the available storages.
+    /// * transport: The address and port to listen on, see [TransportConfig].
+    ///
+    /// The pairing codes of the device aren't printed, they can be generated with
+    /// [PairingCodes](crate::pairing::PairingCodes) for showing or printing them.
     pub fn new(
         dev_det: BasicInfoConfig,
         dev_att: Box<dyn DevAttDataFetcher>,
         dev_comm: CommissioningData,
+        storage: Arc<dyn KvStorage>,
+        transport: TransportConfig,
     ) -> Result<Box<Matter>, Error> {
-        let mdns = Mdns::get()?;
-        mdns.set_values(dev_det.vid, dev_det.pid, &dev_det.device_name);
+        let transport_mgr = transport::mgr::Mgr::new(&transport)?;
+        let mdns = Arc::new(Mdns::new());
+        mdns.set_tcp(transport.tcp);
+        Matter::new_with_transport(dev_det, dev_att, dev_comm, storage, transport_mgr, mdns)
+    }
+
+    /// Creates a new Matter object that communicates through the given network interface,
+    /// instead of the sockets
+    ///
+    /// This is mostly for the tests, where a [Loopback](transport::loopback::Loopback)
+    /// connects the device to a controller in the same process. The parameters are as for
+    /// [new](Matter::new).
+    pub fn new_with_network(
+        dev_det: BasicInfoConfig,
+        dev_att: Box<dyn DevAttDataFetcher>,
+        dev_comm: CommissioningData,
+        storage: Arc<dyn KvStorage>,
+        network: Box<dyn NetworkInterface>,
+    ) -> Result<Box<Matter>, Error> {
+        let transport_mgr = transport::mgr::Mgr::new_with_network(network)?;
+        let mdns = Arc::new(Mdns::new());
+        Matter::new_with_transport(dev_det, dev_att, dev_comm, storage, transport_mgr, mdns)
+    }
 
-        print_pairing_code_and_qr(&dev_det, &dev_comm, DiscoveryCapabilities::default());
+    fn new_with_transport(
+        dev_det: BasicInfoConfig,
+        dev_att: Box<dyn DevAttDataFetcher>,
+        dev_comm: CommissioningData,
+        storage: Arc<dyn KvStorage>,
+        mut transport_mgr: transport::mgr::Mgr,
+        mdns: Arc<Mdns>,
+    ) -> Result<Box<Matter>, Error> {
+        mdns.set_values(dev_det.vid, dev_det.pid, &dev_det.device_name);
+        mdns.set_port(transport_mgr.get_port());
+        transport_mgr.set_storage(storage.clone())?;
 
-        let fabric_mgr = Arc::new(FabricMgr::new()?);
-        let acl_mgr = Arc::new(AclMgr::new()?);
-        let mut pase = PaseMgr::new();
+        let fabric_mgr = Arc::new(FabricMgr::new(storage.clone(), mdns.clone())?);
+        let acl_mgr = Arc::new(AclMgr::new(storage.clone())?);
+        let group_mgr = Arc::new(GroupMgr::new(storage.clone(), fabric_mgr.clone())?);
+        transport_mgr.set_group_mgr(group_mgr.clone());
+        let mut pase = PaseMgr::new(mdns);
         let open_comm_window = fabric_mgr.is_empty();
-        let data_model =
-            DataModel::new(dev_det, dev_att, fabric_mgr.clone(), acl_mgr, pase.clone())?;
+        let data_model = DataModel::new(
+            dev_det,
+            dev_att,
+            fabric_mgr.clone(),
+            acl_mgr,
+            group_mgr,
+            pase.clone(),
+            storage,
+        )?;
         let mut matter = Box::new(Matter {
-            transport_mgr: transport::mgr::Mgr::new()?,
+            transport_mgr,
             data_model,
             fabric_mgr,
+            pase: pase.clone(),
         });
         let interaction_model =
             Box::new(InteractionModel::new(Box::new(matter.data_model.clone())));
@@ -82,7 +139,11 @@ impl Matter {
             pase.enable_pase_session(dev_comm.verifier, dev_comm.discriminator)?;
         }
 
-        let secure_channel = Box::new(SecureChannel::new(pase, matter.fabric_mgr.clone()));
+        let secure_channel = Box::new(SecureChannel::new(
+            pase,
+            matter.fabric_mgr.clone(),
+            matter.transport_mgr.get_work_q(),
+        ));
         matter.transport_mgr.register_protocol(secure_channel)?;
         Ok(matter)
     }
@@ -96,13 +157,59 @@ impl Matter {
         self.data_model.clone()
     }
 
+    /// Capture all the messages that the device receives and sends, in plaintext
+    ///
+    /// This is for debugging the communication with other Matter devices, like with a
+    /// [JsonCapture](transport::capture::JsonCapture) into a file.
+    pub fn set_capture(&mut self, capture: Box<dyn Capture>) {
+        self.transport_mgr.set_capture(capture);
+    }
+
+    /// Set the source of the UTC time that the certificates are checked against
+    ///
+    /// This is the system clock by default. While the time isn't known, the certificates
+    /// are checked against the Last Known Good UTC Time, which is persisted.
+    pub fn set_utc_time(&self, utc_time: Box<dyn UtcTime>) {
+        self.fabric_mgr.set_utc_time(utc_time);
+    }
+
+    /// Returns a handle to stop the Matter stack
+    ///
+    /// The handle can be used from any task or thread, to make [run](Matter::run) or
+    /// [start_daemon](Matter::start_daemon) return.
+    pub fn get_stop_handle(&self) -> StopHandle {
+        self.transport_mgr.get_stop_handle()
+    }
+
+    /// Returns a handle to list and close the secure sessions, while the Matter stack runs
+    pub fn get_session_control(&self) -> SessionControl {
+        self.transport_mgr.get_session_control()
+    }
+
+    /// Runs the Matter stack
+    ///
+    /// The returned future handles the communication with other Matter devices on the
+    /// network, so it is typically run next to the application's other tasks on an async
+    /// executor. It completes if there is an error on the network, or once the stack is
+    /// stopped through a [StopHandle].
+    ///
+    /// On a stop, the active sessions are closed and the mDNS records are withdrawn. The
+    /// Matter object should then be dropped, which frees the UDP port, and a new one
+    /// created to start the device again.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        self.transport_mgr.run().await?;
+        self.pase.disable_pase_session();
+        self.fabric_mgr.withdraw_mdns();
+        Ok(())
+    }
+
     /// Starts the Matter daemon
     ///
-    /// This call does NOT return
+    /// This call does NOT return, until the daemon is stopped through a [StopHandle]
     ///
-    /// This call starts the Matter daemon that starts communication with other Matter
-    /// devices on the network.
+    /// This call runs [run](Matter::run) on the current thread, for applications that
+    /// don't have an async executor of their own.
     pub fn start_daemon(&mut self) -> Result<(), Error> {
-        self.transport_mgr.start()
+        smol::block_on(self.run())
     }
 }
@@ -107,6 +107,21 @@ impl CryptoKeyPair for KeyPair {
     }
 }
 
+pub fn x509_pubkey(_der: &[u8], _out_key: &mut [u8]) -> Result<usize, Error> {
+    error!("This API should never get called");
+    Err(Error::Invalid)
+}
+
+pub fn x509_verify(_der: &[u8], _issuer_der: &[u8]) -> Result<(), Error> {
+    error!("This API should never get called");
+    Err(Error::Invalid)
+}
+
+pub fn csr_pubkey(_der: &[u8], _out_key: &mut [u8]) -> Result<usize, Error> {
+    error!("This API should never get called");
+    Err(Error::Invalid)
+}
+
 pub fn pbkdf2_hmac(pass: &[u8], iter: usize, salt: &[u8], key: &mut [u8]) -> Result<(), Error> {
     error!("This API should never get called");
 
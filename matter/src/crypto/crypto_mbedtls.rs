@@ -19,6 +19,7 @@ use std::sync::Arc;
 
 use log::error;
 use mbedtls::{
+    alloc::List as MbedtlsList,
     bignum::Mpi,
     cipher::{Authenticated, Cipher},
     ecp::EcPoint,
@@ -272,6 +273,91 @@ fn convert_asn1_sign_to_r_s(signature: &mut [u8]) -> Result<usize, Error> {
     }
 }
 
+fn ec_point_from_pk(key: &Pk, out_key: &mut [u8]) -> Result<usize, Error> {
+    let group = EcGroup::new(EcGroupId::SecP256R1)?;
+    let vec = key.ec_public()?.to_binary(&group, false)?;
+    let len = vec.len();
+    if len > out_key.len() {
+        return Err(Error::NoSpace);
+    }
+    out_key[..len].copy_from_slice(vec.as_slice());
+    Ok(len)
+}
+
+/// Get the (uncompressed) EC public key of a DER encoded X.509 certificate
+pub fn x509_pubkey(der: &[u8], out_key: &mut [u8]) -> Result<usize, Error> {
+    let cert = x509::Certificate::from_der(der)?;
+    ec_point_from_pk(cert.public_key(), out_key)
+}
+
+/// Verify the signature of a DER encoded X.509 certificate, against the public key of its
+/// DER encoded issuer
+pub fn x509_verify(der: &[u8], issuer_der: &[u8]) -> Result<(), Error> {
+    let mut chain = MbedtlsList::<x509::Certificate>::new();
+    chain.push(x509::Certificate::from_der(der)?);
+    let mut trust_ca = MbedtlsList::<x509::Certificate>::new();
+    trust_ca.push(x509::Certificate::from_der(issuer_der)?);
+    x509::Certificate::verify(&chain, &trust_ca, None, None).map_err(|e| {
+        error!("Error in certificate verification {}", e);
+        Error::InvalidSignature
+    })
+}
+
+/// Split the first element off a DER encoded buffer. Returns its tag, the whole element,
+/// its contents, and the rest of the buffer.
+fn der_next(buf: &[u8]) -> Result<(u8, &[u8], &[u8], &[u8]), Error> {
+    let tag = *buf.first().ok_or(Error::Invalid)?;
+    let first = *buf.get(1).ok_or(Error::Invalid)?;
+    let (hdr_len, len) = if first & 0x80 == 0 {
+        (2, first as usize)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 2 {
+            return Err(Error::Invalid);
+        }
+        let len_bytes = buf.get(2..2 + n).ok_or(Error::Invalid)?;
+        (
+            2 + n,
+            len_bytes.iter().fold(0, |l, b| (l << 8) | *b as usize),
+        )
+    };
+    let end = hdr_len + len;
+    if end > buf.len() {
+        return Err(Error::Invalid);
+    }
+    Ok((tag, &buf[..end], &buf[hdr_len..end], &buf[end..]))
+}
+
+/// Get the (uncompressed) EC public key of a DER encoded CSR, once its signature is verified
+pub fn csr_pubkey(der: &[u8], out_key: &mut [u8]) -> Result<usize, Error> {
+    let csr = x509::Csr::from_der(der)?;
+
+    // No rust-mbedtls API yet for verifying the CSR's signature, so we pick the CSR apart:
+    // the CertificationRequestInfo, the signature algorithm, and the signature
+    let (_, _, csr_seq, _) = der_next(der)?;
+    let (_, info, _, rest) = der_next(csr_seq)?;
+    let (_, _, _, rest) = der_next(rest)?;
+    let (tag, _, signature, _) = der_next(rest)?;
+    // A BIT STRING, without any unused bits
+    if tag != 0x03 || signature.first() != Some(&0) {
+        return Err(Error::InvalidSignature);
+    }
+
+    let mut key = Pk::public_from_ec_components(
+        EcGroup::new(EcGroupId::SecP256R1)?,
+        csr.public_key().ec_public()?,
+    )?;
+    let mut info_hash = [0_u8; super::SHA256_HASH_LEN_BYTES];
+    Md::hash(hash::Type::Sha256, info, &mut info_hash)?;
+    key.verify(hash::Type::Sha256, &info_hash, &signature[1..])
+        .map_err(|e| {
+            error!("Error in CSR verification {}", e);
+            Error::InvalidSignature
+        })?;
+
+    ec_point_from_pk(csr.public_key(), out_key)
+}
+
 pub fn pbkdf2_hmac(pass: &[u8], iter: usize, salt: &[u8], key: &mut [u8]) -> Result<(), Error> {
     mbedtls::hash::pbkdf2_hmac(Type::Sha256, pass, salt, iter as u32, key)
         .map_err(|_e| Error::TLSStack)
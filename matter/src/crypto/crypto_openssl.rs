@@ -34,7 +34,7 @@ use openssl::nid::Nid;
 use openssl::pkey::{self, Id, PKey, Private};
 use openssl::pkey_ctx::PkeyCtx;
 use openssl::symm::{self};
-use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use openssl::x509::{X509NameBuilder, X509Req, X509ReqBuilder, X509};
 
 // We directly use the hmac crate here, there was a self-referential structure
 // problem while using OpenSSL's Signer
@@ -186,10 +186,11 @@ impl CryptoKeyPair for KeyPair {
         safemem::write_bytes(signature, 0);
 
         let sig = EcdsaSig::sign(&msg, self.private_key()?)?;
-        let r = sig.r().to_vec();
-        signature[0..r.len()].copy_from_slice(r.as_slice());
-        let s = sig.s().to_vec();
-        signature[32..(32 + s.len())].copy_from_slice(s.as_slice());
+        // 'r' and 's' may be shorter than 32 bytes, these have to be zero-padded in front
+        let r = sig.r().to_vec_padded(32)?;
+        signature[0..32].copy_from_slice(r.as_slice());
+        let s = sig.s().to_vec_padded(32)?;
+        signature[32..64].copy_from_slice(s.as_slice());
         Ok(64)
     }
 
@@ -233,6 +234,48 @@ pub fn pubkey_from_der<'a>(der: &'a [u8], out_key: &mut [u8]) -> Result<(), Erro
     }
 }
 
+fn ec_point_from_pkey(key: &PKey<pkey::Public>, out_key: &mut [u8]) -> Result<usize, Error> {
+    let key = key.ec_key()?;
+    let mut bn_ctx = BigNumContext::new()?;
+    let s =
+        key.public_key()
+            .to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut bn_ctx)?;
+    let len = s.len();
+    if len > out_key.len() {
+        return Err(Error::NoSpace);
+    }
+    out_key[..len].copy_from_slice(s.as_slice());
+    Ok(len)
+}
+
+/// Get the (uncompressed) EC public key of a DER encoded X.509 certificate
+pub fn x509_pubkey(der: &[u8], out_key: &mut [u8]) -> Result<usize, Error> {
+    ec_point_from_pkey(&X509::from_der(der)?.public_key()?, out_key)
+}
+
+/// Verify the signature of a DER encoded X.509 certificate, against the public key of its
+/// DER encoded issuer
+pub fn x509_verify(der: &[u8], issuer_der: &[u8]) -> Result<(), Error> {
+    let cert = X509::from_der(der)?;
+    let issuer = X509::from_der(issuer_der)?;
+    if cert.verify(&*issuer.public_key()?)? {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+/// Get the (uncompressed) EC public key of a DER encoded CSR, once the CSR's signature is
+/// verified
+pub fn csr_pubkey(der: &[u8], out_key: &mut [u8]) -> Result<usize, Error> {
+    let csr = X509Req::from_der(der)?;
+    let key = csr.public_key()?;
+    if !csr.verify(&key)? {
+        return Err(Error::InvalidSignature);
+    }
+    ec_point_from_pkey(&key, out_key)
+}
+
 pub fn pbkdf2_hmac(pass: &[u8], iter: usize, salt: &[u8], key: &mut [u8]) -> Result<(), Error> {
     openssl::pkcs5::pbkdf2_hmac(pass, salt, iter, MessageDigest::sha256(), key)
         .map_err(|_e| Error::TLSStack)
@@ -0,0 +1,309 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use super::objects::*;
+use crate::{
+    cmd_enter,
+    error::*,
+    group_keys::{self, GroupMgr},
+    interaction_model::{command::CommandReq, core::IMStatusCode, messages::ib},
+    tlv::{
+        FromTLV, Nullable, TLVArray, TLVArrayOwned, TLVElement, TLVWriter, TagType, ToTLV, UtfStr,
+    },
+};
+use log::info;
+use num_derive::FromPrimitive;
+
+pub const ID: u32 = 0x0004;
+
+// The group names are supported
+const NAME_SUPPORT: u8 = 0x80;
+
+pub enum Attributes {
+    NameSupport = 0x0,
+}
+
+// The responses have the ids of their commands
+#[derive(FromPrimitive)]
+pub enum Commands {
+    AddGroup = 0x00,
+    ViewGroup = 0x01,
+    GetGroupMembership = 0x02,
+    RemoveGroup = 0x03,
+    RemoveAllGroups = 0x04,
+    AddGroupIfIdentifying = 0x05,
+}
+
+fn attr_name_support_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::NameSupport as u16,
+        AttrValue::Uint8(NAME_SUPPORT),
+        Access::RV,
+        Quality::FIXED,
+    )
+}
+
+/// The groups that an endpoint is a member of
+///
+/// The groups are in the group table of the [GroupMgr], along with the groups of the other
+/// endpoints.
+pub struct GroupsCluster {
+    base: Cluster,
+    group_mgr: Arc<GroupMgr>,
+}
+
+impl GroupsCluster {
+    pub fn new(group_mgr: Arc<GroupMgr>) -> Result<Box<Self>, Error> {
+        let mut cluster = Box::new(GroupsCluster {
+            base: Cluster::new(ID)?,
+            group_mgr,
+        });
+        cluster.base.add_attribute(attr_name_support_new()?)?;
+        Ok(cluster)
+    }
+
+    fn send_group_resp(
+        cmd_req: &mut CommandReq,
+        cmd: Commands,
+        status: IMStatusCode,
+        group_id: u16,
+        name: Option<&str>,
+    ) {
+        let resp = GroupResp {
+            status: status as u8,
+            group_id,
+            name: name.map(|n| n.to_owned()),
+        };
+        let invoke_resp = ib::InvResp::cmd_new(
+            cmd_req.cmd.path.endpoint.unwrap_or_default(),
+            ID,
+            cmd as u16,
+            EncodeValue::Value(&resp),
+        );
+        let _ = invoke_resp.to_tlv(cmd_req.resp, TagType::Anonymous);
+        cmd_req.trans.complete();
+    }
+
+    fn add_group(&self, fab_idx: u8, endpoint: u16, req: &AddGroupReq) -> IMStatusCode {
+        let name = match req.name.to_string() {
+            Ok(name) if name.len() <= group_keys::MAX_GROUP_NAME_LEN => name,
+            _ => return IMStatusCode::ConstraintError,
+        };
+        if req.group_id == 0 {
+            return IMStatusCode::ConstraintError;
+        }
+        // The group can only be added once it has a key
+        if !self.group_mgr.has_key(fab_idx, req.group_id) {
+            return IMStatusCode::UnsupportedAccess;
+        }
+        match self
+            .group_mgr
+            .add_group(fab_idx, req.group_id, endpoint, &name)
+        {
+            Ok(()) => IMStatusCode::Sucess,
+            Err(Error::NoSpace) => IMStatusCode::ResourceExhausted,
+            Err(_) => IMStatusCode::Failure,
+        }
+    }
+
+    fn handle_command_addgroup(
+        &mut self,
+        cmd_req: &mut CommandReq,
+        fab_idx: u8,
+        endpoint: u16,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("AddGroup");
+        let req = AddGroupReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        let status = self.add_group(fab_idx, endpoint, &req);
+        Self::send_group_resp(cmd_req, Commands::AddGroup, status, req.group_id, None);
+        Ok(())
+    }
+
+    fn handle_command_viewgroup(
+        &mut self,
+        cmd_req: &mut CommandReq,
+        fab_idx: u8,
+        endpoint: u16,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("ViewGroup");
+        let req = GroupReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        let group = if req.group_id == 0 {
+            Err(IMStatusCode::ConstraintError)
+        } else {
+            self.group_mgr
+                .get_group(fab_idx, req.group_id, endpoint)
+                .ok_or(IMStatusCode::NotFound)
+        };
+        match group {
+            Ok(group) => Self::send_group_resp(
+                cmd_req,
+                Commands::ViewGroup,
+                IMStatusCode::Sucess,
+                req.group_id,
+                Some(group.get_name()),
+            ),
+            Err(status) => {
+                Self::send_group_resp(cmd_req, Commands::ViewGroup, status, req.group_id, Some(""))
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_command_getgroupmembership(
+        &mut self,
+        cmd_req: &mut CommandReq,
+        fab_idx: u8,
+        endpoint: u16,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("GetGroupMembership");
+        let req = GetGroupMembershipReq::from_tlv(&cmd_req.data)
+            .map_err(|_| IMStatusCode::InvalidCommand)?;
+        let groups = self
+            .group_mgr
+            .get_groups(fab_idx, endpoint)
+            .map_err(|_| IMStatusCode::Failure)?;
+        // All the groups of the endpoint, if the list is empty, otherwise only the ones in
+        // the list
+        let mut requested = req.group_list.iter().peekable();
+        let groups = if requested.peek().is_none() {
+            groups
+        } else {
+            requested.filter(|g| groups.contains(g)).collect()
+        };
+
+        let resp = GetGroupMembershipResp {
+            capacity: Nullable::NotNull(self.group_mgr.get_capacity(fab_idx) as u8),
+            group_list: TLVArrayOwned::new(groups),
+        };
+        let invoke_resp = ib::InvResp::cmd_new(
+            endpoint,
+            ID,
+            Commands::GetGroupMembership as u16,
+            EncodeValue::Value(&resp),
+        );
+        let _ = invoke_resp.to_tlv(cmd_req.resp, TagType::Anonymous);
+        cmd_req.trans.complete();
+        Ok(())
+    }
+
+    fn handle_command_removegroup(
+        &mut self,
+        cmd_req: &mut CommandReq,
+        fab_idx: u8,
+        endpoint: u16,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("RemoveGroup");
+        let req = GroupReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        let status = if req.group_id == 0 {
+            IMStatusCode::ConstraintError
+        } else {
+            match self.group_mgr.remove_group(fab_idx, req.group_id, endpoint) {
+                Ok(()) => IMStatusCode::Sucess,
+                Err(Error::NotFound) => IMStatusCode::NotFound,
+                Err(_) => IMStatusCode::Failure,
+            }
+        };
+        Self::send_group_resp(cmd_req, Commands::RemoveGroup, status, req.group_id, None);
+        Ok(())
+    }
+}
+
+impl ClusterType for GroupsCluster {
+    fn base(&self) -> &Cluster {
+        &self.base
+    }
+    fn base_mut(&mut self) -> &mut Cluster {
+        &mut self.base
+    }
+
+    fn handle_command(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        let cmd = cmd_req
+            .cmd
+            .path
+            .leaf
+            .map(num::FromPrimitive::from_u32)
+            .ok_or(IMStatusCode::UnsupportedCommand)?
+            .ok_or(IMStatusCode::UnsupportedCommand)?;
+        // The groups are fabric-scoped
+        let fab_idx = cmd_req
+            .trans
+            .session
+            .get_local_fabric_idx()
+            .ok_or(IMStatusCode::UnsupportedAccess)?;
+        let endpoint = cmd_req
+            .cmd
+            .path
+            .endpoint
+            .ok_or(IMStatusCode::UnsupportedEndpoint)?;
+        match cmd {
+            Commands::AddGroup => self.handle_command_addgroup(cmd_req, fab_idx, endpoint),
+            Commands::ViewGroup => self.handle_command_viewgroup(cmd_req, fab_idx, endpoint),
+            Commands::GetGroupMembership => {
+                self.handle_command_getgroupmembership(cmd_req, fab_idx, endpoint)
+            }
+            Commands::RemoveGroup => self.handle_command_removegroup(cmd_req, fab_idx, endpoint),
+            Commands::RemoveAllGroups => {
+                cmd_enter!("RemoveAllGroups");
+                self.group_mgr
+                    .remove_all_groups(fab_idx, endpoint)
+                    .map_err(|_| IMStatusCode::Failure)?;
+                cmd_req.trans.complete();
+                Err(IMStatusCode::Sucess)
+            }
+            Commands::AddGroupIfIdentifying => {
+                cmd_enter!("AddGroupIfIdentifying");
+                // There is no Identify cluster, so the endpoint is never identifying
+                AddGroupReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+                cmd_req.trans.complete();
+                Err(IMStatusCode::Sucess)
+            }
+        }
+    }
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct AddGroupReq<'a> {
+    group_id: u16,
+    name: UtfStr<'a>,
+}
+
+#[derive(FromTLV)]
+struct GroupReq {
+    group_id: u16,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct GetGroupMembershipReq<'a> {
+    group_list: TLVArray<'a, u16>,
+}
+
+#[derive(ToTLV)]
+struct GroupResp {
+    status: u8,
+    group_id: u16,
+    name: Option<String>,
+}
+
+#[derive(ToTLV)]
+struct GetGroupMembershipResp {
+    capacity: Nullable<u8>,
+    group_list: TLVArrayOwned<u16>,
+}
@@ -17,6 +17,7 @@
 
 use super::{
     cluster_basic_information::BasicInfoConfig,
+    cluster_groups::GroupsCluster,
     device_types::device_type_add_root_node,
     objects::{self, *},
     sdm::dev_att::DevAttDataFetcher,
@@ -26,27 +27,34 @@ use crate::{
     acl::{AccessReq, Accessor, AccessorSubjects, AclMgr, AuthMode},
     error::*,
     fabric::FabricMgr,
+    group_keys::GroupMgr,
     interaction_model::{
         command::CommandReq,
-        core::IMStatusCode,
+        core::{IMStatusCode, MAX_REPORT_TAIL},
         messages::{
-            ib::{self, AttrData, DataVersionFilter},
+            ib::{self, AttrData, DataVersionFilter, EventData, EventPath, EventResp, EventStatus},
             msg::{self, InvReq, ReadReq, WriteReq},
             GenericPath,
         },
-        InteractionConsumer, Transaction,
+        InteractionConsumer, ResumeCursor, Transaction,
     },
+    persist::KvStorage,
     secure_channel::pake::PaseMgr,
     tlv::{TLVArray, TLVWriter, TagType, ToTLV},
     transport::session::{Session, SessionMode},
 };
 use log::{error, info};
-use std::sync::{Arc, RwLock};
+use std::{
+    cell::Cell,
+    sync::{Arc, RwLock},
+};
 
 #[derive(Clone)]
 pub struct DataModel {
     pub node: Arc<RwLock<Box<Node>>>,
+    pub events: Arc<EventMgr>,
     acl_mgr: Arc<AclMgr>,
+    group_mgr: Arc<GroupMgr>,
 }
 
 impl DataModel {
@@ -55,11 +63,15 @@ impl DataModel {
         dev_att: Box<dyn DevAttDataFetcher>,
         fabric_mgr: Arc<FabricMgr>,
         acl_mgr: Arc<AclMgr>,
+        group_mgr: Arc<GroupMgr>,
         pase_mgr: PaseMgr,
+        storage: Arc<dyn KvStorage>,
     ) -> Result<Self, Error> {
         let dm = DataModel {
             node: Arc::new(RwLock::new(Node::new()?)),
+            events: Arc::new(EventMgr::new_persisted(storage)?),
             acl_mgr: acl_mgr.clone(),
+            group_mgr: group_mgr.clone(),
         };
         {
             let mut node = dm.node.write()?;
@@ -70,6 +82,7 @@ impl DataModel {
                 dev_att,
                 fabric_mgr,
                 acl_mgr,
+                group_mgr,
                 pase_mgr,
             )?;
         }
@@ -154,17 +167,33 @@ impl DataModel {
     }
 
     // Encode a read attribute from a path that may or may not be wildcard
+    //
+    // The first 'skip' attributes matching the path are skipped, these were encoded in an
+    // earlier chunk. If we run out of space in this chunk, the index of the attribute to
+    // resume from is returned.
     fn handle_read_attr_path(
         node: &Node,
         accessor: &Accessor,
         attr_encoder: &mut AttrReadEncoder,
         attr_details: &mut AttrDetails,
-    ) {
+        skip: usize,
+    ) -> Option<usize> {
         let path = attr_encoder.path;
         // Skip error reporting for wildcard paths, don't for concrete paths
         attr_encoder.skip_error(path.is_wildcard());
 
+        let mut attr_index = 0;
+        let mut resume = None;
         let result = node.for_each_attribute(&path, |path, c| {
+            if resume.is_some() {
+                // Out of space, nothing more goes in this chunk
+                return Ok(());
+            }
+            attr_index += 1;
+            if attr_index <= skip {
+                return Ok(());
+            }
+
             // Ignore processing if data filter matches.
             // For a wildcard attribute, this may end happening unnecessarily for all attributes, although
             // a single skip for the cluster is sufficient. That requires us to replace this for_each with a
@@ -180,13 +209,55 @@ impl DataModel {
             // Set the cluster's data version
             attr_encoder.set_data_ver(cluster_data_ver);
             let mut access_req = AccessReq::new(accessor, path, Access::READ);
+            let anchor = attr_encoder.tw.get_tail();
             Cluster::read_attribute(c, &mut access_req, attr_encoder, attr_details);
+            if attr_encoder.tw.get_tail() > MAX_REPORT_TAIL {
+                // This attribute goes in the next chunk
+                attr_encoder.tw.rewind_to(anchor);
+                resume = Some(attr_index - 1);
+            }
             Ok(())
         });
         if let Err(e) = result {
             // We hit this only if this is a non-wildcard path
             attr_encoder.encode_status(e, 0);
         }
+        resume
+    }
+
+    // Encode an event as an EventReportIB
+    fn encode_event(tw: &mut TLVWriter, event: &Event) -> Result<(), Error> {
+        let path = EventPath::new(&GenericPath::new(
+            Some(event.endpoint),
+            Some(event.cluster),
+            Some(event.event_id),
+        ));
+        // The closure can't return the error, so it is picked up once the event is encoded
+        let result = Cell::new(Ok(()));
+        let data = |_: TagType, tw: &mut TLVWriter| {
+            // The payload is stored along with its tag
+            if let Err(e) = tw.raw_tlv(event.data()) {
+                result.set(Err(e));
+            }
+        };
+        EventResp::Data(EventData {
+            path,
+            event_number: event.number,
+            priority: event.priority as u8,
+            epoch_ts: Some(event.timestamp),
+            system_ts: None,
+            delta_epoch_ts: None,
+            delta_system_ts: None,
+            data: EncodeValue::Closure(&data),
+        })
+        .to_tlv(tw, TagType::Anonymous)?;
+        result.get()
+    }
+
+    fn event_path_matches(path: &EventPath, event: &Event) -> bool {
+        (path.endpoint.is_none() || path.endpoint == Some(event.endpoint))
+            && (path.cluster.is_none() || path.cluster == Some(event.cluster))
+            && (path.event.is_none() || path.event == Some(event.event_id))
     }
 
     // Handle command from a path that may or may not be wildcard
@@ -229,6 +300,12 @@ impl DataModel {
                 }
                 Accessor::new(c.fab_idx, subject, AuthMode::Case, self.acl_mgr.clone())
             }
+            SessionMode::Group(g) => Accessor::new(
+                g.fab_idx,
+                AccessorSubjects::new(g.group_id as u64),
+                AuthMode::Group,
+                self.acl_mgr.clone(),
+            ),
             SessionMode::Pase => Accessor::new(
                 0,
                 AccessorSubjects::new(1),
@@ -245,6 +322,21 @@ impl DataModel {
         }
     }
 
+    /// The concrete paths that a path of a group message is expanded to: one for each
+    /// endpoint of the group. A path with an endpoint that isn't in the group is dropped.
+    fn group_paths(&self, sess: &Session, path: GenericPath) -> Vec<GenericPath> {
+        let g = match sess.get_session_mode() {
+            SessionMode::Group(g) => g,
+            _ => return vec![path],
+        };
+        self.group_mgr
+            .get_endpoints(g.fab_idx, g.group_id)
+            .into_iter()
+            .filter(|e| path.endpoint.is_none() || path.endpoint == Some(*e))
+            .map(|e| GenericPath::new(Some(e), path.cluster, path.leaf))
+            .collect()
+    }
+
     /// Returns true if the path matches the cluster path and the data version is a match
     fn data_filter_matches(
         filters: &Option<&TLVArray<DataVersionFilter>>,
@@ -269,6 +361,9 @@ impl DataModel {
 impl objects::ChangeConsumer for DataModel {
     fn endpoint_added(&self, id: u16, endpoint: &mut Endpoint) -> Result<(), Error> {
         endpoint.add_cluster(DescriptorCluster::new(id, self.clone())?)?;
+        if id != 0 {
+            endpoint.add_cluster(GroupsCluster::new(self.group_mgr.clone())?)?;
+        }
         Ok(())
     }
 }
@@ -285,7 +380,15 @@ impl InteractionConsumer for DataModel {
         tw.start_array(TagType::Context(msg::WriteRespTag::WriteResponses as u8))?;
         let mut node = self.node.write().unwrap();
         for attr_data in write_req.write_requests.iter() {
-            DataModel::handle_write_attr_path(&mut node, &accessor, &attr_data, tw);
+            if !trans.session.is_group() {
+                DataModel::handle_write_attr_path(&mut node, &accessor, &attr_data, tw);
+                continue;
+            }
+            for path in self.group_paths(trans.session, attr_data.path.to_gp()) {
+                let mut attr_data = attr_data;
+                attr_data.path.endpoint = path.endpoint;
+                DataModel::handle_write_attr_path(&mut node, &accessor, &attr_data, tw);
+            }
         }
         tw.end_container()?;
 
@@ -297,7 +400,8 @@ impl InteractionConsumer for DataModel {
         read_req: &ReadReq,
         trans: &mut Transaction,
         tw: &mut TLVWriter,
-    ) -> Result<(), Error> {
+        resume_from: Option<ResumeCursor>,
+    ) -> Result<Option<ResumeCursor>, Error> {
         let mut attr_encoder = AttrReadEncoder::new(tw);
         if let Some(filters) = &read_req.dataver_filters {
             attr_encoder.set_data_ver_filters(filters);
@@ -313,6 +417,8 @@ impl InteractionConsumer for DataModel {
             fab_filter: read_req.fabric_filtered,
         };
 
+        let start = resume_from.unwrap_or_default();
+        let mut resume = None;
         if let Some(attr_requests) = &read_req.attr_requests {
             let accessor = self.sess_to_accessor(trans.session);
             let node = self.node.read().unwrap();
@@ -320,21 +426,110 @@ impl InteractionConsumer for DataModel {
                 .tw
                 .start_array(TagType::Context(msg::ReportDataTag::AttributeReports as u8))?;
 
-            for attr_path in attr_requests.iter() {
+            for (path_index, attr_path) in attr_requests.iter().enumerate().skip(start.path_index) {
                 attr_encoder.set_path(attr_path.to_gp());
                 // Extract the attr_path fields into various structures
                 attr_details.list_index = attr_path.list_index;
                 attr_details.fab_idx = accessor.fab_idx;
-                DataModel::handle_read_attr_path(
+                let skip = if path_index == start.path_index {
+                    start.attr_index
+                } else {
+                    0
+                };
+                if let Some(attr_index) = DataModel::handle_read_attr_path(
                     &node,
                     &accessor,
                     &mut attr_encoder,
                     &mut attr_details,
-                );
+                    skip,
+                ) {
+                    resume = Some(ResumeCursor {
+                        path_index,
+                        attr_index,
+                        event_min: None,
+                    });
+                    break;
+                }
             }
             tw.end_container()?;
         }
-        Ok(())
+
+        if resume == Some(start) {
+            // Not even a single attribute could be encoded in this chunk
+            error!("Attribute too large to fit in a report");
+            return Err(Error::NoSpace);
+        }
+        Ok(resume)
+    }
+
+    fn consume_read_event(
+        &self,
+        read_req: &ReadReq,
+        trans: &mut Transaction,
+        tw: &mut TLVWriter,
+        resume_from: Option<u64>,
+    ) -> Result<Option<u64>, Error> {
+        let event_requests = if let Some(e) = &read_req.event_requests {
+            e
+        } else {
+            return Ok(None);
+        };
+        let accessor = self.sess_to_accessor(trans.session);
+
+        tw.start_array(TagType::Context(msg::ReportDataTag::EventReports as u8))?;
+        if resume_from.is_none() {
+            // Report the concrete paths that don't exist, only in the first chunk
+            let node = self.node.read().unwrap();
+            for path in event_requests.iter() {
+                let path = path.to_gp();
+                if let Err(e) = node.for_each_cluster(&path, |_, _| Ok(())) {
+                    EventResp::Status(EventStatus::new(&path, e, 0))
+                        .to_tlv(tw, TagType::Anonymous)?;
+                }
+            }
+        }
+
+        let start = resume_from.unwrap_or_else(|| read_req.event_min());
+        let mut resume = None;
+        let result = self.events.for_each_event(start, |event| {
+            if !event_requests
+                .iter()
+                .any(|p| DataModel::event_path_matches(&p, event))
+            {
+                return Ok(());
+            }
+            let path = GenericPath::new(
+                Some(event.endpoint),
+                Some(event.cluster),
+                Some(event.event_id),
+            );
+            let mut access_req = AccessReq::new(&accessor, &path, Access::READ);
+            access_req.set_target_perms(Access::RV);
+            if !access_req.allow() {
+                return Ok(());
+            }
+
+            let anchor = tw.get_tail();
+            let result = DataModel::encode_event(tw, event);
+            if result.is_err() || tw.get_tail() > MAX_REPORT_TAIL {
+                // This event, and the ones after it, go in the next chunk
+                tw.rewind_to(anchor);
+                resume = Some(event.number);
+                return Err(Error::NoSpace);
+            }
+            Ok(())
+        });
+        if resume.is_none() {
+            result?;
+        }
+        tw.end_container()?;
+
+        if resume.is_some() && resume == resume_from {
+            // Not even a single event could be encoded in this chunk
+            error!("Event too large to fit in a report");
+            return Err(Error::NoSpace);
+        }
+        Ok(resume)
     }
 
     fn consume_invoke_cmd(
@@ -343,6 +538,12 @@ impl InteractionConsumer for DataModel {
         trans: &mut Transaction,
         tw: &mut TLVWriter,
     ) -> Result<(), Error> {
+        // Only a group message is checked here, the other sessions are checked by the clusters
+        let group_accessor = if trans.session.is_group() {
+            Some(self.sess_to_accessor(trans.session))
+        } else {
+            None
+        };
         let mut node = self.node.write().unwrap();
         if let Some(inv_requests) = &inv_req_msg.inv_requests {
             // Array of InvokeResponse IBs
@@ -354,19 +555,45 @@ impl InteractionConsumer for DataModel {
                     continue;
                 };
                 info!("Invoke Commmand Handler executing: {:?}", i.path);
-                let mut cmd_req = CommandReq {
-                    cmd: i.path,
-                    data,
-                    trans,
-                    resp: tw,
-                };
-                DataModel::handle_command_path(&mut node, &mut cmd_req);
+                for path in self.group_paths(trans.session, i.path.path) {
+                    if let Some(accessor) = &group_accessor {
+                        // A group command needs at least Operate, else the path is dropped
+                        let mut access_req = AccessReq::new(accessor, &path, Access::WRITE);
+                        access_req.set_target_perms(Access::WRITE | Access::NEED_OPERATE);
+                        if !access_req.allow() {
+                            continue;
+                        }
+                    }
+                    let mut cmd = i.path;
+                    cmd.path = path;
+                    let mut cmd_req = CommandReq {
+                        cmd,
+                        data,
+                        trans,
+                        resp: tw,
+                    };
+                    DataModel::handle_command_path(&mut node, &mut cmd_req);
+                }
             }
             tw.end_container()?;
         }
 
         Ok(())
     }
+
+    fn change_marker(&self) -> Option<u64> {
+        let node = self.node.read().ok()?;
+        let mut marker = self.events.next_number().ok()?;
+        let all = GenericPath::new(None, None, None);
+        node.for_each_cluster(&all, |_, c| {
+            marker = marker
+                .wrapping_mul(31)
+                .wrapping_add(c.base().get_dataver() as u64);
+            Ok(())
+        })
+        .ok()?;
+        Some(marker)
+    }
 }
 
 /// Encoder for generating a response to a read request
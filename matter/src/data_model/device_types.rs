@@ -25,9 +25,11 @@ use super::sdm::general_commissioning::GenCommCluster;
 use super::sdm::noc::NocCluster;
 use super::sdm::nw_commissioning::NwCommCluster;
 use super::system_model::access_control::AccessControlCluster;
+use super::system_model::group_key_management::GroupKeyManagementCluster;
 use crate::acl::AclMgr;
 use crate::error::*;
 use crate::fabric::FabricMgr;
+use crate::group_keys::GroupMgr;
 use crate::secure_channel::pake::PaseMgr;
 use std::sync::Arc;
 use std::sync::RwLockWriteGuard;
@@ -45,6 +47,7 @@ pub fn device_type_add_root_node(
     dev_att: Box<dyn DevAttDataFetcher>,
     fabric_mgr: Arc<FabricMgr>,
     acl_mgr: Arc<AclMgr>,
+    group_mgr: Arc<GroupMgr>,
     pase_mgr: PaseMgr,
 ) -> Result<u32, Error> {
     // Add the root endpoint
@@ -65,6 +68,7 @@ pub fn device_type_add_root_node(
         NocCluster::new(dev_att, fabric_mgr, acl_mgr.clone(), failsafe)?,
     )?;
     node.add_cluster(0, AccessControlCluster::new(acl_mgr)?)?;
+    node.add_cluster(0, GroupKeyManagementCluster::new(group_mgr)?)?;
     Ok(endpoint)
 }
 
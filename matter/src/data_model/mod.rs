@@ -20,6 +20,7 @@ pub mod device_types;
 pub mod objects;
 
 pub mod cluster_basic_information;
+pub mod cluster_groups;
 pub mod cluster_on_off;
 pub mod cluster_template;
 pub mod sdm;
@@ -0,0 +1,279 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info};
+
+use crate::{
+    error::*,
+    interaction_model::messages::ib::EventDataTag,
+    persist::KvStorage,
+    tlv::{TLVWriter, TagType},
+    utils::writebuf::WriteBuf,
+};
+
+/// The maximum number of events that are held in the event store
+pub const MAX_EVENTS: usize = 32;
+/// The maximum size of the TLV encoded payload of an event
+pub const MAX_EVENT_DATA: usize = 256;
+// How far ahead of the next event number the persisted value is. This is the number of
+// event numbers that are skipped on a reboot, and how often the event number is persisted.
+const EVENT_NUMBER_EPOCH: u64 = 1000;
+
+pub const EVENT_NUMBER_KEY: &str = "event_num";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventPriority {
+    Debug = 0,
+    Info = 1,
+    Critical = 2,
+}
+
+pub struct Event {
+    pub endpoint: u16,
+    pub cluster: u32,
+    pub event_id: u32,
+    pub number: u64,
+    pub priority: EventPriority,
+    /// Milliseconds since the UNIX epoch
+    pub timestamp: u64,
+    // The TLV encoded payload, with the context tag of the Data field in the EventDataIB
+    data: Vec<u8>,
+}
+
+impl Event {
+    /// The TLV encoded payload of the event, including its tag
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+struct EventBuf {
+    events: VecDeque<Event>,
+    next_number: u64,
+    // The event numbers can go up to this value, before the next epoch has to be persisted
+    limit: u64,
+}
+
+/// A bounded store of the events emitted by the clusters
+///
+/// Every event gets a monotonically increasing event number. Once the store is full, the
+/// oldest event of the lowest priority is dropped to make room for a new one.
+///
+/// The event numbers keep increasing across reboots if they are persisted, the same way as
+/// the [global message counters](crate::transport::msg_ctr::GlobalCtr): a value one epoch
+/// ahead of the next event number is stored, and the numbering resumes from it.
+pub struct EventMgr {
+    buf: RwLock<EventBuf>,
+    storage: Option<Arc<dyn KvStorage>>,
+}
+
+impl Default for EventMgr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventMgr {
+    /// An event store whose event numbers start at 0, and aren't persisted
+    pub fn new() -> Self {
+        Self::new_with_number(0, None)
+    }
+
+    /// An event store whose event numbers resume from the value persisted in the storage,
+    /// or start at 0 if there is none
+    pub fn new_persisted(storage: Arc<dyn KvStorage>) -> Result<Self, Error> {
+        let mut stored = 0;
+        let next_number = match storage.get_kv_u64(EVENT_NUMBER_KEY, &mut stored) {
+            Ok(()) => {
+                info!("Resuming the event numbers from {}", stored);
+                stored
+            }
+            Err(Error::NotFound) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self::new_with_number(next_number, Some(storage)))
+    }
+
+    fn new_with_number(next_number: u64, storage: Option<Arc<dyn KvStorage>>) -> Self {
+        Self {
+            buf: RwLock::new(EventBuf {
+                events: VecDeque::with_capacity(MAX_EVENTS),
+                next_number,
+                limit: next_number,
+            }),
+            storage,
+        }
+    }
+
+    /// Emit an event
+    ///
+    /// The payload of the event is encoded by the closure 'f', with the tag that is passed to it.
+    /// Returns the event number of the event. This fails if the next epoch of the event
+    /// numbers has to be persisted, and can't be, as the numbers could then go back after a
+    /// reboot.
+    pub fn emit<F>(
+        &self,
+        endpoint: u16,
+        cluster: u32,
+        event_id: u32,
+        priority: EventPriority,
+        f: F,
+    ) -> Result<u64, Error>
+    where
+        F: FnOnce(&mut TLVWriter, TagType) -> Result<(), Error>,
+    {
+        let mut data = [0u8; MAX_EVENT_DATA];
+        let mut wb = WriteBuf::new(&mut data, MAX_EVENT_DATA);
+        let mut tw = TLVWriter::new(&mut wb);
+        f(&mut tw, TagType::Context(EventDataTag::Data as u8))?;
+        let data = wb.as_borrow_slice().to_vec();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::SysTimeFail)?
+            .as_millis() as u64;
+
+        let mut buf = self.buf.write()?;
+        if let Some(storage) = &self.storage {
+            if buf.next_number >= buf.limit {
+                let limit = buf.next_number + EVENT_NUMBER_EPOCH;
+                if let Err(e) = storage.set_kv_u64(EVENT_NUMBER_KEY, limit) {
+                    error!("Error in persisting the event number: {:?}", e);
+                    return Err(e);
+                }
+                buf.limit = limit;
+            }
+        }
+        if buf.events.len() >= MAX_EVENTS {
+            // Drop the oldest of the lowest priority events
+            let lowest = buf.events.iter().map(|e| e.priority).min();
+            if let Some(index) = buf.events.iter().position(|e| Some(e.priority) == lowest) {
+                buf.events.remove(index);
+            }
+        }
+        let number = buf.next_number;
+        buf.next_number += 1;
+        buf.events.push_back(Event {
+            endpoint,
+            cluster,
+            event_id,
+            number,
+            priority,
+            timestamp,
+            data,
+        });
+        Ok(number)
+    }
+
+    /// The event number that will be assigned to the next event
+    pub fn next_number(&self) -> Result<u64, Error> {
+        Ok(self.buf.read()?.next_number)
+    }
+
+    /// Call 'f' for all the events, in order, starting with the event number 'event_min'.
+    /// The iteration stops at the first error returned by 'f'.
+    pub fn for_each_event<F>(&self, event_min: u64, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&Event) -> Result<(), Error>,
+    {
+        let buf = self.buf.read()?;
+        for e in buf.events.iter().filter(|e| e.number >= event_min) {
+            f(e)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{EventMgr, EventPriority, EVENT_NUMBER_EPOCH, EVENT_NUMBER_KEY, MAX_EVENTS};
+    use crate::persist::{KvStorage, MemKvStorage};
+
+    fn emit(events: &EventMgr, priority: EventPriority) -> u64 {
+        events
+            .emit(1, 6, 0, priority, |tw, tag| tw.u8(tag, 5))
+            .unwrap()
+    }
+
+    fn numbers(events: &EventMgr, event_min: u64) -> Vec<u64> {
+        let mut numbers = Vec::new();
+        events
+            .for_each_event(event_min, |e| {
+                numbers.push(e.number);
+                Ok(())
+            })
+            .unwrap();
+        numbers
+    }
+
+    #[test]
+    fn test_event_numbers() {
+        let events = EventMgr::new();
+        assert_eq!(emit(&events, EventPriority::Info), 0);
+        assert_eq!(emit(&events, EventPriority::Info), 1);
+        assert_eq!(emit(&events, EventPriority::Debug), 2);
+        assert_eq!(events.next_number(), Ok(3));
+        assert_eq!(numbers(&events, 0), vec![0, 1, 2]);
+        assert_eq!(numbers(&events, 1), vec![1, 2]);
+        assert_eq!(numbers(&events, 3), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_event_eviction() {
+        let events = EventMgr::new();
+        emit(&events, EventPriority::Critical);
+        emit(&events, EventPriority::Info);
+        emit(&events, EventPriority::Debug);
+        for _ in 3..MAX_EVENTS {
+            emit(&events, EventPriority::Info);
+        }
+        // The debug event goes first
+        emit(&events, EventPriority::Info);
+        assert!(!numbers(&events, 0).contains(&2));
+        // Then the oldest of the info events
+        emit(&events, EventPriority::Info);
+        let n = numbers(&events, 0);
+        assert_eq!(n.len(), MAX_EVENTS);
+        assert_eq!(n[0], 0);
+        assert!(!n.contains(&1));
+    }
+
+    #[test]
+    fn test_event_numbers_persisted() {
+        let storage = Arc::new(MemKvStorage::new());
+        let events = EventMgr::new_persisted(storage.clone()).unwrap();
+        assert_eq!(emit(&events, EventPriority::Info), 0);
+        assert_eq!(emit(&events, EventPriority::Info), 1);
+        let mut stored = 0;
+        storage.get_kv_u64(EVENT_NUMBER_KEY, &mut stored).unwrap();
+        assert_eq!(stored, EVENT_NUMBER_EPOCH);
+
+        // After a reboot, the numbers carry on past the ones that were used
+        let events = EventMgr::new_persisted(storage.clone()).unwrap();
+        assert_eq!(emit(&events, EventPriority::Info), EVENT_NUMBER_EPOCH);
+        storage.get_kv_u64(EVENT_NUMBER_KEY, &mut stored).unwrap();
+        assert_eq!(stored, 2 * EVENT_NUMBER_EPOCH);
+    }
+}
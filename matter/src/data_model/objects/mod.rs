@@ -32,3 +32,6 @@ pub use privilege::*;
 
 mod encoder;
 pub use encoder::*;
+
+mod event;
+pub use event::*;
@@ -189,6 +189,22 @@ impl NocCluster {
             None
         };
 
+        // The NOC has to chain up to the root that was added before, and be valid now
+        let mut verifier = noc_value.verify_chain_start(self.fabric_mgr.get_cert_time());
+        if let Some(icac) = &icac_value {
+            verifier = verifier.add_cert(icac).map_err(|e| {
+                error!("Invalid ICAC: {:?}", e);
+                NocStatus::InvalidNOC
+            })?;
+        }
+        verifier
+            .add_cert(&noc_data.root_ca)
+            .and_then(|v| v.finalise())
+            .map_err(|e| {
+                error!("Invalid NOC: {:?}", e);
+                NocStatus::InvalidNOC
+            })?;
+
         let fabric = Fabric::new(
             noc_data.key_pair,
             noc_data.root_ca,
@@ -404,7 +420,16 @@ impl NocCluster {
                     CommonReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
                 info!("Received Trusted Cert:{:x?}", req.str);
 
-                noc_data.root_ca = Cert::new(req.str.0).map_err(|_| IMStatusCode::Failure)?;
+                let root_ca = Cert::new(req.str.0).map_err(|_| IMStatusCode::Failure)?;
+                // The root has to be a self-signed CA, in its validity period
+                root_ca
+                    .verify_chain_start(self.fabric_mgr.get_cert_time())
+                    .finalise()
+                    .map_err(|e| {
+                        error!("Invalid Trusted Root Cert: {:?}", e);
+                        IMStatusCode::InvalidCommand
+                    })?;
+                noc_data.root_ca = root_ca;
             }
             _ => (),
         }
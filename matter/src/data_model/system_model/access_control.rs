@@ -199,6 +199,7 @@ mod tests {
             objects::{AttrDetails, ClusterType, Privilege},
         },
         interaction_model::messages::ib::ListOperation,
+        persist::MemKvStorage,
         tlv::{get_root_node_struct, ElementType, TLVElement, TLVWriter, TagType, ToTLV},
         utils::writebuf::WriteBuf,
     };
@@ -213,7 +214,7 @@ mod tests {
         let mut writebuf = WriteBuf::new(&mut buf, buf_len);
         let mut tw = TLVWriter::new(&mut writebuf);
 
-        let acl_mgr = Arc::new(AclMgr::new_with(false).unwrap());
+        let acl_mgr = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         let mut acl = AccessControlCluster::new(acl_mgr.clone()).unwrap();
 
         let new = AclEntry::new(2, Privilege::VIEW, AuthMode::Case);
@@ -242,7 +243,7 @@ mod tests {
         let mut tw = TLVWriter::new(&mut writebuf);
 
         // Add 3 ACLs, belonging to fabric index 2, 1 and 2, in that order
-        let acl_mgr = Arc::new(AclMgr::new_with(false).unwrap());
+        let acl_mgr = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         let mut verifier = [
             AclEntry::new(2, Privilege::VIEW, AuthMode::Case),
             AclEntry::new(1, Privilege::VIEW, AuthMode::Case),
@@ -277,7 +278,7 @@ mod tests {
     /// - The listindex used for delete should be relative to the current fabric
     fn acl_cluster_delete() {
         // Add 3 ACLs, belonging to fabric index 2, 1 and 2, in that order
-        let acl_mgr = Arc::new(AclMgr::new_with(false).unwrap());
+        let acl_mgr = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         let input = [
             AclEntry::new(2, Privilege::VIEW, AuthMode::Case),
             AclEntry::new(1, Privilege::VIEW, AuthMode::Case),
@@ -313,7 +314,7 @@ mod tests {
         let mut writebuf = WriteBuf::new(&mut buf, buf_len);
 
         // Add 3 ACLs, belonging to fabric index 2, 1 and 2, in that order
-        let acl_mgr = Arc::new(AclMgr::new_with(false).unwrap());
+        let acl_mgr = Arc::new(AclMgr::new(Arc::new(MemKvStorage::new())).unwrap());
         let input = [
             AclEntry::new(2, Privilege::VIEW, AuthMode::Case),
             AclEntry::new(1, Privilege::VIEW, AuthMode::Case),
@@ -0,0 +1,419 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use num_derive::FromPrimitive;
+
+use crate::cmd_enter;
+use crate::data_model::objects::*;
+use crate::error::*;
+use crate::group_keys::{self, GroupKeyMapEntry, GroupMgr, KeySetPolicy};
+use crate::interaction_model::command::CommandReq;
+use crate::interaction_model::core::IMStatusCode;
+use crate::interaction_model::messages::ib::{self, attr_list_write, ListOperation};
+use crate::tlv::{
+    FromTLV, Nullable, OctetStr, TLVArrayOwned, TLVElement, TLVWriter, TagType, ToTLV,
+};
+use log::{error, info};
+
+pub const ID: u32 = 0x003F;
+
+#[derive(FromPrimitive)]
+pub enum Attributes {
+    GroupKeyMap = 0,
+    GroupTable = 1,
+    MaxGroupsPerFabric = 2,
+    MaxGroupKeysPerFabric = 3,
+}
+
+#[derive(FromPrimitive)]
+pub enum Commands {
+    KeySetWrite = 0x00,
+    KeySetRead = 0x01,
+    KeySetReadResp = 0x02,
+    KeySetRemove = 0x03,
+    KeySetReadAllIndices = 0x04,
+    KeySetReadAllIndicesResp = 0x05,
+}
+
+pub struct GroupKeyManagementCluster {
+    base: Cluster,
+    group_mgr: Arc<GroupMgr>,
+}
+
+impl GroupKeyManagementCluster {
+    pub fn new(group_mgr: Arc<GroupMgr>) -> Result<Box<Self>, Error> {
+        let mut c = Box::new(GroupKeyManagementCluster {
+            base: Cluster::new(ID)?,
+            group_mgr,
+        });
+        c.base.add_attribute(attr_group_key_map_new()?)?;
+        c.base.add_attribute(attr_group_table_new()?)?;
+        c.base.add_attribute(attr_max_groups_per_fabric_new()?)?;
+        c.base
+            .add_attribute(attr_max_group_keys_per_fabric_new()?)?;
+        Ok(c)
+    }
+
+    /// Write the Group Key Map Attribute
+    ///
+    /// Like the ACL, the entries are fabric-scoped and the list index is relative to the
+    /// accessing fabric
+    fn write_key_map_attr(
+        &mut self,
+        op: &ListOperation,
+        data: &TLVElement,
+        fab_idx: u8,
+    ) -> Result<(), IMStatusCode> {
+        info!("Performing Group Key Map operation {:?}", op);
+        let result = match op {
+            ListOperation::AddItem | ListOperation::EditItem(_) => {
+                let mut entry =
+                    GroupKeyMapEntry::from_tlv(data).map_err(|_| IMStatusCode::ConstraintError)?;
+                // Overwrite the fabric index with our accessing fabric index
+                entry.fab_idx = Some(fab_idx);
+                if self
+                    .group_mgr
+                    .get_key_set(fab_idx, entry.key_set_id)
+                    .is_err()
+                {
+                    return Err(IMStatusCode::ConstraintError);
+                }
+
+                if let ListOperation::EditItem(index) = op {
+                    self.group_mgr.edit_key_map(*index as u8, fab_idx, entry)
+                } else {
+                    self.group_mgr.add_key_map(fab_idx, entry)
+                }
+            }
+            ListOperation::DeleteItem(index) => {
+                self.group_mgr.delete_key_map(*index as u8, fab_idx)
+            }
+            ListOperation::DeleteList => self.group_mgr.delete_key_map_for_fabric(fab_idx),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(Error::NoSpace) => Err(IMStatusCode::ResourceExhausted),
+            _ => Err(IMStatusCode::ConstraintError),
+        }
+    }
+
+    fn handle_command_keysetwrite(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        cmd_enter!("KeySetWrite");
+        let fab_idx = get_fab_idx(cmd_req)?;
+        let req =
+            KeySetWriteReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        let key_set = req.key_set;
+        if key_set.id == group_keys::IPK_KEY_SET_ID {
+            return Err(IMStatusCode::InvalidCommand);
+        }
+        if key_set.policy != KeySetPolicy::TrustFirst as u8 {
+            return Err(IMStatusCode::ConstraintError);
+        }
+
+        // The epoch keys have to be in order, without gaps
+        let mut epoch_keys = Vec::new();
+        let mut last = false;
+        for (key, start_time) in [
+            (key_set.epoch_key0, key_set.epoch_start_time0),
+            (key_set.epoch_key1, key_set.epoch_start_time1),
+            (key_set.epoch_key2, key_set.epoch_start_time2),
+        ] {
+            match (key.unwrap_notnull(), start_time.unwrap_notnull()) {
+                (Some(key), Some(start_time)) if !last => epoch_keys.push((key.0, start_time)),
+                (None, None) => last = true,
+                _ => return Err(IMStatusCode::InvalidCommand),
+            }
+        }
+        if epoch_keys.is_empty() {
+            return Err(IMStatusCode::InvalidCommand);
+        }
+
+        let result =
+            self.group_mgr
+                .set_key_set(fab_idx, key_set.id, KeySetPolicy::TrustFirst, &epoch_keys);
+        cmd_req.trans.complete();
+        match result {
+            Ok(()) => Err(IMStatusCode::Sucess),
+            Err(Error::NoSpace) => Err(IMStatusCode::ResourceExhausted),
+            Err(Error::InvalidKeyLength) | Err(Error::Invalid) => {
+                Err(IMStatusCode::ConstraintError)
+            }
+            Err(_) => Err(IMStatusCode::Failure),
+        }
+    }
+
+    fn handle_command_keysetread(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        cmd_enter!("KeySetRead");
+        let fab_idx = get_fab_idx(cmd_req)?;
+        let req = KeySetReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        // The epoch keys are never read back, only their start times. The IPK is kept with
+        // the fabric, as a single epoch key that is active from the start.
+        let (policy, start_times) = if req.id == group_keys::IPK_KEY_SET_ID {
+            let start_times = [Nullable::NotNull(0), Nullable::Null, Nullable::Null];
+            (KeySetPolicy::TrustFirst as u8, start_times)
+        } else {
+            let key_set = self
+                .group_mgr
+                .get_key_set(fab_idx, req.id)
+                .map_err(|_| IMStatusCode::NotFound)?;
+            let start_time = |i: usize| match key_set.epoch_keys[i] {
+                Some(k) => Nullable::NotNull(k.start_time),
+                None => Nullable::Null,
+            };
+            (
+                key_set.policy,
+                [start_time(0), start_time(1), start_time(2)],
+            )
+        };
+        let [epoch_start_time0, epoch_start_time1, epoch_start_time2] = start_times;
+        let resp = KeySetReadResp {
+            key_set: GroupKeySetStruct {
+                id: req.id,
+                policy,
+                epoch_key0: Nullable::Null,
+                epoch_start_time0,
+                epoch_key1: Nullable::Null,
+                epoch_start_time1,
+                epoch_key2: Nullable::Null,
+                epoch_start_time2,
+            },
+        };
+        let invoke_resp = ib::InvResp::cmd_new(
+            0,
+            ID,
+            Commands::KeySetReadResp as u16,
+            EncodeValue::Value(&resp),
+        );
+        let _ = invoke_resp.to_tlv(cmd_req.resp, TagType::Anonymous);
+        cmd_req.trans.complete();
+        Ok(())
+    }
+
+    fn handle_command_keysetremove(
+        &mut self,
+        cmd_req: &mut CommandReq,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("KeySetRemove");
+        let fab_idx = get_fab_idx(cmd_req)?;
+        let req = KeySetReq::from_tlv(&cmd_req.data).map_err(|_| IMStatusCode::InvalidCommand)?;
+        if req.id == group_keys::IPK_KEY_SET_ID {
+            return Err(IMStatusCode::InvalidCommand);
+        }
+        let result = self.group_mgr.remove_key_set(fab_idx, req.id);
+        cmd_req.trans.complete();
+        match result {
+            Ok(()) => Err(IMStatusCode::Sucess),
+            Err(Error::NotFound) => Err(IMStatusCode::NotFound),
+            Err(_) => Err(IMStatusCode::Failure),
+        }
+    }
+
+    fn handle_command_keysetreadallindices(
+        &mut self,
+        cmd_req: &mut CommandReq,
+    ) -> Result<(), IMStatusCode> {
+        cmd_enter!("KeySetReadAllIndices");
+        let fab_idx = get_fab_idx(cmd_req)?;
+        let ids = self
+            .group_mgr
+            .get_key_set_ids(fab_idx)
+            .map_err(|_| IMStatusCode::Failure)?;
+        let resp = KeySetReadAllIndicesResp {
+            ids: TLVArrayOwned::new(ids),
+        };
+        let invoke_resp = ib::InvResp::cmd_new(
+            0,
+            ID,
+            Commands::KeySetReadAllIndicesResp as u16,
+            EncodeValue::Value(&resp),
+        );
+        let _ = invoke_resp.to_tlv(cmd_req.resp, TagType::Anonymous);
+        cmd_req.trans.complete();
+        Ok(())
+    }
+}
+
+// The key sets are fabric-scoped, they can only be managed over a CASE session
+fn get_fab_idx(cmd_req: &CommandReq) -> Result<u8, IMStatusCode> {
+    cmd_req
+        .trans
+        .session
+        .get_local_fabric_idx()
+        .ok_or(IMStatusCode::UnsupportedAccess)
+}
+
+impl ClusterType for GroupKeyManagementCluster {
+    fn base(&self) -> &Cluster {
+        &self.base
+    }
+    fn base_mut(&mut self) -> &mut Cluster {
+        &mut self.base
+    }
+
+    fn read_custom_attribute(&self, encoder: &mut dyn Encoder, attr: &AttrDetails) {
+        match num::FromPrimitive::from_u16(attr.attr_id) {
+            Some(Attributes::GroupKeyMap) => encoder.encode(EncodeValue::Closure(&|tag, tw| {
+                let _ = tw.start_array(tag);
+                let _ = self.group_mgr.for_each_key_map(|entry| {
+                    if !attr.fab_filter || Some(attr.fab_idx) == entry.fab_idx {
+                        let _ = entry.to_tlv(tw, TagType::Anonymous);
+                    }
+                });
+                let _ = tw.end_container();
+            })),
+            Some(Attributes::GroupTable) => encoder.encode(EncodeValue::Closure(&|tag, tw| {
+                let _ = tw.start_array(tag);
+                let _ = self.group_mgr.for_each_group(|group, fab_idx| {
+                    if !attr.fab_filter || attr.fab_idx == fab_idx {
+                        let info = GroupInfo {
+                            group_id: group.group_id,
+                            endpoints: TLVArrayOwned::new(
+                                group.endpoints.iter().flatten().copied().collect(),
+                            ),
+                            name: Some(group.get_name().to_owned()),
+                            fab_idx,
+                        };
+                        let _ = info.to_tlv(tw, TagType::Anonymous);
+                    }
+                });
+                let _ = tw.end_container();
+            })),
+            _ => {
+                error!("Attribute not yet supported: this shouldn't happen");
+            }
+        }
+    }
+
+    fn write_attribute(
+        &mut self,
+        attr: &AttrDetails,
+        data: &TLVElement,
+    ) -> Result<(), IMStatusCode> {
+        let result =
+            if let Some(Attributes::GroupKeyMap) = num::FromPrimitive::from_u16(attr.attr_id) {
+                attr_list_write(attr, data, |op, data| {
+                    self.write_key_map_attr(&op, data, attr.fab_idx)
+                })
+            } else {
+                error!("Attribute not yet supported: this shouldn't happen");
+                Err(IMStatusCode::NotFound)
+            };
+        if result.is_ok() {
+            self.base.cluster_changed();
+        }
+        result
+    }
+
+    fn handle_command(&mut self, cmd_req: &mut CommandReq) -> Result<(), IMStatusCode> {
+        let cmd = cmd_req
+            .cmd
+            .path
+            .leaf
+            .map(num::FromPrimitive::from_u32)
+            .ok_or(IMStatusCode::UnsupportedCommand)?
+            .ok_or(IMStatusCode::UnsupportedCommand)?;
+        match cmd {
+            Commands::KeySetWrite => self.handle_command_keysetwrite(cmd_req),
+            Commands::KeySetRead => self.handle_command_keysetread(cmd_req),
+            Commands::KeySetRemove => self.handle_command_keysetremove(cmd_req),
+            Commands::KeySetReadAllIndices => self.handle_command_keysetreadallindices(cmd_req),
+            _ => Err(IMStatusCode::UnsupportedCommand),
+        }
+    }
+}
+
+fn attr_group_key_map_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::GroupKeyMap as u16,
+        AttrValue::Custom,
+        Access::RWFVM,
+        Quality::NONE,
+    )
+}
+
+fn attr_group_table_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::GroupTable as u16,
+        AttrValue::Custom,
+        Access::RV | Access::FAB_SCOPED,
+        Quality::NONE,
+    )
+}
+
+fn attr_max_groups_per_fabric_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::MaxGroupsPerFabric as u16,
+        AttrValue::Uint16(group_keys::GROUPS_PER_FABRIC as u16),
+        Access::RV,
+        Quality::FIXED,
+    )
+}
+
+fn attr_max_group_keys_per_fabric_new() -> Result<Attribute, Error> {
+    Attribute::new(
+        Attributes::MaxGroupKeysPerFabric as u16,
+        AttrValue::Uint16(group_keys::GROUP_KEYS_PER_FABRIC as u16),
+        Access::RV,
+        Quality::FIXED,
+    )
+}
+
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct GroupKeySetStruct<'a> {
+    id: u16,
+    policy: u8,
+    epoch_key0: Nullable<OctetStr<'a>>,
+    epoch_start_time0: Nullable<u64>,
+    epoch_key1: Nullable<OctetStr<'a>>,
+    epoch_start_time1: Nullable<u64>,
+    epoch_key2: Nullable<OctetStr<'a>>,
+    epoch_start_time2: Nullable<u64>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(lifetime = "'a")]
+struct KeySetWriteReq<'a> {
+    key_set: GroupKeySetStruct<'a>,
+}
+
+#[derive(FromTLV)]
+struct KeySetReq {
+    id: u16,
+}
+
+#[derive(ToTLV)]
+struct KeySetReadResp<'a> {
+    key_set: GroupKeySetStruct<'a>,
+}
+
+#[derive(ToTLV)]
+struct KeySetReadAllIndicesResp {
+    ids: TLVArrayOwned<u16>,
+}
+
+#[derive(ToTLV)]
+#[tlvargs(start = 1)]
+struct GroupInfo {
+    group_id: u16,
+    endpoints: TLVArrayOwned<u16>,
+    name: Option<String>,
+    #[tagval(0xFE)]
+    fab_idx: u8,
+}
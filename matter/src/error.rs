@@ -19,7 +19,7 @@ use std::{
     array::TryFromSliceError, fmt, string::FromUtf8Error, sync::PoisonError, time::SystemTimeError,
 };
 
-use async_channel::{SendError, TryRecvError};
+use async_channel::{RecvError, SendError, TryRecvError};
 use log::error;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -27,10 +27,14 @@ pub enum Error {
     AttributeNotFound,
     AttributeIsCustom,
     BufferTooSmall,
+    // The Matter Certificate is out of its validity period
+    CertExpired,
+    CertNotYetValid,
     ClusterNotFound,
     CommandNotFound,
     EndpointNotFound,
     Crypto,
+    Duplicate,
     TLSStack,
     MdnsError,
     Network,
@@ -59,14 +63,24 @@ pub enum Error {
     InvalidPeerAddr,
     // Invalid Auth Key in the Matter Certificate
     InvalidAuthKey,
+    // The Matter Certificate isn't allowed to be used this way in the chain, like an issuer
+    // that isn't a CA
+    InvalidCertUsage,
+    // The device attestation information isn't consistent, like a DAC of another vendor
+    // than its PAI, or of a product that the Certification Declaration doesn't certify
+    InvalidAttestation,
     InvalidSignature,
     InvalidState,
     InvalidTime,
     InvalidArgument,
+    InvalidChecksum,
     RwLock,
+    Timeout,
     TLVNotFound,
     TLVTypeMismatch,
     TruncatedPacket,
+    // A stored record is of a newer version than this release supports
+    UnsupportedVersion,
     Utf8Fail,
 }
 
@@ -124,6 +138,13 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+impl From<RecvError> for Error {
+    fn from(e: RecvError) -> Self {
+        error!("Error in channel recv {}", e);
+        Self::Invalid
+    }
+}
+
 impl From<TryRecvError> for Error {
     fn from(e: TryRecvError) -> Self {
         error!("Error in channel try_recv {}", e);
@@ -15,20 +15,25 @@
  *    limitations under the License.
  */
 
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::{Arc, RwLock};
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use log::{error, info};
 use owning_ref::RwLockReadGuardRef;
 
 use crate::{
-    cert::Cert,
+    cert::{Cert, CertTime},
     crypto::{self, crypto_dummy::KeyPairDummy, hkdf_sha256, CryptoKeyPair, HmacSha256, KeyPair},
     error::Error,
     group_keys::KeySet,
     mdns::{self, Mdns},
-    sys::{Psm, SysMdnsService},
-    tlv::{OctetStr, TLVWriter, TagType, ToTLV, UtfStr},
+    persist::{
+        record::{self, Persist},
+        KvStorage,
+    },
+    sys::SysMdnsService,
+    tlv::{FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV, UtfStr},
+    utils::epoch::{SysUtcTime, UtcTime},
 };
 
 const MAX_CERT_TLV_LEN: usize = 350;
@@ -48,6 +53,12 @@ const ST_IPK: &str = "ipk";
 const ST_LBL: &str = "label";
 const ST_PBKEY: &str = "pubkey";
 const ST_PRKEY: &str = "privkey";
+// The keys that older releases stored the members of a fabric in
+const LEGACY_KEYS: [&str; 8] = [
+    ST_RCA, ST_ICA, ST_NOC, ST_IPK, ST_LBL, ST_PBKEY, ST_PRKEY, ST_VID,
+];
+
+const LAST_KNOWN_GOOD_TIME_KEY: &str = "lkg_time";
 
 #[allow(dead_code)]
 pub struct Fabric {
@@ -104,22 +115,25 @@ impl Fabric {
         };
         Fabric::get_compressed_id(f.root_ca.get_pubkey(), fabric_id, &mut f.compressed_id)?;
         f.ipk = KeySet::new(ipk, &f.compressed_id)?;
+        Ok(f)
+    }
 
+    /// Publish the operational mDNS record of this node in the fabric
+    fn publish(&mut self, mdns: &Mdns) -> Result<(), Error> {
         let mut mdns_service_name = String::with_capacity(33);
-        for c in f.compressed_id {
+        for c in self.compressed_id {
             mdns_service_name.push_str(&format!("{:02X}", c));
         }
         mdns_service_name.push('-');
         let mut node_id_be: [u8; 8] = [0; 8];
-        BigEndian::write_u64(&mut node_id_be, node_id);
+        BigEndian::write_u64(&mut node_id_be, self.node_id);
         for c in node_id_be {
             mdns_service_name.push_str(&format!("{:02X}", c));
         }
         info!("MDNS Service Name: {}", mdns_service_name);
-        f.mdns_service = Some(
-            Mdns::get()?.publish_service(&mdns_service_name, mdns::ServiceMode::Commissioned)?,
-        );
-        Ok(f)
+        self.mdns_service =
+            Some(mdns.publish_service(&mdns_service_name, mdns::ServiceMode::Commissioned)?);
+        Ok(())
     }
 
     pub fn dummy() -> Result<Self, Error> {
@@ -150,7 +164,9 @@ impl Fabric {
             .map_err(|_| Error::NoSpace)
     }
 
-    pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<(), Error> {
+    /// Compute the destination identifier, used in Sigma1, for the node with the given
+    /// node id in this fabric
+    pub fn get_dest_id(&self, random: &[u8], node_id: u64, out: &mut [u8]) -> Result<(), Error> {
         let mut mac = HmacSha256::new(self.ipk.op_key())?;
 
         mac.update(random)?;
@@ -160,11 +176,15 @@ impl Fabric {
         LittleEndian::write_u64(&mut buf, self.fabric_id);
         mac.update(&buf)?;
 
-        LittleEndian::write_u64(&mut buf, self.node_id);
+        LittleEndian::write_u64(&mut buf, node_id);
         mac.update(&buf)?;
 
+        mac.finish(out)
+    }
+
+    pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<(), Error> {
         let mut id = [0_u8; crypto::SHA256_HASH_LEN_BYTES];
-        mac.finish(&mut id)?;
+        self.get_dest_id(random, self.node_id, &mut id)?;
         if id.as_slice() == target {
             Ok(())
         } else {
@@ -184,6 +204,19 @@ impl Fabric {
         self.fabric_id
     }
 
+    pub fn get_compressed_fabric_id(&self) -> &[u8] {
+        &self.compressed_id
+    }
+
+    // The latest of the Not Before of the fabric's certificates
+    fn latest_not_before(&self) -> u32 {
+        let icac = self.icac.as_ref().map(|c| c.get_not_before());
+        self.root_ca
+            .get_not_before()
+            .max(self.noc.get_not_before())
+            .max(icac.unwrap_or_default())
+    }
+
     pub fn get_fabric_desc(&self, fab_idx: u8) -> FabricDescriptor {
         FabricDescriptor {
             root_public_key: OctetStr::new(self.root_ca.get_pubkey()),
@@ -195,38 +228,8 @@ impl Fabric {
         }
     }
 
-    fn store(&self, index: usize, psm: &MutexGuard<Psm>) -> Result<(), Error> {
-        let mut key = [0u8; MAX_CERT_TLV_LEN];
-        let len = self.root_ca.as_tlv(&mut key)?;
-        psm.set_kv_slice(fb_key!(index, ST_RCA), &key[..len])?;
-
-        let len = if let Some(icac) = &self.icac {
-            icac.as_tlv(&mut key)?
-        } else {
-            0
-        };
-        psm.set_kv_slice(fb_key!(index, ST_ICA), &key[..len])?;
-
-        let len = self.noc.as_tlv(&mut key)?;
-        psm.set_kv_slice(fb_key!(index, ST_NOC), &key[..len])?;
-        psm.set_kv_slice(fb_key!(index, ST_IPK), self.ipk.epoch_key())?;
-        psm.set_kv_slice(fb_key!(index, ST_LBL), self.label.as_bytes())?;
-
-        let mut key = [0_u8; crypto::EC_POINT_LEN_BYTES];
-        let len = self.key_pair.get_public_key(&mut key)?;
-        let key = &key[..len];
-        psm.set_kv_slice(fb_key!(index, ST_PBKEY), key)?;
-
-        let mut key = [0_u8; crypto::BIGNUM_LEN_BYTES];
-        let len = self.key_pair.get_private_key(&mut key)?;
-        let key = &key[..len];
-        psm.set_kv_slice(fb_key!(index, ST_PRKEY), key)?;
-
-        psm.set_kv_u64(fb_key!(index, ST_VID), self.vendor_id.into())?;
-        Ok(())
-    }
-
-    fn load(index: usize, psm: &MutexGuard<Psm>) -> Result<Self, Error> {
+    // Older releases stored each member of the fabric in a separate key
+    fn load_legacy(index: usize, psm: &dyn KvStorage) -> Result<Self, Error> {
         let mut root_ca = Vec::new();
         psm.get_kv_slice(fb_key!(index, ST_RCA), &mut root_ca)?;
         let root_ca = Cert::new(root_ca.as_slice())?;
@@ -275,9 +278,103 @@ impl Fabric {
             f
         })
     }
+
+    fn remove_legacy(index: usize, psm: &dyn KvStorage) -> Result<(), Error> {
+        for key in LEGACY_KEYS {
+            psm.remove_kv(fb_key!(index, key))?;
+        }
+        Ok(())
+    }
+
+    // Move the legacy keys that can't be loaded out of the way
+    fn quarantine_legacy(index: usize, psm: &dyn KvStorage) -> Result<(), Error> {
+        for key in LEGACY_KEYS {
+            let mut val = Vec::new();
+            match psm.get_kv_slice(fb_key!(index, key), &mut val) {
+                Ok(_) => record::quarantine(psm, fb_key!(index, key), &val)?,
+                Err(Error::NotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// The record of a fabric in the storage
+#[derive(FromTLV, ToTLV)]
+#[tlvargs(lifetime = "'a")]
+struct FabricRecord<'a> {
+    root_ca: OctetStr<'a>,
+    icac: Option<OctetStr<'a>>,
+    noc: OctetStr<'a>,
+    ipk: OctetStr<'a>,
+    label: UtfStr<'a>,
+    pub_key: OctetStr<'a>,
+    priv_key: OctetStr<'a>,
+    vendor_id: u16,
+}
+
+impl Persist for Fabric {
+    const VERSION: u16 = 1;
+
+    fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+        let mut root_ca = [0u8; MAX_CERT_TLV_LEN];
+        let root_ca_len = self.root_ca.as_tlv(&mut root_ca)?;
+        let mut icac = [0u8; MAX_CERT_TLV_LEN];
+        let icac_len = if let Some(c) = &self.icac {
+            Some(c.as_tlv(&mut icac)?)
+        } else {
+            None
+        };
+        let mut noc = [0u8; MAX_CERT_TLV_LEN];
+        let noc_len = self.noc.as_tlv(&mut noc)?;
+        let mut pub_key = [0_u8; crypto::EC_POINT_LEN_BYTES];
+        let pub_key_len = self.key_pair.get_public_key(&mut pub_key)?;
+        let mut priv_key = [0_u8; crypto::BIGNUM_LEN_BYTES];
+        let priv_key_len = self.key_pair.get_private_key(&mut priv_key)?;
+
+        FabricRecord {
+            root_ca: OctetStr::new(&root_ca[..root_ca_len]),
+            icac: icac_len.map(|len| OctetStr::new(&icac[..len])),
+            noc: OctetStr::new(&noc[..noc_len]),
+            ipk: OctetStr::new(self.ipk.epoch_key()),
+            label: UtfStr(self.label.as_bytes()),
+            pub_key: OctetStr::new(&pub_key[..pub_key_len]),
+            priv_key: OctetStr::new(&priv_key[..priv_key_len]),
+            vendor_id: self.vendor_id,
+        }
+        .to_tlv(tw, TagType::Anonymous)
+    }
+
+    fn decode(payload: &TLVElement) -> Result<Self, Error> {
+        let r = FabricRecord::from_tlv(payload)?;
+        let label = String::from_utf8(r.label.0.to_vec()).map_err(|_| Error::Utf8Fail)?;
+        let icac = if let Some(icac) = r.icac {
+            Some(Cert::new(icac.0)?)
+        } else {
+            None
+        };
+        let keypair = KeyPair::new_from_components(r.pub_key.0, r.priv_key.0)?;
+
+        let mut f = Fabric::new(
+            keypair,
+            Cert::new(r.root_ca.0)?,
+            icac,
+            Cert::new(r.noc.0)?,
+            r.ipk.0,
+            r.vendor_id,
+        )?;
+        f.label = label;
+        Ok(f)
+    }
 }
 
 pub const MAX_SUPPORTED_FABRICS: usize = 3;
+
+fn fabric_key(index: usize) -> String {
+    format!("fabric{}", index)
+}
+
 #[derive(Default)]
 pub struct FabricMgrInner {
     // The outside world expects Fabric Index to be one more than the actual one
@@ -287,41 +384,87 @@ pub struct FabricMgrInner {
 
 pub struct FabricMgr {
     inner: RwLock<FabricMgrInner>,
-    psm: Arc<Mutex<Psm>>,
+    psm: Arc<dyn KvStorage>,
+    mdns: Arc<Mdns>,
+    utc_time: RwLock<Box<dyn UtcTime>>,
+    // The Last Known Good UTC Time: the latest Not Before of the certificates of the
+    // fabrics, which is a time that has surely passed
+    last_known_good: RwLock<u32>,
 }
 
 impl FabricMgr {
-    pub fn new() -> Result<Self, Error> {
+    /// Create the Fabric Manager, with the fabrics that are in the storage
+    ///
+    /// The operational mDNS records of the fabrics are published through `mdns`.
+    pub fn new(psm: Arc<dyn KvStorage>, mdns: Arc<Mdns>) -> Result<Self, Error> {
         let dummy_fabric = Fabric::dummy()?;
         let mut mgr = FabricMgrInner::default();
         mgr.fabrics[0] = Some(dummy_fabric);
+        let mut last_known_good = 0;
+        match psm.get_kv_u64(LAST_KNOWN_GOOD_TIME_KEY, &mut last_known_good) {
+            Ok(()) | Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
         let mut fm = Self {
             inner: RwLock::new(mgr),
-            psm: Psm::get()?,
+            psm,
+            mdns,
+            utc_time: RwLock::new(Box::new(SysUtcTime)),
+            last_known_good: RwLock::new(last_known_good as u32),
         };
         fm.load()?;
         Ok(fm)
     }
 
     fn store(&self, index: usize, fabric: &Fabric) -> Result<(), Error> {
-        let psm = self.psm.lock().unwrap();
-        fabric.store(index, &psm)
+        record::store(self.psm.as_ref(), &fabric_key(index), fabric)
     }
 
     fn load(&mut self) -> Result<(), Error> {
         let mut mgr = self.inner.write()?;
-        let psm = self.psm.lock().unwrap();
         for i in 0..MAX_SUPPORTED_FABRICS {
-            let result = Fabric::load(i, &psm);
-            if let Ok(fabric) = result {
-                info!("Adding new fabric at index {}", i);
-                mgr.fabrics[i] = Some(fabric);
+            // A fabric that can't be loaded shouldn't keep the others from loading
+            match self.load_fabric(i) {
+                Ok(Some(mut fabric)) => {
+                    info!("Adding new fabric at index {}", i);
+                    if let Err(e) = fabric.publish(&self.mdns) {
+                        error!("Couldn't publish fabric {}: {:?}", i, e);
+                    }
+                    self.advance_last_known_good(&fabric);
+                    mgr.fabrics[i] = Some(fabric);
+                }
+                Ok(None) => (),
+                // Don't run, and risk overwriting a fabric of a newer release
+                Err(Error::UnsupportedVersion) => return Err(Error::UnsupportedVersion),
+                Err(e) => error!("Couldn't load fabric {}: {:?}", i, e),
             }
         }
         Ok(())
     }
 
-    pub fn add(&self, f: Fabric) -> Result<u8, Error> {
+    fn load_fabric(&self, index: usize) -> Result<Option<Fabric>, Error> {
+        let psm = self.psm.as_ref();
+        if let Some(fabric) = record::load(psm, &fabric_key(index))? {
+            return Ok(Some(fabric));
+        }
+
+        match Fabric::load_legacy(index, psm) {
+            Ok(fabric) => {
+                info!("Migrating fabric {} from the legacy keys", index);
+                self.store(index, &fabric)?;
+                Fabric::remove_legacy(index, psm)?;
+                Ok(Some(fabric))
+            }
+            Err(Error::NotFound) => Ok(None),
+            Err(e) => {
+                error!("Couldn't load the legacy fabric {}: {:?}", index, e);
+                Fabric::quarantine_legacy(index, psm)?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn add(&self, mut f: Fabric) -> Result<u8, Error> {
         let mut mgr = self.inner.write()?;
         let index = mgr
             .fabrics
@@ -330,11 +473,44 @@ impl FabricMgr {
             .ok_or(Error::NoSpace)?;
 
         self.store(index, &f)?;
+        f.publish(&self.mdns)?;
+        self.advance_last_known_good(&f);
 
         mgr.fabrics[index] = Some(f);
         Ok(index as u8)
     }
 
+    /// Set the source of the UTC time that the certificates are checked against, instead of
+    /// the system clock
+    pub fn set_utc_time(&self, utc_time: Box<dyn UtcTime>) {
+        *self.utc_time.write().unwrap() = utc_time;
+    }
+
+    /// The time that the validity periods of the certificates are checked against, see
+    /// [CertTime::from_clock]
+    pub fn get_cert_time(&self) -> CertTime {
+        let last_known_good = *self.last_known_good.read().unwrap();
+        CertTime::from_clock(self.utc_time.read().unwrap().now(), last_known_good)
+    }
+
+    // The certificates of a fabric were valid when it was added, so the time has passed
+    // their Not Before. The time is persisted, so that it never goes back, even if the
+    // fabric is removed.
+    fn advance_last_known_good(&self, fabric: &Fabric) {
+        let not_before = fabric.latest_not_before();
+        let mut last_known_good = self.last_known_good.write().unwrap();
+        if not_before <= *last_known_good {
+            return;
+        }
+        *last_known_good = not_before;
+        if let Err(e) = self
+            .psm
+            .set_kv_u64(LAST_KNOWN_GOOD_TIME_KEY, not_before as u64)
+        {
+            error!("Couldn't persist the Last Known Good UTC Time: {:?}", e);
+        }
+    }
+
     pub fn match_dest_id(&self, random: &[u8], target: &[u8]) -> Result<usize, Error> {
         let mgr = self.inner.read()?;
         for i in 0..MAX_SUPPORTED_FABRICS {
@@ -375,6 +551,15 @@ impl FabricMgr {
         count
     }
 
+    /// Withdraw the operational mDNS records of all the fabrics, before the stack is shut
+    /// down. They are published again, when the fabrics are loaded by the next FabricMgr.
+    pub fn withdraw_mdns(&self) {
+        let mut mgr = self.inner.write().unwrap();
+        for fabric in mgr.fabrics.iter_mut().flatten() {
+            fabric.mdns_service = None;
+        }
+    }
+
     // Parameters to T are the Fabric and its Fabric Index
     pub fn for_each<T>(&self, mut f: T) -> Result<(), Error>
     where
@@ -404,8 +589,7 @@ impl FabricMgr {
         if let Some(fabric) = &mut mgr.fabrics[index] {
             let old = fabric.label.clone();
             fabric.label = label;
-            let psm = self.psm.lock().unwrap();
-            if fabric.store(index, &psm).is_err() {
+            if record::store(self.psm.as_ref(), &fabric_key(index), fabric).is_err() {
                 fabric.label = old;
                 return Err(Error::StdIoError);
             }
@@ -413,3 +597,183 @@ impl FabricMgr {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{controller::ca::CertAuthority, persist::MemKvStorage};
+
+    struct TestUtcTime(Option<u32>);
+
+    impl UtcTime for TestUtcTime {
+        fn now(&self) -> Option<u32> {
+            self.0
+        }
+    }
+
+    use super::*;
+
+    #[test]
+    fn test_persist() {
+        let storage = Arc::new(MemKvStorage::new());
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        let index = fm.add(ca.new_fabric(0x1234, &[]).unwrap()).unwrap();
+        fm.set_label(index, "Home".into()).unwrap();
+
+        // A new Fabric Manager on the same storage has the fabric
+        let fm = FabricMgr::new(storage, Arc::new(Mdns::new())).unwrap();
+        let fabric = fm.get_fabric(index as usize).unwrap();
+        let fabric = (*fabric).as_ref().unwrap();
+        assert_eq!(fabric.get_node_id(), 0x1234);
+        assert_eq!(fabric.get_fabric_id(), 0xABCD);
+        assert_eq!(fabric.label, "Home");
+    }
+
+    #[test]
+    fn test_cert_time() {
+        let storage = Arc::new(MemKvStorage::new());
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        fm.set_utc_time(Box::new(TestUtcTime(None)));
+        assert_eq!(fm.get_cert_time(), CertTime::LastKnownGood(0));
+
+        // The certificates of the fabric were valid, so their time has passed
+        let fabric = ca.new_fabric(0x1234, &[]).unwrap();
+        let not_before = fabric.latest_not_before();
+        assert!(not_before > 0);
+        fm.add(fabric).unwrap();
+        assert_eq!(fm.get_cert_time(), CertTime::LastKnownGood(not_before));
+
+        // A time before that can't be right
+        fm.set_utc_time(Box::new(TestUtcTime(Some(not_before - 1))));
+        assert_eq!(fm.get_cert_time(), CertTime::LastKnownGood(not_before));
+        fm.set_utc_time(Box::new(TestUtcTime(Some(not_before + 1))));
+        assert_eq!(fm.get_cert_time(), CertTime::Utc(not_before + 1));
+
+        // The time is persisted
+        let fm = FabricMgr::new(storage, Arc::new(Mdns::new())).unwrap();
+        fm.set_utc_time(Box::new(TestUtcTime(None)));
+        assert_eq!(fm.get_cert_time(), CertTime::LastKnownGood(not_before));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let storage = Arc::new(MemKvStorage::new());
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        fm.add(ca.new_fabric(1, &[]).unwrap()).unwrap();
+        fm.add(ca.new_fabric(2, &[]).unwrap()).unwrap();
+
+        // A corrupt fabric is skipped, and the others still load
+        let mut val = Vec::new();
+        storage.get_kv_slice("fabric1", &mut val).unwrap();
+        let len = val.len();
+        val[len / 2] ^= 0xff;
+        storage.set_kv_slice("fabric1", &val).unwrap();
+
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        assert_eq!(fm.used_count(), 1);
+        assert!(fm.get_fabric(1).unwrap().is_none());
+        assert!(fm.get_fabric(2).unwrap().is_some());
+        let mut quarantined = Vec::new();
+        storage
+            .get_kv_slice("fabric1.corrupt", &mut quarantined)
+            .unwrap();
+        assert_eq!(quarantined, val);
+    }
+
+    // Store the fabric the way older releases did
+    fn store_legacy(f: &Fabric, index: usize, psm: &dyn KvStorage) {
+        let mut buf = [0u8; MAX_CERT_TLV_LEN];
+        let len = f.root_ca.as_tlv(&mut buf).unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_RCA), &buf[..len])
+            .unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_ICA), &[]).unwrap();
+        let len = f.noc.as_tlv(&mut buf).unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_NOC), &buf[..len])
+            .unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_IPK), f.ipk.epoch_key())
+            .unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_LBL), b"Home").unwrap();
+        let len = f.key_pair.get_public_key(&mut buf).unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_PBKEY), &buf[..len])
+            .unwrap();
+        let len = f.key_pair.get_private_key(&mut buf).unwrap();
+        psm.set_kv_slice(fb_key!(index, ST_PRKEY), &buf[..len])
+            .unwrap();
+        psm.set_kv_u64(fb_key!(index, ST_VID), f.vendor_id.into())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let storage = Arc::new(MemKvStorage::new());
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        store_legacy(&ca.new_fabric(0x1234, &[]).unwrap(), 1, storage.as_ref());
+
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        let fabric = fm.get_fabric(1).unwrap();
+        let fabric = (*fabric).as_ref().unwrap();
+        assert_eq!(fabric.get_node_id(), 0x1234);
+        assert_eq!(fabric.label, "Home");
+
+        // The legacy keys are replaced with a record
+        let mut val = Vec::new();
+        assert_eq!(
+            storage.get_kv_slice("fb1rca", &mut val),
+            Err(Error::NotFound)
+        );
+        assert!(storage.get_kv_slice("fabric1", &mut val).is_ok());
+    }
+
+    #[test]
+    fn test_corrupt_legacy() {
+        let storage = Arc::new(MemKvStorage::new());
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        store_legacy(&ca.new_fabric(0x1234, &[]).unwrap(), 1, storage.as_ref());
+        storage.set_kv_slice("fb1noc", &[0x15, 0x18]).unwrap();
+
+        // The legacy keys are moved out of the way
+        let fm = FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap();
+        assert!(fm.get_fabric(1).unwrap().is_none());
+        let mut val = Vec::new();
+        assert_eq!(
+            storage.get_kv_slice("fb1noc", &mut val),
+            Err(Error::NotFound)
+        );
+        assert_eq!(storage.get_kv_slice("fb1noc.corrupt", &mut val), Ok(2));
+        assert!(storage.get_kv_slice("fb1rca.corrupt", &mut val).is_ok());
+    }
+
+    // A record from a release that is newer than us
+    struct NewerRecord;
+
+    impl Persist for NewerRecord {
+        const VERSION: u16 = u16::MAX;
+
+        fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+            tw.u8(TagType::Anonymous, 0)
+        }
+
+        fn decode(_payload: &TLVElement) -> Result<Self, Error> {
+            Ok(NewerRecord)
+        }
+    }
+
+    #[test]
+    fn test_newer_record() {
+        let storage = Arc::new(MemKvStorage::new());
+        record::store(storage.as_ref(), "fabric1", &NewerRecord).unwrap();
+        let mut newer = Vec::new();
+        storage.get_kv_slice("fabric1", &mut newer).unwrap();
+
+        // We refuse to start, and leave the record alone
+        assert_eq!(
+            FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).err(),
+            Some(Error::UnsupportedVersion)
+        );
+        let mut val = Vec::new();
+        storage.get_kv_slice("fabric1", &mut val).unwrap();
+        assert_eq!(val, newer);
+    }
+}
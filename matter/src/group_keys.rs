@@ -15,34 +15,52 @@
  *    limitations under the License.
  */
 
-use std::sync::{Arc, Mutex, Once};
+//! The group keys and the groups that the node is a member of
+//!
+//! The key sets, the map of the groups to the key sets and the group table are fabric-scoped,
+//! they are managed through the Group Key Management and the Groups clusters. The transport
+//! looks up the operational group keys here, to decrypt the group messages.
 
-use crate::{crypto, error::Error};
+use std::{
+    net::Ipv6Addr,
+    sync::{Arc, RwLock},
+};
 
-// This is just makeshift implementation for now, not used anywhere
-pub struct GroupKeys {}
+use log::error;
 
-static mut G_GRP_KEYS: Option<Arc<Mutex<GroupKeys>>> = None;
-static INIT: Once = Once::new();
+use crate::{
+    crypto,
+    error::Error,
+    fabric::{FabricMgr, MAX_SUPPORTED_FABRICS},
+    persist::{
+        record::{self, Persist},
+        KvStorage,
+    },
+    tlv::{FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+};
 
-impl GroupKeys {
-    fn new() -> Self {
-        Self {}
-    }
+/// The key sets that a fabric can have, including the IPK
+pub const GROUP_KEYS_PER_FABRIC: usize = 3;
+/// The groups that a fabric can have, this is also the number of entries in the group key
+/// map of a fabric
+pub const GROUPS_PER_FABRIC: usize = 4;
+/// The endpoints that can be members of a group
+pub const ENDPOINTS_PER_GROUP: usize = 4;
+pub const MAX_GROUP_NAME_LEN: usize = 16;
+/// The key set of the IPK, this is managed through the Operational Credentials cluster
+pub const IPK_KEY_SET_ID: u16 = 0;
+pub const EPOCH_KEYS_PER_SET: usize = 3;
 
-    pub fn get() -> Result<Arc<Mutex<Self>>, Error> {
-        unsafe {
-            INIT.call_once(|| {
-                G_GRP_KEYS = Some(Arc::new(Mutex::new(GroupKeys::new())));
-            });
-            Ok(G_GRP_KEYS.as_ref().ok_or(Error::Invalid)?.clone())
-        }
-    }
+// The IPK is kept with the fabric
+const KEY_SETS_PER_FABRIC: usize = GROUP_KEYS_PER_FABRIC - 1;
 
-    pub fn insert_key() -> Result<(), Error> {
-        Ok(())
-    }
-}
+type SymmKey = [u8; crypto::SYMM_KEY_LEN_BYTES];
+type EpochKeys = [Option<EpochKey>; EPOCH_KEYS_PER_SET];
+type GroupEndpoints = [Option<u16>; ENDPOINTS_PER_GROUP];
+type GroupName = [u8; MAX_GROUP_NAME_LEN];
+type KeySets = [Option<GroupKeySet>; KEY_SETS_PER_FABRIC];
+type KeyMap = [Option<GroupKeyMapEntry>; GROUPS_PER_FABRIC];
+type Groups = [Option<GroupEntry>; GROUPS_PER_FABRIC];
 
 #[derive(Debug, Default)]
 pub struct KeySet {
@@ -58,7 +76,13 @@ impl KeySet {
         Ok(ks)
     }
 
-    fn op_key_from_ipk(ipk: &[u8], compressed_id: &[u8], opkey: &mut [u8]) -> Result<(), Error> {
+    /// Derive the operational group key from an epoch key, for the fabric with the given
+    /// compressed fabric id
+    pub fn op_key_from_ipk(
+        ipk: &[u8],
+        compressed_id: &[u8],
+        opkey: &mut [u8],
+    ) -> Result<(), Error> {
         const GRP_KEY_INFO: [u8; 13] = [
             0x47, 0x72, 0x6f, 0x75, 0x70, 0x4b, 0x65, 0x79, 0x20, 0x76, 0x31, 0x2e, 0x30,
         ];
@@ -66,6 +90,17 @@ impl KeySet {
         crypto::hkdf_sha256(compressed_id, ipk, &GRP_KEY_INFO, opkey).map_err(|_| Error::NoSpace)
     }
 
+    /// The group session id of an operational group key, this is a hash of the key that
+    /// the group messages carry instead of a session id
+    pub fn group_session_id(op_key: &[u8]) -> Result<u16, Error> {
+        const GRP_KEY_HASH_INFO: &[u8] = b"GroupKeyHash";
+
+        let mut hash = [0u8; 2];
+        crypto::hkdf_sha256(&[], op_key, GRP_KEY_HASH_INFO, &mut hash)
+            .map_err(|_| Error::NoSpace)?;
+        Ok(u16::from_be_bytes(hash))
+    }
+
     pub fn op_key(&self) -> &[u8] {
         &self.op_key
     }
@@ -74,3 +109,712 @@ impl KeySet {
         &self.epoch_key
     }
 }
+
+/// The IPv6 multicast address that the messages to a group of a fabric are sent to
+pub fn group_multicast_addr(fabric_id: u64, group_id: u16) -> Ipv6Addr {
+    let mut addr = [0u8; 16];
+    addr[..5].copy_from_slice(&[0xff, 0x35, 0x00, 0x40, 0xfd]);
+    addr[5..13].copy_from_slice(&fabric_id.to_be_bytes());
+    addr[14..].copy_from_slice(&group_id.to_be_bytes());
+    Ipv6Addr::from(addr)
+}
+
+/// The security policy of a key set
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeySetPolicy {
+    TrustFirst = 0,
+    CacheAndSync = 1,
+}
+
+/// An epoch key of a key set, along with the operational group key that is derived from it
+#[derive(ToTLV, FromTLV, Copy, Clone, Debug, PartialEq)]
+pub struct EpochKey {
+    pub key: SymmKey,
+    /// The time, in microseconds since the Matter epoch, that the key becomes active from
+    pub start_time: u64,
+    op_key: SymmKey,
+    sess_id: u16,
+}
+
+impl EpochKey {
+    pub fn new(key: &[u8], start_time: u64, compressed_id: &[u8]) -> Result<Self, Error> {
+        if key.len() != crypto::SYMM_KEY_LEN_BYTES {
+            return Err(Error::InvalidKeyLength);
+        }
+        let ks = KeySet::new(key, compressed_id)?;
+        Ok(Self {
+            key: ks.epoch_key,
+            start_time,
+            op_key: ks.op_key,
+            sess_id: KeySet::group_session_id(&ks.op_key)?,
+        })
+    }
+
+    pub fn op_key(&self) -> &[u8] {
+        &self.op_key
+    }
+
+    pub fn get_sess_id(&self) -> u16 {
+        self.sess_id
+    }
+}
+
+/// A key set of a fabric, with up to [EPOCH_KEYS_PER_SET] epoch keys
+#[derive(ToTLV, FromTLV, Copy, Clone, Debug, PartialEq)]
+pub struct GroupKeySet {
+    pub id: u16,
+    pub policy: u8,
+    pub epoch_keys: EpochKeys,
+}
+
+/// An entry of the group key map, that maps a group to the key set its messages are
+/// secured with
+#[derive(ToTLV, FromTLV, Copy, Clone, Debug, PartialEq)]
+#[tlvargs(start = 1)]
+pub struct GroupKeyMapEntry {
+    pub group_id: u16,
+    pub key_set_id: u16,
+    #[tagval(0xFE)]
+    pub fab_idx: Option<u8>,
+}
+
+/// A group of the group table, with the endpoints that are its members
+#[derive(ToTLV, FromTLV, Copy, Clone, Debug, PartialEq)]
+pub struct GroupEntry {
+    pub group_id: u16,
+    pub endpoints: GroupEndpoints,
+    name: GroupName,
+    name_len: u8,
+}
+
+impl GroupEntry {
+    fn new(group_id: u16) -> Self {
+        Self {
+            group_id,
+            endpoints: [None; ENDPOINTS_PER_GROUP],
+            name: [0; MAX_GROUP_NAME_LEN],
+            name_len: 0,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        // The name was a str when it was set
+        std::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or_default()
+    }
+
+    fn set_name(&mut self, name: &str) -> Result<(), Error> {
+        if name.len() > MAX_GROUP_NAME_LEN {
+            return Err(Error::Invalid);
+        }
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.name_len = name.len() as u8;
+        Ok(())
+    }
+
+    pub fn has_endpoint(&self, endpoint: u16) -> bool {
+        self.endpoints.contains(&Some(endpoint))
+    }
+}
+
+// The group state of a fabric
+#[derive(ToTLV, FromTLV, Default, Clone, Debug)]
+struct FabricGroups {
+    key_sets: KeySets,
+    key_map: KeyMap,
+    groups: Groups,
+}
+
+impl Persist for FabricGroups {
+    const VERSION: u16 = 1;
+
+    fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+        self.to_tlv(tw, TagType::Anonymous)
+    }
+
+    fn decode(payload: &TLVElement) -> Result<Self, Error> {
+        Self::from_tlv(payload)
+    }
+}
+
+impl FabricGroups {
+    fn get_group_mut(&mut self, group_id: u16) -> Option<&mut GroupEntry> {
+        self.groups
+            .iter_mut()
+            .flatten()
+            .find(|g| g.group_id == group_id)
+    }
+
+    // The entry of the key map at the given list index
+    fn key_map_at(&mut self, index: u8) -> Result<&mut Option<GroupKeyMapEntry>, Error> {
+        self.key_map
+            .iter_mut()
+            .filter(|e| e.is_some())
+            .nth(index as usize)
+            .ok_or(Error::NotFound)
+    }
+
+    // A key map is unique for a group and a key set
+    fn check_key_map(&self, entry: &GroupKeyMapEntry, skip: Option<u8>) -> Result<(), Error> {
+        if entry.group_id == 0 || entry.key_set_id == IPK_KEY_SET_ID {
+            return Err(Error::Invalid);
+        }
+        let duplicate = self
+            .key_map
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter(|(i, _)| Some(*i as u8) != skip)
+            .any(|(_, e)| e.group_id == entry.group_id && e.key_set_id == entry.key_set_id);
+        if duplicate {
+            Err(Error::Invalid)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn groups_key(fab_idx: usize) -> String {
+    format!("groups{}", fab_idx)
+}
+
+/// The key sets, the group key map and the group table of all the fabrics
+pub struct GroupMgr {
+    fabrics: RwLock<[FabricGroups; MAX_SUPPORTED_FABRICS]>,
+    fabric_mgr: Arc<FabricMgr>,
+    psm: Arc<dyn KvStorage>,
+}
+
+impl GroupMgr {
+    /// Create the Group Manager, with the groups that are in the storage
+    pub fn new(psm: Arc<dyn KvStorage>, fabric_mgr: Arc<FabricMgr>) -> Result<Self, Error> {
+        let mut fabrics: [FabricGroups; MAX_SUPPORTED_FABRICS] = Default::default();
+        for (i, f) in fabrics.iter_mut().enumerate() {
+            match record::load(psm.as_ref(), &groups_key(i)) {
+                Ok(Some(groups)) => *f = groups,
+                Ok(None) => (),
+                Err(Error::UnsupportedVersion) => return Err(Error::UnsupportedVersion),
+                Err(e) => error!("Couldn't load the groups of fabric {}: {:?}", i, e),
+            }
+        }
+        Ok(Self {
+            fabrics: RwLock::new(fabrics),
+            fabric_mgr,
+            psm,
+        })
+    }
+
+    // Modify the groups of a fabric, these are stored if the modification succeeds
+    fn modify<T>(
+        &self,
+        fab_idx: u8,
+        f: impl FnOnce(&mut FabricGroups) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut fabrics = self.fabrics.write()?;
+        let groups = fabrics.get_mut(fab_idx as usize).ok_or(Error::NotFound)?;
+        // Only a modification that succeeds, and is stored, takes effect
+        let mut modified = groups.clone();
+        let result = f(&mut modified)?;
+        record::store(self.psm.as_ref(), &groups_key(fab_idx as usize), &modified)?;
+        *groups = modified;
+        Ok(result)
+    }
+
+    fn read<T>(&self, fab_idx: u8, f: impl FnOnce(&FabricGroups) -> T) -> Result<T, Error> {
+        let fabrics = self.fabrics.read()?;
+        fabrics.get(fab_idx as usize).map(f).ok_or(Error::NotFound)
+    }
+
+    /// Add or replace a key set of a fabric
+    ///
+    /// The epoch keys are the keys and their start times, the first one is mandatory and
+    /// each one has to start after the one before it.
+    pub fn set_key_set(
+        &self,
+        fab_idx: u8,
+        id: u16,
+        policy: KeySetPolicy,
+        epoch_keys: &[(&[u8], u64)],
+    ) -> Result<(), Error> {
+        if id == IPK_KEY_SET_ID || policy != KeySetPolicy::TrustFirst {
+            return Err(Error::Invalid);
+        }
+        if epoch_keys.is_empty()
+            || epoch_keys.len() > EPOCH_KEYS_PER_SET
+            || epoch_keys.windows(2).any(|w| w[1].1 <= w[0].1)
+        {
+            return Err(Error::Invalid);
+        }
+
+        let mut compressed_id = [0u8; 8];
+        {
+            let fabric = self.fabric_mgr.get_fabric(fab_idx as usize)?;
+            let fabric = (*fabric).as_ref().ok_or(Error::NotFound)?;
+            compressed_id.copy_from_slice(fabric.get_compressed_fabric_id());
+        }
+        let mut key_set = GroupKeySet {
+            id,
+            policy: policy as u8,
+            epoch_keys: [None; EPOCH_KEYS_PER_SET],
+        };
+        for (i, (key, start_time)) in epoch_keys.iter().enumerate() {
+            key_set.epoch_keys[i] = Some(EpochKey::new(key, *start_time, &compressed_id)?);
+        }
+
+        self.modify(fab_idx, |groups| {
+            let slot = match groups
+                .key_sets
+                .iter()
+                .position(|k| matches!(k, Some(k) if k.id == id))
+            {
+                Some(index) => index,
+                None => groups
+                    .key_sets
+                    .iter()
+                    .position(|k| k.is_none())
+                    .ok_or(Error::NoSpace)?,
+            };
+            groups.key_sets[slot] = Some(key_set);
+            Ok(())
+        })
+    }
+
+    pub fn get_key_set(&self, fab_idx: u8, id: u16) -> Result<GroupKeySet, Error> {
+        self.read(fab_idx, |groups| {
+            groups
+                .key_sets
+                .iter()
+                .flatten()
+                .find(|k| k.id == id)
+                .copied()
+        })?
+        .ok_or(Error::NotFound)
+    }
+
+    /// Remove a key set of a fabric, along with the entries of the group key map that refer
+    /// to it
+    pub fn remove_key_set(&self, fab_idx: u8, id: u16) -> Result<(), Error> {
+        if id == IPK_KEY_SET_ID {
+            return Err(Error::Invalid);
+        }
+        self.modify(fab_idx, |groups| {
+            let key_set = groups
+                .key_sets
+                .iter_mut()
+                .find(|k| matches!(k, Some(k) if k.id == id))
+                .ok_or(Error::NotFound)?;
+            *key_set = None;
+            for entry in groups.key_map.iter_mut() {
+                if matches!(entry, Some(e) if e.key_set_id == id) {
+                    *entry = None;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// The ids of the key sets of a fabric, including the IPK
+    pub fn get_key_set_ids(&self, fab_idx: u8) -> Result<Vec<u16>, Error> {
+        self.read(fab_idx, |groups| {
+            let mut ids = vec![IPK_KEY_SET_ID];
+            ids.extend(groups.key_sets.iter().flatten().map(|k| k.id));
+            ids
+        })
+    }
+
+    pub fn for_each_key_map<T>(&self, mut f: T) -> Result<(), Error>
+    where
+        T: FnMut(&GroupKeyMapEntry),
+    {
+        let fabrics = self.fabrics.read()?;
+        for (fab_idx, groups) in fabrics.iter().enumerate() {
+            for entry in groups.key_map.iter().flatten() {
+                f(&GroupKeyMapEntry {
+                    fab_idx: Some(fab_idx as u8),
+                    ..*entry
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_key_map(&self, fab_idx: u8, entry: GroupKeyMapEntry) -> Result<(), Error> {
+        self.modify(fab_idx, |groups| {
+            groups.check_key_map(&entry, None)?;
+            let slot = groups
+                .key_map
+                .iter_mut()
+                .find(|e| e.is_none())
+                .ok_or(Error::NoSpace)?;
+            *slot = Some(entry);
+            Ok(())
+        })
+    }
+
+    // Since the entries are fabric-scoped, the index is only for entries of the fabric
+    pub fn edit_key_map(
+        &self,
+        index: u8,
+        fab_idx: u8,
+        entry: GroupKeyMapEntry,
+    ) -> Result<(), Error> {
+        self.modify(fab_idx, |groups| {
+            groups.check_key_map(&entry, Some(index))?;
+            *groups.key_map_at(index)? = Some(entry);
+            Ok(())
+        })
+    }
+
+    pub fn delete_key_map(&self, index: u8, fab_idx: u8) -> Result<(), Error> {
+        self.modify(fab_idx, |groups| {
+            *groups.key_map_at(index)? = None;
+            Ok(())
+        })
+    }
+
+    pub fn delete_key_map_for_fabric(&self, fab_idx: u8) -> Result<(), Error> {
+        self.modify(fab_idx, |groups| {
+            groups.key_map = Default::default();
+            Ok(())
+        })
+    }
+
+    /// Whether the group has a key set in the group key map
+    pub fn has_key(&self, fab_idx: u8, group_id: u16) -> bool {
+        self.read(fab_idx, |groups| {
+            groups
+                .key_map
+                .iter()
+                .flatten()
+                .any(|e| e.group_id == group_id)
+        })
+        .unwrap_or(false)
+    }
+
+    /// Make the endpoint a member of the group, this adds the group to the group table if
+    /// it isn't there yet. The name of the group is updated in any case.
+    pub fn add_group(
+        &self,
+        fab_idx: u8,
+        group_id: u16,
+        endpoint: u16,
+        name: &str,
+    ) -> Result<(), Error> {
+        if group_id == 0 {
+            return Err(Error::Invalid);
+        }
+        self.modify(fab_idx, |groups| {
+            if groups.get_group_mut(group_id).is_none() {
+                let slot = groups
+                    .groups
+                    .iter_mut()
+                    .find(|g| g.is_none())
+                    .ok_or(Error::NoSpace)?;
+                *slot = Some(GroupEntry::new(group_id));
+            }
+            let group = groups.get_group_mut(group_id).ok_or(Error::NotFound)?;
+            if !group.has_endpoint(endpoint) {
+                let slot = group
+                    .endpoints
+                    .iter_mut()
+                    .find(|e| e.is_none())
+                    .ok_or(Error::NoSpace)?;
+                *slot = Some(endpoint);
+            }
+            group.set_name(name)
+        })
+    }
+
+    /// Remove the endpoint from the group, the group is removed from the group table once it
+    /// has no members
+    pub fn remove_group(&self, fab_idx: u8, group_id: u16, endpoint: u16) -> Result<(), Error> {
+        self.modify(fab_idx, |groups| {
+            let slot = groups
+                .groups
+                .iter_mut()
+                .find(
+                    |g| matches!(g, Some(g) if g.group_id == group_id && g.has_endpoint(endpoint)),
+                )
+                .ok_or(Error::NotFound)?;
+            if let Some(group) = slot {
+                for e in group.endpoints.iter_mut() {
+                    if *e == Some(endpoint) {
+                        *e = None;
+                    }
+                }
+                if group.endpoints.iter().all(|e| e.is_none()) {
+                    *slot = None;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove the endpoint from all the groups of the fabric
+    pub fn remove_all_groups(&self, fab_idx: u8, endpoint: u16) -> Result<(), Error> {
+        for group_id in self.get_groups(fab_idx, endpoint)? {
+            self.remove_group(fab_idx, group_id, endpoint)?;
+        }
+        Ok(())
+    }
+
+    /// The group, if the endpoint is one of its members
+    pub fn get_group(&self, fab_idx: u8, group_id: u16, endpoint: u16) -> Option<GroupEntry> {
+        self.read(fab_idx, |groups| {
+            groups
+                .groups
+                .iter()
+                .flatten()
+                .find(|g| g.group_id == group_id && g.has_endpoint(endpoint))
+                .copied()
+        })
+        .ok()
+        .flatten()
+    }
+
+    /// The groups of the fabric that the endpoint is a member of
+    pub fn get_groups(&self, fab_idx: u8, endpoint: u16) -> Result<Vec<u16>, Error> {
+        self.read(fab_idx, |groups| {
+            groups
+                .groups
+                .iter()
+                .flatten()
+                .filter(|g| g.has_endpoint(endpoint))
+                .map(|g| g.group_id)
+                .collect()
+        })
+    }
+
+    /// The number of groups that can still be added to the group table of the fabric
+    pub fn get_capacity(&self, fab_idx: u8) -> usize {
+        self.read(fab_idx, |groups| {
+            groups.groups.iter().filter(|g| g.is_none()).count()
+        })
+        .unwrap_or(0)
+    }
+
+    /// The endpoints that are members of the group
+    pub fn get_endpoints(&self, fab_idx: u8, group_id: u16) -> Vec<u16> {
+        self.read(fab_idx, |groups| {
+            groups
+                .groups
+                .iter()
+                .flatten()
+                .filter(|g| g.group_id == group_id)
+                .flat_map(|g| g.endpoints.iter().flatten().copied())
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    // Parameters to T are the Group and its Fabric Index
+    pub fn for_each_group<T>(&self, mut f: T) -> Result<(), Error>
+    where
+        T: FnMut(&GroupEntry, u8),
+    {
+        let fabrics = self.fabrics.read()?;
+        for (fab_idx, groups) in fabrics.iter().enumerate() {
+            for group in groups.groups.iter().flatten() {
+                f(group, fab_idx as u8)
+            }
+        }
+        Ok(())
+    }
+
+    /// The operational group keys, with their fabric index, that the messages to the group
+    /// with the given group session id may be secured with
+    ///
+    /// Several fabrics may have the group, and the group session id is only a hash of the
+    /// key, so it is for the caller to find out which of these keys the message is secured
+    /// with.
+    pub fn get_op_keys(&self, group_id: u16, sess_id: u16) -> Vec<(u8, SymmKey)> {
+        let fabrics = self.fabrics.read().unwrap();
+        let mut keys = Vec::new();
+        for (fab_idx, groups) in fabrics.iter().enumerate() {
+            for entry in groups.key_map.iter().flatten() {
+                if entry.group_id != group_id {
+                    continue;
+                }
+                let key_sets = groups.key_sets.iter().flatten();
+                for key_set in key_sets.filter(|k| k.id == entry.key_set_id) {
+                    for epoch_key in key_set.epoch_keys.iter().flatten() {
+                        if epoch_key.sess_id == sess_id {
+                            keys.push((fab_idx as u8, epoch_key.op_key));
+                        }
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    /// The multicast addresses of all the groups in the group table
+    pub fn get_multicast_addrs(&self) -> Vec<Ipv6Addr> {
+        let mut addrs = Vec::new();
+        let _ = self.for_each_group(|group, fab_idx| {
+            if let Ok(fabric) = self.fabric_mgr.get_fabric(fab_idx as usize) {
+                if let Some(fabric) = (*fabric).as_ref() {
+                    let addr = group_multicast_addr(fabric.get_fabric_id(), group.group_id);
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+        });
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{controller::ca::CertAuthority, mdns::Mdns, persist::MemKvStorage};
+
+    const KEY0: [u8; 16] = [
+        0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae,
+        0xaf,
+    ];
+    const KEY1: [u8; 16] = [
+        0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe,
+        0xbf,
+    ];
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn group_mgr(storage: Arc<dyn KvStorage>) -> (GroupMgr, u8) {
+        let ca = CertAuthority::new(1, 0xABCD, 0xFFF1).unwrap();
+        let fm = Arc::new(FabricMgr::new(storage.clone(), Arc::new(Mdns::new())).unwrap());
+        let fab_idx = fm.add(ca.new_fabric(0x1234, &[]).unwrap()).unwrap();
+        (GroupMgr::new(storage, fm).unwrap(), fab_idx)
+    }
+
+    #[test]
+    fn test_multicast_addr() {
+        assert_eq!(
+            group_multicast_addr(0x1122_3344_5566_7788, 0xabcd),
+            "ff35:40:fd11:2233:4455:6677:8800:abcd"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_key_sets() {
+        let (gm, fab_idx) = group_mgr(Arc::new(MemKvStorage::new()));
+        let policy = KeySetPolicy::TrustFirst;
+        gm.set_key_set(fab_idx, 1, policy, &[(&KEY0, 1)]).unwrap();
+        gm.set_key_set(fab_idx, 2, policy, &[(&KEY0, 1), (&KEY1, 2)])
+            .unwrap();
+        assert_eq!(gm.get_key_set_ids(fab_idx), Ok(vec![0, 1, 2]));
+
+        // The IPK and the unsupported or invalid key sets
+        assert_eq!(
+            gm.set_key_set(fab_idx, 0, policy, &[(&KEY0, 1)]),
+            Err(Error::Invalid)
+        );
+        let cache = KeySetPolicy::CacheAndSync;
+        assert_eq!(
+            gm.set_key_set(fab_idx, 3, cache, &[(&KEY0, 1)]),
+            Err(Error::Invalid)
+        );
+        assert_eq!(
+            gm.set_key_set(fab_idx, 3, policy, &[(&KEY0, 2), (&KEY1, 2)]),
+            Err(Error::Invalid)
+        );
+        assert_eq!(
+            gm.set_key_set(fab_idx, 3, policy, &[(&KEY0[..8], 1)]),
+            Err(Error::InvalidKeyLength)
+        );
+        // Out of space, replacing one is fine though
+        assert_eq!(
+            gm.set_key_set(fab_idx, 3, policy, &[(&KEY0, 1)]),
+            Err(Error::NoSpace)
+        );
+        gm.set_key_set(fab_idx, 2, policy, &[(&KEY1, 5)]).unwrap();
+        let key_set = gm.get_key_set(fab_idx, 2).unwrap();
+        assert_eq!(key_set.epoch_keys[0].unwrap().key, KEY1);
+        assert_eq!(key_set.epoch_keys[0].unwrap().start_time, 5);
+        assert!(key_set.epoch_keys[1].is_none());
+
+        // The key map entries of a removed key set go along with it
+        let entry = |group_id, key_set_id| GroupKeyMapEntry {
+            group_id,
+            key_set_id,
+            fab_idx: None,
+        };
+        gm.add_key_map(fab_idx, entry(0x101, 1)).unwrap();
+        gm.add_key_map(fab_idx, entry(0x102, 2)).unwrap();
+        assert_eq!(
+            gm.add_key_map(fab_idx, entry(0x102, 2)),
+            Err(Error::Invalid)
+        );
+        gm.remove_key_set(fab_idx, 2).unwrap();
+        assert_eq!(gm.remove_key_set(fab_idx, 2), Err(Error::NotFound));
+        assert_eq!(gm.get_key_set(fab_idx, 2), Err(Error::NotFound));
+        assert!(gm.has_key(fab_idx, 0x101));
+        assert!(!gm.has_key(fab_idx, 0x102));
+    }
+
+    #[test]
+    fn test_op_keys() {
+        let storage: Arc<dyn KvStorage> = Arc::new(MemKvStorage::new());
+        let (gm, fab_idx) = group_mgr(storage.clone());
+        let policy = KeySetPolicy::TrustFirst;
+        gm.set_key_set(fab_idx, 1, policy, &[(&KEY0, 1), (&KEY1, 2)])
+            .unwrap();
+        let key_set = gm.get_key_set(fab_idx, 1).unwrap();
+        let epoch_key = key_set.epoch_keys[1].unwrap();
+        let sess_id = epoch_key.get_sess_id();
+        assert_eq!(KeySet::group_session_id(epoch_key.op_key()), Ok(sess_id));
+
+        // No keys until the group is mapped to the key set
+        assert!(gm.get_op_keys(0x101, sess_id).is_empty());
+        gm.add_key_map(
+            fab_idx,
+            GroupKeyMapEntry {
+                group_id: 0x101,
+                key_set_id: 1,
+                fab_idx: None,
+            },
+        )
+        .unwrap();
+        let mut op_key = [0u8; 16];
+        op_key.copy_from_slice(epoch_key.op_key());
+        assert_eq!(gm.get_op_keys(0x101, sess_id), vec![(fab_idx, op_key)]);
+        assert!(gm.get_op_keys(0x102, sess_id).is_empty());
+
+        // The keys and the map are persisted
+        let gm = GroupMgr::new(storage, gm.fabric_mgr.clone()).unwrap();
+        assert_eq!(gm.get_op_keys(0x101, sess_id), vec![(fab_idx, op_key)]);
+    }
+
+    #[test]
+    fn test_groups() {
+        let (gm, fab_idx) = group_mgr(Arc::new(MemKvStorage::new()));
+        gm.add_group(fab_idx, 0x101, 1, "Kitchen").unwrap();
+        gm.add_group(fab_idx, 0x101, 2, "Kitchen").unwrap();
+        gm.add_group(fab_idx, 0x102, 1, "").unwrap();
+        assert_eq!(gm.add_group(fab_idx, 0, 1, ""), Err(Error::Invalid));
+        assert_eq!(
+            gm.add_group(fab_idx, 0x103, 1, "A name that is too long"),
+            Err(Error::Invalid)
+        );
+
+        assert_eq!(
+            gm.get_group(fab_idx, 0x101, 2).unwrap().get_name(),
+            "Kitchen"
+        );
+        assert!(gm.get_group(fab_idx, 0x102, 2).is_none());
+        assert_eq!(gm.get_groups(fab_idx, 1), Ok(vec![0x101, 0x102]));
+        assert_eq!(gm.get_endpoints(fab_idx, 0x101), vec![1, 2]);
+        assert_eq!(gm.get_multicast_addrs().len(), 2);
+        assert_eq!(gm.get_capacity(fab_idx), GROUPS_PER_FABRIC - 2);
+
+        gm.remove_all_groups(fab_idx, 1).unwrap();
+        assert_eq!(gm.get_groups(fab_idx, 1), Ok(vec![]));
+        assert_eq!(gm.get_endpoints(fab_idx, 0x101), vec![2]);
+        // The group without members is gone
+        assert_eq!(gm.get_multicast_addrs().len(), 1);
+        assert_eq!(gm.remove_group(fab_idx, 0x101, 1), Err(Error::NotFound));
+        gm.remove_group(fab_idx, 0x101, 2).unwrap();
+        assert!(gm.get_multicast_addrs().is_empty());
+    }
+}
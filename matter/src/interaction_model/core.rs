@@ -18,11 +18,12 @@
 use std::time::{Duration, SystemTime};
 
 use crate::{
+    crypto::AEAD_MIC_LEN_BYTES,
     error::*,
     interaction_model::messages::msg::StatusResp,
     tlv::{self, get_root_node_struct, FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
     transport::{
-        exchange::Exchange,
+        exchange::{Exchange, ExchangeMgr},
         packet::Packet,
         proto_demux::{self, ProtoCtx, ResponseRequired},
         session::Session,
@@ -42,7 +43,12 @@ use super::{messages::msg::TimedReq, InteractionConsumer};
  */
 
 /* Interaction Model ID as per the Matter Spec */
-const PROTO_ID_INTERACTION_MODEL: usize = 0x01;
+pub const PROTO_ID_INTERACTION_MODEL: usize = 0x01;
+
+/// Attribute reports are chunked such that the report, along with the transport headers and
+/// the MIC, fits in the IPv6 minimum MTU. This is the max tail of the packet's write buffer
+/// up to which attribute reports are encoded, leaving some space to close out the report.
+pub const MAX_REPORT_TAIL: usize = 1280 - 40 - 8 - AEAD_MIC_LEN_BYTES - 16;
 
 #[derive(FromPrimitive, Debug, Copy, Clone, PartialEq)]
 pub enum OpCode {
@@ -97,7 +103,10 @@ impl<'a> Transaction<'a> {
 
 impl InteractionModel {
     pub fn new(consumer: Box<dyn InteractionConsumer>) -> InteractionModel {
-        InteractionModel { consumer }
+        InteractionModel {
+            consumer,
+            subs_mgr: Default::default(),
+        }
     }
 
     pub fn handle_status_resp(
@@ -110,7 +119,10 @@ impl InteractionModel {
         let req = StatusResp::from_tlv(&root)?;
 
         let mut handled = false;
-        let result = self.handle_subscription_confirm(trans, proto_tx, &mut handled);
+        let mut result = self.handle_read_chunk_ack(trans, req.status, proto_tx, &mut handled);
+        if !handled {
+            result = self.handle_subscription_confirm(trans, req.status, proto_tx, &mut handled);
+        }
         if handled {
             result
         } else {
@@ -177,6 +189,19 @@ impl proto_demux::HandleProto for InteractionModel {
         let buf = ctx.rx.as_borrow_slice();
         info!("{} {:?}", "Received command".cyan(), proto_opcode);
         tlv::print_tlv_list(buf);
+        if trans.session.is_group() {
+            // The group messages are only Invoke and Write requests, and nothing is sent back
+            match proto_opcode {
+                OpCode::InvokeRequest => self.handle_invoke_req(&mut trans, buf, &mut ctx.tx)?,
+                OpCode::WriteRequest => self.handle_write_req(&mut trans, buf, &mut ctx.tx)?,
+                _ => {
+                    error!("Opcode not allowed in a group message: {:?}", proto_opcode);
+                    return Err(Error::InvalidOpcode);
+                }
+            };
+            ctx.exch_ctx.exch.close();
+            return Ok(ResponseRequired::No);
+        }
         let result = match proto_opcode {
             OpCode::InvokeRequest => self.handle_invoke_req(&mut trans, buf, &mut ctx.tx)?,
             OpCode::ReadRequest => self.handle_read_req(&mut trans, buf, &mut ctx.tx)?,
@@ -203,6 +228,14 @@ impl proto_demux::HandleProto for InteractionModel {
     fn get_proto_id(&self) -> usize {
         PROTO_ID_INTERACTION_MODEL
     }
+
+    fn handle_periodic(&mut self, exch_mgr: &mut ExchangeMgr) -> Result<(), Error> {
+        self.handle_subscriptions(exch_mgr)
+    }
+
+    fn handle_exchange_failure(&mut self, exch: &mut Exchange) {
+        self.handle_subscription_failure(exch)
+    }
 }
 
 #[derive(FromPrimitive, Debug, Clone, Copy, PartialEq)]
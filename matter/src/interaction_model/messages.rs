@@ -76,15 +76,15 @@ pub mod msg {
         EventPath,
     };
 
-    #[derive(FromTLV)]
+    #[derive(Default, FromTLV, ToTLV)]
     #[tlvargs(lifetime = "'a")]
     pub struct SubscribeReq<'a> {
         pub keep_subs: bool,
         pub min_int_floor: u16,
         pub max_int_ceil: u16,
         pub attr_requests: Option<TLVArray<'a, AttrPath>>,
-        event_requests: Option<TLVArray<'a, EventPath>>,
-        event_filters: Option<TLVArray<'a, EventFilter>>,
+        pub event_requests: Option<TLVArray<'a, EventPath>>,
+        pub event_filters: Option<TLVArray<'a, EventFilter>>,
         // The Context Tags are discontiguous for some reason
         _dummy: Option<bool>,
         pub fabric_filtered: bool,
@@ -92,6 +92,30 @@ pub mod msg {
     }
 
     impl<'a> SubscribeReq<'a> {
+        pub fn new(fabric_filtered: bool, min_int_floor: u16, max_int_ceil: u16) -> Self {
+            Self {
+                fabric_filtered,
+                min_int_floor,
+                max_int_ceil,
+                ..Default::default()
+            }
+        }
+
+        pub fn set_attr_requests(mut self, requests: &'a [AttrPath]) -> Self {
+            self.attr_requests = Some(TLVArray::new(requests));
+            self
+        }
+
+        pub fn set_event_requests(mut self, requests: &'a [EventPath]) -> Self {
+            self.event_requests = Some(TLVArray::new(requests));
+            self
+        }
+
+        pub fn set_event_filters(mut self, filters: &'a [EventFilter]) -> Self {
+            self.event_filters = Some(TLVArray::new(filters));
+            self
+        }
+
         pub fn to_read_req(&self) -> ReadReq<'a> {
             ReadReq {
                 attr_requests: self.attr_requests,
@@ -103,7 +127,7 @@ pub mod msg {
         }
     }
 
-    #[derive(ToTLV)]
+    #[derive(FromTLV, ToTLV)]
     pub struct SubscribeResp {
         pub subs_id: u32,
         // The Context Tags are discontiguous for some reason
@@ -163,8 +187,8 @@ pub mod msg {
     #[tlvargs(lifetime = "'a")]
     pub struct ReadReq<'a> {
         pub attr_requests: Option<TLVArray<'a, AttrPath>>,
-        event_requests: Option<TLVArray<'a, EventPath>>,
-        event_filters: Option<TLVArray<'a, EventFilter>>,
+        pub event_requests: Option<TLVArray<'a, EventPath>>,
+        pub event_filters: Option<TLVArray<'a, EventFilter>>,
         pub fabric_filtered: bool,
         pub dataver_filters: Option<TLVArray<'a, DataVersionFilter>>,
     }
@@ -181,6 +205,31 @@ pub mod msg {
             self.attr_requests = Some(TLVArray::new(requests));
             self
         }
+
+        pub fn set_event_requests(mut self, requests: &'a [EventPath]) -> Self {
+            self.event_requests = Some(TLVArray::new(requests));
+            self
+        }
+
+        pub fn set_event_filters(mut self, filters: &'a [EventFilter]) -> Self {
+            self.event_filters = Some(TLVArray::new(filters));
+            self
+        }
+
+        pub fn set_dataver_filters(mut self, filters: &'a [DataVersionFilter]) -> Self {
+            self.dataver_filters = Some(TLVArray::new(filters));
+            self
+        }
+
+        /// The event number to start the event reports from, as per the event filters
+        pub fn event_min(&self) -> u64 {
+            self.event_filters
+                .iter()
+                .flat_map(|f| f.iter())
+                .filter_map(|f| f.event_min)
+                .max()
+                .unwrap_or_default()
+        }
     }
 
     #[derive(ToTLV, FromTLV)]
@@ -213,8 +262,7 @@ pub mod msg {
     pub struct ReportDataMsg<'a> {
         pub subscription_id: Option<u32>,
         pub attr_reports: Option<TLVArray<'a, AttrResp<'a>>>,
-        // TODO
-        pub event_reports: Option<bool>,
+        pub event_reports: Option<TLVArray<'a, ib::EventResp<'a>>>,
         pub more_chunks: Option<bool>,
         pub suppress_response: Option<bool>,
     }
@@ -222,8 +270,8 @@ pub mod msg {
     pub enum ReportDataTag {
         SubscriptionId = 0,
         AttributeReports = 1,
-        _EventReport = 2,
-        _MoreChunkedMsgs = 3,
+        EventReports = 2,
+        MoreChunkedMsgs = 3,
         SupressResponse = 4,
     }
 
@@ -278,8 +326,8 @@ pub mod ib {
 
     #[derive(FromTLV, ToTLV, Copy, Clone, PartialEq, Debug)]
     pub struct CmdStatus {
-        path: CmdPath,
-        status: Status,
+        pub path: CmdPath,
+        pub status: Status,
     }
 
     impl CmdStatus {
@@ -521,7 +569,7 @@ pub mod ib {
         pub data_ver: u32,
     }
 
-    #[derive(FromTLV, ToTLV, Copy, Clone)]
+    #[derive(Default, FromTLV, ToTLV, Copy, Clone, Debug, PartialEq)]
     #[tlvargs(datatype = "list")]
     pub struct EventPath {
         pub node: Option<u64>,
@@ -531,9 +579,74 @@ pub mod ib {
         pub is_urgent: Option<bool>,
     }
 
-    #[derive(FromTLV, ToTLV, Copy, Clone)]
+    impl EventPath {
+        pub fn new(path: &GenericPath) -> Self {
+            Self {
+                endpoint: path.endpoint,
+                cluster: path.cluster,
+                event: path.leaf,
+                ..Default::default()
+            }
+        }
+
+        pub fn to_gp(&self) -> GenericPath {
+            GenericPath::new(self.endpoint, self.cluster, self.event)
+        }
+    }
+
+    #[derive(Default, FromTLV, ToTLV, Copy, Clone, Debug, PartialEq)]
     pub struct EventFilter {
         pub node: Option<u64>,
         pub event_min: Option<u64>,
     }
+
+    // Event Response
+    #[derive(Clone, Copy, FromTLV, ToTLV, PartialEq, Debug)]
+    #[tlvargs(lifetime = "'a")]
+    pub enum EventResp<'a> {
+        Status(EventStatus),
+        Data(EventData<'a>),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, FromTLV, ToTLV)]
+    pub struct EventStatus {
+        pub path: EventPath,
+        pub status: Status,
+    }
+
+    impl EventStatus {
+        pub fn new(path: &GenericPath, status: IMStatusCode, cluster_status: u16) -> Self {
+            Self {
+                path: EventPath::new(path),
+                status: Status::new(status, cluster_status),
+            }
+        }
+    }
+
+    // This enum is helpful when we are constructing the event data
+    // step by step in incremental manner
+    pub enum EventDataTag {
+        Path = 0,
+        EventNumber = 1,
+        Priority = 2,
+        EpochTimestamp = 3,
+        SystemTimestamp = 4,
+        DeltaEpochTimestamp = 5,
+        DeltaSystemTimestamp = 6,
+        Data = 7,
+    }
+
+    // Event Data
+    #[derive(Clone, Copy, PartialEq, FromTLV, ToTLV, Debug)]
+    #[tlvargs(lifetime = "'a")]
+    pub struct EventData<'a> {
+        pub path: EventPath,
+        pub event_number: u64,
+        pub priority: u8,
+        pub epoch_ts: Option<u64>,
+        pub system_ts: Option<u64>,
+        pub delta_epoch_ts: Option<u64>,
+        pub delta_system_ts: Option<u64>,
+        pub data: EncodeValue<'a>,
+    }
 }
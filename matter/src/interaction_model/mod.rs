@@ -34,6 +34,19 @@ pub struct Transaction<'a> {
     pub exch: &'a mut Exchange,
 }
 
+/// The point from which the attribute reports of a read are resumed, when the reports
+/// have to be split across multiple chunks
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ResumeCursor {
+    /// Index of the attribute path within the read request
+    pub path_index: usize,
+    /// Index of the attribute, within all the attributes that match the path
+    pub attr_index: usize,
+    /// Set once all the attribute reports are done, the event number to resume the event
+    /// reports from
+    pub event_min: Option<u64>,
+}
+
 pub trait InteractionConsumer {
     fn consume_invoke_cmd(
         &self,
@@ -42,12 +55,25 @@ pub trait InteractionConsumer {
         tw: &mut TLVWriter,
     ) -> Result<(), Error>;
 
+    /// Encode the attribute reports for the read request. If all the reports don't fit
+    /// in this message, the point to resume from, in the next chunk, is returned.
     fn consume_read_attr(
         &self,
         req: &ReadReq,
         trans: &mut Transaction,
         tw: &mut TLVWriter,
-    ) -> Result<(), Error>;
+        resume_from: Option<ResumeCursor>,
+    ) -> Result<Option<ResumeCursor>, Error>;
+
+    /// Encode the event reports for the read request. If all the reports don't fit in this
+    /// message, the event number to resume from, in the next chunk, is returned.
+    fn consume_read_event(
+        &self,
+        req: &ReadReq,
+        trans: &mut Transaction,
+        tw: &mut TLVWriter,
+        resume_from: Option<u64>,
+    ) -> Result<Option<u64>, Error>;
 
     fn consume_write_attr(
         &self,
@@ -55,10 +81,18 @@ pub trait InteractionConsumer {
         trans: &mut Transaction,
         tw: &mut TLVWriter,
     ) -> Result<(), Error>;
+
+    /// A marker that changes whenever any of the data versions changes, or an event is
+    /// emitted. The subscriptions skip encoding a report while this stays the same. None
+    /// if the consumer can't tell, in which case a report is always encoded to find out.
+    fn change_marker(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub struct InteractionModel {
     consumer: Box<dyn InteractionConsumer>,
+    subs_mgr: subscribe::SubsMgr,
 }
 pub mod command;
 pub mod core;
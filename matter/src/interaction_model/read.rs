@@ -17,16 +17,72 @@
 
 use crate::{
     error::Error,
-    interaction_model::core::OpCode,
+    interaction_model::core::{IMStatusCode, OpCode},
     tlv::{get_root_node_struct, FromTLV, TLVWriter, TagType},
     transport::{packet::Packet, proto_demux::ResponseRequired},
 };
 
 use super::{
-    messages::msg::{self, ReadReq},
-    InteractionModel, Transaction,
+    messages::{
+        ib::{AttrPath, DataVersionFilter, EventFilter, EventPath},
+        msg::{self, ReadReq},
+    },
+    InteractionConsumer, InteractionModel, ResumeCursor, Transaction,
 };
 
+/// An owned copy of a read request. This is used where the request has to outlive the
+/// message it was received in, like chunked reports and subscriptions.
+#[derive(Clone, Default)]
+pub struct OwnedReadReq {
+    pub attr_requests: Vec<AttrPath>,
+    pub event_requests: Vec<EventPath>,
+    pub event_filters: Vec<EventFilter>,
+    pub dataver_filters: Vec<DataVersionFilter>,
+    pub fabric_filtered: bool,
+}
+
+impl OwnedReadReq {
+    pub fn new(req: &ReadReq) -> Self {
+        Self {
+            attr_requests: req
+                .attr_requests
+                .map(|a| a.iter().collect())
+                .unwrap_or_default(),
+            event_requests: req
+                .event_requests
+                .map(|e| e.iter().collect())
+                .unwrap_or_default(),
+            event_filters: req
+                .event_filters
+                .map(|f| f.iter().collect())
+                .unwrap_or_default(),
+            dataver_filters: req
+                .dataver_filters
+                .map(|f| f.iter().collect())
+                .unwrap_or_default(),
+            fabric_filtered: req.fabric_filtered,
+        }
+    }
+
+    pub fn to_read_req(&self) -> ReadReq<'_> {
+        let req = ReadReq::new(self.fabric_filtered)
+            .set_attr_requests(&self.attr_requests)
+            .set_event_filters(&self.event_filters)
+            .set_dataver_filters(&self.dataver_filters);
+        if self.event_requests.is_empty() {
+            req
+        } else {
+            req.set_event_requests(&self.event_requests)
+        }
+    }
+}
+
+/// A report that didn't fit in a single message, and is being sent in chunks
+pub(super) struct ChunkedReport {
+    pub req: OwnedReadReq,
+    pub resume: ResumeCursor,
+}
+
 impl InteractionModel {
     pub fn handle_read_req(
         &mut self,
@@ -34,22 +90,113 @@ impl InteractionModel {
         rx_buf: &[u8],
         proto_tx: &mut Packet,
     ) -> Result<ResponseRequired, Error> {
-        proto_tx.set_proto_opcode(OpCode::ReportData as u8);
-
-        let mut tw = TLVWriter::new(proto_tx.get_writebuf()?);
         let root = get_root_node_struct(rx_buf)?;
         let read_req = ReadReq::from_tlv(&root)?;
 
+        let resume = InteractionModel::write_report(
+            self.consumer.as_ref(),
+            trans,
+            None,
+            &read_req,
+            None,
+            proto_tx,
+        )?;
+        if let Some(resume) = resume {
+            trans.exch.set_data_boxed(Box::new(ChunkedReport {
+                req: OwnedReadReq::new(&read_req),
+                resume,
+            }));
+        } else {
+            trans.complete();
+        }
+        Ok(ResponseRequired::Yes)
+    }
+
+    /// Send the next chunk of a read, once the peer acknowledges the previous one
+    pub fn handle_read_chunk_ack(
+        &mut self,
+        trans: &mut Transaction,
+        status: IMStatusCode,
+        proto_tx: &mut Packet,
+        request_handled: &mut bool,
+    ) -> Result<ResponseRequired, Error> {
+        *request_handled = false;
+        let mut chunked = if let Some(c) = trans.exch.take_data_boxed::<ChunkedReport>() {
+            c
+        } else {
+            return Ok(ResponseRequired::No);
+        };
+        *request_handled = true;
+
+        if status != IMStatusCode::Sucess {
+            trans.complete();
+            return Ok(ResponseRequired::No);
+        }
+
+        let resume = InteractionModel::write_report(
+            self.consumer.as_ref(),
+            trans,
+            None,
+            &chunked.req.to_read_req(),
+            Some(chunked.resume),
+            proto_tx,
+        )?;
+        if let Some(resume) = resume {
+            chunked.resume = resume;
+            trans.exch.set_data_boxed(chunked);
+        } else {
+            trans.complete();
+        }
+        Ok(ResponseRequired::Yes)
+    }
+
+    /// Encode a ReportData message with as many attribute reports as fit in it. If more
+    /// chunks have to follow, the point to resume from is returned.
+    pub(super) fn write_report(
+        consumer: &dyn InteractionConsumer,
+        trans: &mut Transaction,
+        subs_id: Option<u32>,
+        read_req: &ReadReq,
+        resume_from: Option<ResumeCursor>,
+        proto_tx: &mut Packet,
+    ) -> Result<Option<ResumeCursor>, Error> {
+        proto_tx.set_proto_opcode(OpCode::ReportData as u8);
+
+        let mut tw = TLVWriter::new(proto_tx.get_writebuf()?);
         tw.start_struct(TagType::Anonymous)?;
-        self.consumer.consume_read_attr(&read_req, trans, &mut tw)?;
-        // Supress response always true for read interaction
+        if let Some(subs_id) = subs_id {
+            tw.u32(
+                TagType::Context(msg::ReportDataTag::SubscriptionId as u8),
+                subs_id,
+            )?;
+        }
+        // The event reports follow once all the attribute reports are done
+        let attrs_done = resume_from.and_then(|r| r.event_min).is_some();
+        let mut resume = None;
+        if !attrs_done {
+            resume = consumer.consume_read_attr(read_req, trans, &mut tw, resume_from)?;
+        }
+        if resume.is_none() && read_req.event_requests.is_some() {
+            let event_resume = resume_from.and_then(|r| r.event_min);
+            resume = consumer
+                .consume_read_event(read_req, trans, &mut tw, event_resume)?
+                .map(|event_min| ResumeCursor {
+                    event_min: Some(event_min),
+                    ..Default::default()
+                });
+        }
+        if resume.is_some() {
+            tw.bool(
+                TagType::Context(msg::ReportDataTag::MoreChunkedMsgs as u8),
+                true,
+            )?;
+        }
+        // A read doesn't expect a status response, unless there are more chunks to follow
         tw.bool(
             TagType::Context(msg::ReportDataTag::SupressResponse as u8),
-            true,
+            subs_id.is_none() && resume.is_none(),
         )?;
         tw.end_container()?;
-
-        trans.complete();
-        Ok(ResponseRequired::Yes)
+        Ok(resume)
     }
 }
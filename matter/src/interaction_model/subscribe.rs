@@ -15,23 +15,33 @@
  *    limitations under the License.
  */
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
 
 use crate::{
     error::Error,
-    interaction_model::core::OpCode,
+    interaction_model::core::{IMStatusCode, OpCode, PROTO_ID_INTERACTION_MODEL},
     tlv::{get_root_node_struct, FromTLV, TLVWriter, TagType, ToTLV},
-    transport::{packet::Packet, proto_demux::ResponseRequired},
+    transport::{
+        exchange::{Exchange, ExchangeMgr},
+        packet::Packet,
+        proto_demux::ResponseRequired,
+        session::{Session, SessionMode},
+    },
 };
 
-use log::error;
+use log::{error, info};
 
 use super::{
-    messages::msg::{self, SubscribeReq, SubscribeResp},
-    InteractionModel, Transaction,
+    messages::{
+        ib::{AttrResp, ClusterPath, DataVersionFilter, EventFilter, EventResp},
+        msg::{ReportDataMsg, SubscribeReq, SubscribeResp},
+    },
+    read::{ChunkedReport, OwnedReadReq},
+    InteractionConsumer, InteractionModel, Transaction,
 };
 
-static SUBS_ID: AtomicU32 = AtomicU32::new(1);
+/// The maximum number of subscriptions that are maintained at any given time
+const MAX_SUBSCRIPTIONS: usize = 8;
 
 impl InteractionModel {
     pub fn handle_subscribe_req(
@@ -40,36 +50,32 @@ impl InteractionModel {
         rx_buf: &[u8],
         proto_tx: &mut Packet,
     ) -> Result<ResponseRequired, Error> {
-        proto_tx.set_proto_opcode(OpCode::ReportData as u8);
-
-        let mut tw = TLVWriter::new(proto_tx.get_writebuf()?);
         let root = get_root_node_struct(rx_buf)?;
         let req = SubscribeReq::from_tlv(&root)?;
 
-        let ctx = Box::new(SubsCtx {
-            state: SubsState::Confirming,
-            // TODO
-            id: SUBS_ID.fetch_add(1, Ordering::SeqCst),
-        });
-
-        let read_req = req.to_read_req();
-        tw.start_struct(TagType::Anonymous)?;
-        tw.u32(
-            TagType::Context(msg::ReportDataTag::SubscriptionId as u8),
-            ctx.id,
-        )?;
-        self.consumer.consume_read_attr(&read_req, trans, &mut tw)?;
-        tw.bool(
-            TagType::Context(msg::ReportDataTag::SupressResponse as u8),
-            false,
-        )?;
-        tw.end_container()?;
-
         if !trans.exch.is_data_none() {
             error!("Exchange data already set!");
             return Err(Error::InvalidState);
         }
-        trans.exch.set_data_boxed(ctx);
+
+        let mut subs = Subscription::new(self.subs_mgr.get_next_id(), &req, trans.session);
+        if !req.keep_subs {
+            self.subs_mgr.remove_for_peer(&subs);
+        }
+        if !self.subs_mgr.has_space() {
+            error!("No space for new subscriptions");
+            trans.complete();
+            InteractionModel::create_status_response(proto_tx, IMStatusCode::ResourceExhausted)?;
+            return Ok(ResponseRequired::Yes);
+        }
+
+        // The priming report
+        let (_, chunked) = subs.write_report(self.consumer.as_ref(), trans, None, proto_tx)?;
+        trans.exch.set_data_boxed(Box::new(SubsCtx {
+            id: subs.id,
+            state: SubsState::Confirming(subs),
+            chunked,
+        }));
 
         Ok(ResponseRequired::Yes)
     }
@@ -77,39 +83,523 @@ impl InteractionModel {
     pub fn handle_subscription_confirm(
         &mut self,
         trans: &mut Transaction,
+        status: IMStatusCode,
         proto_tx: &mut Packet,
         request_handled: &mut bool,
     ) -> Result<ResponseRequired, Error> {
         *request_handled = false;
-        if let Some(ctx) = trans.exch.get_data_boxed::<SubsCtx>() {
-            if ctx.state != SubsState::Confirming {
-                // Not relevant for us
-                return Err(Error::Invalid);
-            }
-            *request_handled = true;
-            ctx.state = SubsState::Confirmed;
-            proto_tx.set_proto_opcode(OpCode::SubscriptResponse as u8);
-
-            // TODO
-            let resp = SubscribeResp::new(ctx.id, 40);
-            let mut tw = TLVWriter::new(proto_tx.get_writebuf()?);
-            resp.to_tlv(&mut tw, TagType::Anonymous)?;
-            trans.complete();
-            Ok(ResponseRequired::Yes)
+        let mut ctx = if let Some(ctx) = trans.exch.take_data_boxed::<SubsCtx>() {
+            ctx
         } else {
             trans.complete();
-            Err(Error::Invalid)
+            return Err(Error::Invalid);
+        };
+        *request_handled = true;
+
+        if status != IMStatusCode::Sucess {
+            info!("Report for subscription {} failed: {:?}", ctx.id, status);
+            trans.complete();
+            if let SubsState::Reporting = ctx.state {
+                self.subs_mgr.remove(ctx.id);
+            }
+            return Ok(ResponseRequired::No);
+        }
+
+        if let Some(chunked) = ctx.chunked.take() {
+            // Send the next chunk of the report
+            let subs = match &mut ctx.state {
+                SubsState::Confirming(subs) => subs,
+                SubsState::Reporting => self.subs_mgr.get_mut(ctx.id).ok_or(Error::NotFound)?,
+            };
+            let (_, chunked) =
+                subs.write_report(self.consumer.as_ref(), trans, Some(chunked), proto_tx)?;
+            ctx.chunked = chunked;
+            trans.exch.set_data_boxed(ctx);
+            return Ok(ResponseRequired::Yes);
+        }
+
+        trans.complete();
+        match ctx.state {
+            SubsState::Confirming(subs) => {
+                let resp = SubscribeResp::new(subs.id, subs.max_int);
+                if self.subs_mgr.add(subs).is_err() {
+                    error!("No space for new subscriptions");
+                    InteractionModel::create_status_response(
+                        proto_tx,
+                        IMStatusCode::ResourceExhausted,
+                    )?;
+                    return Ok(ResponseRequired::Yes);
+                }
+
+                proto_tx.set_proto_opcode(OpCode::SubscriptResponse as u8);
+                let mut tw = TLVWriter::new(proto_tx.get_writebuf()?);
+                resp.to_tlv(&mut tw, TagType::Anonymous)?;
+                Ok(ResponseRequired::Yes)
+            }
+            SubsState::Reporting => {
+                self.subs_mgr.report_acked(ctx.id);
+                Ok(ResponseRequired::No)
+            }
+        }
+    }
+
+    /// The peer did not acknowledge a message on a subscription exchange, even after all
+    /// the retransmissions. A subscriber that doesn't acknowledge our reports is gone.
+    pub(super) fn handle_subscription_failure(&mut self, exch: &mut Exchange) {
+        if let Some(ctx) = exch.take_data_boxed::<SubsCtx>() {
+            if let SubsState::Reporting = ctx.state {
+                info!("Report not acknowledged, removing subscription {}", ctx.id);
+                self.subs_mgr.remove(ctx.id);
+            }
+        }
+    }
+
+    /// Send out reports for all the subscriptions that are due, either because the data that
+    /// they cover has changed, or because their max interval has lapsed
+    pub(super) fn handle_subscriptions(&mut self, exch_mgr: &mut ExchangeMgr) -> Result<(), Error> {
+        let now = SystemTime::now();
+        for slot in self.subs_mgr.subs.iter_mut() {
+            let subs = if let Some(s) = slot { s } else { continue };
+            let keep_alive = match subs.report_due(now) {
+                ReportDue::No => continue,
+                ReportDue::IfChanged => false,
+                ReportDue::KeepAlive => true,
+                ReportDue::Expired => {
+                    info!(
+                        "Subscriber not responding, removing subscription {}",
+                        subs.id
+                    );
+                    *slot = None;
+                    continue;
+                }
+            };
+
+            match InteractionModel::send_report(
+                self.consumer.as_ref(),
+                subs,
+                keep_alive,
+                now,
+                exch_mgr,
+            ) {
+                Ok(()) => (),
+                Err(Error::NoSession) => {
+                    info!("Session gone, removing subscription {}", subs.id);
+                    *slot = None;
+                }
+                Err(e) => error!(
+                    "Error in sending report for subscription {}: {}",
+                    subs.id, e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn send_report(
+        consumer: &dyn InteractionConsumer,
+        subs: &mut Subscription,
+        keep_alive: bool,
+        now: SystemTime,
+        exch_mgr: &mut ExchangeMgr,
+    ) -> Result<(), Error> {
+        if !keep_alive && !subs.may_have_changed(consumer) {
+            return Ok(());
         }
+
+        let mut tx = exch_mgr.new_tx()?;
+        tx.set_proto_id(PROTO_ID_INTERACTION_MODEL as u16);
+
+        let mut exch_ctx = exch_mgr.initiate(subs.sess_id)?;
+        let mut trans = Transaction::new(&mut exch_ctx.sess, exch_ctx.exch);
+        // Only the clusters that have changed since our last report will be encoded
+        let result = subs.write_report(consumer, &mut trans, None, &mut tx);
+        let chunked = match result {
+            Ok((changed, chunked)) if changed || keep_alive || chunked.is_some() => chunked,
+            _ => {
+                // Either there is nothing to report, or we failed to generate the report
+                exch_ctx.exch.close();
+                return result.map(|_| ());
+            }
+        };
+
+        exch_ctx.exch.set_data_boxed(Box::new(SubsCtx {
+            id: subs.id,
+            state: SubsState::Reporting,
+            chunked,
+        }));
+        let exch_id = exch_ctx.exch.get_id();
+        subs.report_sent(now);
+        exch_mgr.send(exch_id, tx)
     }
 }
 
-#[derive(PartialEq)]
 enum SubsState {
-    Confirming,
-    Confirmed,
+    // The priming report has been sent, waiting for the subscriber to confirm it
+    Confirming(Subscription),
+    // A report has been sent on an active subscription
+    Reporting,
 }
 
 struct SubsCtx {
     state: SubsState,
     id: u32,
+    // Set if the report has more chunks to follow
+    chunked: Option<ChunkedReport>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ReportDue {
+    No,
+    // The min interval has lapsed, report if anything has changed
+    IfChanged,
+    // The max interval has lapsed, report even if nothing has changed
+    KeepAlive,
+    // The subscriber didn't respond to our last report
+    Expired,
+}
+
+pub struct Subscription {
+    id: u32,
+    // The local session id of the session with the subscriber
+    sess_id: u16,
+    fab_idx: u8,
+    peer_node_id: u64,
+    min_int: u16,
+    max_int: u16,
+    // The data version filters in this are the data versions of the clusters, as of
+    // our last report
+    req: OwnedReadReq,
+    last_report: SystemTime,
+    report_pending: bool,
+    // The change marker of the consumer, as of our last report
+    marker: Option<u64>,
+}
+
+impl Subscription {
+    fn new(id: u32, req: &SubscribeReq, session: &Session) -> Self {
+        let fab_idx = match session.get_session_mode() {
+            SessionMode::Case(c) => c.fab_idx,
+            _ => 0,
+        };
+        Self {
+            id,
+            sess_id: session.get_local_sess_id(),
+            fab_idx,
+            peer_node_id: session.get_peer_node_id().unwrap_or_default(),
+            min_int: req.min_int_floor,
+            // We are happy to go with whatever the subscriber prefers, as long as it doesn't
+            // have us sending keep-alives back-to-back
+            max_int: req.max_int_ceil.max(req.min_int_floor).max(1),
+            req: OwnedReadReq::new(&req.to_read_req()),
+            last_report: SystemTime::now(),
+            report_pending: false,
+            marker: None,
+        }
+    }
+
+    // Whether anything may have changed since our last report, and a report has to be
+    // encoded to find out what
+    fn may_have_changed(&self, consumer: &dyn InteractionConsumer) -> bool {
+        self.marker.is_none() || consumer.change_marker() != self.marker
+    }
+
+    fn report_due(&self, now: SystemTime) -> ReportDue {
+        let elapsed = now.duration_since(self.last_report).unwrap_or_default();
+        let max_int = Duration::from_secs(self.max_int as u64);
+        if self.report_pending {
+            if elapsed > max_int {
+                ReportDue::Expired
+            } else {
+                ReportDue::No
+            }
+        } else if elapsed >= max_int {
+            ReportDue::KeepAlive
+        } else if elapsed >= Duration::from_secs(self.min_int as u64) {
+            ReportDue::IfChanged
+        } else {
+            ReportDue::No
+        }
+    }
+
+    fn report_sent(&mut self, now: SystemTime) {
+        self.last_report = now;
+        self.report_pending = true;
+    }
+
+    /// Encode a chunk of a report for this subscription. Returns whether anything changed,
+    /// and the rest of the report, if more chunks have to follow.
+    fn write_report(
+        &mut self,
+        consumer: &dyn InteractionConsumer,
+        trans: &mut Transaction,
+        chunked: Option<ChunkedReport>,
+        proto_tx: &mut Packet,
+    ) -> Result<(bool, Option<ChunkedReport>), Error> {
+        if chunked.is_none() {
+            // Anything that changes while the report is encoded is reported the next time
+            self.marker = consumer.change_marker();
+        }
+        let resume = if let Some(c) = &chunked {
+            InteractionModel::write_report(
+                consumer,
+                trans,
+                Some(self.id),
+                &c.req.to_read_req(),
+                Some(c.resume),
+                proto_tx,
+            )?
+        } else {
+            InteractionModel::write_report(
+                consumer,
+                trans,
+                Some(self.id),
+                &self.req.to_read_req(),
+                None,
+                proto_tx,
+            )?
+        };
+        // The rest of the chunks use the data versions as of the start of the report
+        let chunked = resume.map(|resume| ChunkedReport {
+            req: chunked.map_or_else(|| self.req.clone(), |c| c.req),
+            resume,
+        });
+        let changed = self.update_filters(proto_tx.as_borrow_slice())?;
+        Ok((changed, chunked))
+    }
+
+    /// Update the cluster data versions and the event number from a report that was generated
+    /// for this subscription. Returns true if any of the data versions changed, or if there were
+    /// any new events.
+    fn update_filters(&mut self, report: &[u8]) -> Result<bool, Error> {
+        let root = get_root_node_struct(report)?;
+        let report = ReportDataMsg::from_tlv(&root)?;
+
+        let mut changed = false;
+        if let Some(attr_reports) = report.attr_reports {
+            for attr_resp in attr_reports.iter() {
+                if let AttrResp::Data(d) = attr_resp {
+                    if let (Some(data_ver), Some(endpoint), Some(cluster)) =
+                        (d.data_ver, d.path.endpoint, d.path.cluster)
+                    {
+                        changed |= self.set_dataver(endpoint, cluster, data_ver);
+                    }
+                }
+            }
+        }
+        if let Some(event_reports) = report.event_reports {
+            for event_resp in event_reports.iter() {
+                if let EventResp::Data(d) = event_resp {
+                    self.set_event_min(d.event_number + 1);
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    // The next report only carries the events that are newer than the ones already reported
+    fn set_event_min(&mut self, event_min: u64) {
+        if self.req.to_read_req().event_min() < event_min {
+            self.req.event_filters = vec![EventFilter {
+                node: None,
+                event_min: Some(event_min),
+            }];
+        }
+    }
+
+    fn set_dataver(&mut self, endpoint: u16, cluster: u32, data_ver: u32) -> bool {
+        if let Some(f) = self
+            .req
+            .dataver_filters
+            .iter_mut()
+            .find(|f| f.path.endpoint == endpoint && f.path.cluster == cluster)
+        {
+            let changed = f.data_ver != data_ver;
+            f.data_ver = data_ver;
+            changed
+        } else {
+            self.req.dataver_filters.push(DataVersionFilter {
+                path: ClusterPath {
+                    node: None,
+                    endpoint,
+                    cluster,
+                },
+                data_ver,
+            });
+            true
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SubsMgr {
+    subs: [Option<Subscription>; MAX_SUBSCRIPTIONS],
+    next_id: u32,
+}
+
+impl SubsMgr {
+    fn get_next_id(&mut self) -> u32 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+
+    fn has_space(&self) -> bool {
+        self.subs.iter().any(|s| s.is_none())
+    }
+
+    fn add(&mut self, subs: Subscription) -> Result<(), Error> {
+        let slot = self
+            .subs
+            .iter_mut()
+            .find(|s| s.is_none())
+            .ok_or(Error::NoSpace)?;
+        *slot = Some(subs);
+        Ok(())
+    }
+
+    fn get_mut(&mut self, id: u32) -> Option<&mut Subscription> {
+        self.subs.iter_mut().flatten().find(|s| s.id == id)
+    }
+
+    fn remove(&mut self, id: u32) {
+        for slot in self.subs.iter_mut() {
+            if slot.as_ref().map(|s| s.id) == Some(id) {
+                *slot = None;
+            }
+        }
+    }
+
+    // Remove the subscriptions of the new subscription's subscriber. Only a CASE session
+    // identifies the subscriber across sessions, for the other sessions these are the
+    // subscriptions of the same session.
+    fn remove_for_peer(&mut self, new: &Subscription) {
+        for slot in self.subs.iter_mut() {
+            if let Some(s) = slot {
+                let same_peer = if new.fab_idx == 0 {
+                    s.fab_idx == 0 && s.sess_id == new.sess_id
+                } else {
+                    s.fab_idx == new.fab_idx && s.peer_node_id == new.peer_node_id
+                };
+                if same_peer {
+                    info!("Removing existing subscription {}", s.id);
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn report_acked(&mut self, id: u32) {
+        if let Some(s) = self.get_mut(id) {
+            s.report_pending = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{ReportDue, SubsMgr, Subscription};
+    use crate::{
+        interaction_model::messages::msg::SubscribeReq,
+        transport::{network::Address, session::Session},
+    };
+
+    fn subscription(min_int: u16, max_int: u16) -> Subscription {
+        Subscription {
+            min_int,
+            max_int,
+            ..peer_subscription(1, 1, 1, 10)
+        }
+    }
+
+    fn peer_subscription(id: u32, sess_id: u16, fab_idx: u8, peer_node_id: u64) -> Subscription {
+        Subscription {
+            id,
+            sess_id,
+            fab_idx,
+            peer_node_id,
+            min_int: 0,
+            max_int: 10,
+            req: Default::default(),
+            last_report: SystemTime::now(),
+            report_pending: false,
+            marker: None,
+        }
+    }
+
+    #[test]
+    fn test_report_due() {
+        let mut subs = subscription(2, 10);
+        let now = subs.last_report;
+        let secs = |s| now + Duration::from_secs(s);
+
+        assert_eq!(subs.report_due(secs(1)), ReportDue::No);
+        assert_eq!(subs.report_due(secs(2)), ReportDue::IfChanged);
+        assert_eq!(subs.report_due(secs(9)), ReportDue::IfChanged);
+        assert_eq!(subs.report_due(secs(10)), ReportDue::KeepAlive);
+
+        // While a report is outstanding, nothing else is sent
+        subs.report_sent(secs(10));
+        assert_eq!(subs.report_due(secs(15)), ReportDue::No);
+        assert_eq!(subs.report_due(secs(21)), ReportDue::Expired);
+    }
+
+    #[test]
+    fn test_zero_intervals() {
+        let req = SubscribeReq::new(false, 0, 0);
+        let session = Session::new(Address::default(), None);
+        let subs = Subscription::new(1, &req, &session);
+        assert_eq!(subs.max_int, 1);
+
+        let now = subs.last_report;
+        assert_eq!(subs.report_due(now), ReportDue::IfChanged);
+        assert_eq!(
+            subs.report_due(now + Duration::from_secs(1)),
+            ReportDue::KeepAlive
+        );
+    }
+
+    #[test]
+    fn test_set_dataver() {
+        let mut subs = subscription(0, 10);
+        assert!(subs.set_dataver(1, 6, 100));
+        assert!(!subs.set_dataver(1, 6, 100));
+        assert!(subs.set_dataver(1, 8, 100));
+        assert!(subs.set_dataver(1, 6, 101));
+        assert_eq!(subs.req.dataver_filters.len(), 2);
+    }
+
+    #[test]
+    fn test_set_event_min() {
+        let mut subs = subscription(0, 10);
+        assert_eq!(subs.req.to_read_req().event_min(), 0);
+        subs.set_event_min(5);
+        assert_eq!(subs.req.to_read_req().event_min(), 5);
+        // The event number never goes back
+        subs.set_event_min(3);
+        assert_eq!(subs.req.to_read_req().event_min(), 5);
+    }
+
+    #[test]
+    fn test_remove_for_peer() {
+        let mut subs_mgr = SubsMgr::default();
+        // Two CASE sessions with the same peer, another peer, and two PASE sessions
+        subs_mgr.add(peer_subscription(1, 1, 1, 10)).unwrap();
+        subs_mgr.add(peer_subscription(2, 2, 1, 10)).unwrap();
+        subs_mgr.add(peer_subscription(3, 3, 1, 11)).unwrap();
+        subs_mgr.add(peer_subscription(4, 4, 0, 0)).unwrap();
+        subs_mgr.add(peer_subscription(5, 5, 0, 0)).unwrap();
+        let ids = |subs_mgr: &SubsMgr| -> Vec<u32> {
+            subs_mgr.subs.iter().flatten().map(|s| s.id).collect()
+        };
+
+        // The peer's subscriptions from all of its CASE sessions are removed
+        subs_mgr.remove_for_peer(&peer_subscription(6, 6, 1, 10));
+        assert_eq!(ids(&subs_mgr), vec![3, 4, 5]);
+
+        // A PASE session only removes its own subscriptions
+        subs_mgr.remove_for_peer(&peer_subscription(7, 4, 0, 0));
+        assert_eq!(ids(&subs_mgr), vec![3, 5]);
+    }
 }
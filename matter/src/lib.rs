@@ -28,6 +28,9 @@
 //! use matter::data_model::device_types::device_type_add_on_off_light;
 //! use matter::data_model::cluster_basic_information::BasicInfoConfig;
 //! use matter::secure_channel::spake2p::VerifierData;
+//! use matter::persist::MemKvStorage;
+//! use matter::transport::network::TransportConfig;
+//! use std::sync::Arc;
 //!
 //! # use matter::data_model::sdm::dev_att::{DataType, DevAttDataFetcher};
 //! # use matter::error::Error;
@@ -54,9 +57,16 @@
 //!     device_name: "OnOff Light".to_string(),
 //! };
 //!
+//! /// The storage for the fabrics and ACLs, a real device would use one of the file
+//! /// storages, so these survive a restart
+//! let storage = Arc::new(MemKvStorage::new());
+//!
+//! /// Listen on the Matter port, on all the interfaces
+//! let transport = TransportConfig::default();
+//!
 //! /// Get the Matter Object
 //! /// The dev_att is an object that implements the DevAttDataFetcher trait.
-//! let mut matter = Matter::new(dev_info, dev_att, comm_data).unwrap();
+//! let mut matter = Matter::new(dev_info, dev_att, comm_data, storage, transport).unwrap();
 //! let dm = matter.get_data_model();
 //! {
 //!     let mut node = dm.node.write().unwrap();
@@ -71,6 +81,7 @@
 pub mod acl;
 pub mod cert;
 pub mod codec;
+pub mod controller;
 pub mod core;
 pub mod crypto;
 pub mod data_model;
@@ -80,6 +91,7 @@ pub mod group_keys;
 pub mod interaction_model;
 pub mod mdns;
 pub mod pairing;
+pub mod persist;
 pub mod secure_channel;
 pub mod sys;
 pub mod tlv;
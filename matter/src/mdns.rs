@@ -15,12 +15,12 @@
  *    limitations under the License.
  */
 
-use std::sync::{Arc, Mutex, Once};
+use std::sync::Mutex;
 
 use crate::{
     error::Error,
     sys::{sys_publish_service, SysMdnsService},
-    transport::udp::MATTER_PORT,
+    transport::{mrp::MrpParams, udp::MATTER_PORT},
 };
 
 #[derive(Default)]
@@ -32,6 +32,10 @@ pub struct MdnsInner {
     pid: u16,
     /// Device name
     device_name: String,
+    /// The port that the services are published with
+    port: u16,
+    /// Whether the TCP transport is supported, this is the T key
+    tcp: bool,
 }
 
 pub struct Mdns {
@@ -41,9 +45,6 @@ pub struct Mdns {
 const SHORT_DISCRIMINATOR_MASK: u16 = 0xF00;
 const SHORT_DISCRIMINATOR_SHIFT: u16 = 8;
 
-static mut G_MDNS: Option<Arc<Mdns>> = None;
-static INIT: Once = Once::new();
-
 pub enum ServiceMode {
     /// The commissioned state
     Commissioned,
@@ -52,24 +53,24 @@ pub enum ServiceMode {
 }
 
 impl Mdns {
-    fn new() -> Self {
+    /// Create the mDNS service handler, each Matter instance has its own
+    pub fn new() -> Self {
         Self {
             inner: Mutex::new(MdnsInner {
+                port: MATTER_PORT,
                 ..Default::default()
             }),
         }
     }
+}
 
-    /// Get a handle to the globally unique mDNS instance
-    pub fn get() -> Result<Arc<Self>, Error> {
-        unsafe {
-            INIT.call_once(|| {
-                G_MDNS = Some(Arc::new(Mdns::new()));
-            });
-            Ok(G_MDNS.as_ref().ok_or(Error::Invalid)?.clone())
-        }
+impl Default for Mdns {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
+impl Mdns {
     /// Set mDNS service specific values
     /// Values like vid, pid, discriminator etc
     // TODO: More things like device-type etc can be added here
@@ -80,6 +81,16 @@ impl Mdns {
         inner.device_name = device_name.chars().take(32).collect();
     }
 
+    /// Set the port that the transport is listening on, if it isn't the Matter port
+    pub fn set_port(&self, port: u16) {
+        self.inner.lock().unwrap().port = port;
+    }
+
+    /// Advertise that the TCP transport is supported
+    pub fn set_tcp(&self, tcp: bool) {
+        self.inner.lock().unwrap().tcp = tcp;
+    }
+
     /// Publish a mDNS service
     /// name - is the service name (comma separated subtypes may follow)
     /// mode - the current service mode
@@ -87,7 +98,17 @@ impl Mdns {
     pub fn publish_service(&self, name: &str, mode: ServiceMode) -> Result<SysMdnsService, Error> {
         match mode {
             ServiceMode::Commissioned => {
-                sys_publish_service(name, "_matter._tcp", MATTER_PORT, &[])
+                let inner = self.inner.lock().unwrap();
+                let [sii, sai, sat] = mrp_txt_values();
+                let mut txt_kvs = vec![
+                    ["SII", &sii], /* Sleepy Idle Interval */
+                    ["SAI", &sai], /* Sleepy Active Interval */
+                    ["SAT", &sat], /* Sleepy Active Threshold */
+                ];
+                if inner.tcp {
+                    txt_kvs.push(["T", "1"]);
+                }
+                sys_publish_service(name, "_matter._tcp", inner.port, &txt_kvs)
             }
             ServiceMode::Commissionable(discriminator) => {
                 let inner = self.inner.lock().unwrap();
@@ -95,22 +116,39 @@ impl Mdns {
                 let serv_type = format!("_matterc._udp,_S{},_L{}", short, discriminator);
 
                 let str_discriminator = format!("{}", discriminator);
-                let txt_kvs = [
+                let vp = format!("{}+{}", inner.vid, inner.pid);
+                let [sii, sai, sat] = mrp_txt_values();
+                let mut txt_kvs = vec![
                     ["D", &str_discriminator],
                     ["CM", "1"],
                     ["DN", &inner.device_name],
-                    ["VP", &format!("{}+{}", inner.vid, inner.pid)],
-                    ["SII", "5000"], /* Sleepy Idle Interval */
-                    ["SAI", "300"],  /* Sleepy Active Interval */
-                    ["PH", "33"],    /* Pairing Hint */
-                    ["PI", ""],      /* Pairing Instruction */
+                    ["VP", &vp],
+                    ["SII", &sii], /* Sleepy Idle Interval */
+                    ["SAI", &sai], /* Sleepy Active Interval */
+                    ["SAT", &sat], /* Sleepy Active Threshold */
+                    ["PH", "33"],  /* Pairing Hint */
+                    ["PI", ""],    /* Pairing Instruction */
                 ];
-                sys_publish_service(name, &serv_type, MATTER_PORT, &txt_kvs)
+                if inner.tcp {
+                    txt_kvs.push(["T", "1"]);
+                }
+                sys_publish_service(name, &serv_type, inner.port, &txt_kvs)
             }
         }
     }
 }
 
+// The SII, SAI and SAT values, these are the same MRP parameters that we send in the
+// session establishment
+fn mrp_txt_values() -> [String; 3] {
+    let mrp = MrpParams::local();
+    [
+        mrp.idle_interval().as_millis().to_string(),
+        mrp.active_interval().as_millis().to_string(),
+        mrp.active_threshold().as_millis().to_string(),
+    ]
+}
+
 fn compute_short_discriminator(discriminator: u16) -> u16 {
     (discriminator & SHORT_DISCRIMINATOR_MASK) >> SHORT_DISCRIMINATOR_SHIFT
 }
@@ -119,6 +157,19 @@ fn compute_short_discriminator(discriminator: u16) -> u16 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn mrp_txt_values_match_the_session_parameters() {
+        let mrp = MrpParams::local();
+        assert_eq!(
+            mrp_txt_values(),
+            [
+                mrp.idle_interval.unwrap().to_string(),
+                mrp.active_interval.unwrap().to_string(),
+                mrp.active_threshold.unwrap().to_string(),
+            ]
+        );
+    }
+
     #[test]
     fn can_compute_short_discriminator() {
         let discriminator: u16 = 0b0000_1111_0000_0000;
@@ -17,20 +17,26 @@
 
 use super::*;
 
-pub(super) fn compute_pairing_code(comm_data: &CommissioningData) -> String {
-    // 0: no Vendor ID and Product ID present in Manual Pairing Code
-    const VID_PID_PRESENT: u8 = 0;
+pub(super) fn compute_pairing_code(
+    comm_data: &CommissioningData,
+    vid_pid: Option<(u16, u16)>,
+) -> String {
+    // Whether the Vendor ID and the Product ID are present in the Manual Pairing Code
+    let vid_pid_present: u8 = if vid_pid.is_some() { 1 } else { 0 };
 
     let passwd = passwd_from_comm_data(comm_data);
     let CommissioningData { discriminator, .. } = comm_data;
 
     let mut digits = String::new();
-    digits.push_str(&((VID_PID_PRESENT << 2) | (discriminator >> 10) as u8).to_string());
+    digits.push_str(&((vid_pid_present << 2) | (discriminator >> 10) as u8).to_string());
     digits.push_str(&format!(
         "{:0>5}",
         ((discriminator & 0x300) << 6) | (passwd & 0x3FFF) as u16
     ));
     digits.push_str(&format!("{:0>4}", passwd >> 14));
+    if let Some((vid, pid)) = vid_pid {
+        digits.push_str(&format!("{:0>5}{:0>5}", vid, pid));
+    }
 
     let check_digit = digits.calculate_verhoeff_check_digit();
     digits.push_str(&check_digit.to_string());
@@ -38,15 +44,26 @@ pub(super) fn compute_pairing_code(comm_data: &CommissioningData) -> String {
     digits
 }
 
-pub(super) fn pretty_print_pairing_code(pairing_code: &str) {
-    assert!(pairing_code.len() == 11);
+/// Group the digits of the pairing code, like `0087-6800-071`
+pub(super) fn pretty_pairing_code(pairing_code: &str) -> String {
+    assert!(pairing_code.len() == 11 || pairing_code.len() == 21);
     let mut pretty = String::new();
     pretty.push_str(&pairing_code[..4]);
     pretty.push('-');
     pretty.push_str(&pairing_code[4..8]);
     pretty.push('-');
-    pretty.push_str(&pairing_code[8..]);
-    info!("Pairing Code: {}", pretty);
+    pretty.push_str(&pairing_code[8..11]);
+    if pairing_code.len() == 21 {
+        pretty.push('-');
+        pretty.push_str(&pairing_code[11..16]);
+        pretty.push('-');
+        pretty.push_str(&pairing_code[16..]);
+    }
+    pretty
+}
+
+pub(super) fn pretty_print_pairing_code(pairing_code: &str) {
+    info!("Pairing Code: {}", pretty_pairing_code(pairing_code));
 }
 
 #[cfg(test)]
@@ -60,14 +77,26 @@ mod tests {
             verifier: VerifierData::new_with_pw(123456),
             discriminator: 250,
         };
-        let pairing_code = compute_pairing_code(&comm_data);
+        let pairing_code = compute_pairing_code(&comm_data, None);
         assert_eq!(pairing_code, "00876800071");
+        assert_eq!(pretty_pairing_code(&pairing_code), "0087-6800-071");
 
         let comm_data = CommissioningData {
             verifier: VerifierData::new_with_pw(34567890),
             discriminator: 2976,
         };
-        let pairing_code = compute_pairing_code(&comm_data);
+        let pairing_code = compute_pairing_code(&comm_data, None);
         assert_eq!(pairing_code, "26318621095");
+
+        let comm_data = CommissioningData {
+            verifier: VerifierData::new_with_pw(20202021),
+            discriminator: 3840,
+        };
+        let pairing_code = compute_pairing_code(&comm_data, Some((65521, 32769)));
+        assert_eq!(pairing_code, "749701123365521327694");
+        assert_eq!(
+            pretty_pairing_code(&pairing_code),
+            "7497-0112-336-55213-27694"
+        );
     }
 }
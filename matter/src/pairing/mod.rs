@@ -18,11 +18,15 @@
 //! This module contains the logic for generating the pairing code and the QR code for easy pairing.
 
 pub mod code;
+pub mod payload;
 pub mod qr;
 pub mod vendor_identifiers;
 
 use log::info;
-use qrcode::{render::unicode, QrCode, Version};
+use qrcode::{
+    render::{svg, unicode},
+    QrCode, Version,
+};
 use verhoeff::Verhoeff;
 
 use crate::{
@@ -31,10 +35,14 @@ use crate::{
 };
 
 use self::{
-    code::{compute_pairing_code, pretty_print_pairing_code},
-    qr::{payload_base38_representation, print_qr_code, QrSetupPayload},
+    code::{compute_pairing_code, pretty_pairing_code, pretty_print_pairing_code},
+    qr::{
+        payload_base38_representation, print_qr_code, svg_qr_code, CommissionningFlowType,
+        QRCodeInfoType, QrBitmap, QrSetupPayload, SerialNumber,
+    },
 };
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct DiscoveryCapabilities {
     on_ip_network: bool,
     ble: bool,
@@ -53,6 +61,18 @@ impl DiscoveryCapabilities {
     pub fn has_value(&self) -> bool {
         self.on_ip_network || self.ble || self.soft_access_point
     }
+
+    pub fn on_ip_network(&self) -> bool {
+        self.on_ip_network
+    }
+
+    pub fn ble(&self) -> bool {
+        self.ble
+    }
+
+    pub fn soft_access_point(&self) -> bool {
+        self.soft_access_point
+    }
 }
 
 impl Default for DiscoveryCapabilities {
@@ -79,6 +99,104 @@ impl DiscoveryCapabilities {
         }
         bits
     }
+
+    fn from_bits(bits: u8) -> Self {
+        DiscoveryCapabilities {
+            on_ip_network: bits & (1 << 2) != 0,
+            ble: bits & (1 << 1) != 0,
+            soft_access_point: bits & (1 << 0) != 0,
+        }
+    }
+}
+
+/// The options of the onboarding payload of a device, see [PairingCodes]
+pub struct PairingOptions {
+    pub flow_type: CommissionningFlowType,
+    pub discovery_capabilities: DiscoveryCapabilities,
+    /// The serial number in the QR code, instead of the one of the [BasicInfoConfig]
+    pub serial_number: Option<SerialNumber>,
+    /// The vendor data in the QR code, with the tags in the [0x80, 0xFF] range
+    pub vendor_data: Vec<(u8, QRCodeInfoType)>,
+}
+
+impl Default for PairingOptions {
+    fn default() -> Self {
+        Self {
+            flow_type: CommissionningFlowType::Standard,
+            discovery_capabilities: DiscoveryCapabilities::default(),
+            serial_number: None,
+            vendor_data: Vec::new(),
+        }
+    }
+}
+
+/// The QR code and the manual pairing code of a device, for its onboarding
+///
+/// These can be shown by a provisioning UI or printed on a label, with the QR code
+/// rendered through [svg](PairingCodes::svg) or [bitmap](PairingCodes::bitmap).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairingCodes {
+    /// The base38 representation of the QR code, like `MT:YNJV7VSC00CMVH7SR00`
+    pub qr_code: String,
+    /// The manual pairing code, of 11 digits, or of 21 digits with the vendor and product
+    /// ids for any commissioning flow other than the standard one
+    pub manual_code: String,
+}
+
+impl PairingCodes {
+    /// Generate the codes of the device
+    ///
+    /// This fails if the commissioning data has a verifier instead of a password, or if
+    /// the payload is invalid, like for an invalid password or vendor data tag.
+    pub fn new(
+        dev_det: &BasicInfoConfig,
+        comm_data: &CommissioningData,
+        options: &PairingOptions,
+    ) -> Result<Self, Error> {
+        let mut qr_code_data =
+            QrSetupPayload::new(dev_det, comm_data, options.discovery_capabilities);
+        qr_code_data.set_commissioning_flow(options.flow_type);
+        if let Some(serial_number) = &options.serial_number {
+            qr_code_data.add_serial_number(serial_number.clone());
+        }
+        for (tag, data) in &options.vendor_data {
+            qr_code_data.add_optional_vendor_data(*tag, data.clone())?;
+        }
+        let qr_code = payload_base38_representation(&qr_code_data)?;
+
+        let vid_pid = if options.flow_type != CommissionningFlowType::Standard {
+            Some((dev_det.vid, dev_det.pid))
+        } else {
+            None
+        };
+        let manual_code = compute_pairing_code(comm_data, vid_pid);
+
+        Ok(Self {
+            qr_code,
+            manual_code,
+        })
+    }
+
+    /// The manual pairing code with its digits grouped, as it is usually printed
+    pub fn pretty_manual_code(&self) -> String {
+        pretty_pairing_code(&self.manual_code)
+    }
+
+    /// Render the QR code as an SVG image, of at least the given width and height
+    pub fn svg(&self, min_size: u32) -> Result<String, Error> {
+        svg_qr_code(&self.qr_code, min_size)
+    }
+
+    /// The modules of the QR code, to render it as a bitmap
+    pub fn bitmap(&self) -> Result<QrBitmap, Error> {
+        QrBitmap::new(&self.qr_code)
+    }
+
+    /// Print the manual pairing code and the QR code to the log
+    pub fn print(&self) -> Result<(), Error> {
+        pretty_print_pairing_code(&self.manual_code);
+        print_qr_code(&self.qr_code)
+    }
 }
 
 /// Prepares and prints the pairing code and the QR code for easy pairing.
@@ -87,12 +205,13 @@ pub fn print_pairing_code_and_qr(
     comm_data: &CommissioningData,
     discovery_capabilities: DiscoveryCapabilities,
 ) {
-    let pairing_code = compute_pairing_code(comm_data);
-    let qr_code_data = QrSetupPayload::new(dev_det, comm_data, discovery_capabilities);
-    let data_str = payload_base38_representation(&qr_code_data).expect("Failed to encode");
-
-    pretty_print_pairing_code(&pairing_code);
-    print_qr_code(&data_str);
+    let options = PairingOptions {
+        discovery_capabilities,
+        ..Default::default()
+    };
+    PairingCodes::new(dev_det, comm_data, &options)
+        .and_then(|codes| codes.print())
+        .expect("Failed to encode");
 }
 
 pub(self) fn passwd_from_comm_data(comm_data: &CommissioningData) -> u32 {
@@ -102,3 +221,111 @@ pub(self) fn passwd_from_comm_data(comm_data: &CommissioningData) -> u32 {
         VerifierOption::Verifier(_) => 0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pairing::payload::SetupPayload, secure_channel::spake2p::VerifierData};
+
+    fn dev_det() -> BasicInfoConfig {
+        BasicInfoConfig {
+            vid: 65521,
+            pid: 32769,
+            serial_no: "1234567890".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn comm_data() -> CommissioningData {
+        CommissioningData {
+            verifier: VerifierData::new_with_pw(20202021),
+            discriminator: 3840,
+        }
+    }
+
+    #[test]
+    fn can_generate_pairing_codes() {
+        let codes = PairingCodes::new(&dev_det(), &comm_data(), &Default::default()).unwrap();
+        assert_eq!(
+            codes.qr_code,
+            "MT:-24J0AFN00KA064IJ3P0IXZB0DK5N1K8SQ1RYCU1-A40"
+        );
+        assert_eq!(codes.manual_code, "34970112332");
+        assert_eq!(codes.pretty_manual_code(), "3497-0112-332");
+    }
+
+    #[test]
+    fn can_generate_pairing_codes_with_options() {
+        let options = PairingOptions {
+            flow_type: CommissionningFlowType::Custom,
+            discovery_capabilities: DiscoveryCapabilities::new(true, true, false),
+            serial_number: Some(SerialNumber::UInt32(42)),
+            vendor_data: vec![(0x82, QRCodeInfoType::String("myData".to_string()))],
+        };
+        let codes = PairingCodes::new(&dev_det(), &comm_data(), &options).unwrap();
+        assert_eq!(codes.manual_code, "749701123365521327694");
+
+        // The user intent flow also has the vendor and product ids in the manual code
+        let user_intent = PairingOptions {
+            flow_type: CommissionningFlowType::UserIntent,
+            ..Default::default()
+        };
+        let user_intent = PairingCodes::new(&dev_det(), &comm_data(), &user_intent).unwrap();
+        assert_eq!(user_intent.manual_code, "749701123365521327694");
+        assert_eq!(
+            SetupPayload::parse(&user_intent.qr_code).unwrap().flow_type,
+            Some(CommissionningFlowType::UserIntent)
+        );
+        // The manual code doesn't tell it from the custom flow
+        assert_eq!(
+            SetupPayload::parse(&user_intent.manual_code)
+                .unwrap()
+                .flow_type,
+            None
+        );
+
+        let payload = SetupPayload::parse(&codes.qr_code).unwrap();
+        assert_eq!(payload.flow_type, Some(CommissionningFlowType::Custom));
+        assert_eq!(
+            payload.discovery_capabilities,
+            Some(options.discovery_capabilities)
+        );
+        assert_eq!(payload.serial_number(), Some(SerialNumber::UInt32(42)));
+        assert_eq!(
+            payload.optional_data.get(&0x82).map(|d| &d.data),
+            Some(&QRCodeInfoType::String("myData".to_string()))
+        );
+
+        // The vendor data has to be in the vendor tags
+        let options = PairingOptions {
+            vendor_data: vec![(0x01, QRCodeInfoType::Int32(1))],
+            ..Default::default()
+        };
+        assert_eq!(
+            PairingCodes::new(&dev_det(), &comm_data(), &options),
+            Err(Error::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn can_render_qr_code() {
+        let codes = PairingCodes::new(&dev_det(), &comm_data(), &Default::default()).unwrap();
+        let svg = codes.svg(200).unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.ends_with("</svg>"));
+
+        let bitmap = codes.bitmap().unwrap();
+        // The QR code of version 3 is 29 modules wide, with the finder patterns in three corners
+        // and their separators
+        assert_eq!(bitmap.width(), 29);
+        assert!(bitmap.is_dark(0, 0) && bitmap.is_dark(28, 0) && bitmap.is_dark(0, 28));
+        assert!(!bitmap.is_dark(7, 0));
+
+        let (side, pixels) = bitmap.to_luma8(2);
+        assert_eq!(side, (29 + 8) * 2);
+        assert_eq!(pixels.len(), side * side);
+        // The quiet zone is light, and the corner of the finder pattern dark
+        assert_eq!(pixels[0], 0xFF);
+        assert_eq!(pixels[8 * side + 8], 0x00);
+    }
+}
@@ -0,0 +1,385 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The parsing of the onboarding payloads, from the QR codes and the manual pairing codes
+
+use std::{collections::BTreeMap, convert::TryFrom};
+
+use crate::{
+    secure_channel::spake2p::is_valid_passcode,
+    tlv::{self, ElementType, TagType},
+};
+
+use super::{
+    qr::{
+        CommissionningFlowType, OptionalQRCodeInfo, QRCodeInfoType, SerialNumber,
+        COMMISSIONING_FLOW_FIELD_LENGTH_IN_BITS, PADDING_FIELD_LENGTH_IN_BITS,
+        PAYLOAD_DISCRIMINATOR_FIELD_LENGTH_IN_BITS, PRODUCT_IDFIELD_LENGTH_IN_BITS,
+        RENDEZVOUS_INFO_FIELD_LENGTH_IN_BITS, SERIAL_NUMBER_TAG,
+        SETUP_PINCODE_FIELD_LENGTH_IN_BITS, TOTAL_PAYLOAD_DATA_SIZE_IN_BYTES,
+        VENDOR_IDFIELD_LENGTH_IN_BITS, VERSION_FIELD_LENGTH_IN_BITS,
+    },
+    *,
+};
+
+const QR_CODE_PREFIX: &str = "MT:";
+
+// See section 5.1.4.1. Manual Pairing Code in the Matter specification
+const MANUAL_CODE_SHORT_LEN: usize = 11;
+const MANUAL_CODE_LONG_LEN: usize = 21;
+const MANUAL_CODE_VID_PID_PRESENT: u8 = 1 << 2;
+const MANUAL_CODE_PASSCODE_LOW_BITS: u32 = 14;
+const SHORT_DISCRIMINATOR_SHIFT: u16 = 8;
+
+/// The discriminator of an onboarding payload
+///
+/// The QR code has all the 12 bits of the discriminator, whereas the manual pairing code
+/// only has its upper 4 bits.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Discriminator {
+    Long(u16),
+    Short(u8),
+}
+
+impl Discriminator {
+    /// Whether a device that advertises the given 12-bit discriminator matches
+    pub fn matches(&self, discriminator: u16) -> bool {
+        match *self {
+            Discriminator::Long(d) => d == discriminator,
+            Discriminator::Short(d) => d as u16 == discriminator >> SHORT_DISCRIMINATOR_SHIFT,
+        }
+    }
+}
+
+/// An onboarding payload, as parsed from a QR code or a manual pairing code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetupPayload {
+    pub version: u8,
+    /// The vendor id, 0 if the manual pairing code doesn't have it
+    pub vid: u16,
+    /// The product id, 0 if the manual pairing code doesn't have it
+    pub pid: u16,
+    /// The commissioning flow. A manual pairing code only tells the standard flow from the
+    /// others, so this is `None` for a manual pairing code of a non-standard flow.
+    pub flow_type: Option<CommissionningFlowType>,
+    /// The discovery capabilities, which only the QR code has
+    pub discovery_capabilities: Option<DiscoveryCapabilities>,
+    pub discriminator: Discriminator,
+    pub passcode: u32,
+    /// The optional vendor and extension data of the QR code, by their tag
+    pub optional_data: BTreeMap<u8, OptionalQRCodeInfo>,
+}
+
+impl SetupPayload {
+    /// Parse a QR code, if the string starts with `MT:`, or else a manual pairing code
+    pub fn parse(code: &str) -> Result<Self, Error> {
+        if code.trim().starts_with(QR_CODE_PREFIX) {
+            Self::from_qr_code(code)
+        } else {
+            Self::from_manual_code(code)
+        }
+    }
+
+    /// Parse the base38 representation of a QR code, like `MT:YNJV7VSC00CMVH7SR00`
+    pub fn from_qr_code(qr_code: &str) -> Result<Self, Error> {
+        let data = qr_code
+            .trim()
+            .strip_prefix(QR_CODE_PREFIX)
+            .ok_or(Error::InvalidData)?;
+        let bits = base38::decode(data)?;
+        if bits.len() < TOTAL_PAYLOAD_DATA_SIZE_IN_BYTES {
+            return Err(Error::InvalidData);
+        }
+
+        let mut offset = 0;
+        let mut read = |number_of_bits| read_bits(&bits, &mut offset, number_of_bits);
+        let version = read(VERSION_FIELD_LENGTH_IN_BITS) as u8;
+        let vid = read(VENDOR_IDFIELD_LENGTH_IN_BITS) as u16;
+        let pid = read(PRODUCT_IDFIELD_LENGTH_IN_BITS) as u16;
+        let flow_type = flow_type_from_bits(read(COMMISSIONING_FLOW_FIELD_LENGTH_IN_BITS))?;
+        let discovery_capabilities =
+            DiscoveryCapabilities::from_bits(read(RENDEZVOUS_INFO_FIELD_LENGTH_IN_BITS) as u8);
+        let discriminator = read(PAYLOAD_DISCRIMINATOR_FIELD_LENGTH_IN_BITS) as u16;
+        let passcode = read(SETUP_PINCODE_FIELD_LENGTH_IN_BITS) as u32;
+        let padding = read(PADDING_FIELD_LENGTH_IN_BITS);
+
+        // Only the version 0 of the payload is defined
+        if version != 0 || padding != 0 || !is_valid_passcode(passcode) {
+            return Err(Error::InvalidData);
+        }
+
+        Ok(Self {
+            version,
+            vid,
+            pid,
+            flow_type: Some(flow_type),
+            discovery_capabilities: Some(discovery_capabilities),
+            discriminator: Discriminator::Long(discriminator),
+            passcode,
+            optional_data: parse_optional_data(&bits[TOTAL_PAYLOAD_DATA_SIZE_IN_BYTES..])?,
+        })
+    }
+
+    /// Parse an 11-digit or a 21-digit manual pairing code
+    ///
+    /// The dashes and the whitespace, as the codes are usually printed, are ignored.
+    pub fn from_manual_code(code: &str) -> Result<Self, Error> {
+        let code: String = code
+            .chars()
+            .filter(|c| *c != '-' && !c.is_whitespace())
+            .collect();
+        if !code.bytes().all(|b| b.is_ascii_digit())
+            || (code.len() != MANUAL_CODE_SHORT_LEN && code.len() != MANUAL_CODE_LONG_LEN)
+        {
+            return Err(Error::InvalidData);
+        }
+        if !code.as_str().validate_verhoeff_check_digit() {
+            return Err(Error::InvalidChecksum);
+        }
+
+        // The chunks can't overflow, as they are at most 5 digits
+        let chunk = |range: std::ops::Range<usize>| code[range].parse::<u32>().unwrap();
+        let first = chunk(0..1) as u8;
+        let second = chunk(1..6);
+        let third = chunk(6..10);
+
+        // The first digit has the VID/PID flag, and the digits 8 and 9 are reserved
+        let vid_pid_present = first & MANUAL_CODE_VID_PID_PRESENT != 0;
+        if first > 7
+            || vid_pid_present != (code.len() == MANUAL_CODE_LONG_LEN)
+            || second >= 1 << 16
+            || third
+                >= 1 << (SETUP_PINCODE_FIELD_LENGTH_IN_BITS as u32 - MANUAL_CODE_PASSCODE_LOW_BITS)
+        {
+            return Err(Error::InvalidData);
+        }
+
+        let discriminator = ((first & 0x03) << 2) | (second >> MANUAL_CODE_PASSCODE_LOW_BITS) as u8;
+        let passcode = (third << MANUAL_CODE_PASSCODE_LOW_BITS)
+            | (second & ((1 << MANUAL_CODE_PASSCODE_LOW_BITS) - 1));
+        if !is_valid_passcode(passcode) {
+            return Err(Error::InvalidData);
+        }
+
+        let (vid, pid, flow_type) = if vid_pid_present {
+            let vid = u16::try_from(chunk(10..15)).map_err(|_| Error::InvalidData)?;
+            let pid = u16::try_from(chunk(15..20)).map_err(|_| Error::InvalidData)?;
+            // Either the user intent or the custom flow
+            (vid, pid, None)
+        } else {
+            (0, 0, Some(CommissionningFlowType::Standard))
+        };
+
+        Ok(Self {
+            version: 0,
+            vid,
+            pid,
+            flow_type,
+            discovery_capabilities: None,
+            discriminator: Discriminator::Short(discriminator),
+            passcode,
+            optional_data: BTreeMap::new(),
+        })
+    }
+
+    /// The serial number in the optional data of the QR code, if any
+    pub fn serial_number(&self) -> Option<SerialNumber> {
+        match &self.optional_data.get(&SERIAL_NUMBER_TAG)?.data {
+            QRCodeInfoType::String(s) => Some(SerialNumber::String(s.clone())),
+            QRCodeInfoType::UInt32(n) => Some(SerialNumber::UInt32(*n)),
+            _ => None,
+        }
+    }
+}
+
+fn read_bits(bits: &[u8], offset: &mut usize, number_of_bits: usize) -> u64 {
+    let mut value = 0;
+    for i in 0..number_of_bits {
+        let index = *offset + i;
+        if bits[index / 8] & (1 << (index % 8)) != 0 {
+            value |= 1 << i;
+        }
+    }
+    *offset += number_of_bits;
+    value
+}
+
+fn flow_type_from_bits(bits: u64) -> Result<CommissionningFlowType, Error> {
+    match bits {
+        0 => Ok(CommissionningFlowType::Standard),
+        1 => Ok(CommissionningFlowType::UserIntent),
+        2 => Ok(CommissionningFlowType::Custom),
+        _ => Err(Error::InvalidData),
+    }
+}
+
+fn parse_optional_data(data: &[u8]) -> Result<BTreeMap<u8, OptionalQRCodeInfo>, Error> {
+    let mut optional_data = BTreeMap::new();
+    if data.is_empty() {
+        return Ok(optional_data);
+    }
+
+    let root = tlv::get_root_node_struct(data)?;
+    for element in root.enter().ok_or(Error::InvalidData)? {
+        let tag = match element.get_tag() {
+            TagType::Context(tag) => tag,
+            _ => return Err(Error::InvalidData),
+        };
+        let data = match element.get_element_type() {
+            ElementType::Utf8l(s) => QRCodeInfoType::String(
+                std::str::from_utf8(s)
+                    .map_err(|_| Error::Utf8Fail)?
+                    .to_owned(),
+            ),
+            ElementType::S8(v) => QRCodeInfoType::Int32(v.into()),
+            ElementType::S16(v) => QRCodeInfoType::Int32(v.into()),
+            ElementType::S32(v) => QRCodeInfoType::Int32(v),
+            ElementType::S64(v) => QRCodeInfoType::Int64(v),
+            ElementType::U8(v) => QRCodeInfoType::UInt32(v.into()),
+            ElementType::U16(v) => QRCodeInfoType::UInt32(v.into()),
+            ElementType::U32(v) => QRCodeInfoType::UInt32(v),
+            ElementType::U64(v) => QRCodeInfoType::UInt64(v),
+            _ => return Err(Error::InvalidData),
+        };
+        optional_data.insert(tag, OptionalQRCodeInfo { tag, data });
+    }
+    Ok(optional_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pairing::code::compute_pairing_code, secure_channel::spake2p::VerifierData};
+
+    #[test]
+    fn can_parse_qr_code() {
+        let payload = SetupPayload::parse("MT:YNJV7VSC00CMVH7SR00").unwrap();
+        assert_eq!(payload.version, 0);
+        assert_eq!(payload.vid, 9050);
+        assert_eq!(payload.pid, 65279);
+        assert_eq!(payload.flow_type, Some(CommissionningFlowType::Standard));
+        assert_eq!(
+            payload.discovery_capabilities,
+            Some(DiscoveryCapabilities::new(false, true, false))
+        );
+        assert_eq!(payload.discriminator, Discriminator::Long(2976));
+        assert_eq!(payload.passcode, 34567890);
+        assert!(payload.optional_data.is_empty());
+        assert!(payload.serial_number().is_none());
+    }
+
+    #[test]
+    fn can_parse_qr_code_with_optional_data() {
+        let payload = SetupPayload::from_qr_code(
+            "MT:-24J0AFN00KA064IJ3P0IXZB0DK5N1K8SQ1RYCU1UXH34YY0V3KY.O3DKN440F710Q940",
+        )
+        .unwrap();
+        assert_eq!(payload.vid, 65521);
+        assert_eq!(payload.pid, 32769);
+        assert_eq!(payload.discriminator, Discriminator::Long(3840));
+        assert_eq!(payload.passcode, 20202021);
+        assert!(matches!(
+            payload.serial_number(),
+            Some(SerialNumber::String(s)) if s == "1234567890"
+        ));
+        assert_eq!(
+            payload.optional_data.get(&0x82).map(|d| &d.data),
+            Some(&QRCodeInfoType::String("myData".to_owned()))
+        );
+        assert_eq!(
+            payload.optional_data.get(&0x83).map(|d| &d.data),
+            Some(&QRCodeInfoType::Int32(65550))
+        );
+    }
+
+    #[test]
+    fn can_not_parse_invalid_qr_code() {
+        // No prefix
+        assert_eq!(
+            SetupPayload::from_qr_code("YNJV7VSC00CMVH7SR00"),
+            Err(Error::InvalidData)
+        );
+        // Too short
+        assert_eq!(
+            SetupPayload::from_qr_code("MT:YNJV7VSC00"),
+            Err(Error::InvalidData)
+        );
+        // Not base38
+        assert_eq!(
+            SetupPayload::from_qr_code("MT:YNJV7VSC00CMVH7SR0*"),
+            Err(Error::InvalidData)
+        );
+    }
+
+    #[test]
+    fn can_parse_manual_code() {
+        let payload = SetupPayload::parse("2631-862-1095").unwrap();
+        assert_eq!(payload.vid, 0);
+        assert_eq!(payload.pid, 0);
+        assert_eq!(payload.flow_type, Some(CommissionningFlowType::Standard));
+        assert_eq!(payload.discovery_capabilities, None);
+        assert_eq!(payload.discriminator, Discriminator::Short(11));
+        assert!(payload.discriminator.matches(2976));
+        assert!(!payload.discriminator.matches(250));
+        assert_eq!(payload.passcode, 34567890);
+
+        let payload = SetupPayload::parse("749701123365521327694").unwrap();
+        assert_eq!(payload.vid, 65521);
+        assert_eq!(payload.pid, 32769);
+        assert_eq!(payload.flow_type, None);
+        assert_eq!(payload.discriminator, Discriminator::Short(15));
+        assert_eq!(payload.passcode, 20202021);
+    }
+
+    #[test]
+    fn can_parse_computed_manual_code() {
+        let comm_data = CommissioningData {
+            verifier: VerifierData::new_with_pw(123456),
+            discriminator: 250,
+        };
+        let payload =
+            SetupPayload::from_manual_code(&compute_pairing_code(&comm_data, None)).unwrap();
+        assert_eq!(payload.passcode, 123456);
+        assert!(payload.discriminator.matches(250));
+    }
+
+    #[test]
+    fn can_not_parse_invalid_manual_code() {
+        // Wrong check digit
+        assert_eq!(
+            SetupPayload::from_manual_code("26318621094"),
+            Err(Error::InvalidChecksum)
+        );
+        // Wrong length
+        assert_eq!(
+            SetupPayload::from_manual_code("2631862109"),
+            Err(Error::InvalidData)
+        );
+        // Not digits
+        assert_eq!(
+            SetupPayload::from_manual_code("2631862109a"),
+            Err(Error::InvalidData)
+        );
+        // The VID/PID flag is set, but the code is short
+        let mut code = "6631862109".to_owned();
+        code.push_str(&code.as_str().calculate_verhoeff_check_digit().to_string());
+        assert_eq!(
+            SetupPayload::from_manual_code(&code),
+            Err(Error::InvalidData)
+        );
+    }
+}
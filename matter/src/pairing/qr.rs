@@ -18,6 +18,7 @@
 use std::collections::BTreeMap;
 
 use crate::{
+    secure_channel::spake2p::is_valid_passcode,
     tlv::{TLVWriter, TagType},
     utils::writebuf::WriteBuf,
 };
@@ -29,14 +30,14 @@ use super::{
 
 // See section 5.1.2. QR Code in the Matter specification
 const LONG_BITS: usize = 12;
-const VERSION_FIELD_LENGTH_IN_BITS: usize = 3;
-const VENDOR_IDFIELD_LENGTH_IN_BITS: usize = 16;
-const PRODUCT_IDFIELD_LENGTH_IN_BITS: usize = 16;
-const COMMISSIONING_FLOW_FIELD_LENGTH_IN_BITS: usize = 2;
-const RENDEZVOUS_INFO_FIELD_LENGTH_IN_BITS: usize = 8;
-const PAYLOAD_DISCRIMINATOR_FIELD_LENGTH_IN_BITS: usize = LONG_BITS;
-const SETUP_PINCODE_FIELD_LENGTH_IN_BITS: usize = 27;
-const PADDING_FIELD_LENGTH_IN_BITS: usize = 4;
+pub(super) const VERSION_FIELD_LENGTH_IN_BITS: usize = 3;
+pub(super) const VENDOR_IDFIELD_LENGTH_IN_BITS: usize = 16;
+pub(super) const PRODUCT_IDFIELD_LENGTH_IN_BITS: usize = 16;
+pub(super) const COMMISSIONING_FLOW_FIELD_LENGTH_IN_BITS: usize = 2;
+pub(super) const RENDEZVOUS_INFO_FIELD_LENGTH_IN_BITS: usize = 8;
+pub(super) const PAYLOAD_DISCRIMINATOR_FIELD_LENGTH_IN_BITS: usize = LONG_BITS;
+pub(super) const SETUP_PINCODE_FIELD_LENGTH_IN_BITS: usize = 27;
+pub(super) const PADDING_FIELD_LENGTH_IN_BITS: usize = 4;
 const TOTAL_PAYLOAD_DATA_SIZE_IN_BITS: usize = VERSION_FIELD_LENGTH_IN_BITS
     + VENDOR_IDFIELD_LENGTH_IN_BITS
     + PRODUCT_IDFIELD_LENGTH_IN_BITS
@@ -45,15 +46,16 @@ const TOTAL_PAYLOAD_DATA_SIZE_IN_BITS: usize = VERSION_FIELD_LENGTH_IN_BITS
     + PAYLOAD_DISCRIMINATOR_FIELD_LENGTH_IN_BITS
     + SETUP_PINCODE_FIELD_LENGTH_IN_BITS
     + PADDING_FIELD_LENGTH_IN_BITS;
-const TOTAL_PAYLOAD_DATA_SIZE_IN_BYTES: usize = TOTAL_PAYLOAD_DATA_SIZE_IN_BITS / 8;
+pub(super) const TOTAL_PAYLOAD_DATA_SIZE_IN_BYTES: usize = TOTAL_PAYLOAD_DATA_SIZE_IN_BITS / 8;
 
 // Spec 5.1.4.2 CHIP-Common Reserved Tags
-const SERIAL_NUMBER_TAG: u8 = 0x00;
+pub(super) const SERIAL_NUMBER_TAG: u8 = 0x00;
 // const PBKDFITERATIONS_TAG: u8 = 0x01;
 // const BPKFSALT_TAG: u8 = 0x02;
 // const NUMBER_OFDEVICES_TAG: u8 = 0x03;
 // const COMMISSIONING_TIMEOUT_TAG: u8 = 0x04;
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum QRCodeInfoType {
     String(String),
     Int32(i32),
@@ -62,11 +64,13 @@ pub enum QRCodeInfoType {
     UInt64(u64),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum SerialNumber {
     String(String),
     UInt32(u32),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct OptionalQRCodeInfo {
     // the tag number of the optional info
     pub tag: u8,
@@ -185,7 +189,7 @@ impl<'data> QrSetupPayload<'data> {
 
         let passwd = passwd_from_comm_data(self.comm_data);
 
-        if !Self::is_valid_setup_pin(passwd) {
+        if !is_valid_passcode(passwd) {
             return false;
         }
 
@@ -207,29 +211,8 @@ impl<'data> QrSetupPayload<'data> {
         true
     }
 
-    fn is_valid_setup_pin(setup_pin: u32) -> bool {
-        const SETUP_PINCODE_MAXIMUM_VALUE: u32 = 99999998;
-        const SETUP_PINCODE_UNDEFINED_VALUE: u32 = 0;
-
-        // SHALL be restricted to the values 0x0000001 to 0x5F5E0FE (00000001 to 99999998 in decimal), excluding the invalid Passcode
-        // values.
-        if setup_pin == SETUP_PINCODE_UNDEFINED_VALUE
-            || setup_pin > SETUP_PINCODE_MAXIMUM_VALUE
-            || setup_pin == 11111111
-            || setup_pin == 22222222
-            || setup_pin == 33333333
-            || setup_pin == 44444444
-            || setup_pin == 55555555
-            || setup_pin == 66666666
-            || setup_pin == 77777777
-            || setup_pin == 88888888
-            || setup_pin == 12345678
-            || setup_pin == 87654321
-        {
-            return false;
-        }
-
-        true
+    pub fn set_commissioning_flow(&mut self, flow_type: CommissionningFlowType) {
+        self.flow_type = flow_type;
     }
 
     fn has_tlv(&self) -> bool {
@@ -238,7 +221,7 @@ impl<'data> QrSetupPayload<'data> {
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CommissionningFlowType {
     Standard = 0,
     UserIntent = 1,
@@ -317,16 +300,87 @@ fn estimate_struct_overhead(first_field_size: usize) -> usize {
     first_field_size + 4 + 2
 }
 
-pub(super) fn print_qr_code(qr_data: &str) {
-    let needed_version = compute_qr_version(qr_data);
-    let code =
-        QrCode::with_version(qr_data, Version::Normal(needed_version), qrcode::EcLevel::M).unwrap();
-    let image = code
+pub(super) fn print_qr_code(qr_data: &str) -> Result<(), Error> {
+    let image = qr_code(qr_data)?
         .render::<unicode::Dense1x2>()
         .dark_color(unicode::Dense1x2::Light)
         .light_color(unicode::Dense1x2::Dark)
         .build();
     info!("\n{}", image);
+    Ok(())
+}
+
+pub(super) fn svg_qr_code(qr_data: &str, min_size: u32) -> Result<String, Error> {
+    Ok(qr_code(qr_data)?
+        .render::<svg::Color>()
+        .min_dimensions(min_size, min_size)
+        .build())
+}
+
+/// The modules of a QR code, from which an image, like a PNG, can be rendered
+#[derive(Debug, Clone, PartialEq)]
+pub struct QrBitmap {
+    width: usize,
+    modules: Vec<bool>,
+}
+
+impl QrBitmap {
+    // The quiet zone around the code, in modules, see ISO/IEC 18004
+    const QUIET_ZONE: usize = 4;
+
+    pub(super) fn new(qr_data: &str) -> Result<Self, Error> {
+        let code = qr_code(qr_data)?;
+        Ok(Self {
+            width: code.width(),
+            modules: code
+                .to_colors()
+                .iter()
+                .map(|c| *c == qrcode::Color::Dark)
+                .collect(),
+        })
+    }
+
+    /// The width and the height of the code, in modules
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Whether the module in the given column and row is dark
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.width + x]
+    }
+
+    /// Render the code as 8-bit grayscale pixels, row by row, with the quiet zone around it
+    ///
+    /// Each module is `scale` pixels wide, and the image is a square of the returned side.
+    pub fn to_luma8(&self, scale: usize) -> (usize, Vec<u8>) {
+        const DARK: u8 = 0x00;
+        const LIGHT: u8 = 0xFF;
+
+        let side = (self.width + 2 * Self::QUIET_ZONE) * scale;
+        let mut pixels = vec![LIGHT; side * side];
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if !self.is_dark(x, y) {
+                    continue;
+                }
+                let (left, top) = (
+                    (x + Self::QUIET_ZONE) * scale,
+                    (y + Self::QUIET_ZONE) * scale,
+                );
+                for row in top..top + scale {
+                    pixels[row * side + left..row * side + left + scale].fill(DARK);
+                }
+            }
+        }
+        (side, pixels)
+    }
+}
+
+fn qr_code(qr_data: &str) -> Result<QrCode, Error> {
+    let needed_version = compute_qr_version(qr_data);
+    QrCode::with_version(qr_data, Version::Normal(needed_version), qrcode::EcLevel::M)
+        .map_err(|_| Error::InvalidArgument)
 }
 
 fn compute_qr_version(qr_data: &str) -> i16 {
@@ -0,0 +1,75 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    fs::{self, DirBuilder, File},
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+use super::KvStorage;
+
+/// A storage that keeps each key in a file of the same name, in a directory
+pub struct DirKvStorage {
+    dir: PathBuf,
+}
+
+impl DirKvStorage {
+    /// Use the given directory for storage, the directory is created if it doesn't exist
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        DirBuilder::new().recursive(true).create(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    fn key_path(&self, key: &str) -> Result<PathBuf, Error> {
+        // The key must be a plain file name, the temporary files start with a '.'
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+            return Err(Error::Invalid);
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl KvStorage for DirKvStorage {
+    fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
+        super::write_atomic(&self.key_path(key)?, val)
+    }
+
+    fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error> {
+        let mut f = match File::open(self.key_path(key)?) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(f.read_to_end(val)?)
+    }
+
+    fn remove_kv(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.key_path(key)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    error::Error,
+    tlv::{self, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV, UtfStr},
+    utils::writebuf::WriteBuf,
+};
+
+use log::error;
+
+use super::KvStorage;
+
+// The suffix of the name that a corrupt file is moved to
+const CORRUPT_SUFFIX: &str = ".corrupt";
+
+// The TLV overhead of an entry: the struct, and the control and length of its members
+const ENTRY_OVERHEAD: usize = 16;
+
+#[derive(FromTLV, ToTLV)]
+#[tlvargs(lifetime = "'a")]
+struct KvEntry<'a> {
+    key: UtfStr<'a>,
+    val: OctetStr<'a>,
+}
+
+/// A storage that keeps all the keys in a single file
+///
+/// The values are cached in memory, and the whole file is rewritten on every change.
+/// This suits the small amount of state that a Matter node has to store.
+pub struct FileKvStorage {
+    path: PathBuf,
+    map: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl FileKvStorage {
+    /// Use the given file for storage, the file is created on the first write
+    ///
+    /// A file that can't be parsed is moved out of the way, to a file of the same name with
+    /// a '.corrupt' suffix, and the storage starts out empty.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+
+        let mut map = BTreeMap::new();
+        match File::open(&path) {
+            Ok(mut f) => {
+                let mut buf = Vec::new();
+                f.read_to_end(&mut buf)?;
+                if let Err(e) = FileKvStorage::parse(&buf, &mut map) {
+                    let mut bad_path = OsString::from(path.as_os_str());
+                    bad_path.push(CORRUPT_SUFFIX);
+                    error!("Storage {:?} is corrupt: {:?}", path, e);
+                    error!("Moving it to {:?}", bad_path);
+                    fs::rename(&path, &bad_path)?;
+                    map.clear();
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self {
+            path,
+            map: Mutex::new(map),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn parse(buf: &[u8], map: &mut BTreeMap<String, Vec<u8>>) -> Result<(), Error> {
+        let root = tlv::get_root_node(buf)?;
+        if let Some(iter) = root.confirm_array()?.enter() {
+            for element in iter {
+                let entry = KvEntry::from_tlv(&element)?;
+                map.insert(entry.key.to_string()?, entry.val.0.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, map: &BTreeMap<String, Vec<u8>>) -> Result<(), Error> {
+        let len = map
+            .iter()
+            .map(|(k, v)| k.len() + v.len() + ENTRY_OVERHEAD)
+            .sum::<usize>()
+            + ENTRY_OVERHEAD;
+        let mut buf = vec![0u8; len];
+        let mut wb = WriteBuf::new(&mut buf, len);
+        let mut tw = TLVWriter::new(&mut wb);
+        tw.start_array(TagType::Anonymous)?;
+        for (key, val) in map.iter() {
+            KvEntry {
+                key: UtfStr::new(key.as_bytes()),
+                val: OctetStr::new(val),
+            }
+            .to_tlv(&mut tw, TagType::Anonymous)?;
+        }
+        tw.end_container()?;
+        super::write_atomic(&self.path, wb.as_slice())
+    }
+}
+
+impl KvStorage for FileKvStorage {
+    fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
+        let mut map = self.map.lock()?;
+        let old = map.insert(key.to_owned(), val.to_vec());
+        let result = self.write(&map);
+        if result.is_err() {
+            // Keep the cache in line with the file
+            match old {
+                Some(old) => map.insert(key.to_owned(), old),
+                None => map.remove(key),
+            };
+        }
+        result
+    }
+
+    fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error> {
+        let map = self.map.lock()?;
+        let v = map.get(key).ok_or(Error::NotFound)?;
+        val.extend_from_slice(v);
+        Ok(v.len())
+    }
+
+    fn remove_kv(&self, key: &str) -> Result<(), Error> {
+        let mut map = self.map.lock()?;
+        if let Some(old) = map.remove(key) {
+            let result = self.write(&map);
+            if result.is_err() {
+                map.insert(key.to_owned(), old);
+            }
+            result
+        } else {
+            Ok(())
+        }
+    }
+}
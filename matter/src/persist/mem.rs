@@ -0,0 +1,53 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::error::Error;
+
+use super::KvStorage;
+
+/// A storage that only keeps the values in memory, these are lost once it is dropped
+#[derive(Default)]
+pub struct MemKvStorage {
+    map: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemKvStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvStorage for MemKvStorage {
+    fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
+        self.map.lock()?.insert(key.to_owned(), val.to_vec());
+        Ok(())
+    }
+
+    fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error> {
+        let map = self.map.lock()?;
+        let v = map.get(key).ok_or(Error::NotFound)?;
+        val.extend_from_slice(v);
+        Ok(v.len())
+    }
+
+    fn remove_kv(&self, key: &str) -> Result<(), Error> {
+        self.map.lock()?.remove(key);
+        Ok(())
+    }
+}
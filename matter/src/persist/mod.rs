@@ -0,0 +1,236 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Persistent storage of the Matter stack's state, like the fabrics and the ACLs.
+//!
+//! The stack stores its state through the [KvStorage] trait, so an application can pick
+//! where it is stored. The following backends are provided:
+//! - [DirKvStorage]: one file per key, in a directory
+//! - [FileKvStorage]: all the keys in a single file
+//! - [MemKvStorage]: in-memory only, which is handy for tests
+//!
+//! On top of that, the [record] module stores versioned and checksummed records.
+
+use std::{
+    convert::TryInto,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::error::Error;
+
+mod dir;
+mod file;
+mod mem;
+pub mod record;
+
+pub use self::dir::DirKvStorage;
+pub use self::file::FileKvStorage;
+pub use self::mem::MemKvStorage;
+
+/// A key-value store
+///
+/// The keys are short ASCII strings. Reading a key that isn't present returns
+/// [Error::NotFound].
+pub trait KvStorage: Send + Sync {
+    /// Store the value for the key, replacing any earlier value
+    fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error>;
+
+    /// Append the value for the key to `val`, returns the length of the value
+    fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error>;
+
+    /// Remove the key, removing a key that isn't present is not an error
+    fn remove_kv(&self, key: &str) -> Result<(), Error>;
+
+    fn set_kv_u64(&self, key: &str, val: u64) -> Result<(), Error> {
+        self.set_kv_slice(key, &val.to_be_bytes())
+    }
+
+    fn get_kv_u64(&self, key: &str, val: &mut u64) -> Result<(), Error> {
+        let mut vec = Vec::new();
+        self.get_kv_slice(key, &mut vec)?;
+        *val = u64::from_be_bytes(vec.as_slice().try_into()?);
+        Ok(())
+    }
+}
+
+/// Write the file such that it either has the old or the new contents, even if we are
+/// interrupted half-way. The data is written to a temporary file, which is then renamed
+/// over the original.
+///
+/// The temporary file is hidden, and unique to this write, so that concurrent writers of
+/// the same file don't trip over each other's temporary files.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().ok_or(Error::Invalid)?.to_string_lossy();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = (|| {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    sync_parent(path)
+}
+
+/// Flush the directory entry of the file, so that a rename survives a power loss
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::error::Error;
+
+    use super::{DirKvStorage, FileKvStorage, KvStorage, MemKvStorage};
+
+    /// A path under the temporary directory, that is unique to this test run
+    fn test_path(name: &str) -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "matter_persist_{}_{}_{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst),
+            name
+        ));
+        path
+    }
+
+    fn check_storage(s: &dyn KvStorage) {
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("missing", &mut val), Err(Error::NotFound));
+
+        s.set_kv_slice("key1", &[1, 2, 3]).unwrap();
+        s.set_kv_slice("key2", &[]).unwrap();
+        s.set_kv_u64("key3", 0x1234).unwrap();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Ok(3));
+        assert_eq!(val, [1, 2, 3]);
+
+        // Overwrite
+        s.set_kv_slice("key1", &[4, 5]).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Ok(2));
+        assert_eq!(val, [4, 5]);
+
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key2", &mut val), Ok(0));
+        let mut val = 0;
+        s.get_kv_u64("key3", &mut val).unwrap();
+        assert_eq!(val, 0x1234);
+
+        s.remove_kv("key2").unwrap();
+        s.remove_kv("key2").unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key2", &mut val), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        check_storage(&MemKvStorage::new());
+    }
+
+    #[test]
+    fn test_dir_storage() {
+        let path = test_path("dir");
+        check_storage(&DirKvStorage::new(&path).unwrap());
+
+        // The values are there after a restart
+        let s = DirKvStorage::new(&path).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Ok(2));
+        assert_eq!(val, [4, 5]);
+        assert_eq!(s.set_kv_slice("../key", &[1]), Err(Error::Invalid));
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_storage() {
+        let path = test_path("file");
+        check_storage(&FileKvStorage::new(&path).unwrap());
+
+        // The values are there after a restart
+        let s = FileKvStorage::new(&path).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Ok(2));
+        assert_eq!(val, [4, 5]);
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key2", &mut val), Err(Error::NotFound));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_storage_corrupt() {
+        let path = test_path("corrupt");
+        std::fs::write(&path, [0x16, 0x15, 0x01]).unwrap();
+
+        // The corrupt file is moved out of the way, we start afresh
+        let s = FileKvStorage::new(&path).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Err(Error::NotFound));
+        let mut bad_path = path.clone().into_os_string();
+        bad_path.push(".corrupt");
+        assert_eq!(std::fs::read(&bad_path).unwrap(), [0x16, 0x15, 0x01]);
+
+        s.set_kv_slice("key1", &[1]).unwrap();
+        let s = FileKvStorage::new(&path).unwrap();
+        assert_eq!(s.get_kv_slice("key1", &mut val), Ok(1));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&bad_path).unwrap();
+    }
+
+    #[test]
+    fn test_separate_storages() {
+        // Two instances don't see each other's values
+        let s1 = DirKvStorage::new(test_path("dir1")).unwrap();
+        let s2 = DirKvStorage::new(test_path("dir2")).unwrap();
+        s1.set_kv_slice("key", &[1]).unwrap();
+        let mut val = Vec::new();
+        assert_eq!(s2.get_kv_slice("key", &mut val), Err(Error::NotFound));
+        std::fs::remove_dir_all(s1.path()).unwrap();
+        std::fs::remove_dir_all(s2.path()).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Versioned records on top of a [KvStorage]
+//!
+//! A record is a TLV payload, wrapped in an envelope with the version of the payload's
+//! schema and a CRC of the payload:
+//! ```text
+//! {
+//!     0: version (u16),
+//!     1: CRC-32 of the payload (u32),
+//!     2: payload (octet string),
+//! }
+//! ```
+//! Loading a record that is corrupt doesn't fail. The record is moved to a separate key,
+//! so that it can be inspected later, and the caller carries on as if it wasn't there.
+//! A record that was stored by a newer release is left alone, and fails the loading, so
+//! that we don't overwrite what we don't understand.
+
+use log::{error, info, warn};
+
+use crate::{
+    error::Error,
+    tlv::{self, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
+    utils::writebuf::WriteBuf,
+};
+
+use super::KvStorage;
+
+/// The maximum length of a record's payload
+pub const MAX_PAYLOAD_LEN: usize = 2048;
+
+// The TLV overhead of the envelope: the struct, the version, the CRC and the
+// control and length of the payload
+const ENVELOPE_OVERHEAD: usize = 16;
+
+// The suffix of the key that a corrupt record is moved to
+const QUARANTINE_SUFFIX: &str = ".corrupt";
+
+#[derive(FromTLV, ToTLV)]
+#[tlvargs(lifetime = "'a")]
+struct Envelope<'a> {
+    version: u16,
+    crc: u32,
+    payload: OctetStr<'a>,
+}
+
+/// A type that is stored as a versioned record
+pub trait Persist: Sized {
+    /// The version of the schema that [encode](Persist::encode) writes
+    const VERSION: u16;
+
+    /// Write the payload of the record
+    fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error>;
+
+    /// Read the record from a payload of the current version
+    fn decode(payload: &TLVElement) -> Result<Self, Error>;
+
+    /// Convert a payload of an older version to the current version
+    ///
+    /// This is called when a record that was stored by an older release is loaded.
+    /// The migrated record is written back with the current version.
+    fn migrate(version: u16, _payload: &[u8]) -> Result<Vec<u8>, Error> {
+        error!("Don't know how to migrate from version {}", version);
+        Err(Error::Invalid)
+    }
+}
+
+/// Store the value as a record under the key
+pub fn store<T: Persist>(psm: &dyn KvStorage, key: &str, value: &T) -> Result<(), Error> {
+    let mut buf = vec![0; MAX_PAYLOAD_LEN];
+    let mut wb = WriteBuf::new(&mut buf, MAX_PAYLOAD_LEN);
+    let mut tw = TLVWriter::new(&mut wb);
+    value.encode(&mut tw)?;
+    store_payload(psm, key, T::VERSION, wb.as_slice())
+}
+
+fn store_payload(
+    psm: &dyn KvStorage,
+    key: &str,
+    version: u16,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let envelope = Envelope {
+        version,
+        crc: crc32(payload),
+        payload: OctetStr::new(payload),
+    };
+    let len = payload.len() + ENVELOPE_OVERHEAD;
+    let mut buf = vec![0; len];
+    let mut wb = WriteBuf::new(&mut buf, len);
+    let mut tw = TLVWriter::new(&mut wb);
+    envelope.to_tlv(&mut tw, TagType::Anonymous)?;
+    psm.set_kv_slice(key, wb.as_slice())
+}
+
+/// Load the record under the key
+///
+/// Returns `None` if there is no record. A record that is corrupt, or that can't be
+/// migrated to the current version, is quarantined and also returns `None`. An error is
+/// returned if the storage itself fails, or with [Error::UnsupportedVersion] if the record
+/// is of a newer version, in which case the record is left untouched.
+pub fn load<T: Persist>(psm: &dyn KvStorage, key: &str) -> Result<Option<T>, Error> {
+    let mut buf = Vec::new();
+    match psm.get_kv_slice(key, &mut buf) {
+        Ok(_) => (),
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    match decode::<T>(&buf) {
+        Ok((value, None)) => Ok(Some(value)),
+        Ok((value, Some(payload))) => {
+            info!("Migrated record {} to version {}", key, T::VERSION);
+            if let Err(e) = store_payload(psm, key, T::VERSION, &payload) {
+                warn!("Couldn't store the migrated record {}: {:?}", key, e);
+            }
+            Ok(Some(value))
+        }
+        Err(Error::UnsupportedVersion) => {
+            error!("Record {} is from a newer release, leaving it alone", key);
+            Err(Error::UnsupportedVersion)
+        }
+        Err(e) => {
+            error!("Record {} is corrupt: {:?}", key, e);
+            quarantine(psm, key, &buf)?;
+            Ok(None)
+        }
+    }
+}
+
+// Returns the value, and the migrated payload if the record was of an older version
+fn decode<T: Persist>(buf: &[u8]) -> Result<(T, Option<Vec<u8>>), Error> {
+    let root = tlv::get_root_node_struct(buf)?;
+    let envelope = Envelope::from_tlv(&root)?;
+    let payload = envelope.payload.0;
+    if crc32(payload) != envelope.crc {
+        error!("CRC mismatch");
+        return Err(Error::InvalidData);
+    }
+
+    if envelope.version == T::VERSION {
+        let value = T::decode(&tlv::get_root_node(payload)?)?;
+        Ok((value, None))
+    } else if envelope.version < T::VERSION {
+        let migrated = T::migrate(envelope.version, payload)?;
+        let value = T::decode(&tlv::get_root_node(&migrated)?)?;
+        Ok((value, Some(migrated)))
+    } else {
+        error!(
+            "Version {} is newer than the supported version {}",
+            envelope.version,
+            T::VERSION
+        );
+        Err(Error::UnsupportedVersion)
+    }
+}
+
+/// Move the value out of the way, to a key with the `.corrupt` suffix
+///
+/// This is also for the values of older releases that were stored without a record, and
+/// can't be decoded.
+pub fn quarantine(psm: &dyn KvStorage, key: &str, buf: &[u8]) -> Result<(), Error> {
+    let bad_key = format!("{}{}", key, QUARANTINE_SUFFIX);
+    error!("Moving record {} to {}", key, bad_key);
+    psm.set_kv_slice(&bad_key, buf)?;
+    psm.remove_kv(key)
+}
+
+/// CRC-32 as used by Ethernet and zlib (reflected, polynomial 0xEDB88320)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        persist::{KvStorage, MemKvStorage},
+        tlv::{self, FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+        utils::writebuf::WriteBuf,
+    };
+
+    use super::{crc32, Persist};
+
+    // Version 1 of the test record only had `a`
+    #[derive(FromTLV, ToTLV, Debug, PartialEq)]
+    struct TestRecord {
+        a: u32,
+        b: u32,
+    }
+
+    impl Persist for TestRecord {
+        const VERSION: u16 = 2;
+
+        fn encode(&self, tw: &mut TLVWriter) -> Result<(), Error> {
+            self.to_tlv(tw, TagType::Anonymous)
+        }
+
+        fn decode(payload: &TLVElement) -> Result<Self, Error> {
+            TestRecord::from_tlv(payload)
+        }
+
+        fn migrate(version: u16, payload: &[u8]) -> Result<Vec<u8>, Error> {
+            if version != 1 {
+                return Err(Error::Invalid);
+            }
+            let a = tlv::get_root_node_struct(payload)?.find_tag(0)?.u32()?;
+            let mut buf = [0; 32];
+            let mut wb = WriteBuf::new(&mut buf, 32);
+            let mut tw = TLVWriter::new(&mut wb);
+            TestRecord { a, b: 0 }.to_tlv(&mut tw, TagType::Anonymous)?;
+            Ok(wb.as_slice().to_vec())
+        }
+    }
+
+    fn get(psm: &dyn KvStorage, key: &str) -> Result<Vec<u8>, Error> {
+        let mut val = Vec::new();
+        psm.get_kv_slice(key, &mut val)?;
+        Ok(val)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_store_load() {
+        let psm = MemKvStorage::new();
+        assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+
+        let rec = TestRecord { a: 10, b: 20 };
+        super::store(&psm, "rec", &rec).unwrap();
+        assert_eq!(super::load(&psm, "rec"), Ok(Some(rec)));
+
+        psm.remove_kv("rec").unwrap();
+        assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let psm = MemKvStorage::new();
+        super::store(&psm, "rec", &TestRecord { a: 10, b: 20 }).unwrap();
+        let good = get(&psm, "rec").unwrap();
+
+        // Flip a bit in the payload, which is at the end of the envelope
+        let mut bad = good.clone();
+        let len = bad.len();
+        bad[len - 3] ^= 0x01;
+        psm.set_kv_slice("rec", &bad).unwrap();
+        assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+
+        // The record is quarantined
+        assert_eq!(get(&psm, "rec"), Err(Error::NotFound));
+        assert_eq!(get(&psm, "rec.corrupt"), Ok(bad));
+
+        // A truncated record, or garbage
+        for len in 0..good.len() - 1 {
+            psm.set_kv_slice("rec", &good[..len]).unwrap();
+            assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+        }
+        // Except for the last byte, which is the end of the envelope's structure. The TLV
+        // parser doesn't need it, and all the fields, the CRC checked payload included, are
+        // still there.
+        psm.set_kv_slice("rec", &good[..good.len() - 1]).unwrap();
+        assert_eq!(
+            super::load::<TestRecord>(&psm, "rec"),
+            Ok(Some(TestRecord { a: 10, b: 20 }))
+        );
+        psm.set_kv_slice("rec", &[0xff; 40]).unwrap();
+        assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let psm = MemKvStorage::new();
+
+        // A record of version 1
+        let payload = [0x15, 0x24, 0x00, 0x0a, 0x18];
+        super::store_payload(&psm, "rec", 1, &payload).unwrap();
+        assert_eq!(
+            super::load(&psm, "rec"),
+            Ok(Some(TestRecord { a: 10, b: 0 }))
+        );
+
+        // The record was written back at the current version
+        super::store(&psm, "rec2", &TestRecord { a: 10, b: 0 }).unwrap();
+        assert_eq!(get(&psm, "rec"), get(&psm, "rec2"));
+
+        // Newer versions are left alone
+        super::store_payload(&psm, "rec", 3, &payload).unwrap();
+        let newer = get(&psm, "rec").unwrap();
+        assert_eq!(
+            super::load::<TestRecord>(&psm, "rec"),
+            Err(Error::UnsupportedVersion)
+        );
+        assert_eq!(get(&psm, "rec"), Ok(newer));
+        assert_eq!(get(&psm, "rec.corrupt"), Err(Error::NotFound));
+
+        // Versions that can't be migrated are quarantined
+        super::store_payload(&psm, "rec", 0, &payload).unwrap();
+        assert_eq!(super::load::<TestRecord>(&psm, "rec"), Ok(None));
+        assert!(get(&psm, "rec.corrupt").is_ok());
+    }
+}
@@ -15,22 +15,26 @@
  *    limitations under the License.
  */
 
-use std::sync::Arc;
+use std::{collections::VecDeque, sync::Arc};
 
-use log::{error, trace};
+use log::{error, info, trace};
 use owning_ref::RwLockReadGuardRef;
 use rand::prelude::*;
+use subtle::ConstantTimeEq;
 
 use crate::{
-    cert::Cert,
+    cert::{Cert, CertTime},
     crypto::{self, CryptoKeyPair, KeyPair, Sha256},
     error::Error,
     fabric::{Fabric, FabricMgr, FabricMgrInner},
     secure_channel::common::SCStatusCodes,
     secure_channel::common::{self, OpCode},
-    tlv::{get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType},
+    secure_channel::status_report::StatusReport,
+    tlv::{get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
     transport::{
+        mrp::MrpParams,
         network::Address,
+        packet::Packet,
         proto_demux::{ProtoCtx, ResponseRequired},
         queue::{Msg, WorkQ},
         session::{CaseDetails, CloneData, NocCatIds, SessionMode},
@@ -38,12 +42,130 @@ use crate::{
     utils::writebuf::WriteBuf,
 };
 
+const RESUMPTION_ID_LEN: usize = 16;
+// The number of peers whose sessions we can resume, the oldest one is forgotten first
+const MAX_RESUMPTION_RECORDS: usize = 16;
+
+const S1RK_INFO: &[u8] = b"Sigma1_Resume";
+const S2RK_INFO: &[u8] = b"Sigma2_Resume";
+const RESUMPTION_SEKEYS_INFO: &[u8] = b"SessionResumptionKeys";
+const RESUME1_MIC_NONCE: &[u8; crypto::AEAD_NONCE_LEN_BYTES] = b"NCASE_SigmaS1";
+const RESUME2_MIC_NONCE: &[u8; crypto::AEAD_NONCE_LEN_BYTES] = b"NCASE_SigmaS2";
+
 #[derive(PartialEq)]
 enum State {
     Sigma1Rx,
     Sigma3Rx,
 }
 
+/// What a CASE session with a peer can be resumed with, without the full Sigma exchange
+#[derive(Clone)]
+pub struct ResumptionRecord {
+    pub resumption_id: [u8; RESUMPTION_ID_LEN],
+    pub shared_secret: [u8; crypto::ECDH_SHARED_SECRET_LEN_BYTES],
+    pub local_fabric_idx: u8,
+    pub fabric_id: u64,
+    pub peer_nodeid: u64,
+    pub peer_catids: NocCatIds,
+}
+
+impl ResumptionRecord {
+    /// The initiatorResumeMIC of the Sigma1 that resumes with this record
+    pub fn sigma1_resume_mic(
+        &self,
+        initiator_random: &[u8],
+    ) -> Result<[u8; crypto::AEAD_MIC_LEN_BYTES], Error> {
+        self.get_resume_mic(initiator_random, S1RK_INFO, RESUME1_MIC_NONCE)
+    }
+
+    /// The sigma2ResumeMIC of the Sigma2_Resume, this record has the new resumption id
+    pub fn sigma2_resume_mic(
+        &self,
+        initiator_random: &[u8],
+    ) -> Result<[u8; crypto::AEAD_MIC_LEN_BYTES], Error> {
+        self.get_resume_mic(initiator_random, S2RK_INFO, RESUME2_MIC_NONCE)
+    }
+
+    /// The keys of the resumed session, this record has the new resumption id
+    pub fn get_session_keys(&self, initiator_random: &[u8], key: &mut [u8]) -> Result<(), Error> {
+        if key.len() < 48 {
+            return Err(Error::NoSpace);
+        }
+        self.get_resume_key(initiator_random, RESUMPTION_SEKEYS_INFO, key)
+    }
+
+    fn get_resume_mic(
+        &self,
+        initiator_random: &[u8],
+        info: &[u8],
+        nonce: &[u8],
+    ) -> Result<[u8; crypto::AEAD_MIC_LEN_BYTES], Error> {
+        let mut key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        self.get_resume_key(initiator_random, info, &mut key)?;
+        // The MIC is that of an empty message
+        let mut mic = [0_u8; crypto::AEAD_MIC_LEN_BYTES];
+        crypto::encrypt_in_place(&key, nonce, &[], &mut mic, 0)?;
+        Ok(mic)
+    }
+
+    fn get_resume_key(
+        &self,
+        initiator_random: &[u8],
+        info: &[u8],
+        key: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut salt = Vec::<u8>::with_capacity(64);
+        salt.extend_from_slice(initiator_random);
+        salt.extend_from_slice(&self.resumption_id);
+        crypto::hkdf_sha256(salt.as_slice(), &self.shared_secret, info, key)
+            .map_err(|_x| Error::NoSpace)
+    }
+}
+
+/// The resumption records, one for each peer
+#[derive(Default)]
+pub struct ResumptionStore {
+    records: VecDeque<ResumptionRecord>,
+}
+
+impl ResumptionStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add the record, in place of the previous record of the same peer
+    pub fn add(&mut self, record: ResumptionRecord) {
+        self.remove(record.local_fabric_idx, record.peer_nodeid);
+        if self.records.len() == MAX_RESUMPTION_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn get(&self, resumption_id: &[u8]) -> Option<&ResumptionRecord> {
+        self.records
+            .iter()
+            .find(|r| r.resumption_id[..] == *resumption_id)
+    }
+
+    pub fn get_by_peer(&self, fab_idx: u8, peer_nodeid: u64) -> Option<&ResumptionRecord> {
+        self.records
+            .iter()
+            .find(|r| r.local_fabric_idx == fab_idx && r.peer_nodeid == peer_nodeid)
+    }
+
+    pub fn remove(&mut self, fab_idx: u8, peer_nodeid: u64) {
+        self.records
+            .retain(|r| r.local_fabric_idx != fab_idx || r.peer_nodeid != peer_nodeid);
+    }
+}
+
+// A resumed session, until the initiator confirms it
+struct CaseResumption {
+    clone_data: CloneData,
+    record: ResumptionRecord,
+}
+
 pub struct CaseSession {
     state: State,
     peer_sessid: u16,
@@ -53,6 +175,7 @@ pub struct CaseSession {
     our_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
     peer_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
     local_fabric_idx: usize,
+    resumption_id: [u8; RESUMPTION_ID_LEN],
 }
 impl CaseSession {
     pub fn new(peer_sessid: u16, local_sessid: u16) -> Result<Self, Error> {
@@ -65,20 +188,29 @@ impl CaseSession {
             our_pub_key: [0; crypto::EC_POINT_LEN_BYTES],
             peer_pub_key: [0; crypto::EC_POINT_LEN_BYTES],
             local_fabric_idx: 0,
+            resumption_id: [0; RESUMPTION_ID_LEN],
         })
     }
 }
 
 pub struct Case {
     fabric_mgr: Arc<FabricMgr>,
+    resumption: ResumptionStore,
 }
 
 impl Case {
     pub fn new(fabric_mgr: Arc<FabricMgr>) -> Self {
-        Self { fabric_mgr }
+        Self {
+            fabric_mgr,
+            resumption: ResumptionStore::new(),
+        }
     }
 
-    pub fn casesigma3_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
+    pub fn casesigma3_handler(
+        &mut self,
+        ctx: &mut ProtoCtx,
+        work_q: &WorkQ,
+    ) -> Result<ResponseRequired, Error> {
         let mut case_session = ctx
             .exch_ctx
             .exch
@@ -124,7 +256,8 @@ impl Case {
         if let Some(icac) = d.initiator_icac {
             initiator_icac = Some(Cert::new(icac.0)?);
         }
-        if let Err(e) = Case::validate_certs(fabric, &initiator_noc, &initiator_icac) {
+        let time = self.fabric_mgr.get_cert_time();
+        if let Err(e) = Case::validate_certs(fabric, &initiator_noc, &initiator_icac, time) {
             error!("Certificate Chain doesn't match: {}", e);
             common::create_sc_status_report(
                 &mut ctx.tx,
@@ -135,12 +268,13 @@ impl Case {
             return Ok(ResponseRequired::Yes);
         }
 
-        if Case::validate_sigma3_sign(
+        if Case::validate_sigma_sign(
             d.initiator_noc.0,
             d.initiator_icac.map(|a| a.0),
             &initiator_noc,
+            &case_session.peer_pub_key,
+            &case_session.our_pub_key,
             d.signature.0,
-            &case_session,
         )
         .is_err()
         {
@@ -158,16 +292,28 @@ impl Case {
         let mut peer_catids: NocCatIds = Default::default();
         initiator_noc.get_cat_ids(&mut peer_catids);
         case_session.tt_hash.update(ctx.rx.as_borrow_slice())?;
-        let clone_data = Case::get_session_clone_data(
+        let peer_nodeid = initiator_noc.get_node_id()?;
+        let mut clone_data = Case::get_session_clone_data(
             fabric.ipk.op_key(),
             fabric.get_node_id(),
-            initiator_noc.get_node_id()?,
+            peer_nodeid,
             ctx.exch_ctx.sess.get_peer_addr(),
             &case_session,
             &peer_catids,
         )?;
+        // The session can be resumed with the resumption id of our Sigma2
+        self.resumption.add(ResumptionRecord {
+            resumption_id: case_session.resumption_id,
+            shared_secret: case_session.shared_secret,
+            local_fabric_idx: case_session.local_fabric_idx as u8,
+            fabric_id: fabric.get_fabric_id(),
+            peer_nodeid,
+            peer_catids,
+        });
+        // The initiator's parameters were recorded on the unsecured session, from the Sigma1
+        clone_data.peer_mrp = ctx.exch_ctx.sess.get_mrp_params();
         // Queue a transport mgr request to add a new session
-        WorkQ::get()?.sync_send(Msg::NewSession(clone_data))?;
+        work_q.sync_send(Msg::NewSession(clone_data))?;
 
         common::create_sc_status_report(
             &mut ctx.tx,
@@ -187,6 +333,16 @@ impl Case {
         let root = get_root_node_struct(rx_buf)?;
         let r = Sigma1Req::from_tlv(&root)?;
 
+        if let Some(record) = self.get_resumable(&r) {
+            let mut initiator_random = [0_u8; 32];
+            initiator_random.copy_from_slice(r.initiator_random.0);
+            let peer_sessid = r.initiator_sessid;
+            if let Some(mrp) = r.initiator_mrp {
+                ctx.exch_ctx.sess.set_mrp_params(mrp);
+            }
+            return self.handle_resumption(ctx, record, &initiator_random, peer_sessid);
+        }
+
         let local_fabric_idx = self
             .fabric_mgr
             .match_dest_id(r.initiator_random.0, r.dest_id.0);
@@ -201,6 +357,10 @@ impl Case {
             return Ok(ResponseRequired::Yes);
         }
 
+        if let Some(mrp) = r.initiator_mrp {
+            ctx.exch_ctx.sess.set_mrp_params(mrp);
+        }
+
         let local_sessid = ctx.exch_ctx.sess.reserve_new_sess_id();
         let mut case_session = Box::new(CaseSession::new(r.initiator_sessid, local_sessid)?);
         case_session.tt_hash.update(rx_buf)?;
@@ -247,8 +407,9 @@ impl Case {
                 return Ok(ResponseRequired::Yes);
             }
 
-            let sign_len = Case::get_sigma2_sign(
-                &fabric,
+            // We are guaranteed this unwrap will work
+            let sign_len = Case::get_sigma_sign(
+                fabric.as_ref().as_ref().unwrap(),
                 &case_session.our_pub_key,
                 &case_session.peer_pub_key,
                 &mut signature,
@@ -272,12 +433,156 @@ impl Case {
         tw.u16(TagType::Context(2), local_sessid)?;
         tw.str8(TagType::Context(3), &case_session.our_pub_key)?;
         tw.str16(TagType::Context(4), encrypted)?;
+        MrpParams::local().to_tlv(&mut tw, TagType::Context(5))?;
         tw.end_container()?;
         case_session.tt_hash.update(ctx.tx.as_borrow_slice())?;
         ctx.exch_ctx.exch.set_data_boxed(case_session);
         Ok(ResponseRequired::Yes)
     }
 
+    /// Handle the StatusReport of the initiator, that confirms the resumption of its session
+    ///
+    /// Any other StatusReport, like the one of an initiator that gives up on the session
+    /// establishment, only ends the exchange.
+    pub fn casestatusreport_handler(
+        &mut self,
+        ctx: &mut ProtoCtx,
+        work_q: &WorkQ,
+    ) -> Result<ResponseRequired, Error> {
+        let resumption = ctx.exch_ctx.exch.take_data_boxed::<CaseResumption>();
+        ctx.exch_ctx.exch.close();
+
+        let report = StatusReport::parse(ctx.rx.as_borrow_slice())?;
+        let resumption = match resumption {
+            Some(resumption) => resumption,
+            None => {
+                info!(
+                    "Status report, general code: {} protocol code: {}",
+                    report.general_code, report.proto_code
+                );
+                return Ok(ResponseRequired::No);
+            }
+        };
+        if report.general_code != 0 || report.proto_code != 0 {
+            error!(
+                "Session resumption failed, general code: {} protocol code: {}",
+                report.general_code, report.proto_code
+            );
+            return Ok(ResponseRequired::No);
+        }
+
+        // The previous resumption id of the peer is no longer valid
+        self.resumption.add(resumption.record);
+        work_q.sync_send(Msg::NewSession(resumption.clone_data))?;
+        Ok(ResponseRequired::No)
+    }
+
+    /// The resumption record that the Sigma1 can resume with, if any. Otherwise, this is
+    /// a full session establishment.
+    fn get_resumable(&self, r: &Sigma1Req) -> Option<ResumptionRecord> {
+        let (resumption_id, mic) = match (&r.resumption_id, &r.initiator_resume_mic) {
+            (Some(id), Some(mic)) => (id.0, mic.0),
+            _ => return None,
+        };
+        let record = match self.resumption.get(resumption_id) {
+            Some(record) => record,
+            None => {
+                info!("Unknown resumption id, falling back to a full CASE");
+                return None;
+            }
+        };
+        if r.initiator_random.0.len() != 32 {
+            return None;
+        }
+        match record.sigma1_resume_mic(r.initiator_random.0) {
+            Ok(expected) if bool::from(expected[..].ct_eq(mic)) => (),
+            _ => {
+                error!("Invalid resumption MIC, falling back to a full CASE");
+                return None;
+            }
+        }
+        let fabric = self
+            .fabric_mgr
+            .get_fabric(record.local_fabric_idx as usize)
+            .ok()?;
+        match fabric.as_ref().as_ref() {
+            Some(f) if f.get_fabric_id() == record.fabric_id => Some(record.clone()),
+            _ => None,
+        }
+    }
+
+    fn handle_resumption(
+        &mut self,
+        ctx: &mut ProtoCtx,
+        record: ResumptionRecord,
+        initiator_random: &[u8],
+        peer_sessid: u16,
+    ) -> Result<ResponseRequired, Error> {
+        let local_nodeid = {
+            let fabric = self
+                .fabric_mgr
+                .get_fabric(record.local_fabric_idx as usize)?;
+            fabric
+                .as_ref()
+                .as_ref()
+                .ok_or(Error::Invalid)?
+                .get_node_id()
+        };
+
+        // The resumed session gets a resumption id of its own
+        let mut record = record;
+        rand::thread_rng().fill_bytes(&mut record.resumption_id);
+
+        let local_sessid = ctx.exch_ctx.sess.reserve_new_sess_id();
+        Case::get_sigma2_resume(&mut ctx.tx, &record, initiator_random, local_sessid)?;
+
+        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        record.get_session_keys(initiator_random, &mut session_keys)?;
+        let mut clone_data = CloneData::new(
+            local_nodeid,
+            record.peer_nodeid,
+            peer_sessid,
+            local_sessid,
+            ctx.exch_ctx.sess.get_peer_addr(),
+            SessionMode::Case(CaseDetails::new(
+                record.local_fabric_idx,
+                &record.peer_catids,
+            )),
+        );
+        clone_data.dec_key.copy_from_slice(&session_keys[0..16]);
+        clone_data.enc_key.copy_from_slice(&session_keys[16..32]);
+        clone_data
+            .att_challenge
+            .copy_from_slice(&session_keys[32..48]);
+        clone_data.peer_mrp = ctx.exch_ctx.sess.get_mrp_params();
+        trace!("Resuming the session of node {:x}", record.peer_nodeid);
+
+        // The session is added once the initiator confirms it
+        ctx.exch_ctx
+            .exch
+            .set_data_boxed(Box::new(CaseResumption { clone_data, record }));
+        Ok(ResponseRequired::Yes)
+    }
+
+    /// Encode the Sigma2_Resume, with the new resumption id of the record
+    fn get_sigma2_resume(
+        tx: &mut Packet,
+        record: &ResumptionRecord,
+        initiator_random: &[u8],
+        local_sessid: u16,
+    ) -> Result<(), Error> {
+        let mic = record.sigma2_resume_mic(initiator_random)?;
+        tx.set_proto_id(common::PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::CASESigma2Resume as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str8(TagType::Context(1), &record.resumption_id)?;
+        tw.str8(TagType::Context(2), &mic)?;
+        tw.u16(TagType::Context(3), local_sessid)?;
+        MrpParams::local().to_tlv(&mut tw, TagType::Context(4))?;
+        tw.end_container()
+    }
+
     fn get_session_clone_data(
         ipk: &[u8],
         local_nodeid: u64,
@@ -314,33 +619,41 @@ impl Case {
         Ok(clone_data)
     }
 
-    fn validate_sigma3_sign(
-        initiator_noc: &[u8],
-        initiator_icac: Option<&[u8]>,
-        initiator_noc_cert: &Cert,
+    /// Validate the signature of the peer in Sigma2 (for the initiator) or Sigma3 (for the
+    /// responder). Either way, the peer signs its own ephemeral key first.
+    fn validate_sigma_sign(
+        peer_noc: &[u8],
+        peer_icac: Option<&[u8]>,
+        peer_noc_cert: &Cert,
+        peer_pub_key: &[u8],
+        our_pub_key: &[u8],
         sign: &[u8],
-        case_session: &CaseSession,
     ) -> Result<(), Error> {
         const MAX_TBS_SIZE: usize = 800;
         let mut buf: [u8; MAX_TBS_SIZE] = [0; MAX_TBS_SIZE];
         let mut write_buf = WriteBuf::new(&mut buf, MAX_TBS_SIZE);
         let mut tw = TLVWriter::new(&mut write_buf);
         tw.start_struct(TagType::Anonymous)?;
-        tw.str16(TagType::Context(1), initiator_noc)?;
-        if let Some(icac) = initiator_icac {
+        tw.str16(TagType::Context(1), peer_noc)?;
+        if let Some(icac) = peer_icac {
             tw.str16(TagType::Context(2), icac)?;
         }
-        tw.str8(TagType::Context(3), &case_session.peer_pub_key)?;
-        tw.str8(TagType::Context(4), &case_session.our_pub_key)?;
+        tw.str8(TagType::Context(3), peer_pub_key)?;
+        tw.str8(TagType::Context(4), our_pub_key)?;
         tw.end_container()?;
 
-        let key = KeyPair::new_from_public(initiator_noc_cert.get_pubkey())?;
+        let key = KeyPair::new_from_public(peer_noc_cert.get_pubkey())?;
         key.verify_msg(write_buf.as_slice(), sign)?;
         Ok(())
     }
 
-    fn validate_certs(fabric: &Fabric, noc: &Cert, icac: &Option<Cert>) -> Result<(), Error> {
-        let mut verifier = noc.verify_chain_start();
+    fn validate_certs(
+        fabric: &Fabric,
+        noc: &Cert,
+        icac: &Option<Cert>,
+        time: CertTime,
+    ) -> Result<(), Error> {
+        let mut verifier = noc.verify_chain_start(time);
 
         if fabric.get_fabric_id() != noc.get_fabric_id()? {
             return Err(Error::Invalid);
@@ -439,8 +752,10 @@ impl Case {
 
     fn get_sigma2_key(
         ipk: &[u8],
-        our_random: &[u8],
-        case_session: &mut CaseSession,
+        responder_random: &[u8],
+        responder_pub_key: &[u8],
+        tt: &Sha256,
+        shared_secret: &[u8],
         key: &mut [u8],
     ) -> Result<(), Error> {
         const S2K_INFO: [u8; 6] = [0x53, 0x69, 0x67, 0x6d, 0x61, 0x32];
@@ -449,17 +764,17 @@ impl Case {
         }
         let mut salt = Vec::<u8>::with_capacity(256);
         salt.extend_from_slice(ipk);
-        salt.extend_from_slice(our_random);
-        salt.extend_from_slice(&case_session.our_pub_key);
+        salt.extend_from_slice(responder_random);
+        salt.extend_from_slice(responder_pub_key);
 
-        let tt = case_session.tt_hash.clone();
+        let tt = tt.clone();
 
         let mut tt_hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
         tt.finish(&mut tt_hash)?;
         salt.extend_from_slice(&tt_hash);
         //        println!("Sigma2Key: salt: {:x?}, len: {}", salt, salt.len());
 
-        crypto::hkdf_sha256(salt.as_slice(), &case_session.shared_secret, &S2K_INFO, key)
+        crypto::hkdf_sha256(salt.as_slice(), shared_secret, &S2K_INFO, key)
             .map_err(|_x| Error::NoSpace)?;
         //        println!("Sigma2Key: key: {:x?}", key);
 
@@ -473,8 +788,7 @@ impl Case {
         signature: &[u8],
         out: &mut [u8],
     ) -> Result<usize, Error> {
-        let mut resumption_id: [u8; 16] = [0; 16];
-        rand::thread_rng().fill_bytes(&mut resumption_id);
+        rand::thread_rng().fill_bytes(&mut case_session.resumption_id);
 
         // We are guaranteed this unwrap will work
         let fabric = fabric.as_ref().as_ref().unwrap();
@@ -483,7 +797,9 @@ impl Case {
         Case::get_sigma2_key(
             fabric.ipk.op_key(),
             our_random,
-            case_session,
+            &case_session.our_pub_key,
+            &case_session.tt_hash,
+            &case_session.shared_secret,
             &mut sigma2_key,
         )?;
 
@@ -496,7 +812,7 @@ impl Case {
         };
 
         tw.str8(TagType::Context(3), signature)?;
-        tw.str8(TagType::Context(4), &resumption_id)?;
+        tw.str8(TagType::Context(4), &case_session.resumption_id)?;
         tw.end_container()?;
         //println!("TBE is {:x?}", write_buf.as_borrow_slice());
         let nonce: [u8; crypto::AEAD_NONCE_LEN_BYTES] = [
@@ -520,14 +836,14 @@ impl Case {
         Ok(write_buf.as_slice().len())
     }
 
-    fn get_sigma2_sign(
-        fabric: &RwLockReadGuardRef<FabricMgrInner, Option<Fabric>>,
+    /// Sign our certificates and the ephemeral keys for Sigma2 (for the responder) or Sigma3
+    /// (for the initiator). Either way, we sign our own ephemeral key first.
+    fn get_sigma_sign(
+        fabric: &Fabric,
         our_pub_key: &[u8],
         peer_pub_key: &[u8],
         signature: &mut [u8],
     ) -> Result<usize, Error> {
-        // We are guaranteed this unwrap will work
-        let fabric = fabric.as_ref().as_ref().unwrap();
         const MAX_TBS_SIZE: usize = 800;
         let mut buf: [u8; MAX_TBS_SIZE] = [0; MAX_TBS_SIZE];
         let mut write_buf = WriteBuf::new(&mut buf, MAX_TBS_SIZE);
@@ -545,6 +861,322 @@ impl Case {
     }
 }
 
+/// The initiator side of a CASE session establishment
+pub struct CaseInitiator {
+    local_sessid: u16,
+    peer_sessid: u16,
+    peer_nodeid: u64,
+    local_fabric_idx: u8,
+    tt_hash: Sha256,
+    // The ephemeral key pair, until the shared secret is derived with it
+    key_pair: Option<KeyPair>,
+    shared_secret: [u8; crypto::ECDH_SHARED_SECRET_LEN_BYTES],
+    our_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
+    peer_pub_key: [u8; crypto::EC_POINT_LEN_BYTES],
+    peer_catids: NocCatIds,
+    peer_mrp: MrpParams,
+    our_random: [u8; 32],
+    // The record to resume with, and then the record of the new session
+    resumption: Option<ResumptionRecord>,
+    resumed: bool,
+}
+
+impl CaseInitiator {
+    pub fn new(local_sessid: u16, local_fabric_idx: u8, peer_nodeid: u64) -> Result<Self, Error> {
+        // Create an ephemeral Key Pair
+        let key_pair = KeyPair::new()?;
+        let mut our_pub_key = [0; crypto::EC_POINT_LEN_BYTES];
+        let _ = key_pair.get_public_key(&mut our_pub_key)?;
+        Ok(Self {
+            local_sessid,
+            peer_sessid: 0,
+            peer_nodeid,
+            local_fabric_idx,
+            tt_hash: Sha256::new()?,
+            key_pair: Some(key_pair),
+            shared_secret: [0; crypto::ECDH_SHARED_SECRET_LEN_BYTES],
+            our_pub_key,
+            peer_pub_key: [0; crypto::EC_POINT_LEN_BYTES],
+            peer_catids: Default::default(),
+            peer_mrp: Default::default(),
+            our_random: [0; 32],
+            resumption: None,
+            resumed: false,
+        })
+    }
+
+    /// Offer to resume the previous session with the peer, the responder falls back to
+    /// the full session establishment if it can't
+    pub fn set_resumption(&mut self, record: ResumptionRecord) {
+        self.resumption = Some(record);
+    }
+
+    /// Encode the Sigma1 message that starts the session establishment
+    pub fn sigma1(&mut self, fabric: &Fabric, tx: &mut Packet) -> Result<(), Error> {
+        tx.set_proto_id(common::PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::CASESigma1 as u8);
+
+        rand::thread_rng().fill_bytes(&mut self.our_random);
+        let mut dest_id = [0_u8; crypto::SHA256_HASH_LEN_BYTES];
+        fabric.get_dest_id(&self.our_random, self.peer_nodeid, &mut dest_id)?;
+
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str8(TagType::Context(1), &self.our_random)?;
+        tw.u16(TagType::Context(2), self.local_sessid)?;
+        tw.str8(TagType::Context(3), &dest_id)?;
+        tw.str8(TagType::Context(4), &self.our_pub_key)?;
+        MrpParams::local().to_tlv(&mut tw, TagType::Context(5))?;
+        if let Some(record) = &self.resumption {
+            let mic = record.sigma1_resume_mic(&self.our_random)?;
+            tw.str8(TagType::Context(6), &record.resumption_id)?;
+            tw.str8(TagType::Context(7), &mic)?;
+        }
+        tw.end_container()?;
+        self.tt_hash.update(tx.as_borrow_slice())?;
+        Ok(())
+    }
+
+    /// Validate the Sigma2 message from the responder, and encode the Sigma3 message in
+    /// response to it. The responder's certificates are checked against the given time.
+    pub fn sigma3(
+        &mut self,
+        fabric: &Fabric,
+        time: CertTime,
+        rx: &[u8],
+        tx: &mut Packet,
+    ) -> Result<(), Error> {
+        let root = get_root_node_struct(rx)?;
+        let r = Sigma2Resp::from_tlv(&root)?;
+        if r.responder_pub_key.0.len() != crypto::EC_POINT_LEN_BYTES {
+            error!("Invalid public key length");
+            return Err(Error::Invalid);
+        }
+        self.peer_sessid = r.responder_sessid;
+        self.peer_mrp = r.responder_mrp.unwrap_or_default();
+        self.peer_pub_key.copy_from_slice(r.responder_pub_key.0);
+
+        // Derive the Shared Secret
+        let key_pair = self.key_pair.take().ok_or(Error::InvalidState)?;
+        let len = key_pair.derive_secret(r.responder_pub_key.0, &mut self.shared_secret)?;
+        if len != 32 {
+            error!("Derived secret length incorrect");
+            return Err(Error::Invalid);
+        }
+
+        // Decrypt the responder's certificates and signature
+        let mut sigma2_key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_sigma2_key(
+            fabric.ipk.op_key(),
+            r.responder_random.0,
+            &self.peer_pub_key,
+            &self.tt_hash,
+            &self.shared_secret,
+            &mut sigma2_key,
+        )?;
+        let encrypted = r.encrypted.0;
+        let mut decrypted: [u8; 800] = [0; 800];
+        if encrypted.len() > decrypted.len() || encrypted.len() < crypto::AEAD_MIC_LEN_BYTES {
+            error!("Invalid encrypted data length");
+            return Err(Error::Invalid);
+        }
+        let decrypted = &mut decrypted[..encrypted.len()];
+        decrypted.copy_from_slice(encrypted);
+        let nonce: [u8; crypto::AEAD_NONCE_LEN_BYTES] = [
+            0x4e, 0x43, 0x41, 0x53, 0x45, 0x5f, 0x53, 0x69, 0x67, 0x6d, 0x61, 0x32, 0x4e,
+        ];
+        crypto::decrypt_in_place(&sigma2_key, &nonce, &[], decrypted)?;
+        let decrypted = &decrypted[..encrypted.len() - crypto::AEAD_MIC_LEN_BYTES];
+
+        let root = get_root_node_struct(decrypted)?;
+        let d = Sigma2Decrypt::from_tlv(&root)?;
+        let responder_noc = Cert::new(d.responder_noc.0)?;
+        let mut responder_icac = None;
+        if let Some(icac) = d.responder_icac {
+            responder_icac = Some(Cert::new(icac.0)?);
+        }
+        if let Err(e) = Case::validate_certs(fabric, &responder_noc, &responder_icac, time) {
+            error!("Certificate Chain doesn't match: {}", e);
+            return Err(Error::Invalid);
+        }
+        if responder_noc.get_node_id()? != self.peer_nodeid {
+            error!("Responder's node id doesn't match");
+            return Err(Error::Invalid);
+        }
+        if Case::validate_sigma_sign(
+            d.responder_noc.0,
+            d.responder_icac.map(|a| a.0),
+            &responder_noc,
+            &self.peer_pub_key,
+            &self.our_pub_key,
+            d.signature.0,
+        )
+        .is_err()
+        {
+            error!("Sigma2 Signature doesn't match");
+            return Err(Error::InvalidSignature);
+        }
+        responder_noc.get_cat_ids(&mut self.peer_catids);
+        self.tt_hash.update(rx)?;
+        if d.resumption_id.0.len() != RESUMPTION_ID_LEN {
+            error!("Invalid resumption id length");
+            return Err(Error::Invalid);
+        }
+        let mut resumption_id = [0; RESUMPTION_ID_LEN];
+        resumption_id.copy_from_slice(d.resumption_id.0);
+        self.resumption = Some(ResumptionRecord {
+            resumption_id,
+            shared_secret: self.shared_secret,
+            local_fabric_idx: self.local_fabric_idx,
+            fabric_id: fabric.get_fabric_id(),
+            peer_nodeid: self.peer_nodeid,
+            peer_catids: self.peer_catids,
+        });
+
+        // Derive the Encrypted Part
+        const MAX_ENCRYPTED_SIZE: usize = 800;
+
+        let mut encrypted: [u8; MAX_ENCRYPTED_SIZE] = [0; MAX_ENCRYPTED_SIZE];
+        let encrypted_len = {
+            let mut signature = [0u8; crypto::EC_SIGNATURE_LEN_BYTES];
+            let sign_len = Case::get_sigma_sign(
+                fabric,
+                &self.our_pub_key,
+                &self.peer_pub_key,
+                &mut signature,
+            )?;
+            let signature = &signature[..sign_len];
+            self.get_sigma3_encryption(fabric, signature, &mut encrypted)?
+        };
+        let encrypted = &encrypted[0..encrypted_len];
+
+        tx.set_proto_id(common::PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::CASESigma3 as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str16(TagType::Context(1), encrypted)?;
+        tw.end_container()?;
+        self.tt_hash.update(tx.as_borrow_slice())?;
+        Ok(())
+    }
+
+    /// Validate the Sigma2_Resume message from the responder, that accepted to resume the
+    /// session, and encode the StatusReport that confirms the resumption
+    pub fn sigma2_resume(&mut self, rx: &[u8], tx: &mut Packet) -> Result<(), Error> {
+        let root = get_root_node_struct(rx)?;
+        let r = Sigma2ResumeResp::from_tlv(&root)?;
+        let mut record = self.resumption.clone().ok_or(Error::InvalidState)?;
+        if r.resumption_id.0.len() != RESUMPTION_ID_LEN {
+            error!("Invalid resumption id length");
+            return Err(Error::Invalid);
+        }
+        record.resumption_id.copy_from_slice(r.resumption_id.0);
+        let mic = record.sigma2_resume_mic(&self.our_random)?;
+        if !bool::from(mic[..].ct_eq(r.sigma2_resume_mic.0)) {
+            error!("Sigma2_Resume MIC doesn't match");
+            return Err(Error::Invalid);
+        }
+
+        self.peer_sessid = r.responder_sessid;
+        self.peer_mrp = r.responder_mrp.unwrap_or_default();
+        self.peer_catids = record.peer_catids;
+        self.resumption = Some(record);
+        self.resumed = true;
+        common::create_sc_status_report(tx, SCStatusCodes::SessionEstablishmentSuccess, None)
+    }
+
+    /// Whether the responder resumed the previous session, rather than establishing a new one
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// The record that this session can be resumed with later, once it is established
+    pub fn get_resumption_record(&self) -> Option<&ResumptionRecord> {
+        self.resumption.as_ref()
+    }
+
+    /// The data for the new secure session, once the responder has confirmed the Sigma3, or
+    /// once we have confirmed the Sigma2_Resume
+    pub fn get_session_clone_data(
+        &self,
+        fabric: &Fabric,
+        peer_addr: Address,
+    ) -> Result<CloneData, Error> {
+        let mut session_keys = [0_u8; 3 * crypto::SYMM_KEY_LEN_BYTES];
+        match &self.resumption {
+            Some(record) if self.resumed => {
+                record.get_session_keys(&self.our_random, &mut session_keys)?
+            }
+            _ => Case::get_session_keys(
+                fabric.ipk.op_key(),
+                &self.tt_hash,
+                &self.shared_secret,
+                &mut session_keys,
+            )?,
+        }
+
+        let mut clone_data = CloneData::new(
+            fabric.get_node_id(),
+            self.peer_nodeid,
+            self.peer_sessid,
+            self.local_sessid,
+            peer_addr,
+            SessionMode::Case(CaseDetails::new(self.local_fabric_idx, &self.peer_catids)),
+        );
+        clone_data.peer_mrp = self.peer_mrp;
+
+        // The I2R key is what we encrypt with, as the initiator
+        clone_data.enc_key.copy_from_slice(&session_keys[0..16]);
+        clone_data.dec_key.copy_from_slice(&session_keys[16..32]);
+        clone_data
+            .att_challenge
+            .copy_from_slice(&session_keys[32..48]);
+        Ok(clone_data)
+    }
+
+    fn get_sigma3_encryption(
+        &self,
+        fabric: &Fabric,
+        signature: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut sigma3_key = [0_u8; crypto::SYMM_KEY_LEN_BYTES];
+        Case::get_sigma3_key(
+            fabric.ipk.op_key(),
+            &self.tt_hash,
+            &self.shared_secret,
+            &mut sigma3_key,
+        )?;
+
+        let mut write_buf = WriteBuf::new(out, out.len());
+        let mut tw = TLVWriter::new(&mut write_buf);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str16_as(TagType::Context(1), |buf| fabric.noc.as_tlv(buf))?;
+        if let Some(icac_cert) = &fabric.icac {
+            tw.str16_as(TagType::Context(2), |buf| icac_cert.as_tlv(buf))?
+        };
+        tw.str8(TagType::Context(3), signature)?;
+        tw.end_container()?;
+
+        let nonce: [u8; crypto::AEAD_NONCE_LEN_BYTES] = [
+            0x4e, 0x43, 0x41, 0x53, 0x45, 0x5f, 0x53, 0x69, 0x67, 0x6d, 0x61, 0x33, 0x4e,
+        ];
+        const TAG_LEN: usize = 16;
+        let tag = [0u8; TAG_LEN];
+        write_buf.append(&tag)?;
+        let cipher_text = write_buf.as_mut_slice();
+
+        crypto::encrypt_in_place(
+            &sigma3_key,
+            &nonce,
+            &[],
+            cipher_text,
+            cipher_text.len() - TAG_LEN,
+        )?;
+        Ok(write_buf.as_slice().len())
+    }
+}
+
 #[derive(FromTLV)]
 #[tlvargs(start = 1, lifetime = "'a")]
 struct Sigma1Req<'a> {
@@ -552,6 +1184,9 @@ struct Sigma1Req<'a> {
     initiator_sessid: u16,
     dest_id: OctetStr<'a>,
     peer_pub_key: OctetStr<'a>,
+    initiator_mrp: Option<MrpParams>,
+    resumption_id: Option<OctetStr<'a>>,
+    initiator_resume_mic: Option<OctetStr<'a>>,
 }
 
 #[derive(FromTLV)]
@@ -561,3 +1196,143 @@ struct Sigma3Decrypt<'a> {
     initiator_icac: Option<OctetStr<'a>>,
     signature: OctetStr<'a>,
 }
+
+#[derive(FromTLV)]
+#[tlvargs(start = 1, lifetime = "'a")]
+struct Sigma2Resp<'a> {
+    responder_random: OctetStr<'a>,
+    responder_sessid: u16,
+    responder_pub_key: OctetStr<'a>,
+    encrypted: OctetStr<'a>,
+    responder_mrp: Option<MrpParams>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(start = 1, lifetime = "'a")]
+struct Sigma2Decrypt<'a> {
+    responder_noc: OctetStr<'a>,
+    responder_icac: Option<OctetStr<'a>>,
+    signature: OctetStr<'a>,
+    resumption_id: OctetStr<'a>,
+}
+
+#[derive(FromTLV)]
+#[tlvargs(start = 1, lifetime = "'a")]
+struct Sigma2ResumeResp<'a> {
+    resumption_id: OctetStr<'a>,
+    sigma2_resume_mic: OctetStr<'a>,
+    responder_sessid: u16,
+    responder_mrp: Option<MrpParams>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::packet::PacketPool;
+
+    fn record(peer_nodeid: u64) -> ResumptionRecord {
+        let mut record = ResumptionRecord {
+            resumption_id: [0; RESUMPTION_ID_LEN],
+            shared_secret: [0; crypto::ECDH_SHARED_SECRET_LEN_BYTES],
+            local_fabric_idx: 1,
+            fabric_id: 0,
+            peer_nodeid,
+            peer_catids: Default::default(),
+        };
+        rand::thread_rng().fill_bytes(&mut record.resumption_id);
+        rand::thread_rng().fill_bytes(&mut record.shared_secret);
+        record
+    }
+
+    // Resume the session of the record, with the responder's record of it. Returns the
+    // initiator, and the keys that the responder derived.
+    fn resume(
+        record: &ResumptionRecord,
+        responder_record: &ResumptionRecord,
+    ) -> Result<(CaseInitiator, [u8; 48]), Error> {
+        let fabric = Fabric::dummy()?;
+        let pool = PacketPool::new();
+        let mut initiator = CaseInitiator::new(10, 1, record.peer_nodeid)?;
+        initiator.set_resumption(record.clone());
+        let mut sigma1 = pool.alloc_tx()?;
+        initiator.sigma1(&fabric, &mut sigma1)?;
+
+        let root = get_root_node_struct(sigma1.as_borrow_slice())?;
+        let r = Sigma1Req::from_tlv(&root)?;
+        assert_eq!(
+            r.resumption_id.map(|id| id.0),
+            Some(&record.resumption_id[..])
+        );
+        let mic = responder_record.sigma1_resume_mic(r.initiator_random.0)?;
+        if r.initiator_resume_mic.map(|mic| mic.0) != Some(&mic[..]) {
+            return Err(Error::Invalid);
+        }
+
+        let mut new_record = responder_record.clone();
+        rand::thread_rng().fill_bytes(&mut new_record.resumption_id);
+        let mut sigma2_resume = pool.alloc_tx()?;
+        Case::get_sigma2_resume(&mut sigma2_resume, &new_record, r.initiator_random.0, 20)?;
+        let mut keys = [0; 48];
+        new_record.get_session_keys(r.initiator_random.0, &mut keys)?;
+
+        let mut status_report = pool.alloc_tx()?;
+        initiator.sigma2_resume(sigma2_resume.as_borrow_slice(), &mut status_report)?;
+        assert_eq!(
+            initiator.get_resumption_record().unwrap().resumption_id,
+            new_record.resumption_id
+        );
+        Ok((initiator, keys))
+    }
+
+    #[test]
+    fn test_resumption() {
+        let record = record(0x1234);
+        let (initiator, keys) = resume(&record, &record).unwrap();
+        assert!(initiator.is_resumed());
+        let clone_data = initiator
+            .get_session_clone_data(
+                &Fabric::dummy().unwrap(),
+                Address::Udp("127.0.0.1:5540".parse().unwrap()),
+            )
+            .unwrap();
+        assert_eq!(clone_data.enc_key, keys[0..16]);
+        assert_eq!(clone_data.dec_key, keys[16..32]);
+        assert_eq!(clone_data.att_challenge, keys[32..48]);
+    }
+
+    #[test]
+    fn test_resumption_wrong_secret() {
+        let record = record(0x1234);
+        let mut other = record.clone();
+        other.shared_secret[0] ^= 1;
+        // The responder doesn't accept the Sigma1 of the initiator
+        assert_eq!(resume(&record, &other).err(), Some(Error::Invalid));
+    }
+
+    #[test]
+    fn test_resumption_store() {
+        let mut store = ResumptionStore::new();
+        let first = record(1);
+        store.add(first.clone());
+        assert!(store.get(&first.resumption_id).is_some());
+
+        // The new record of the same peer replaces its previous one
+        let second = record(1);
+        store.add(second.clone());
+        assert!(store.get(&first.resumption_id).is_none());
+        assert_eq!(
+            store.get_by_peer(1, 1).unwrap().resumption_id,
+            second.resumption_id
+        );
+
+        // The oldest record is evicted once the store is full
+        for peer_nodeid in 2..=MAX_RESUMPTION_RECORDS as u64 + 1 {
+            store.add(record(peer_nodeid));
+        }
+        assert!(store.get_by_peer(1, 1).is_none());
+        assert!(store.get_by_peer(1, 2).is_some());
+
+        store.remove(1, 2);
+        assert!(store.get_by_peer(1, 2).is_none());
+    }
+}
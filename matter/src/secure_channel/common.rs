@@ -19,12 +19,12 @@ use num_derive::FromPrimitive;
 
 use crate::{error::Error, transport::packet::Packet};
 
-use super::status_report::{create_status_report, GeneralCode};
+use super::status_report::{create_status_report, GeneralCode, StatusReport};
 
 /* Interaction Model ID as per the Matter Spec */
 pub const PROTO_ID_SECURE_CHANNEL: usize = 0x00;
 
-#[derive(FromPrimitive, Debug)]
+#[derive(FromPrimitive, Debug, Copy, Clone)]
 pub enum OpCode {
     MsgCounterSyncReq = 0x00,
     MsgCounterSyncResp = 0x01,
@@ -41,7 +41,7 @@ pub enum OpCode {
     StatusReport = 0x40,
 }
 
-#[derive(PartialEq)]
+#[derive(FromPrimitive, Debug, PartialEq)]
 pub enum SCStatusCodes {
     SessionEstablishmentSuccess = 0,
     NoSharedTrustRoots = 1,
@@ -78,6 +78,20 @@ pub fn create_sc_status_report(
     )
 }
 
+/// Whether the received message is a CloseSession from the peer
+pub fn is_close_session(proto_rx: &mut Packet) -> bool {
+    if proto_rx.get_proto_id() != PROTO_ID_SECURE_CHANNEL as u16
+        || proto_rx.get_proto_opcode() != OpCode::StatusReport as u8
+    {
+        return false;
+    }
+    matches!(
+        StatusReport::parse(proto_rx.as_borrow_slice()),
+        Ok(r) if r.proto_id == PROTO_ID_SECURE_CHANNEL as u32
+            && r.proto_code == SCStatusCodes::CloseSession as u16
+    )
+}
+
 pub fn create_mrp_standalone_ack(proto_tx: &mut Packet) {
     proto_tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
     proto_tx.set_proto_opcode(OpCode::MRPStandAloneAck as u8);
@@ -22,7 +22,10 @@ use crate::{
     fabric::FabricMgr,
     secure_channel::common::*,
     tlv,
-    transport::proto_demux::{self, ProtoCtx, ResponseRequired},
+    transport::{
+        proto_demux::{self, ProtoCtx, ResponseRequired},
+        queue::WorkQ,
+    },
 };
 use log::{error, info};
 use num;
@@ -35,13 +38,16 @@ use super::{case::Case, pake::PaseMgr};
 pub struct SecureChannel {
     case: Case,
     pase: PaseMgr,
+    // The new sessions are handed over to the transport through this
+    work_q: WorkQ,
 }
 
 impl SecureChannel {
-    pub fn new(pase: PaseMgr, fabric_mgr: Arc<FabricMgr>) -> SecureChannel {
+    pub fn new(pase: PaseMgr, fabric_mgr: Arc<FabricMgr>, work_q: WorkQ) -> SecureChannel {
         SecureChannel {
             pase,
             case: Case::new(fabric_mgr),
+            work_q,
         }
     }
 }
@@ -58,9 +64,10 @@ impl proto_demux::HandleProto for SecureChannel {
             OpCode::MRPStandAloneAck => Ok(ResponseRequired::No),
             OpCode::PBKDFParamRequest => self.pase.pbkdfparamreq_handler(ctx),
             OpCode::PASEPake1 => self.pase.pasepake1_handler(ctx),
-            OpCode::PASEPake3 => self.pase.pasepake3_handler(ctx),
+            OpCode::PASEPake3 => self.pase.pasepake3_handler(ctx, &self.work_q),
             OpCode::CASESigma1 => self.case.casesigma1_handler(ctx),
-            OpCode::CASESigma3 => self.case.casesigma3_handler(ctx),
+            OpCode::CASESigma3 => self.case.casesigma3_handler(ctx, &self.work_q),
+            OpCode::StatusReport => self.case.casestatusreport_handler(ctx, &self.work_q),
             _ => {
                 error!("OpCode Not Handled: {:?}", proto_opcode);
                 Err(Error::InvalidOpcode)
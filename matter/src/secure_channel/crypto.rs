@@ -18,7 +18,7 @@
 use crate::error::Error;
 
 // This trait allows us to switch between crypto providers like OpenSSL and mbedTLS for Spake2
-// Currently this is only validate for a verifier(responder)
+// It can be used for both, the verifier(responder) and the prover(initiator)
 
 // A verifier will typically do:
 // Step 1: w0 and L
@@ -27,6 +27,14 @@ use crate::error::Error;
 // Step 2: get_pB
 // Step 3: get_TT_as_verifier(pA)
 // Step 4: Computation of cA and cB happens outside since it doesn't use either BigNum or EcPoint
+
+// A prover will typically do:
+// Step 1: w0 and w1
+//      set_w0_from_w0s
+//      set_w1_from_w1s
+// Step 2: get_pA
+// Step 3: get_TT_as_prover(pB)
+// Step 4: Computation of cA and cB happens outside, just like for the verifier
 pub trait CryptoSpake2 {
     fn new() -> Result<Self, Error>
     where
@@ -36,10 +44,13 @@ pub trait CryptoSpake2 {
     fn set_w1_from_w1s(&mut self, w1s: &[u8]) -> Result<(), Error>;
     fn set_w0(&mut self, w0: &[u8]) -> Result<(), Error>;
     fn set_w1(&mut self, w1: &[u8]) -> Result<(), Error>;
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error>;
 
     #[allow(non_snake_case)]
     fn set_L(&mut self, l: &[u8]) -> Result<(), Error>;
     #[allow(non_snake_case)]
+    fn get_L(&mut self, l: &mut [u8]) -> Result<(), Error>;
+    #[allow(non_snake_case)]
     fn set_L_from_w1s(&mut self, w1s: &[u8]) -> Result<(), Error>;
     #[allow(non_snake_case)]
     fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error>;
@@ -51,4 +62,14 @@ pub trait CryptoSpake2 {
         pB: &[u8],
         out: &mut [u8],
     ) -> Result<(), Error>;
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error>;
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error>;
 }
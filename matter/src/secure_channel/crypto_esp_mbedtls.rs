@@ -69,6 +69,10 @@ impl CryptoSpake2 for CryptoEspMbedTls {
         Ok(())
     }
 
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
     #[allow(non_snake_case)]
     #[allow(dead_code)]
     fn set_L(&mut self, w1s: &[u8]) -> Result<(), Error> {
@@ -78,6 +82,11 @@ impl CryptoSpake2 for CryptoEspMbedTls {
         Ok(())
     }
 
+    #[allow(non_snake_case)]
+    fn get_L(&mut self, l: &mut [u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
     #[allow(non_snake_case)]
     fn get_pB(&mut self, pB: &mut [u8]) -> Result<(), Error> {
         // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
@@ -99,6 +108,28 @@ impl CryptoSpake2 for CryptoEspMbedTls {
     ) -> Result<(), Error> {
         Ok(())
     }
+
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for x
+        //   - select random x between 0 to p
+        //   - X = x*P + w0*M
+        //   - pA = X
+
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 impl CryptoEspMbedTls {}
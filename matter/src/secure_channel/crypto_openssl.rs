@@ -117,11 +117,32 @@ impl CryptoSpake2 for CryptoOpenSSL {
         Ok(())
     }
 
+    fn get_w0(&mut self, w0: &mut [u8]) -> Result<(), Error> {
+        let w0_internal = self.w0.to_vec_padded(w0.len() as i32)?;
+        w0.copy_from_slice(&w0_internal);
+        Ok(())
+    }
+
     fn set_L(&mut self, l: &[u8]) -> Result<(), Error> {
         self.L = EcPoint::from_bytes(&self.group, l, &mut self.bn_ctx)?;
         Ok(())
     }
 
+    fn get_L(&mut self, l: &mut [u8]) -> Result<(), Error> {
+        let l_internal = self.L.to_bytes(
+            &self.group,
+            PointConversionForm::UNCOMPRESSED,
+            &mut self.bn_ctx,
+        )?;
+        let l_internal = l_internal.as_slice();
+        if l_internal.len() != l.len() {
+            error!("L length mismatch");
+            return Err(Error::Invalid);
+        }
+        l.copy_from_slice(l_internal);
+        Ok(())
+    }
+
     #[allow(non_snake_case)]
     #[allow(dead_code)]
     fn set_L_from_w1s(&mut self, w1s: &[u8]) -> Result<(), Error> {
@@ -172,6 +193,85 @@ impl CryptoSpake2 for CryptoOpenSSL {
         pA: &[u8],
         pB: &[u8],
         TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let X = EcPoint::from_bytes(&self.group, pA, &mut self.bn_ctx)?;
+        let (Z, V) = CryptoOpenSSL::get_ZV_as_verifier(
+            &self.w0,
+            &self.L,
+            &mut self.M,
+            &X,
+            &self.xy,
+            &self.order,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+
+    #[allow(non_snake_case)]
+    fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        // From the SPAKE2+ spec (https://datatracker.ietf.org/doc/draft-bar-cfrg-spake2plus/)
+        //   for x
+        //   - select random x between 0 to p
+        //   - X = x*P + w0*M
+        //   - pA = X
+        self.order.rand_range(&mut self.xy)?;
+        let P = self.group.generator_opt().ok_or(Error::Invalid)?;
+        let X = CryptoOpenSSL::do_add_mul(
+            P,
+            &self.xy,
+            &self.M,
+            &self.w0,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        let pA_internal = X.to_bytes(
+            &self.group,
+            PointConversionForm::UNCOMPRESSED,
+            &mut self.bn_ctx,
+        )?;
+        let pA_internal = pA_internal.as_slice();
+        if pA_internal.len() != pA.len() {
+            error!("pA length mismatch");
+            return Err(Error::Invalid);
+        }
+        pA.copy_from_slice(pA_internal);
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    fn get_TT_as_prover(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        TT_hash: &mut [u8],
+    ) -> Result<(), Error> {
+        let Y = EcPoint::from_bytes(&self.group, pB, &mut self.bn_ctx)?;
+        let (Z, V) = CryptoOpenSSL::get_ZV_as_prover(
+            &self.w0,
+            &self.w1,
+            &mut self.N,
+            &Y,
+            &self.xy,
+            &self.order,
+            &self.group,
+            &mut self.bn_ctx,
+        )?;
+        self.get_TT(context, pA, pB, &Z, &V, TT_hash)
+    }
+}
+
+impl CryptoOpenSSL {
+    #[allow(non_snake_case)]
+    fn get_TT(
+        &mut self,
+        context: &[u8],
+        pA: &[u8],
+        pB: &[u8],
+        Z: &EcPoint,
+        V: &EcPoint,
+        TT_hash: &mut [u8],
     ) -> Result<(), Error> {
         let mut TT = Hasher::new(MessageDigest::sha256())?;
         // context
@@ -188,18 +288,6 @@ impl CryptoSpake2 for CryptoOpenSSL {
         // Y = pB
         CryptoOpenSSL::add_to_tt(&mut TT, pB)?;
 
-        let X = EcPoint::from_bytes(&self.group, pA, &mut self.bn_ctx)?;
-        let (Z, V) = CryptoOpenSSL::get_ZV_as_verifier(
-            &self.w0,
-            &self.L,
-            &mut self.M,
-            &X,
-            &self.xy,
-            &self.order,
-            &self.group,
-            &mut self.bn_ctx,
-        )?;
-
         // Z
         let tmp = Z.to_bytes(
             &self.group,
@@ -227,9 +315,7 @@ impl CryptoSpake2 for CryptoOpenSSL {
         TT_hash.copy_from_slice(h.as_ref());
         Ok(())
     }
-}
 
-impl CryptoOpenSSL {
     fn add_to_tt(tt: &mut Hasher, buf: &[u8]) -> Result<(), Error> {
         let mut len_buf: [u8; 8] = [0; 8];
         LittleEndian::write_u64(&mut len_buf, buf.len() as u64);
@@ -261,7 +347,6 @@ impl CryptoOpenSSL {
 
     #[inline(always)]
     #[allow(non_snake_case)]
-    #[allow(dead_code)]
     fn get_ZV_as_prover(
         w0: &BigNum,
         w1: &BigNum,
@@ -21,8 +21,11 @@ use std::{
 };
 
 use super::{
-    common::{create_sc_status_report, SCStatusCodes},
-    spake2p::{Spake2P, VerifierData},
+    common::{create_sc_status_report, SCStatusCodes, PROTO_ID_SECURE_CHANNEL},
+    spake2p::{
+        Spake2P, VerifierData, VerifierOption, MAX_ITERATION_COUNT, MAX_SALT_SIZE_BYTES,
+        MIN_ITERATION_COUNT, MIN_SALT_SIZE_BYTES,
+    },
 };
 use crate::{
     crypto,
@@ -33,7 +36,9 @@ use crate::{
     tlv::{self, get_root_node_struct, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
     transport::{
         exchange::ExchangeCtx,
+        mrp::MrpParams,
         network::Address,
+        packet::Packet,
         proto_demux::{ProtoCtx, ResponseRequired},
         queue::{Msg, WorkQ},
         session::{CloneData, SessionMode},
@@ -43,12 +48,13 @@ use log::{error, info};
 use rand::prelude::*;
 
 enum PaseMgrState {
-    Enabled(PAKE, SysMdnsService),
+    Enabled(Box<PAKE>, SysMdnsService),
     Disabled,
 }
 
 pub struct PaseMgrInternal {
     state: PaseMgrState,
+    mdns: Arc<Mdns>,
 }
 
 #[derive(Clone)]
@@ -56,9 +62,12 @@ pub struct PaseMgrInternal {
 pub struct PaseMgr(Arc<Mutex<PaseMgrInternal>>);
 
 impl PaseMgr {
-    pub fn new() -> Self {
+    /// The commissionable mDNS record is published through `mdns`, while the PASE session
+    /// is enabled
+    pub fn new(mdns: Arc<Mdns>) -> Self {
         Self(Arc::new(Mutex::new(PaseMgrInternal {
             state: PaseMgrState::Disabled,
+            mdns,
         })))
     }
 
@@ -70,9 +79,10 @@ impl PaseMgr {
         let mut s = self.0.lock().unwrap();
         let name: u64 = rand::thread_rng().gen_range(0..0xFFFFFFFFFFFFFFFF);
         let name = format!("{:016X}", name);
-        let mdns = Mdns::get()?
+        let mdns = s
+            .mdns
             .publish_service(&name, mdns::ServiceMode::Commissionable(discriminator))?;
-        s.state = PaseMgrState::Enabled(PAKE::new(verifier), mdns);
+        s.state = PaseMgrState::Enabled(Box::new(PAKE::new(verifier)?), mdns);
         Ok(())
     }
 
@@ -108,8 +118,12 @@ impl PaseMgr {
         Ok(ResponseRequired::Yes)
     }
 
-    pub fn pasepake3_handler(&mut self, ctx: &mut ProtoCtx) -> Result<ResponseRequired, Error> {
-        self.if_enabled(ctx, |pake, ctx| pake.handle_pasepake3(ctx))?;
+    pub fn pasepake3_handler(
+        &mut self,
+        ctx: &mut ProtoCtx,
+        work_q: &WorkQ,
+    ) -> Result<ResponseRequired, Error> {
+        self.if_enabled(ctx, |pake, ctx| pake.handle_pasepake3(ctx, work_q))?;
         self.disable_pase_session();
         Ok(ResponseRequired::Yes)
     }
@@ -195,16 +209,23 @@ pub struct PAKE {
 }
 
 impl PAKE {
-    pub fn new(verifier: VerifierData) -> Self {
-        // TODO: Can any PBKDF2 calculation be pre-computed here
-        PAKE {
+    pub fn new(verifier: VerifierData) -> Result<Self, Error> {
+        // Pre-compute the PBKDF2 of the passcode, this way it is done only once, and the
+        // passcode isn't kept around
+        let verifier = match verifier.data {
+            VerifierOption::Password(pw) => {
+                VerifierData::compute(pw, verifier.count, verifier.salt())?
+            }
+            VerifierOption::Verifier(_) => verifier,
+        };
+        Ok(PAKE {
             verifier,
             state: Default::default(),
-        }
+        })
     }
 
     #[allow(non_snake_case)]
-    pub fn handle_pasepake3(&mut self, ctx: &mut ProtoCtx) -> Result<(), Error> {
+    pub fn handle_pasepake3(&mut self, ctx: &mut ProtoCtx, work_q: &WorkQ) -> Result<(), Error> {
         let mut sd = self.state.take_sess_data(&ctx.exch_ctx)?;
 
         let cA = extract_pasepake_1_or_3_params(ctx.rx.as_borrow_slice())?;
@@ -229,6 +250,9 @@ impl PAKE {
                 ctx.exch_ctx.sess.get_peer_addr(),
                 SessionMode::Pase,
             );
+            // The initiator's parameters were recorded on the unsecured session, from the
+            // PBKDFParamRequest
+            clone_data.peer_mrp = ctx.exch_ctx.sess.get_mrp_params();
             clone_data.dec_key.copy_from_slice(&session_keys[0..16]);
             clone_data.enc_key.copy_from_slice(&session_keys[16..32]);
             clone_data
@@ -236,7 +260,7 @@ impl PAKE {
                 .copy_from_slice(&session_keys[32..48]);
 
             // Queue a transport mgr request to add a new session
-            WorkQ::get()?.sync_send(Msg::NewSession(clone_data))?;
+            work_q.sync_send(Msg::NewSession(clone_data))?;
         }
 
         create_sc_status_report(&mut ctx.tx, status_code, None)?;
@@ -286,6 +310,9 @@ impl PAKE {
             error!("Can't yet handle passcode_id != 0");
             return Err(Error::Invalid);
         }
+        if let Some(mrp) = a.initiator_mrp {
+            ctx.exch_ctx.sess.set_mrp_params(mrp);
+        }
 
         let mut our_random: [u8; 32] = [0; 32];
         rand::thread_rng().fill_bytes(&mut our_random);
@@ -302,11 +329,12 @@ impl PAKE {
             our_random: OctetStr(&our_random),
             local_sessid,
             params: None,
+            responder_mrp: Some(MrpParams::local()),
         };
         if !a.has_params {
             let params_resp = PBKDFParamRespParams {
                 count: self.verifier.count,
-                salt: OctetStr(&self.verifier.salt),
+                salt: OctetStr(self.verifier.salt()),
             };
             resp.params = Some(params_resp);
         }
@@ -319,27 +347,160 @@ impl PAKE {
     }
 }
 
-#[derive(ToTLV)]
-#[tlvargs(start = 1)]
+/// The initiator (commissioner) side of a PASE session establishment
+#[allow(non_snake_case)]
+pub struct PakeInitiator {
+    passcode: u32,
+    local_sessid: u16,
+    peer_sessid: u16,
+    our_random: [u8; 32],
+    // The PBKDFParamRequest that we sent, this is part of the Spake2+ context
+    req: Vec<u8>,
+    pA: [u8; 65],
+    spake2p: Spake2P,
+    session_keys: Option<[u8; 48]>,
+    peer_mrp: MrpParams,
+}
+
+impl PakeInitiator {
+    pub fn new(passcode: u32, local_sessid: u16) -> Self {
+        let mut our_random: [u8; 32] = [0; 32];
+        rand::thread_rng().fill_bytes(&mut our_random);
+        Self {
+            passcode,
+            local_sessid,
+            peer_sessid: 0,
+            our_random,
+            req: Vec::new(),
+            pA: [0; 65],
+            spake2p: Spake2P::new(),
+            session_keys: None,
+            peer_mrp: Default::default(),
+        }
+    }
+
+    /// Encode the PBKDFParamRequest that starts the session establishment
+    pub fn pbkdfparamreq(&mut self, tx: &mut Packet) -> Result<(), Error> {
+        tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::PBKDFParamRequest as u8);
+
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        let req = PBKDFParamReq {
+            initiator_random: OctetStr(&self.our_random),
+            initiator_ssid: self.local_sessid,
+            passcode_id: 0,
+            has_params: false,
+            initiator_mrp: Some(MrpParams::local()),
+        };
+        req.to_tlv(&mut tw, TagType::Anonymous)?;
+        self.req = tx.as_borrow_slice().to_vec();
+        Ok(())
+    }
+
+    /// Handle the PBKDFParamResponse from the responder, and encode the Pake1 message in
+    /// response to it
+    #[allow(non_snake_case)]
+    pub fn pasepake1(&mut self, rx: &[u8], tx: &mut Packet) -> Result<(), Error> {
+        let root = tlv::get_root_node(rx)?;
+        let resp = PBKDFParamResp::from_tlv(&root)?;
+        if resp.init_random.0 != self.our_random {
+            error!("Initiator random doesn't match");
+            return Err(Error::Invalid);
+        }
+        let params = resp.params.ok_or(Error::Invalid)?;
+        // The responder shouldn't get to make us run PBKDF2 for ages, or with a weak salt
+        if !(MIN_ITERATION_COUNT..=MAX_ITERATION_COUNT).contains(&params.count)
+            || !(MIN_SALT_SIZE_BYTES..=MAX_SALT_SIZE_BYTES).contains(&params.salt.0.len())
+        {
+            error!("Invalid PBKDF parameters from the responder");
+            return Err(Error::Invalid);
+        }
+        self.peer_sessid = resp.local_sessid;
+        self.peer_mrp = resp.responder_mrp.unwrap_or_default();
+
+        self.spake2p.set_context(&self.req, rx)?;
+        self.spake2p
+            .start_prover(self.passcode, params.count, params.salt.0)?;
+        self.spake2p.get_pA(&mut self.pA)?;
+
+        tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::PASEPake1 as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str8(TagType::Context(1), &self.pA)?;
+        tw.end_container()
+    }
+
+    /// Handle the Pake2 message from the responder, and encode the Pake3 message in
+    /// response to it
+    #[allow(non_snake_case)]
+    pub fn pasepake3(&mut self, rx: &[u8], tx: &mut Packet) -> Result<(), Error> {
+        let root = get_root_node_struct(rx)?;
+        let resp = Pake1Resp::from_tlv(&root)?;
+
+        let mut cA: [u8; 32] = [0; 32];
+        let Ke = self
+            .spake2p
+            .handle_pB(&self.pA, resp.pb.0, resp.cb.0, &mut cA)?;
+
+        // Get the keys
+        let mut session_keys: [u8; 48] = [0; 48];
+        crypto::hkdf_sha256(&[], Ke, &SPAKE2_SESSION_KEYS_INFO, &mut session_keys)
+            .map_err(|_x| Error::NoSpace)?;
+        self.session_keys = Some(session_keys);
+
+        tx.set_proto_id(PROTO_ID_SECURE_CHANNEL as u16);
+        tx.set_proto_opcode(OpCode::PASEPake3 as u8);
+        let mut tw = TLVWriter::new(tx.get_writebuf()?);
+        tw.start_struct(TagType::Anonymous)?;
+        tw.str8(TagType::Context(1), &cA)?;
+        tw.end_container()
+    }
+
+    /// The data for the new secure session, once the responder has confirmed the Pake3
+    pub fn get_session_clone_data(&self, peer_addr: Address) -> Result<CloneData, Error> {
+        let session_keys = self.session_keys.ok_or(Error::InvalidState)?;
+        let mut clone_data = CloneData::new(
+            0,
+            0,
+            self.peer_sessid,
+            self.local_sessid,
+            peer_addr,
+            SessionMode::Pase,
+        );
+        clone_data.peer_mrp = self.peer_mrp;
+        // The I2R key is what we encrypt with, as the initiator
+        clone_data.enc_key.copy_from_slice(&session_keys[0..16]);
+        clone_data.dec_key.copy_from_slice(&session_keys[16..32]);
+        clone_data
+            .att_challenge
+            .copy_from_slice(&session_keys[32..48]);
+        Ok(clone_data)
+    }
+}
+
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct Pake1Resp<'a> {
     pb: OctetStr<'a>,
     cb: OctetStr<'a>,
 }
 
-#[derive(ToTLV)]
-#[tlvargs(start = 1)]
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamRespParams<'a> {
     count: u32,
     salt: OctetStr<'a>,
 }
 
-#[derive(ToTLV)]
-#[tlvargs(start = 1)]
+#[derive(ToTLV, FromTLV)]
+#[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamResp<'a> {
     init_random: OctetStr<'a>,
     our_random: OctetStr<'a>,
     local_sessid: u16,
     params: Option<PBKDFParamRespParams<'a>>,
+    responder_mrp: Option<MrpParams>,
 }
 
 #[allow(non_snake_case)]
@@ -349,11 +510,61 @@ fn extract_pasepake_1_or_3_params(buf: &[u8]) -> Result<&[u8], Error> {
     Ok(pA)
 }
 
-#[derive(FromTLV)]
+#[derive(FromTLV, ToTLV)]
 #[tlvargs(lifetime = "'a", start = 1)]
 struct PBKDFParamReq<'a> {
     initiator_random: OctetStr<'a>,
     initiator_ssid: u16,
     passcode_id: u16,
     has_params: bool,
+    initiator_mrp: Option<MrpParams>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PBKDFParamResp, PBKDFParamRespParams, PakeInitiator};
+    use crate::{
+        error::Error,
+        tlv::{OctetStr, TLVWriter, TagType, ToTLV},
+        transport::packet::PacketPool,
+        utils::writebuf::WriteBuf,
+    };
+
+    // Run the initiator up to the Pake1, with a PBKDFParamResponse carrying these parameters
+    fn pasepake1(count: u32, salt: &[u8]) -> Result<(), Error> {
+        let pool = PacketPool::default();
+        let mut initiator = PakeInitiator::new(123456, 1);
+        let mut tx = pool.alloc_tx().unwrap();
+        initiator.pbkdfparamreq(&mut tx).unwrap();
+
+        let responder_random = [2u8; 32];
+        let resp = PBKDFParamResp {
+            init_random: OctetStr(&initiator.our_random),
+            our_random: OctetStr(&responder_random),
+            local_sessid: 2,
+            params: Some(PBKDFParamRespParams {
+                count,
+                salt: OctetStr(salt),
+            }),
+            responder_mrp: None,
+        };
+        let mut buf = [0u8; 128];
+        let mut wb = WriteBuf::new(&mut buf, 128);
+        let mut tw = TLVWriter::new(&mut wb);
+        resp.to_tlv(&mut tw, TagType::Anonymous).unwrap();
+
+        let mut tx = pool.alloc_tx().unwrap();
+        initiator.pasepake1(wb.as_borrow_slice(), &mut tx)
+    }
+
+    #[test]
+    fn test_initiator_pbkdf_params() {
+        assert_eq!(pasepake1(1000, &[0x53; 16]), Ok(()));
+        assert_eq!(pasepake1(100000, &[0x53; 32]), Ok(()));
+        assert_eq!(pasepake1(999, &[0x53; 16]), Err(Error::Invalid));
+        assert_eq!(pasepake1(100001, &[0x53; 16]), Err(Error::Invalid));
+        assert_eq!(pasepake1(u32::MAX, &[0x53; 16]), Err(Error::Invalid));
+        assert_eq!(pasepake1(1000, &[0x53; 15]), Err(Error::Invalid));
+        assert_eq!(pasepake1(1000, &[0x53; 33]), Err(Error::Invalid));
+    }
 }
@@ -18,6 +18,8 @@
 use crate::{
     crypto::{self, HmacSha256},
     sys,
+    tlv::{self, FromTLV, OctetStr, TLVElement, TLVWriter, TagType, ToTLV},
+    utils::writebuf::WriteBuf,
 };
 use byteorder::{ByteOrder, LittleEndian};
 use log::error;
@@ -46,7 +48,8 @@ use super::{common::SCStatusCodes, crypto::CryptoSpake2};
 // out the specific implementations.
 //
 // In the case of the verifier, we don't actually release the Ke until we
-// validate that the cA is confirmed.
+// validate that the cA is confirmed. Similarly, the prover only releases the
+// Ke once it has validated the cB from the verifier.
 
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Spake2VerifierState {
@@ -81,8 +84,37 @@ const CRYPTO_GROUP_SIZE_BYTES: usize = 32;
 const CRYPTO_W_SIZE_BYTES: usize = CRYPTO_GROUP_SIZE_BYTES + 8;
 const CRYPTO_PUBLIC_KEY_SIZE_BYTES: usize = (2 * CRYPTO_GROUP_SIZE_BYTES) + 1;
 
-const MAX_SALT_SIZE_BYTES: usize = 32;
-const VERIFIER_SIZE_BYTES: usize = CRYPTO_GROUP_SIZE_BYTES + CRYPTO_PUBLIC_KEY_SIZE_BYTES;
+pub const MAX_SALT_SIZE_BYTES: usize = 32;
+pub const MIN_SALT_SIZE_BYTES: usize = 16;
+pub const VERIFIER_SIZE_BYTES: usize = CRYPTO_GROUP_SIZE_BYTES + CRYPTO_PUBLIC_KEY_SIZE_BYTES;
+
+// The range of the PBKDF2 iterations, from the Matter spec
+pub const MIN_ITERATION_COUNT: u32 = 1000;
+pub const MAX_ITERATION_COUNT: u32 = 100000;
+
+const MAX_PASSCODE: u32 = 99999998;
+// The passcodes that are too easy to guess
+const INVALID_PASSCODES: [u32; 10] = [
+    11111111, 22222222, 33333333, 44444444, 55555555, 66666666, 77777777, 88888888, 12345678,
+    87654321,
+];
+
+/// Whether the passcode is valid for the commissioning: it is from 00000001 to 99999998, and
+/// it isn't one of the trivial ones
+pub fn is_valid_passcode(pw: u32) -> bool {
+    pw != 0 && pw <= MAX_PASSCODE && !INVALID_PASSCODES.contains(&pw)
+}
+
+/// Generate a random valid passcode
+pub fn generate_passcode() -> u32 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let pw = rng.gen_range(1..=MAX_PASSCODE);
+        if is_valid_passcode(pw) {
+            return pw;
+        }
+    }
+}
 
 #[cfg(feature = "crypto_openssl")]
 fn crypto_spake2_new() -> Result<Box<dyn CryptoSpake2>, Error> {
@@ -110,6 +142,8 @@ pub struct VerifierData {
     // For the VerifierOption::Verifier, the following fields only serve
     // information purposes
     pub salt: [u8; MAX_SALT_SIZE_BYTES],
+    /// The length of the salt, of the [MIN_SALT_SIZE_BYTES] to 32 bytes
+    pub salt_len: usize,
     pub count: u32,
 }
 
@@ -120,10 +154,20 @@ pub enum VerifierOption {
     Verifier([u8; VERIFIER_SIZE_BYTES]),
 }
 
+// The verifier as it is in the factory data of a device
+#[derive(FromTLV, ToTLV)]
+#[tlvargs(lifetime = "'a")]
+struct FactoryVerifier<'a> {
+    count: u32,
+    salt: OctetStr<'a>,
+    verifier: OctetStr<'a>,
+}
+
 impl VerifierData {
     pub fn new_with_pw(pw: u32) -> Self {
         let mut s = Self {
             salt: [0; MAX_SALT_SIZE_BYTES],
+            salt_len: MAX_SALT_SIZE_BYTES,
             count: sys::SPAKE2_ITERATION_COUNT,
             data: VerifierOption::Password(pw),
         };
@@ -145,7 +189,70 @@ impl VerifierData {
             data: VerifierOption::Verifier(v),
             count,
             salt: s,
+            salt_len: salt.len(),
+        }
+    }
+
+    /// Compute the verifier, w0 and L, of the passcode, so that the passcode itself
+    /// doesn't have to be on the device
+    ///
+    /// This is what the factory provisioning does, along with a random salt and a
+    /// [generate_passcode] passcode.
+    pub fn compute(pw: u32, count: u32, salt: &[u8]) -> Result<Self, Error> {
+        if !is_valid_passcode(pw)
+            || !(MIN_ITERATION_COUNT..=MAX_ITERATION_COUNT).contains(&count)
+            || !(MIN_SALT_SIZE_BYTES..=MAX_SALT_SIZE_BYTES).contains(&salt.len())
+        {
+            return Err(Error::InvalidArgument);
+        }
+
+        let mut w0w1s = [0u8; 2 * CRYPTO_W_SIZE_BYTES];
+        Spake2P::get_w0w1s(pw, count, salt, &mut w0w1s);
+        let w0s_len = w0w1s.len() / 2;
+        let mut crypto_spake2 = crypto_spake2_new()?;
+        crypto_spake2.set_w0_from_w0s(&w0w1s[0..w0s_len])?;
+        crypto_spake2.set_L_from_w1s(&w0w1s[w0s_len..])?;
+
+        let mut verifier = [0u8; VERIFIER_SIZE_BYTES];
+        crypto_spake2.get_w0(&mut verifier[..CRYPTO_GROUP_SIZE_BYTES])?;
+        crypto_spake2.get_L(&mut verifier[CRYPTO_GROUP_SIZE_BYTES..])?;
+        Ok(Self::new(&verifier, count, salt))
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt[..self.salt_len]
+    }
+
+    /// Load the verifier from the factory data, as encoded by [as_tlv](VerifierData::as_tlv)
+    pub fn from_tlv(buf: &[u8]) -> Result<Self, Error> {
+        let root = tlv::get_root_node_struct(buf)?;
+        let f = FactoryVerifier::from_tlv(&root)?;
+        if f.verifier.0.len() != VERIFIER_SIZE_BYTES
+            || !(MIN_SALT_SIZE_BYTES..=MAX_SALT_SIZE_BYTES).contains(&f.salt.0.len())
+            || !(MIN_ITERATION_COUNT..=MAX_ITERATION_COUNT).contains(&f.count)
+        {
+            return Err(Error::InvalidData);
+        }
+        Ok(Self::new(f.verifier.0, f.count, f.salt.0))
+    }
+
+    /// Encode the verifier for the factory data, this is only possible for the computed
+    /// verifiers, and not for the passcodes
+    pub fn as_tlv(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let verifier = match &self.data {
+            VerifierOption::Verifier(v) => v,
+            VerifierOption::Password(_) => return Err(Error::Invalid),
+        };
+        let len = buf.len();
+        let mut wb = WriteBuf::new(buf, len);
+        let mut tw = TLVWriter::new(&mut wb);
+        FactoryVerifier {
+            count: self.count,
+            salt: OctetStr(self.salt()),
+            verifier: OctetStr(verifier),
         }
+        .to_tlv(&mut tw, TagType::Anonymous)?;
+        Ok(wb.as_slice().len())
     }
 }
 
@@ -191,7 +298,7 @@ impl Spake2P {
             VerifierOption::Password(pw) => {
                 // Derive w0 and L from the password
                 let mut w0w1s: [u8; (2 * CRYPTO_W_SIZE_BYTES)] = [0; (2 * CRYPTO_W_SIZE_BYTES)];
-                Spake2P::get_w0w1s(pw, verifier.count, &verifier.salt, &mut w0w1s);
+                Spake2P::get_w0w1s(pw, verifier.count, verifier.salt(), &mut w0w1s);
 
                 let w0s_len = w0w1s.len() / 2;
                 if let Some(crypto_spake2) = &mut self.crypto_spake2 {
@@ -251,9 +358,63 @@ impl Spake2P {
         }
     }
 
+    pub fn start_prover(&mut self, pw: u32, count: u32, salt: &[u8]) -> Result<(), Error> {
+        let mut crypto_spake2 = crypto_spake2_new()?;
+        // Derive w0 and w1 from the password
+        let mut w0w1s = [0u8; 2 * CRYPTO_W_SIZE_BYTES];
+        Spake2P::get_w0w1s(pw, count, salt, &mut w0w1s);
+
+        let w0s_len = w0w1s.len() / 2;
+        crypto_spake2.set_w0_from_w0s(&w0w1s[0..w0s_len])?;
+        crypto_spake2.set_w1_from_w1s(&w0w1s[w0s_len..])?;
+        self.crypto_spake2 = Some(crypto_spake2);
+        self.mode = Spake2Mode::Prover;
+        Ok(())
+    }
+
+    #[allow(non_snake_case)]
+    pub fn get_pA(&mut self, pA: &mut [u8]) -> Result<(), Error> {
+        if self.mode != Spake2Mode::Prover {
+            return Err(Error::InvalidState);
+        }
+        let crypto_spake2 = self.crypto_spake2.as_mut().ok_or(Error::InvalidState)?;
+        crypto_spake2.get_pA(pA)
+    }
+
+    /// Derive the keys from the verifier's pB, validate its cB, and return the cA that
+    /// is to be sent back to the verifier. The Ke is returned only if the cB matches.
+    #[allow(non_snake_case)]
+    pub fn handle_pB(
+        &mut self,
+        pA: &[u8],
+        pB: &[u8],
+        cB: &[u8],
+        cA: &mut [u8],
+    ) -> Result<&[u8], Error> {
+        if self.mode != Spake2Mode::Prover {
+            return Err(Error::InvalidState);
+        }
+
+        let mut crypto_spake2 = self.crypto_spake2.take().ok_or(Error::InvalidState)?;
+        let context = self.context.take().ok_or(Error::InvalidState)?;
+        let mut hash = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        context.finish(&mut hash)?;
+        let mut TT = [0u8; crypto::SHA256_HASH_LEN_BYTES];
+        crypto_spake2.get_TT_as_prover(&hash, pA, pB, &mut TT)?;
+
+        let mut our_cB = [0u8; 32];
+        Spake2P::get_Ke_and_cAcB(&TT, pA, pB, &mut self.Ke, cA, &mut our_cB)?;
+        self.mode = Spake2Mode::Unknown;
+        if cB.ct_eq(&our_cB).unwrap_u8() == 1 {
+            Ok(&self.Ke)
+        } else {
+            error!("cB mismatch");
+            Err(Error::Invalid)
+        }
+    }
+
     #[inline(always)]
     #[allow(non_snake_case)]
-    #[allow(dead_code)]
     fn get_Ke_and_cAcB(
         TT: &[u8],
         pA: &[u8],
@@ -296,10 +457,14 @@ impl Spake2P {
 #[cfg(test)]
 mod tests {
 
-    use super::Spake2P;
+    use super::{generate_passcode, is_valid_passcode, Spake2P, VerifierData, VerifierOption};
     use crate::{
         crypto,
-        secure_channel::{spake2p::CRYPTO_W_SIZE_BYTES, spake2p_test_vectors::test_vectors::*},
+        error::Error,
+        secure_channel::{
+            common::SCStatusCodes, spake2p::CRYPTO_W_SIZE_BYTES,
+            spake2p_test_vectors::test_vectors::*,
+        },
     };
 
     #[test]
@@ -324,6 +489,87 @@ mod tests {
         )
     }
 
+    #[allow(non_snake_case)]
+    fn handshake(verifier: &VerifierData, pw: u32) -> bool {
+        let mut v = Spake2P::new();
+        v.start_verifier(verifier).unwrap();
+        v.set_context(b"req", b"resp").unwrap();
+
+        let mut p = Spake2P::new();
+        p.start_prover(pw, verifier.count, verifier.salt()).unwrap();
+        p.set_context(b"req", b"resp").unwrap();
+
+        let mut pA = [0u8; 65];
+        let mut pB = [0u8; 65];
+        let mut cA = [0u8; 32];
+        let mut cB = [0u8; 32];
+        p.get_pA(&mut pA).unwrap();
+        v.handle_pA(&pA, &mut pB, &mut cB).unwrap();
+        let prover_Ke = match p.handle_pB(&pA, &pB, &cB, &mut cA) {
+            Ok(Ke) => Ke.to_vec(),
+            Err(_) => return false,
+        };
+
+        let (status, verifier_Ke) = v.handle_cA(&cA);
+        status == SCStatusCodes::SessionEstablishmentSuccess
+            && verifier_Ke == Some(prover_Ke.as_slice())
+    }
+
+    #[test]
+    fn test_prover_verifier() {
+        let verifier = VerifierData::new_with_pw(123456);
+        assert!(handshake(&verifier, 123456));
+    }
+
+    #[test]
+    fn test_computed_verifier() {
+        let salt = [0x5a; 16];
+        let verifier = VerifierData::compute(20202021, 1000, &salt).unwrap();
+        assert_eq!(verifier.salt(), &salt);
+        assert!(matches!(verifier.data, VerifierOption::Verifier(_)));
+        assert!(handshake(&verifier, 20202021));
+        assert!(!handshake(&verifier, 20202022));
+
+        // The invalid passcodes, iteration counts and salts
+        assert!(VerifierData::compute(12345678, 1000, &salt).is_err());
+        assert!(VerifierData::compute(20202021, 999, &salt).is_err());
+        assert!(VerifierData::compute(20202021, 1000, &salt[..15]).is_err());
+    }
+
+    #[test]
+    fn test_factory_verifier_tlv() {
+        let salt = [0xa5; 32];
+        let verifier = VerifierData::compute(20202021, 2000, &salt).unwrap();
+        let mut buf = [0u8; 256];
+        let len = verifier.as_tlv(&mut buf).unwrap();
+
+        let loaded = VerifierData::from_tlv(&buf[..len]).unwrap();
+        assert_eq!(loaded.count, 2000);
+        assert_eq!(loaded.salt(), &salt);
+        match (verifier.data, &loaded.data) {
+            (VerifierOption::Verifier(a), VerifierOption::Verifier(b)) => assert_eq!(&a, b),
+            _ => panic!("Not a verifier"),
+        }
+        assert!(handshake(&loaded, 20202021));
+
+        // Only the computed verifiers can be in the factory data
+        let pw = VerifierData::new_with_pw(20202021);
+        assert_eq!(pw.as_tlv(&mut buf), Err(Error::Invalid));
+        // Nor a truncated verifier
+        assert!(VerifierData::from_tlv(&buf[..len - 2]).is_err());
+    }
+
+    #[test]
+    fn test_generate_passcode() {
+        for _ in 0..100 {
+            assert!(is_valid_passcode(generate_passcode()));
+        }
+        assert!(!is_valid_passcode(0));
+        assert!(!is_valid_passcode(99999999));
+        assert!(!is_valid_passcode(11111111));
+        assert!(is_valid_passcode(20202021));
+    }
+
     #[test]
     #[allow(non_snake_case)]
     fn test_get_Ke_and_cAcB() {
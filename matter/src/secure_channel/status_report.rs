@@ -17,9 +17,11 @@
 
 use super::common::*;
 use crate::{error::Error, transport::packet::Packet};
+use byteorder::{ByteOrder, LittleEndian};
+use num_derive::FromPrimitive;
 
 #[allow(dead_code)]
-#[derive(Debug, Copy, Clone)]
+#[derive(FromPrimitive, Debug, Copy, Clone)]
 pub enum GeneralCode {
     Success = 0,
     Failure = 1,
@@ -39,6 +41,27 @@ pub enum GeneralCode {
     PermissionDenied = 15,
     DataLoss = 16,
 }
+/// A received status report, without its protocol specific data
+#[derive(Debug, PartialEq)]
+pub struct StatusReport {
+    pub general_code: u16,
+    pub proto_id: u32,
+    pub proto_code: u16,
+}
+
+impl StatusReport {
+    pub fn parse(payload: &[u8]) -> Result<Self, Error> {
+        if payload.len() < 8 {
+            return Err(Error::TruncatedPacket);
+        }
+        Ok(Self {
+            general_code: LittleEndian::read_u16(&payload[0..2]),
+            proto_id: LittleEndian::read_u32(&payload[2..6]),
+            proto_code: LittleEndian::read_u16(&payload[6..8]),
+        })
+    }
+}
+
 pub fn create_status_report(
     proto_tx: &mut Packet,
     general_code: GeneralCode,
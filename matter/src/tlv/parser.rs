@@ -162,9 +162,23 @@ static VALUE_EXTRACTOR: [ExtractValue; MAX_VALUE_INDEX] = [
     // True 9
     { |_t| (0, ElementType::True) },
     // F32  10
-    { |_t| (0, ElementType::Last) },
+    {
+        |t| {
+            (
+                0,
+                ElementType::F32(LittleEndian::read_f32(&t.buf[t.current..])),
+            )
+        }
+    },
     // F64  11
-    { |_t| (0, ElementType::Last) },
+    {
+        |t| {
+            (
+                0,
+                ElementType::F64(LittleEndian::read_f64(&t.buf[t.current..])),
+            )
+        }
+    },
     // Utf8l 12
     {
         |t| match read_length_value(1, t) {
@@ -288,6 +288,10 @@ impl<T: ToTLV> ToTLV for TLVArrayOwned<T> {
 }
 
 impl<T> TLVArrayOwned<T> {
+    pub fn new(vec: Vec<T>) -> Self {
+        Self(vec)
+    }
+
     pub fn iter(&self) -> Iter<T> {
         self.0.iter()
     }
@@ -161,6 +161,16 @@ impl<'a, 'b> TLVWriter<'a, 'b> {
         }
     }
 
+    pub fn f32(&mut self, tag_type: TagType, data: f32) -> Result<(), Error> {
+        self.put_control_tag(tag_type, WriteElementType::F32)?;
+        self.buf.le_u32(data.to_bits())
+    }
+
+    pub fn f64(&mut self, tag_type: TagType, data: f64) -> Result<(), Error> {
+        self.put_control_tag(tag_type, WriteElementType::F64)?;
+        self.buf.le_u64(data.to_bits())
+    }
+
     pub fn str8(&mut self, tag_type: TagType, data: &[u8]) -> Result<(), Error> {
         if data.len() > 256 {
             error!("use str16() instead");
@@ -257,6 +267,11 @@ impl<'a, 'b> TLVWriter<'a, 'b> {
         }
     }
 
+    /// Copy an element that is already TLV encoded, along with its tag
+    pub fn raw_tlv(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.buf.append(data)
+    }
+
     pub fn get_tail(&self) -> usize {
         self.buf.get_tail()
     }
@@ -269,6 +284,7 @@ impl<'a, 'b> TLVWriter<'a, 'b> {
 #[cfg(test)]
 mod tests {
     use super::{TLVWriter, TagType};
+    use crate::tlv::{ElementType, TLVList};
     use crate::utils::writebuf::WriteBuf;
 
     #[test]
@@ -350,4 +366,29 @@ mod tests {
             [36, 1, 13, 48, 2, 5, 10, 11, 12, 13, 14, 48, 3, 2, 10, 11, 36, 4, 13, 0]
         );
     }
+
+    #[test]
+    fn test_put_float() {
+        let mut buf: [u8; 16] = [0; 16];
+        let buf_len = buf.len();
+        let mut writebuf = WriteBuf::new(&mut buf, buf_len);
+        let mut tw = TLVWriter::new(&mut writebuf);
+
+        tw.f32(TagType::Context(1), 1.5).unwrap();
+        tw.f64(TagType::Anonymous, -2.25).unwrap();
+        assert_eq!(
+            buf,
+            [42, 1, 0, 0, 0xc0, 0x3f, 11, 0, 0, 0, 0, 0, 0, 0x02, 0xc0, 0]
+        );
+
+        let mut iter = TLVList::new(&buf[..15]).iter();
+        assert_eq!(
+            iter.next().unwrap().get_element_type(),
+            ElementType::F32(1.5)
+        );
+        assert_eq!(
+            iter.next().unwrap().get_element_type(),
+            ElementType::F64(-2.25)
+        );
+    }
 }
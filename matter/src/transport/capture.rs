@@ -0,0 +1,296 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use log::error;
+use num::FromPrimitive;
+
+use crate::{
+    error::Error,
+    interaction_model::core::{OpCode as ImOpCode, PROTO_ID_INTERACTION_MODEL},
+    secure_channel::common::{OpCode as ScOpCode, PROTO_ID_SECURE_CHANNEL},
+    tlv::{ElementType, TLVList},
+};
+
+use super::{network::Address, plain_hdr::PlainHdr, proto_hdr::ProtoHdr};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// A message as it is seen by the session manager, in plaintext
+pub struct CapturedMsg<'a> {
+    pub direction: Direction,
+    pub peer: Address,
+    pub plain: &'a PlainHdr,
+    pub proto: &'a ProtoHdr,
+    /// The application payload, after the protocol header
+    pub payload: &'a [u8],
+}
+
+/// A hook that sees all the messages that are received and sent, once they are decrypted
+/// and before they are encrypted, respectively
+///
+/// This is for debugging, see [JsonCapture] for the capture into a file.
+pub trait Capture {
+    fn capture(&self, msg: &CapturedMsg);
+}
+
+/// Captures the messages as JSON lines, with the headers decoded, the protocol and opcode
+/// names, and the payload both in hex and as TLV
+pub struct JsonCapture {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonCapture {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Capture into the file at the given path, which is truncated if it exists
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+}
+
+impl Capture for JsonCapture {
+    fn capture(&self, msg: &CapturedMsg) {
+        let line = to_json(msg);
+        let mut out = self.out.lock().unwrap();
+        // A failing capture mustn't get in the way of the communication
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            error!("Error writing the capture: {:?}", e);
+        }
+    }
+}
+
+fn to_json(msg: &CapturedMsg) -> String {
+    let (transport, peer) = match msg.peer {
+        Address::Udp(a) => ("udp", a),
+        Address::Tcp(a) => ("tcp", a),
+    };
+    let proto = msg.proto;
+    let mut json = format!(
+        "{{\"time\":\"{}\",\"dir\":\"{}\",\"transport\":\"{}\",\"peer\":\"{}\"",
+        Utc::now().to_rfc3339(),
+        if msg.direction == Direction::Rx {
+            "rx"
+        } else {
+            "tx"
+        },
+        transport,
+        peer
+    );
+    let _ = write!(
+        json,
+        ",\"sess_id\":{},\"encrypted\":{},\"msg_ctr\":{}",
+        msg.plain.sess_id,
+        msg.plain.is_encrypted(),
+        msg.plain.ctr
+    );
+    if let Some(src) = msg.plain.get_src_u64() {
+        let _ = write!(json, ",\"src_node_id\":\"{:016X}\"", src);
+    }
+    let _ = write!(
+        json,
+        ",\"exch_id\":{},\"flags\":\"{}\",\"proto_id\":{},\"opcode\":{}",
+        proto.exch_id,
+        flags_str(proto),
+        proto.proto_id,
+        proto.proto_opcode
+    );
+    if let Some(name) = opcode_name(proto.proto_id, proto.proto_opcode) {
+        let _ = write!(json, ",\"opcode_name\":\"{}\"", name);
+    }
+    if let Some(ack) = proto.ack_msg_ctr {
+        let _ = write!(json, ",\"ack_msg_ctr\":{}", ack);
+    }
+    json.push_str(",\"payload\":\"");
+    for b in msg.payload {
+        let _ = write!(json, "{:02x}", b);
+    }
+    json.push('"');
+    if is_tlv(proto.proto_id, proto.proto_opcode) && !msg.payload.is_empty() {
+        let _ = write!(json, ",\"tlv\":\"{}\"", escape(&tlv_to_string(msg.payload)));
+    }
+    json.push('}');
+    json
+}
+
+/// The exchange flags, like `R|I`
+pub fn flags_str(proto: &ProtoHdr) -> String {
+    let flags: Vec<&str> = [
+        (proto.is_vendor(), "V"),
+        (proto.is_security_ext(), "SX"),
+        (proto.is_reliable(), "R"),
+        (proto.is_ack(), "A"),
+        (proto.is_initiator(), "I"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| *flag)
+    .collect();
+    flags.join("|")
+}
+
+pub fn opcode_name(proto_id: u16, opcode: u8) -> Option<String> {
+    match proto_id as usize {
+        PROTO_ID_SECURE_CHANNEL => ScOpCode::from_u8(opcode).map(|o| format!("{:?}", o)),
+        PROTO_ID_INTERACTION_MODEL => ImOpCode::from_u8(opcode).map(|o| format!("{:?}", o)),
+        _ => None,
+    }
+}
+
+// Whether the payload of the message is TLV encoded
+pub fn is_tlv(proto_id: u16, opcode: u8) -> bool {
+    match proto_id as usize {
+        PROTO_ID_INTERACTION_MODEL => true,
+        // The status reports and the message counter sync aren't TLV
+        PROTO_ID_SECURE_CHANNEL => matches!(
+            ScOpCode::from_u8(opcode),
+            Some(
+                ScOpCode::PBKDFParamRequest
+                    | ScOpCode::PBKDFParamResponse
+                    | ScOpCode::PASEPake1
+                    | ScOpCode::PASEPake2
+                    | ScOpCode::PASEPake3
+                    | ScOpCode::CASESigma1
+                    | ScOpCode::CASESigma2
+                    | ScOpCode::CASESigma3
+                    | ScOpCode::CASESigma2Resume
+            )
+        ),
+        _ => false,
+    }
+}
+
+/// Render the TLV elements on a single line, like `{0: U8(1), 1: [U8(2)]}`
+pub fn tlv_to_string(b: &[u8]) -> String {
+    let mut out = String::new();
+    // The closing brackets of the containers that we are in
+    let mut closing = Vec::new();
+    // Whether the next element is the first in its container
+    let mut first = true;
+    for element in TLVList::new(b).iter() {
+        let container = match element.get_element_type() {
+            ElementType::EndCnt => {
+                out.push(closing.pop().unwrap_or('>'));
+                first = false;
+                continue;
+            }
+            ElementType::Struct(_) => Some('}'),
+            ElementType::Array(_) | ElementType::List(_) => Some(']'),
+            _ => None,
+        };
+        if !first {
+            out.push_str(", ");
+        }
+        let _ = write!(out, "{}", element);
+        if let Some(c) = container {
+            closing.push(c);
+        }
+        first = container.is_some();
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv::{TLVWriter, TagType};
+    use crate::utils::writebuf::WriteBuf;
+
+    #[test]
+    fn test_tlv_to_string() {
+        let mut buf = [0; 32];
+        let buf_len = buf.len();
+        let mut wb = WriteBuf::new(&mut buf, buf_len);
+        let mut tw = TLVWriter::new(&mut wb);
+        tw.start_struct(TagType::Anonymous).unwrap();
+        tw.u8(TagType::Context(0), 1).unwrap();
+        tw.start_array(TagType::Context(1)).unwrap();
+        tw.u8(TagType::Anonymous, 2).unwrap();
+        tw.u8(TagType::Anonymous, 3).unwrap();
+        tw.end_container().unwrap();
+        tw.utf8(TagType::Context(2), b"a\"b").unwrap();
+        tw.end_container().unwrap();
+        let len = wb.as_borrow_slice().len();
+
+        let s = tlv_to_string(&buf[..len]);
+        assert_eq!(s, "{0: U8(1), 1: [U8(2), U8(3)], 2: len[3]\"a\"b\"}");
+        assert_eq!(
+            escape(&s),
+            "{0: U8(1), 1: [U8(2), U8(3)], 2: len[3]\\\"a\\\"b\\\"}"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let mut plain = PlainHdr::default();
+        plain.sess_id = 5;
+        plain.ctr = 7;
+        let mut proto = ProtoHdr {
+            exch_id: 3,
+            proto_id: PROTO_ID_SECURE_CHANNEL as u16,
+            proto_opcode: ScOpCode::StatusReport as u8,
+            ..Default::default()
+        };
+        proto.set_reliable();
+        let json = to_json(&CapturedMsg {
+            direction: Direction::Tx,
+            peer: Address::Tcp("[::1]:5540".parse().unwrap()),
+            plain: &plain,
+            proto: &proto,
+            payload: &[0, 1, 0xff],
+        });
+        let fields = json.split_once("\",").unwrap().1;
+        assert_eq!(
+            fields,
+            "\"dir\":\"tx\",\"transport\":\"tcp\",\"peer\":\"[::1]:5540\",\"sess_id\":5,\
+             \"encrypted\":false,\"msg_ctr\":7,\"exch_id\":3,\"flags\":\"R\",\"proto_id\":0,\
+             \"opcode\":64,\"opcode_name\":\"StatusReport\",\"payload\":\"0001ff\"}"
+        );
+    }
+}
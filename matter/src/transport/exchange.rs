@@ -15,21 +15,26 @@
  *    limitations under the License.
  */
 
-use boxslab::{BoxSlab, Slab};
 use colored::*;
 use log::{error, info, trace};
+use rand::Rng;
 use std::any::Any;
 use std::fmt;
 use std::time::SystemTime;
 
 use crate::error::Error;
+use crate::interaction_model::core::PROTO_ID_INTERACTION_MODEL;
 use crate::secure_channel;
 
 use heapless::LinearMap;
 
-use super::packet::PacketPool;
 use super::session::CloneData;
-use super::{mrp::ReliableMessage, packet::Packet, session::SessionHandle, session::SessionMgr};
+use super::{
+    mrp::{ReliableMessage, RetransAction},
+    packet::{Packet, PacketPool},
+    session::SessionHandle,
+    session::{SessionMgr, MAX_SESSIONS},
+};
 
 pub struct ExchangeCtx<'a> {
     pub exch: &'a mut Exchange,
@@ -145,7 +150,14 @@ impl Exchange {
     pub fn take_data_boxed<T: Any>(&mut self) -> Option<Box<T>> {
         let old = std::mem::replace(&mut self.data, DataOption::None);
         if let DataOption::Boxed(d) = old {
-            d.downcast::<T>().ok()
+            match d.downcast::<T>() {
+                Ok(d) => Some(d),
+                Err(d) => {
+                    // Not what the caller is looking for, leave it in place
+                    self.data = DataOption::Boxed(d);
+                    None
+                }
+            }
         } else {
             self.data = old;
             None
@@ -167,7 +179,7 @@ impl Exchange {
 
     fn send(
         &mut self,
-        mut proto_tx: BoxSlab<PacketPool>,
+        mut proto_tx: Box<Packet<'static>>,
         session: &mut SessionHandle,
     ) -> Result<(), Error> {
         trace!("payload: {:x?}", proto_tx.as_borrow_slice());
@@ -183,9 +195,16 @@ impl Exchange {
             proto_tx.proto.set_initiator();
         }
 
+        if session.get_peer_addr().is_reliable() {
+            // MRP is only for the unreliable transports
+            proto_tx.unset_reliable();
+        }
         session.pre_send(&mut proto_tx)?;
         self.mrp.pre_send(&mut proto_tx)?;
-        session.send(proto_tx)
+        let mrp_interval = session.get_mrp_interval();
+        session.send(&mut proto_tx)?;
+        self.mrp.post_send(&mut proto_tx, mrp_interval);
+        Ok(())
     }
 }
 
@@ -215,22 +234,23 @@ pub fn get_complementary_role(is_initiator: bool) -> Role {
     }
 }
 
-const MAX_EXCHANGES: usize = 8;
+pub const MAX_EXCHANGES: usize = 8;
 
 #[derive(Default)]
 pub struct ExchangeMgr {
     // keys: exch-id
     exchanges: LinearMap<u16, Exchange, MAX_EXCHANGES>,
     sess_mgr: SessionMgr,
+    // The exchange id to use for the next exchange that we initiate
+    next_exch_id: u16,
 }
 
-pub const MAX_MRP_ENTRIES: usize = 4;
-
 impl ExchangeMgr {
     pub fn new(sess_mgr: SessionMgr) -> Self {
         Self {
             sess_mgr,
             exchanges: Default::default(),
+            next_exch_id: rand::thread_rng().gen(),
         }
     }
 
@@ -238,6 +258,15 @@ impl ExchangeMgr {
         &mut self.sess_mgr
     }
 
+    /// Allocate a packet for sending
+    pub fn new_tx(&self) -> Result<Box<Packet<'static>>, Error> {
+        self.sess_mgr.get_packet_pool().alloc_tx()
+    }
+
+    pub fn get_packet_pool(&self) -> &PacketPool {
+        self.sess_mgr.get_packet_pool()
+    }
+
     pub fn _get_with_id(
         exchanges: &mut LinearMap<u16, Exchange, MAX_EXCHANGES>,
         exch_id: u16,
@@ -285,23 +314,81 @@ impl ExchangeMgr {
     }
 
     /// The Exchange Mgr receive is like a big processing function
-    pub fn recv(&mut self) -> Result<Option<(BoxSlab<PacketPool>, ExchangeCtx)>, Error> {
+    pub fn recv(&mut self) -> Result<Option<(Box<Packet<'static>>, ExchangeCtx)>, Error> {
         // Get the session
-        let (mut proto_rx, index) = self.sess_mgr.recv()?;
+        let result = self.sess_mgr.recv();
+        self.process_rx(result)
+    }
+
+    /// Receive the packet that is ready on the given network interface, as returned by
+    /// [wait_recv](ExchangeMgr::wait_recv), without blocking
+    pub fn try_recv(
+        &mut self,
+        network: usize,
+    ) -> Result<Option<(Box<Packet<'static>>, ExchangeCtx<'_>)>, Error> {
+        let result = self.sess_mgr.try_recv(network);
+        self.process_rx(result)
+    }
+
+    fn process_rx(
+        &mut self,
+        result: Result<(Box<Packet<'static>>, Option<usize>), Error>,
+    ) -> Result<Option<(Box<Packet<'static>>, ExchangeCtx<'_>)>, Error> {
+        self.remove_closed_sessions();
+        let (mut proto_rx, index) = result?;
 
         let index = if let Some(s) = index {
             s
         } else {
             // The sessions were full, evict one session, and re-perform post-recv
-            let evict_index = self.sess_mgr.get_lru();
+            let evict_index = self.sess_mgr.get_evictable(None).ok_or(Error::NoSpace)?;
             self.evict_session(evict_index)?;
             info!("Reattempting session creation");
             self.sess_mgr.post_recv(&proto_rx)?.ok_or(Error::Invalid)?
         };
+        // Decrypt the message
         let mut session = self.sess_mgr.get_session_handle(index);
+        match session.recv(&mut proto_rx) {
+            Ok(()) => (),
+            Err(Error::Duplicate) => {
+                info!(
+                    "Dropping duplicate message with counter {}",
+                    proto_rx.plain.ctr
+                );
+                if proto_rx.proto.is_reliable() && !session.is_group() {
+                    // The peer hasn't got our acknowledgement, its exchange may well be
+                    // gone on our side
+                    self.send_standalone_ack(index, &proto_rx)?;
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
 
-        // Decrypt the message
-        session.recv(&mut proto_rx)?;
+        if session.is_encrypted() && secure_channel::common::is_close_session(&mut proto_rx) {
+            info!(
+                "Peer closed the session {}, tearing it down",
+                session.get_local_sess_id()
+            );
+            // The peer is waiting for the acknowledgement, which has to go out before the
+            // session is gone
+            if proto_rx.proto.is_reliable() {
+                if let Err(e) = self.send_standalone_ack(index, &proto_rx) {
+                    error!("Error in acknowledging the Close Session {:?}", e);
+                }
+            }
+            self.remove_session(index);
+            return Ok(None);
+        }
+        if session.is_group()
+            && (proto_rx.get_proto_id() != PROTO_ID_INTERACTION_MODEL as u16
+                || proto_rx.proto.is_reliable())
+        {
+            // Only the unreliable Interaction Model messages are sent to a group
+            error!("Dropping a group message that isn't for the Interaction Model");
+            return Ok(None);
+        }
+        let session = self.sess_mgr.get_session_handle(index);
 
         // Get the exchange
         let exch = ExchangeMgr::_get(
@@ -331,7 +418,58 @@ impl ExchangeMgr {
         }
     }
 
-    pub fn send(&mut self, exch_id: u16, proto_tx: BoxSlab<PacketPool>) -> Result<(), Error> {
+    // Acknowledge a message right away, outside of any exchange
+    fn send_standalone_ack(&mut self, index: usize, proto_rx: &Packet) -> Result<(), Error> {
+        let mut tx = self.new_tx()?;
+        ReliableMessage::prepare_ack(proto_rx.proto.exch_id, &mut tx);
+        tx.proto.exch_id = proto_rx.proto.exch_id;
+        if !proto_rx.proto.is_initiator() {
+            tx.proto.set_initiator();
+        }
+        tx.proto.set_ack(proto_rx.plain.ctr);
+        let mut session = self.sess_mgr.get_session_handle(index);
+        session.pre_send(&mut tx)?;
+        session.send(&mut tx)
+    }
+
+    /// Wait until there is a packet for [try_recv](ExchangeMgr::try_recv), returns the index
+    /// of the network interface that it is on
+    pub async fn wait_recv(&self) -> Result<usize, Error> {
+        self.sess_mgr.wait_recv().await
+    }
+
+    fn get_next_exch_id(&mut self) -> u16 {
+        loop {
+            let exch_id = self.next_exch_id;
+            self.next_exch_id = self.next_exch_id.wrapping_add(1);
+            if !self.exchanges.contains_key(&exch_id) {
+                return exch_id;
+            }
+        }
+    }
+
+    /// Create a new exchange, with us as the initiator, on the session with the given
+    /// local session id
+    pub fn initiate(&mut self, sess_id: u16) -> Result<ExchangeCtx, Error> {
+        let sess_idx = self
+            .sess_mgr
+            .get_index_with_id(sess_id)
+            .ok_or(Error::NoSession)?;
+        let exch_id = self.get_next_exch_id();
+        let exch = ExchangeMgr::_get(
+            &mut self.exchanges,
+            sess_idx,
+            exch_id,
+            Role::Initiator,
+            true,
+        )?;
+        Ok(ExchangeCtx {
+            exch,
+            sess: self.sess_mgr.get_session_handle(sess_idx),
+        })
+    }
+
+    pub fn send(&mut self, exch_id: u16, proto_tx: Box<Packet<'static>>) -> Result<(), Error> {
         let exchange =
             ExchangeMgr::_get_with_id(&mut self.exchanges, exch_id).ok_or(Error::NoExchange)?;
         let mut session = self.sess_mgr.get_session_handle(exchange.sess_idx);
@@ -351,35 +489,56 @@ impl ExchangeMgr {
         }
     }
 
-    pub fn pending_acks(&mut self, expired_entries: &mut LinearMap<u16, (), MAX_MRP_ENTRIES>) {
+    pub fn pending_acks(&mut self, expired_entries: &mut LinearMap<u16, (), MAX_EXCHANGES>) {
         for (exch_id, exchange) in self.exchanges.iter() {
-            if exchange.mrp.is_ack_ready() {
+            // Once the exchange is closed, there is nothing to piggyback the ack on
+            if exchange.mrp.is_ack_ready()
+                || (exchange.mrp.is_ack_pending() && !exchange.is_state_open())
+            {
                 expired_entries.insert(*exch_id, ()).unwrap();
             }
         }
     }
 
+    /// Retransmit the messages that haven't been acknowledged in time
+    ///
+    /// The exchanges that have run out of retransmissions are returned in `failed`, along
+    /// with the protocol id of their last message. It is up to the caller to notify the
+    /// protocol and close the exchange.
+    pub fn retransmit(&mut self, failed: &mut LinearMap<u16, u16, MAX_EXCHANGES>) {
+        let now = SystemTime::now();
+        for (exch_id, exchange) in self.exchanges.iter_mut() {
+            match exchange.mrp.retrans_action(now) {
+                RetransAction::None => (),
+                RetransAction::Retransmit(data, peer) => {
+                    if let Err(e) = self.sess_mgr.send_raw(data, peer) {
+                        error!("Error in retransmitting on exch {}: {:?}", exch_id, e);
+                    }
+                }
+                RetransAction::Failed(proto_id) => {
+                    let _ = failed.insert(*exch_id, proto_id);
+                }
+            }
+        }
+    }
+
+    /// The earliest time at which one of the exchanges has an MRP action due
+    pub fn get_next_timeout(&self) -> Option<SystemTime> {
+        self.exchanges
+            .values()
+            .filter_map(|e| e.mrp.get_next_timeout())
+            .min()
+    }
+
     pub fn evict_session(&mut self, index: usize) -> Result<(), Error> {
         info!("Sessions full, vacating session with index: {}", index);
-        // If we enter here, we have an LRU session that needs to be reclaimed
         // As per the spec, we need to send a CLOSE here
+        self.close_session_index(index);
+        Ok(())
+    }
 
-        let mut session = self.sess_mgr.get_session_handle(index);
-        let mut tx = Slab::<PacketPool>::try_new(Packet::new_tx()?).ok_or(Error::NoSpace)?;
-        secure_channel::common::create_sc_status_report(
-            &mut tx,
-            secure_channel::common::SCStatusCodes::CloseSession,
-            None,
-        )?;
-
-        if let Some((_, exchange)) = self.exchanges.iter_mut().find(|(_, e)| e.sess_idx == index) {
-            // Send Close_session on this exchange, and then close the session
-            // Should this be done for all exchanges?
-            error!("Sending Close Session");
-            exchange.send(tx, &mut session)?;
-            // TODO: This wouldn't actually send it out, because 'transport' isn't owned yet.
-        }
-
+    // Remove the session, along with all its exchanges
+    fn remove_session(&mut self, index: usize) {
         let remove_exchanges: Vec<u16> = self
             .exchanges
             .iter()
@@ -400,14 +559,92 @@ impl ExchangeMgr {
             self.exchanges.remove(&exch_id);
         }
         self.sess_mgr.remove(index);
+    }
+
+    // Remove the sessions that were bound to the connections that have closed
+    fn remove_closed_sessions(&mut self) {
+        for peer in self.sess_mgr.take_closed() {
+            while let Some(index) = self.sess_mgr.get_index_with_peer(peer) {
+                info!(
+                    "Removing session with index {}, its connection is closed",
+                    index
+                );
+                self.remove_session(index);
+            }
+        }
+    }
+
+    /// Close all the secure sessions, letting the peers know with a CloseSession
+    pub fn close_sessions(&mut self) {
+        for index in 0..MAX_SESSIONS {
+            if matches!(self.sess_mgr.mut_by_index(index), Some(s) if s.is_encrypted()) {
+                self.close_session_index(index);
+            }
+        }
+    }
+
+    /// Close the secure session with the given local session id, letting the peer know with
+    /// a CloseSession
+    pub fn close_session(&mut self, sess_id: u16) -> Result<(), Error> {
+        let index = self
+            .sess_mgr
+            .get_index_with_id(sess_id)
+            .ok_or(Error::NoSession)?;
+        if !matches!(self.sess_mgr.mut_by_index(index), Some(s) if s.is_encrypted()) {
+            return Err(Error::NoSession);
+        }
+        self.close_session_index(index);
         Ok(())
     }
 
+    /// Close the sessions that have been idle for longer than the idle timeout, unless they
+    /// still have an open exchange
+    pub fn close_idle_sessions(&mut self) {
+        for index in self.sess_mgr.get_idle(SystemTime::now()) {
+            if self.exchanges.values().any(|e| e.sess_idx == index) {
+                continue;
+            }
+            info!("Session with index {} is idle", index);
+            self.close_session_index(index);
+        }
+    }
+
+    // Remove the session, after sending a CloseSession if it is a secure session
+    fn close_session_index(&mut self, index: usize) {
+        let sess_id = match self.sess_mgr.mut_by_index(index) {
+            Some(s) if s.is_encrypted() => s.get_local_sess_id(),
+            Some(_) => {
+                self.remove_session(index);
+                return;
+            }
+            None => return,
+        };
+        info!("Closing session {}", sess_id);
+        if let Err(e) = self.send_close_session(sess_id) {
+            error!("Error in sending Close Session {:?}", e);
+        }
+        self.remove_session(index);
+    }
+
+    fn send_close_session(&mut self, sess_id: u16) -> Result<(), Error> {
+        let mut tx = self.new_tx()?;
+        secure_channel::common::create_sc_status_report(
+            &mut tx,
+            secure_channel::common::SCStatusCodes::CloseSession,
+            None,
+        )?;
+        let exch_id = self.initiate(sess_id)?.exch.get_id();
+        self.send(exch_id, tx)
+    }
+
     pub fn add_session(&mut self, clone_data: &CloneData) -> Result<SessionHandle, Error> {
         let sess_idx = match self.sess_mgr.clone_session(clone_data) {
             Ok(idx) => idx,
             Err(Error::NoSpace) => {
-                let evict_index = self.sess_mgr.get_lru();
+                let evict_index = self
+                    .sess_mgr
+                    .get_evictable(clone_data.get_local_fabric_idx())
+                    .ok_or(Error::NoSpace)?;
                 self.evict_session(evict_index)?;
                 self.sess_mgr.clone_session(clone_data)?
             }
@@ -434,11 +671,14 @@ impl fmt::Display for ExchangeMgr {
 #[cfg(test)]
 #[allow(clippy::bool_assert_comparison)]
 mod tests {
+    use std::{thread, time::Duration};
 
     use crate::{
         error::Error,
+        secure_channel,
         transport::{
-            network::{Address, NetworkInterface},
+            loopback::Loopback,
+            network::{Address, NetworkInterface, RecvReady},
             session::{CloneData, SessionMgr, SessionMode, MAX_SESSIONS},
         },
     };
@@ -476,6 +716,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_initiate() {
+        let mut mgr = ExchangeMgr::new(SessionMgr::new());
+        assert_eq!(mgr.initiate(1).err(), Some(Error::NoSession));
+
+        mgr.add_session(&get_clone_data(100, 1)).unwrap();
+        let e1 = mgr.initiate(1).unwrap().exch.get_id();
+        let e2 = mgr.initiate(1).unwrap().exch.get_id();
+        assert_ne!(e1, e2);
+        assert_eq!(mgr.get_with_id(e1).unwrap().get_role(), Role::Initiator);
+        assert_eq!(mgr.get_with_id(e2).unwrap().get_role(), Role::Initiator);
+    }
+
     fn get_clone_data(peer_sess_id: u16, local_sess_id: u16) -> CloneData {
         CloneData::new(
             12341234,
@@ -504,6 +757,51 @@ mod tests {
         }
     }
 
+    // An exchange manager on the given end of a loopback, with a session to the other end.
+    // The session ids double as the node ids.
+    fn loopback_mgr(network: Loopback, local_sess_id: u16, peer_sess_id: u16) -> ExchangeMgr {
+        let peer = Address::Udp(network.get_peer_addr());
+        let mut sess_mgr = SessionMgr::new();
+        sess_mgr.add_network_interface(Box::new(network)).unwrap();
+        let mut mgr = ExchangeMgr::new(sess_mgr);
+        let clone_data = CloneData::new(
+            local_sess_id as u64,
+            peer_sess_id as u64,
+            peer_sess_id,
+            local_sess_id,
+            peer,
+            SessionMode::Pase,
+        );
+        mgr.add_session(&clone_data).unwrap();
+        mgr
+    }
+
+    #[test]
+    fn test_close_session_ack() {
+        let (a, b) = Loopback::pair();
+        let mut a = loopback_mgr(a, 1, 2);
+        let mut b = loopback_mgr(b, 2, 1);
+
+        // We send our CloseSession unreliably, but the peers may not
+        let mut tx = a.new_tx().unwrap();
+        secure_channel::common::create_sc_status_report(
+            &mut tx,
+            secure_channel::common::SCStatusCodes::CloseSession,
+            None,
+        )
+        .unwrap();
+        tx.set_reliable();
+        let exch_id = a.initiate(1).unwrap().exch.get_id();
+        a.send(exch_id, tx).unwrap();
+        assert!(a.get_next_timeout().is_some());
+
+        // The peer acknowledges the CloseSession, before tearing its session down
+        assert!(b.recv().unwrap().is_none());
+        assert!(b.sess_mgr.get_with_id(2).is_none());
+        a.recv().unwrap();
+        assert!(a.get_next_timeout().is_none());
+    }
+
     pub struct DummyNetwork;
     impl DummyNetwork {
         pub fn new() -> Self {
@@ -516,9 +814,39 @@ mod tests {
             Ok((0, Address::default()))
         }
 
+        fn try_recv(&self, _in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+            Ok((0, Address::default()))
+        }
+
         fn send(&self, _out_buf: &[u8], _addr: Address) -> Result<usize, Error> {
             Ok(0)
         }
+
+        fn wait_recv(&self) -> RecvReady<'_> {
+            Box::pin(smol::future::pending())
+        }
+    }
+
+    #[test]
+    fn test_idle_session_with_exchange() {
+        let mut sess_mgr = SessionMgr::new();
+        sess_mgr
+            .add_network_interface(Box::new(DummyNetwork::new()))
+            .unwrap();
+        sess_mgr.set_idle_timeout(Some(Duration::from_millis(1)));
+        let mut mgr = ExchangeMgr::new(sess_mgr);
+        mgr.add_session(&get_clone_data(100, 1)).unwrap();
+        let exch_id = mgr.initiate(1).unwrap().exch.get_id();
+        thread::sleep(Duration::from_millis(5));
+
+        // The session is idle, but it's still in use by the exchange
+        mgr.close_idle_sessions();
+        assert!(mgr.sess_mgr.get_with_id(1).is_some());
+
+        mgr.get_with_id(exch_id).unwrap().close();
+        mgr.purge();
+        mgr.close_idle_sessions();
+        assert!(mgr.sess_mgr.get_with_id(1).is_none());
     }
 
     #[test]
@@ -0,0 +1,311 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_channel::{unbounded, Receiver, Sender};
+use log::info;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use smol::{future, Timer};
+
+use crate::error::*;
+
+use super::network::{Address, NetworkInterface, RecvReady, RECV_POLL_TIMEOUT};
+
+/// The conditions of the link between two [Loopback] ends, to exercise the Message
+/// Reliability Protocol
+///
+/// The drops and reorders are random, but the same seed gives the same sequence of
+/// decisions, which keeps the tests deterministic.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConditions {
+    /// The fraction of the packets that are lost, from 0 to 1
+    pub drop_rate: f64,
+    /// How long every packet takes to arrive
+    pub delay: Duration,
+    /// The fraction of the packets that are held back, from 0 to 1, so that the packets
+    /// that follow overtake them
+    pub reorder_rate: f64,
+    /// How much longer a packet that is held back takes to arrive
+    pub reorder_delay: Duration,
+    /// The seed for the random decisions
+    pub seed: u64,
+}
+
+struct Frame {
+    // When the packet arrives at the other end
+    at: Instant,
+    data: Vec<u8>,
+}
+
+struct Link {
+    conditions: LinkConditions,
+    rng: StdRng,
+}
+
+impl Link {
+    fn new(conditions: LinkConditions) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(conditions.seed),
+            conditions,
+        }
+    }
+
+    // When a packet sent now arrives, or None if it is lost
+    fn arrival(&mut self) -> Option<Instant> {
+        let c = &self.conditions;
+        if c.drop_rate > 0.0 && self.rng.gen_bool(c.drop_rate.min(1.0)) {
+            return None;
+        }
+        let mut delay = c.delay;
+        if c.reorder_rate > 0.0 && self.rng.gen_bool(c.reorder_rate.min(1.0)) {
+            delay += c.reorder_delay;
+        }
+        Some(Instant::now() + delay)
+    }
+}
+
+/// An in-memory network interface, one of the two ends of a link
+///
+/// This lets two stacks in the same process, like a device and a controller, talk to each
+/// other without a real network. The peers see each other at made-up UDP addresses, so
+/// everything above the network interface behaves as it would over UDP.
+pub struct Loopback {
+    addr: SocketAddr,
+    peer: SocketAddr,
+    tx: Sender<Frame>,
+    rx: Receiver<Frame>,
+    // The packets that were taken off the channel, but haven't arrived yet
+    pending: Mutex<Vec<Frame>>,
+    // The conditions for the packets that we send
+    link: Mutex<Link>,
+}
+
+impl Loopback {
+    /// Create the two ends of a perfect link
+    pub fn pair() -> (Loopback, Loopback) {
+        Self::pair_with(LinkConditions::default())
+    }
+
+    /// Create the two ends of a link with the given conditions, in both directions
+    pub fn pair_with(conditions: LinkConditions) -> (Loopback, Loopback) {
+        let a: SocketAddr = "[fd00::1]:5540".parse().unwrap();
+        let b: SocketAddr = "[fd00::2]:5540".parse().unwrap();
+        let (a_tx, b_rx) = unbounded();
+        let (b_tx, a_rx) = unbounded();
+        // The directions take different, but still deterministic, decisions
+        let b_conditions = LinkConditions {
+            seed: conditions.seed.wrapping_add(1),
+            ..conditions.clone()
+        };
+        (
+            Loopback::new(a, b, a_tx, a_rx, conditions),
+            Loopback::new(b, a, b_tx, b_rx, b_conditions),
+        )
+    }
+
+    fn new(
+        addr: SocketAddr,
+        peer: SocketAddr,
+        tx: Sender<Frame>,
+        rx: Receiver<Frame>,
+        conditions: LinkConditions,
+    ) -> Self {
+        Self {
+            addr,
+            peer,
+            tx,
+            rx,
+            pending: Mutex::new(Vec::new()),
+            link: Mutex::new(Link::new(conditions)),
+        }
+    }
+
+    /// The address at which the other end sees us
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address of the other end
+    pub fn get_peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Change the conditions for the packets that we send from now on
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.link.lock().unwrap() = Link::new(conditions);
+    }
+
+    // Move the packets on the channel to the pending ones, and return when the first of
+    // them arrives
+    fn next_arrival(&self) -> Option<Instant> {
+        let mut pending = self.pending.lock().unwrap();
+        while let Ok(frame) = self.rx.try_recv() {
+            pending.push(frame);
+        }
+        pending.iter().map(|f| f.at).min()
+    }
+
+    fn copy_frame(&self, frame: Frame, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        let len = frame.data.len();
+        if len > in_buf.len() {
+            return Err(Error::NoSpace);
+        }
+        in_buf[..len].copy_from_slice(&frame.data);
+        Ok((len, Address::Udp(self.peer)))
+    }
+
+    fn take_arrived(&self) -> Option<Frame> {
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+        let index = pending
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.at <= now)
+            .min_by_key(|(_, f)| f.at)?
+            .0;
+        Some(pending.remove(index))
+    }
+}
+
+impl NetworkInterface for Loopback {
+    fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        let recv = async {
+            loop {
+                self.wait_recv().await?;
+                if let Some(frame) = self.take_arrived() {
+                    return Ok(frame);
+                }
+            }
+        };
+        let timeout = async {
+            Timer::after(RECV_POLL_TIMEOUT).await;
+            Err(Error::Timeout)
+        };
+        let frame = smol::block_on(future::or(recv, timeout))?;
+        self.copy_frame(frame, in_buf)
+    }
+
+    fn try_recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        self.next_arrival();
+        let frame = self.take_arrived().ok_or(Error::Timeout)?;
+        self.copy_frame(frame, in_buf)
+    }
+
+    fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+        if addr != Address::Udp(self.peer) {
+            return Err(Error::Invalid);
+        }
+        match self.link.lock().unwrap().arrival() {
+            Some(at) => {
+                // Like with UDP, it isn't an error if nobody is listening at the other end
+                let _ = self.tx.try_send(Frame {
+                    at,
+                    data: out_buf.to_vec(),
+                });
+            }
+            None => info!("Dropping the packet to {}", self.peer),
+        }
+        Ok(out_buf.len())
+    }
+
+    fn wait_recv(&self) -> RecvReady<'_> {
+        Box::pin(async move {
+            loop {
+                let next = self.next_arrival();
+                if matches!(next, Some(at) if at <= Instant::now()) {
+                    return Ok(());
+                }
+                let new_frame = async {
+                    match self.rx.recv().await {
+                        Ok(frame) => self.pending.lock().unwrap().push(frame),
+                        // The other end is gone, nothing more can arrive
+                        Err(_) => future::pending().await,
+                    }
+                };
+                match next {
+                    Some(at) => {
+                        let arrived = async {
+                            Timer::at(at).await;
+                        };
+                        future::or(arrived, new_frame).await
+                    }
+                    None => new_frame.await,
+                }
+            }
+        })
+    }
+
+    fn is_for(&self, addr: &Address) -> bool {
+        *addr == Address::Udp(self.peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv_all(end: &Loopback) -> Vec<u8> {
+        let mut buf = [0; 8];
+        let mut received = Vec::new();
+        while let Ok((len, _)) = end.recv(&mut buf) {
+            assert_eq!(len, 1);
+            received.push(buf[0]);
+        }
+        received
+    }
+
+    #[test]
+    fn test_link_conditions() {
+        let (a, b) = Loopback::pair();
+        let b_addr = Address::Udp(b.get_local_addr());
+        a.send(&[1, 2, 3], b_addr).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(
+            b.recv(&mut buf).unwrap(),
+            (3, Address::Udp(a.get_local_addr()))
+        );
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+
+        let conditions = LinkConditions {
+            drop_rate: 0.3,
+            reorder_rate: 0.3,
+            reorder_delay: Duration::from_millis(50),
+            seed: 7,
+            ..Default::default()
+        };
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let (a, b) = Loopback::pair_with(conditions.clone());
+            for i in 0..20 {
+                a.send(&[i], b_addr).unwrap();
+            }
+            let received = recv_all(&b);
+            assert!(received.len() < 20);
+            let mut sorted = received.clone();
+            sorted.sort_unstable();
+            assert_ne!(received, sorted);
+            runs.push(received);
+        }
+        // The same seed gives the same drops and reorders
+        assert_eq!(runs[0], runs[1]);
+    }
+}
@@ -15,36 +15,170 @@
  *    limitations under the License.
  */
 
-use async_channel::Receiver;
-use boxslab::{BoxSlab, Slab};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_channel::{bounded, Receiver, Sender};
 use heapless::LinearMap;
-use log::{debug, error, info};
+use log::{debug, error, info, trace};
+use smol::{future, Timer};
 
 use crate::error::*;
+use crate::group_keys::GroupMgr;
+use crate::persist::KvStorage;
 
 use crate::transport::mrp::ReliableMessage;
-use crate::transport::packet::PacketPool;
-use crate::transport::{exchange, packet::Packet, proto_demux, queue, session, udp};
+use crate::transport::{exchange, packet::Packet, proto_demux, queue, session, tcp, udp};
 
+use super::capture::Capture;
+use super::network::{NetworkInterface, TransportConfig};
 use super::proto_demux::ProtoCtx;
 use super::queue::Msg;
+use super::session::SessionInfo;
+
+// The longest we wait without an event, before letting the protocols originate any
+// messages they have pending, like subscription reports
+const PERIODIC_INTERVAL: Duration = Duration::from_millis(500);
+
+enum Event {
+    // A packet is ready on the network interface with this index
+    Rx(usize),
+    Msg(Msg),
+    Timeout,
+    Stop,
+}
+
+/// A handle to stop the transport's event loop, from another task or thread
+#[derive(Clone)]
+pub struct StopHandle {
+    tx: Sender<()>,
+}
+
+impl StopHandle {
+    /// Request the event loop to stop
+    ///
+    /// The active sessions are closed and [run](Mgr::run) returns. If the event loop isn't
+    /// running, the next run returns right away.
+    pub fn stop(&self) {
+        // A stop that is already pending is just as good
+        let _ = self.tx.try_send(());
+    }
+}
+
+/// A handle to list and close the secure sessions of the transport, from another task or
+/// thread
+///
+/// The requests are served by the transport's event loop, they wait until it runs.
+#[derive(Clone)]
+pub struct SessionControl {
+    work_q: queue::WorkQ,
+}
+
+impl SessionControl {
+    /// The secure sessions that are currently established
+    pub async fn list(&self) -> Result<Vec<SessionInfo>, Error> {
+        let (tx, rx) = bounded(1);
+        self.work_q.send(Msg::ListSessions(tx)).await?;
+        Ok(rx.recv().await?)
+    }
+
+    /// Close the secure session with the given local session id, letting the peer know with
+    /// a CloseSession
+    pub async fn close(&self, sess_id: u16) -> Result<(), Error> {
+        let (tx, rx) = bounded(1);
+        self.work_q.send(Msg::CloseSession(sess_id, tx)).await?;
+        rx.recv().await?
+    }
+}
 
 pub struct Mgr {
     exch_mgr: exchange::ExchangeMgr,
     proto_demux: proto_demux::ProtoDemux,
+    work_q: queue::WorkQ,
     rx_q: Receiver<Msg>,
+    stop_tx: Sender<()>,
+    stop_rx: Receiver<()>,
+    port: u16,
 }
 
 impl Mgr {
-    pub fn new() -> Result<Mgr, Error> {
+    pub fn new(config: &TransportConfig) -> Result<Mgr, Error> {
         let mut sess_mgr = session::SessionMgr::new();
-        let udp_transport = Box::new(udp::UdpListener::new()?);
+        sess_mgr.set_idle_timeout(config.session_idle_timeout);
+        let udp_transport = Box::new(udp::UdpListener::new_with_config(config)?);
+        let port = udp_transport.get_local_addr()?.port();
         sess_mgr.add_network_interface(udp_transport)?;
-        Ok(Mgr {
+        if config.tcp {
+            // On the same port as UDP, as only the one port is advertised
+            let tcp_transport = tcp::TcpTransport::new_with_config(&TransportConfig {
+                port,
+                ..config.clone()
+            })?;
+            sess_mgr.add_network_interface(Box::new(tcp_transport))?;
+        }
+        Ok(Mgr::new_with_sess_mgr(sess_mgr, port))
+    }
+
+    /// Create the transport on the given network interface, instead of the sockets, like
+    /// a [Loopback](super::loopback::Loopback) in the tests
+    pub fn new_with_network(network: Box<dyn NetworkInterface>) -> Result<Mgr, Error> {
+        let mut sess_mgr = session::SessionMgr::new();
+        sess_mgr.add_network_interface(network)?;
+        // There is no port to speak of, so the services are published with the Matter port
+        Ok(Mgr::new_with_sess_mgr(sess_mgr, udp::MATTER_PORT))
+    }
+
+    fn new_with_sess_mgr(sess_mgr: session::SessionMgr, port: u16) -> Mgr {
+        let (work_q, rx_q) = queue::WorkQ::new();
+        let (stop_tx, stop_rx) = bounded(1);
+        Mgr {
             proto_demux: proto_demux::ProtoDemux::new(),
             exch_mgr: exchange::ExchangeMgr::new(sess_mgr),
-            rx_q: queue::WorkQ::init()?,
-        })
+            work_q,
+            rx_q,
+            stop_tx,
+            stop_rx,
+            port,
+        }
+    }
+
+    /// The port that the transport is listening on
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Persist the global message counters in the storage
+    pub fn set_storage(&mut self, storage: Arc<dyn KvStorage>) -> Result<(), Error> {
+        self.exch_mgr.get_sess_mgr().set_storage(storage)
+    }
+
+    /// Receive the group messages, to the groups of the Group Manager
+    pub fn set_group_mgr(&mut self, group_mgr: Arc<GroupMgr>) {
+        self.exch_mgr.get_sess_mgr().set_group_mgr(group_mgr);
+    }
+
+    /// Capture all the messages in plaintext, for debugging
+    pub fn set_capture(&mut self, capture: Box<dyn Capture>) {
+        self.exch_mgr.get_sess_mgr().set_capture(capture);
+    }
+
+    /// The queue through which the protocols hand work over to the transport
+    pub fn get_work_q(&self) -> queue::WorkQ {
+        self.work_q.clone()
+    }
+
+    pub fn get_stop_handle(&self) -> StopHandle {
+        StopHandle {
+            tx: self.stop_tx.clone(),
+        }
+    }
+
+    pub fn get_session_control(&self) -> SessionControl {
+        SessionControl {
+            work_q: self.work_q.clone(),
+        }
     }
 
     // Allows registration of different protocols with the Transport/Protocol Demux
@@ -58,16 +192,24 @@ impl Mgr {
     fn send_to_exchange(
         &mut self,
         exch_id: u16,
-        proto_tx: BoxSlab<PacketPool>,
+        proto_tx: Box<Packet<'static>>,
     ) -> Result<(), Error> {
         self.exch_mgr.send(exch_id, proto_tx)
     }
 
-    fn handle_rxtx(&mut self) -> Result<(), Error> {
-        let result = self.exch_mgr.recv().map_err(|e| {
-            error!("Error in recv: {:?}", e);
-            e
-        })?;
+    fn handle_rxtx(&mut self, network: usize) -> Result<(), Error> {
+        // The exchange context borrows the exchange manager, so the response is allocated from
+        // a handle to the pool
+        let pool = self.exch_mgr.get_packet_pool().clone();
+        let result = match self.exch_mgr.try_recv(network) {
+            Ok(r) => r,
+            // The packet was gone by the time we got to it
+            Err(Error::Timeout) => return Ok(()),
+            Err(e) => {
+                error!("Error in recv: {:?}", e);
+                return Err(e);
+            }
+        };
 
         if result.is_none() {
             // Nothing to process, return quietly
@@ -78,8 +220,9 @@ impl Mgr {
         let (rx, exch_ctx) = result.unwrap();
 
         debug!("Exchange is {:?}", exch_ctx.exch);
-        let tx = Self::new_tx()?;
 
+        // The response can be as large as the peer's transport allows
+        let tx = pool.alloc_tx_with_size(rx.peer.max_msg_size())?;
         let mut proto_ctx = ProtoCtx::new(exch_ctx, rx, tx);
         // Proto Dispatch
         match self.proto_demux.handle(&mut proto_ctx) {
@@ -111,65 +254,165 @@ impl Mgr {
         Ok(())
     }
 
-    fn handle_queue_msgs(&mut self) -> Result<(), Error> {
-        if let Ok(msg) = self.rx_q.try_recv() {
-            match msg {
-                Msg::NewSession(clone_data) => {
-                    // If a new session was created, add it
-                    let _ = self
-                        .exch_mgr
-                        .add_session(&clone_data)
-                        .map_err(|e| error!("Error adding new session {:?}", e));
-                }
-                _ => {
-                    error!("Queue Message Type not yet handled {:?}", msg);
-                }
+    fn handle_queue_msg(&mut self, msg: Msg) {
+        match msg {
+            Msg::NewSession(clone_data) => {
+                // If a new session was created, add it
+                let _ = self
+                    .exch_mgr
+                    .add_session(&clone_data)
+                    .map_err(|e| error!("Error adding new session {:?}", e));
+            }
+            Msg::CloseSession(sess_id, tx) => {
+                // The requester may have given up already
+                let _ = tx.try_send(self.exch_mgr.close_session(sess_id));
+            }
+            Msg::ListSessions(tx) => {
+                let _ = tx.try_send(self.exch_mgr.get_sess_mgr().get_sessions_info());
+            }
+            _ => {
+                error!("Queue Message Type not yet handled {:?}", msg);
             }
         }
-        Ok(())
     }
 
-    pub fn start(&mut self) -> Result<(), Error> {
-        loop {
-            // Handle network operations
-            if self.handle_rxtx().is_err() {
-                error!("Error in handle_rxtx");
-                continue;
+    // The work that follows every event: sending the acknowledgements and retransmissions
+    // that are due, running the protocols' periodic work, closing the idle sessions and purging
+    // the closed exchanges
+    fn handle_housekeeping(&mut self) {
+        let mut acks_to_send: LinearMap<u16, (), { exchange::MAX_EXCHANGES }> = LinearMap::new();
+        self.exch_mgr.pending_acks(&mut acks_to_send);
+        for exch_id in acks_to_send.keys() {
+            info!("Sending MRP Standalone ACK for  exch {}", exch_id);
+            let mut proto_tx = match self.new_tx() {
+                Ok(p) => p,
+                Err(e) => {
+                    error!("Error creating proto_tx {:?}", e);
+                    break;
+                }
+            };
+            ReliableMessage::prepare_ack(*exch_id, &mut proto_tx);
+            if let Err(e) = self.send_to_exchange(*exch_id, proto_tx) {
+                error!("Error in sending Ack {:?}", e);
             }
+        }
+
+        let mut failed: LinearMap<u16, u16, { exchange::MAX_EXCHANGES }> = LinearMap::new();
+        self.exch_mgr.retransmit(&mut failed);
+        for (exch_id, proto_id) in failed.iter() {
+            if let Some(exch) = self.exch_mgr.get_with_id(*exch_id) {
+                self.proto_demux.exchange_failed(*proto_id, exch);
+                exch.close();
+            }
+        }
 
-            if self.handle_queue_msgs().is_err() {
-                error!("Error in handle_queue_msg");
-                continue;
+        // Let the protocols originate any messages they have pending
+        self.proto_demux.periodic(&mut self.exch_mgr);
+
+        self.exch_mgr.close_idle_sessions();
+
+        // Follow the changes to the group table
+        self.exch_mgr.get_sess_mgr().sync_multicast();
+
+        self.exch_mgr.purge();
+
+        trace!("Exchange Mgr: {}", self.exch_mgr);
+    }
+
+    async fn wait_event(&self) -> Result<Event, Error> {
+        let timeout = self
+            .exch_mgr
+            .get_next_timeout()
+            .map(|t| {
+                t.duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .min(PERIODIC_INTERVAL)
+            })
+            .unwrap_or(PERIODIC_INTERVAL);
+
+        let rx = async { Ok(Event::Rx(self.exch_mgr.wait_recv().await?)) };
+        let msg = async {
+            match self.rx_q.recv().await {
+                Ok(msg) => Ok(Event::Msg(msg)),
+                // Nobody can send on the queue anymore
+                Err(_) => future::pending().await,
             }
+        };
+        let timer = async {
+            Timer::after(timeout).await;
+            Ok(Event::Timeout)
+        };
+        let stop = async {
+            // We hold a sender ourselves, so the channel can't be closed
+            let _ = self.stop_rx.recv().await;
+            Ok(Event::Stop)
+        };
+        future::or(stop, future::or(future::or(rx, msg), timer)).await
+    }
 
-            // Handle any pending acknowledgement send
-            let mut acks_to_send: LinearMap<u16, (), { exchange::MAX_MRP_ENTRIES }> =
-                LinearMap::new();
-            self.exch_mgr.pending_acks(&mut acks_to_send);
-            for exch_id in acks_to_send.keys() {
-                info!("Sending MRP Standalone ACK for  exch {}", exch_id);
-                let mut proto_tx = match Self::new_tx() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        error!("Error creating proto_tx {:?}", e);
-                        break;
+    /// Run the transport's event loop
+    ///
+    /// This waits for packets on the network, messages on the work queue and the MRP
+    /// timers. It returns once it is stopped through a [StopHandle], after closing all the
+    /// secure sessions, or if waiting on the network fails.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            match self.wait_event().await? {
+                Event::Stop => {
+                    info!("Stopping the transport");
+                    self.exch_mgr.close_sessions();
+                    return Ok(());
+                }
+                Event::Rx(network) => {
+                    if self.handle_rxtx(network).is_err() {
+                        error!("Error in handle_rxtx");
                     }
-                };
-                ReliableMessage::prepare_ack(*exch_id, &mut proto_tx);
-                if let Err(e) = self.send_to_exchange(*exch_id, proto_tx) {
-                    error!("Error in sending Ack {:?}", e);
                 }
+                Event::Msg(msg) => self.handle_queue_msg(msg),
+                Event::Timeout => (),
             }
+            self.handle_housekeeping();
+        }
+    }
 
-            // Handle exchange purging
-            //    This need not be done in each turn of the loop, maybe once in 5 times or so?
-            self.exch_mgr.purge();
+    /// Run the transport's event loop on the current thread
+    ///
+    /// This call does NOT return, until the event loop is stopped
+    pub fn start(&mut self) -> Result<(), Error> {
+        smol::block_on(self.run())
+    }
 
-            info!("Exchange Mgr: {}", self.exch_mgr);
-        }
+    fn new_tx(&self) -> Result<Box<Packet<'static>>, Error> {
+        self.exch_mgr.new_tx()
     }
+}
 
-    fn new_tx() -> Result<BoxSlab<PacketPool>, Error> {
-        Slab::<PacketPool>::try_new(Packet::new_tx()?).ok_or(Error::PacketPoolExhaust)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_restart() {
+        // The port must be freed once the stopped transport is dropped, so that another one
+        // can be started in the same process. We pick a free port, rather than the Matter
+        // port, which something else may be using.
+        let port = udp::UdpListener::new_with_port(0)
+            .unwrap()
+            .get_local_addr()
+            .unwrap()
+            .port();
+        let config = TransportConfig {
+            port,
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let mut mgr = Mgr::new(&config).unwrap();
+            let stop = mgr.get_stop_handle();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(100));
+                stop.stop();
+            });
+            assert_eq!(mgr.start(), Ok(()));
+        }
     }
 }
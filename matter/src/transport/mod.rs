@@ -15,9 +15,12 @@
  *    limitations under the License.
  */
 
+pub mod capture;
 pub mod exchange;
+pub mod loopback;
 pub mod mgr;
 pub mod mrp;
+pub mod msg_ctr;
 pub mod network;
 pub mod packet;
 pub mod plain_hdr;
@@ -25,4 +28,5 @@ pub mod proto_demux;
 pub mod proto_hdr;
 pub mod queue;
 pub mod session;
+pub mod tcp;
 pub mod udp;
@@ -18,22 +18,116 @@
 use std::time::Duration;
 use std::time::SystemTime;
 
-use crate::{error::*, secure_channel, transport::packet::Packet};
-use log::error;
+use crate::{
+    error::*,
+    secure_channel,
+    tlv::{FromTLV, TLVElement, TLVWriter, TagType, ToTLV},
+    transport::{network::Address, packet::Packet},
+};
+use log::{error, info};
+use rand::Rng;
 
 // 200 ms
 const MRP_STANDALONE_ACK_TIMEOUT: u64 = 200;
 
+// The retransmission intervals that we use if the peer doesn't tell us its own, and that we
+// tell our peers, in milliseconds
+const MRP_IDLE_INTERVAL_DEFAULT: u32 = 500;
+const MRP_ACTIVE_INTERVAL_DEFAULT: u32 = 300;
+/// The peer is considered active, if we heard from it within this time, in milliseconds
+const MRP_ACTIVE_THRESHOLD_DEFAULT: u16 = 4000;
+
+// The range of the retransmission intervals that we accept from a peer, in milliseconds.
+// The maximum is from the Matter spec, retransmitting more often than the minimum only adds
+// to the congestion.
+const MRP_INTERVAL_MIN: u32 = 100;
+const MRP_INTERVAL_MAX: u32 = 3600000;
+
+// The parameters of the exponential backoff
+const MRP_BACKOFF_BASE: f64 = 1.6;
+const MRP_BACKOFF_THRESHOLD: u32 = 1;
+const MRP_BACKOFF_MARGIN: f64 = 1.1;
+const MRP_BACKOFF_JITTER: f64 = 0.25;
+
+/// The number of times a message is sent, including the first transmission, before we give
+/// up on the exchange
+pub const MRP_MAX_TRANSMISSIONS: u32 = 5;
+
+/// The MRP parameters of a node, that are exchanged during the session establishment
+///
+/// The intervals are in milliseconds. The defaults apply to the ones that are missing.
+#[derive(FromTLV, ToTLV, Debug, Default, Copy, Clone, PartialEq)]
+#[tlvargs(start = 1)]
+pub struct MrpParams {
+    pub idle_interval: Option<u32>,
+    pub active_interval: Option<u32>,
+    pub active_threshold: Option<u16>,
+}
+
+impl MrpParams {
+    /// The parameters that we advertise to our peers
+    pub fn local() -> Self {
+        Self {
+            idle_interval: Some(MRP_IDLE_INTERVAL_DEFAULT),
+            active_interval: Some(MRP_ACTIVE_INTERVAL_DEFAULT),
+            active_threshold: Some(MRP_ACTIVE_THRESHOLD_DEFAULT),
+        }
+    }
+
+    pub fn idle_interval(&self) -> Duration {
+        interval(self.idle_interval.unwrap_or(MRP_IDLE_INTERVAL_DEFAULT))
+    }
+
+    pub fn active_interval(&self) -> Duration {
+        interval(self.active_interval.unwrap_or(MRP_ACTIVE_INTERVAL_DEFAULT))
+    }
+
+    /// The peer is considered active, if we heard from it within this time
+    pub fn active_threshold(&self) -> Duration {
+        Duration::from_millis(
+            self.active_threshold
+                .unwrap_or(MRP_ACTIVE_THRESHOLD_DEFAULT) as u64,
+        )
+    }
+}
+
+// The peers don't get to make us retransmit like crazy, or never
+fn interval(ms: u32) -> Duration {
+    Duration::from_millis(ms.clamp(MRP_INTERVAL_MIN, MRP_INTERVAL_MAX) as u64)
+}
+
+/// The time to wait for an acknowledgement, after the message has been sent `send_count`
+/// times. `base_interval` is the peer's idle or active interval.
+pub fn backoff_time(base_interval: Duration, send_count: u32) -> Duration {
+    let exp = send_count.saturating_sub(1 + MRP_BACKOFF_THRESHOLD);
+    let jitter = 1.0 + rand::thread_rng().gen::<f64>() * MRP_BACKOFF_JITTER;
+    base_interval.mul_f64(MRP_BACKOFF_MARGIN * MRP_BACKOFF_BASE.powi(exp as i32) * jitter)
+}
+
 #[derive(Debug)]
 pub struct RetransEntry {
     // The msg counter that we are waiting to be acknowledged
     msg_ctr: u32,
-    // This will additionally have retransmission count and periods once we implement it
+    proto_id: u16,
+    // The message as it was sent on the wire, a retransmission is the exact same message
+    data: Vec<u8>,
+    peer: Address,
+    base_interval: Duration,
+    send_count: u32,
+    next_tx: SystemTime,
 }
 
 impl RetransEntry {
-    pub fn new(msg_ctr: u32) -> Self {
-        Self { msg_ctr }
+    pub fn new(tx: &mut Packet, base_interval: Duration) -> Self {
+        Self {
+            msg_ctr: tx.plain.ctr,
+            proto_id: tx.get_proto_id(),
+            data: tx.as_borrow_slice().to_vec(),
+            peer: tx.peer,
+            base_interval,
+            send_count: 1,
+            next_tx: SystemTime::now() + backoff_time(base_interval, 1),
+        }
     }
 
     pub fn get_msg_ctr(&self) -> u32 {
@@ -41,6 +135,16 @@ impl RetransEntry {
     }
 }
 
+/// What is to be done about a message that we are waiting to be acknowledged
+pub enum RetransAction<'a> {
+    None,
+    /// Send the message again
+    Retransmit(&'a [u8], Address),
+    /// The message was sent the maximum number of times, the exchange has failed. This
+    /// carries the protocol id of the message.
+    Failed(u16),
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct AckEntry {
     // The msg counter that we should acknowledge
@@ -68,7 +172,7 @@ impl AckEntry {
     }
 
     pub fn has_timed_out(&self) -> bool {
-        self.ack_timeout > SystemTime::now()
+        SystemTime::now() >= self.ack_timeout
     }
 }
 
@@ -99,6 +203,49 @@ impl ReliableMessage {
         }
     }
 
+    pub fn is_ack_pending(&self) -> bool {
+        self.ack.is_some()
+    }
+
+    /// The time at which the next acknowledgement or retransmission is due
+    pub fn get_next_timeout(&self) -> Option<SystemTime> {
+        let ack = self.ack.map(|a| a.ack_timeout);
+        let retrans = self.retrans.as_ref().map(|r| r.next_tx);
+        match (ack, retrans) {
+            (Some(a), Some(r)) => Some(a.min(r)),
+            (a, r) => a.or(r),
+        }
+    }
+
+    /// Check if the message that we are waiting to be acknowledged is due for
+    /// retransmission
+    pub fn retrans_action(&mut self, now: SystemTime) -> RetransAction<'_> {
+        match &self.retrans {
+            Some(entry) if entry.next_tx <= now => {
+                if entry.send_count >= MRP_MAX_TRANSMISSIONS {
+                    error!(
+                        "No acknowledgement for message {} after {} transmissions",
+                        entry.msg_ctr, entry.send_count
+                    );
+                    let proto_id = entry.proto_id;
+                    self.retrans = None;
+                    return RetransAction::Failed(proto_id);
+                }
+            }
+            _ => return RetransAction::None,
+        }
+
+        // We are guaranteed this unwrap will work
+        let entry = self.retrans.as_mut().unwrap();
+        entry.send_count += 1;
+        entry.next_tx = now + backoff_time(entry.base_interval, entry.send_count);
+        info!(
+            "Retransmitting message {}, attempt {}",
+            entry.msg_ctr, entry.send_count
+        );
+        RetransAction::Retransmit(&entry.data, entry.peer)
+    }
+
     pub fn prepare_ack(_exch_id: u16, proto_tx: &mut Packet) {
         secure_channel::common::create_mrp_standalone_ack(proto_tx);
     }
@@ -113,20 +260,21 @@ impl ReliableMessage {
             self.ack = None;
         }
 
-        if !proto_tx.is_reliable() {
-            return Ok(());
-        }
-
-        if self.retrans.is_some() {
+        if proto_tx.is_reliable() && self.retrans.is_some() {
             // This indicates there was some existing entry for same sess-id/exch-id, which shouldnt happen
             error!("Previous retrans entry for this exchange already exists");
             return Err(Error::Invalid);
         }
-
-        self.retrans = Some(RetransEntry::new(proto_tx.plain.ctr));
         Ok(())
     }
 
+    /// Keep the message that was just sent, until it is acknowledged
+    pub fn post_send(&mut self, proto_tx: &mut Packet, base_interval: Duration) {
+        if proto_tx.is_reliable() {
+            self.retrans = Some(RetransEntry::new(proto_tx, base_interval));
+        }
+    }
+
     /* A note about Message ACKs, it is a bit asymmetric in the sense that:
      * -  there can be only one pending ACK per exchange (so this is per-exchange)
      * -  there can be only one pending retransmission per exchange (so this is per-exchange)
@@ -158,3 +306,87 @@ impl ReliableMessage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::{
+        tlv::{get_root_node_struct, TLVWriter, TagType},
+        utils::writebuf::WriteBuf,
+    };
+
+    #[test]
+    fn test_backoff_time() {
+        let base = Duration::from_millis(300);
+        // The first retransmissions don't back off, only the margin and the jitter apply
+        for send_count in 1..=2 {
+            let t = backoff_time(base, send_count);
+            assert!(t >= Duration::from_millis(330));
+            assert!(t <= Duration::from_millis(413));
+        }
+        let t = backoff_time(base, 4);
+        assert!(t >= Duration::from_micros(844_800));
+        assert!(t <= Duration::from_micros(1_056_000));
+    }
+
+    #[test]
+    fn test_retrans_gives_up() {
+        let mut mrp = ReliableMessage::new();
+        let mut now = SystemTime::now();
+        mrp.retrans = Some(RetransEntry {
+            msg_ctr: 1,
+            proto_id: 1,
+            data: vec![1, 2, 3],
+            peer: Address::default(),
+            base_interval: Duration::from_millis(300),
+            send_count: 1,
+            next_tx: now,
+        });
+
+        for _ in 1..MRP_MAX_TRANSMISSIONS {
+            match mrp.retrans_action(now) {
+                RetransAction::Retransmit(data, _) => assert_eq!(data, &[1, 2, 3]),
+                _ => panic!("Expected a retransmission"),
+            }
+            // Nothing is due until the backoff time has lapsed
+            assert!(matches!(mrp.retrans_action(now), RetransAction::None));
+            now = mrp.get_next_timeout().unwrap();
+        }
+        assert!(matches!(mrp.retrans_action(now), RetransAction::Failed(1)));
+        assert!(mrp.is_empty());
+    }
+
+    #[test]
+    fn test_mrp_params() {
+        let mut buf = [0; 20];
+        let mut wb = WriteBuf::new(&mut buf, 20);
+        let mut tw = TLVWriter::new(&mut wb);
+        MrpParams::local()
+            .to_tlv(&mut tw, TagType::Anonymous)
+            .unwrap();
+        let root = get_root_node_struct(wb.as_slice()).unwrap();
+        assert_eq!(MrpParams::from_tlv(&root).unwrap(), MrpParams::local());
+
+        // The defaults apply to the intervals that the peer didn't give us
+        let params = MrpParams {
+            idle_interval: Some(1000),
+            active_interval: None,
+            active_threshold: None,
+        };
+        assert_eq!(params.idle_interval(), Duration::from_millis(1000));
+        assert_eq!(params.active_interval(), Duration::from_millis(300));
+        assert_eq!(params.active_threshold(), Duration::from_millis(4000));
+
+        // The intervals are kept within bounds
+        let params = MrpParams {
+            idle_interval: Some(u32::MAX),
+            active_interval: Some(0),
+            active_threshold: Some(500),
+        };
+        assert_eq!(params.idle_interval(), Duration::from_secs(3600));
+        assert_eq!(params.active_interval(), Duration::from_millis(100));
+        assert_eq!(params.active_threshold(), Duration::from_millis(500));
+    }
+}
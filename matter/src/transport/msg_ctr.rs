@@ -0,0 +1,321 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The message counters: the global counters of the unencrypted and the group messages, and
+//! the detection of the duplicate messages that we receive.
+
+use std::sync::Arc;
+
+use log::{error, info};
+use rand::Rng;
+
+use crate::{error::Error, persist::KvStorage};
+
+/// The number of counters behind the largest received one, that are tracked to detect the
+/// duplicates
+pub const MSG_CTR_WINDOW_SIZE: u32 = 32;
+// The counters start at a random value up to this
+const MSG_CTR_INIT_RANGE: u32 = 0x0fff_ffff;
+// How far ahead of the counter in use the persisted value is. This is the number of
+// counters that are lost on a reboot, and how often the counter is persisted.
+const MSG_CTR_EPOCH: u32 = 1000;
+
+pub const UNENCRYPTED_MSG_CTR_KEY: &str = "unenc_ctr";
+pub const GROUP_DATA_MSG_CTR_KEY: &str = "grp_data_ctr";
+
+/// A random initial value for a message counter
+pub fn random_msg_ctr() -> u32 {
+    rand::thread_rng().gen_range(1..=MSG_CTR_INIT_RANGE)
+}
+
+/// A message counter that is shared by all the peers, like the ones of the unencrypted and
+/// the group messages
+///
+/// A persisted counter stores a value one epoch ahead of the counter in use, and moves
+/// that ahead once the counter catches up with it. After a reboot, the counter resumes
+/// from the stored value, so that it never goes back to the counters used before.
+pub struct GlobalCtr {
+    next: u32,
+    // The counter can go up to this value, before the next epoch has to be persisted
+    limit: u32,
+    persist: Option<(Arc<dyn KvStorage>, &'static str)>,
+}
+
+impl Default for GlobalCtr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GlobalCtr {
+    /// A counter that starts at a random value, and isn't persisted
+    pub fn new() -> Self {
+        let next = random_msg_ctr();
+        Self {
+            next,
+            limit: next,
+            persist: None,
+        }
+    }
+
+    /// A counter that resumes from the value persisted under the given key, or starts at a
+    /// random value if there is none
+    pub fn new_persisted(storage: Arc<dyn KvStorage>, key: &'static str) -> Result<Self, Error> {
+        let mut stored = 0;
+        let next = match storage.get_kv_u64(key, &mut stored) {
+            Ok(()) => {
+                info!("Resuming the message counter {} from {}", key, stored);
+                stored as u32
+            }
+            Err(Error::NotFound) => random_msg_ctr(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            next,
+            limit: next,
+            persist: Some((storage, key)),
+        })
+    }
+
+    /// The next counter. This fails if the counter has caught up with the persisted value,
+    /// and the next epoch can't be persisted, as the counter could then go back after a
+    /// reboot.
+    pub fn get_msg_ctr(&mut self) -> Result<u32, Error> {
+        if let Some((storage, key)) = &self.persist {
+            // The counter wraps around, anything less than half the range behind the limit
+            // has reached it
+            if self.next.wrapping_sub(self.limit) < 1 << 31 {
+                let limit = self.next.wrapping_add(MSG_CTR_EPOCH);
+                if let Err(e) = storage.set_kv_u64(key, limit as u64) {
+                    error!("Error in persisting the message counter: {:?}", e);
+                    return Err(e);
+                }
+                self.limit = limit;
+            }
+        }
+        let ctr = self.next;
+        self.next = self.next.wrapping_add(1);
+        Ok(ctr)
+    }
+}
+
+/// The message counters that were received from a peer: the largest one, and a bitmap of
+/// the ones in the window behind it
+///
+/// The first counter received from the peer is trusted, the ones that follow are checked
+/// against it.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RxCtrState {
+    max: Option<u32>,
+    // Bit n is set if the counter max - (n + 1) was received
+    bitmap: u32,
+}
+
+impl RxCtrState {
+    /// Check the counter of a message on a secure unicast session, the counters of which
+    /// never roll over. Returns [Error::Duplicate] if the counter was already received, or is
+    /// behind the window.
+    pub fn recv_encrypted(&mut self, ctr: u32) -> Result<(), Error> {
+        self.recv(ctr, false, false)
+    }
+
+    /// Check the counter of a group message. The counters roll over, and the ones behind
+    /// the window are duplicates.
+    pub fn recv_group(&mut self, ctr: u32) -> Result<(), Error> {
+        self.recv(ctr, true, false)
+    }
+
+    /// Check the counter of an unencrypted message. The counters roll over, and one behind
+    /// the window means that the peer has started afresh, like after a reboot.
+    pub fn recv_unencrypted(&mut self, ctr: u32) -> Result<(), Error> {
+        self.recv(ctr, true, true)
+    }
+
+    fn recv(&mut self, ctr: u32, rollover: bool, reset: bool) -> Result<(), Error> {
+        let max = match self.max {
+            Some(max) => max,
+            None => return self.reset(ctr),
+        };
+        let ahead = if rollover {
+            let delta = ctr.wrapping_sub(max);
+            delta != 0 && delta < 1 << 31
+        } else {
+            ctr > max
+        };
+        if ahead {
+            let shift = ctr.wrapping_sub(max) as u64;
+            self.bitmap = if shift > MSG_CTR_WINDOW_SIZE as u64 {
+                0
+            } else {
+                (((self.bitmap as u64) << shift) | (1 << (shift - 1))) as u32
+            };
+            self.max = Some(ctr);
+            return Ok(());
+        }
+
+        let behind = max.wrapping_sub(ctr);
+        if behind == 0 {
+            Err(Error::Duplicate)
+        } else if behind <= MSG_CTR_WINDOW_SIZE {
+            let bit = 1 << (behind - 1);
+            if self.bitmap & bit != 0 {
+                Err(Error::Duplicate)
+            } else {
+                self.bitmap |= bit;
+                Ok(())
+            }
+        } else if reset {
+            self.reset(ctr)
+        } else {
+            Err(Error::Duplicate)
+        }
+    }
+
+    fn reset(&mut self, ctr: u32) -> Result<(), Error> {
+        self.max = Some(ctr);
+        self.bitmap = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::persist::MemKvStorage;
+
+    #[test]
+    fn test_encrypted() {
+        let mut s = RxCtrState::default();
+        // The first counter is trusted
+        assert_eq!(s.recv_encrypted(100), Ok(()));
+        assert_eq!(s.recv_encrypted(100), Err(Error::Duplicate));
+        assert_eq!(s.recv_encrypted(102), Ok(()));
+        // Out of order, but in the window
+        assert_eq!(s.recv_encrypted(101), Ok(()));
+        assert_eq!(s.recv_encrypted(101), Err(Error::Duplicate));
+        assert_eq!(s.recv_encrypted(102), Err(Error::Duplicate));
+
+        // The window moves along
+        assert_eq!(s.recv_encrypted(134), Ok(()));
+        assert_eq!(s.recv_encrypted(102), Err(Error::Duplicate));
+        assert_eq!(s.recv_encrypted(103), Ok(()));
+        assert_eq!(s.recv_encrypted(103), Err(Error::Duplicate));
+        // Behind the window
+        assert_eq!(s.recv_encrypted(101), Err(Error::Duplicate));
+
+        // A jump past the window forgets it
+        assert_eq!(s.recv_encrypted(1000), Ok(()));
+        assert_eq!(s.recv_encrypted(999), Ok(()));
+        assert_eq!(s.recv_encrypted(134), Err(Error::Duplicate));
+
+        // No rolling over
+        let mut s = RxCtrState::default();
+        assert_eq!(s.recv_encrypted(u32::MAX), Ok(()));
+        assert_eq!(s.recv_encrypted(0), Err(Error::Duplicate));
+    }
+
+    #[test]
+    fn test_rollover() {
+        let mut s = RxCtrState::default();
+        assert_eq!(s.recv_group(u32::MAX - 1), Ok(()));
+        assert_eq!(s.recv_group(1), Ok(()));
+        assert_eq!(s.recv_group(u32::MAX), Ok(()));
+        assert_eq!(s.recv_group(u32::MAX), Err(Error::Duplicate));
+        assert_eq!(s.recv_group(0), Ok(()));
+        // Behind the window
+        assert_eq!(s.recv_group(u32::MAX - 100), Err(Error::Duplicate));
+
+        let mut s = RxCtrState::default();
+        assert_eq!(s.recv_unencrypted(1), Ok(()));
+        assert_eq!(s.recv_unencrypted(u32::MAX), Ok(()));
+        assert_eq!(s.recv_unencrypted(1), Err(Error::Duplicate));
+        // The peer started afresh
+        assert_eq!(s.recv_unencrypted(u32::MAX - 100), Ok(()));
+        assert_eq!(s.recv_unencrypted(u32::MAX - 100), Err(Error::Duplicate));
+        assert_eq!(s.recv_unencrypted(u32::MAX - 99), Ok(()));
+    }
+
+    #[test]
+    fn test_global_ctr() {
+        let storage: Arc<dyn KvStorage> = Arc::new(MemKvStorage::new());
+        let mut ctr = GlobalCtr::new_persisted(storage.clone(), UNENCRYPTED_MSG_CTR_KEY).unwrap();
+        let first = ctr.get_msg_ctr().unwrap();
+        for i in 1..MSG_CTR_EPOCH + 10 {
+            assert_eq!(ctr.get_msg_ctr(), Ok(first.wrapping_add(i)));
+        }
+        let last = first.wrapping_add(MSG_CTR_EPOCH + 9);
+
+        // After a reboot, the counter is ahead of the ones used before
+        let mut ctr = GlobalCtr::new_persisted(storage, UNENCRYPTED_MSG_CTR_KEY).unwrap();
+        let next = ctr.get_msg_ctr().unwrap();
+        assert!(next.wrapping_sub(last) > 0 && next.wrapping_sub(last) <= MSG_CTR_EPOCH);
+    }
+
+    // A storage that fails the writes, while told to
+    struct FlakyStorage {
+        inner: MemKvStorage,
+        fail: AtomicBool,
+    }
+
+    impl KvStorage for FlakyStorage {
+        fn set_kv_slice(&self, key: &str, val: &[u8]) -> Result<(), Error> {
+            if self.fail.load(Ordering::Relaxed) {
+                return Err(Error::StdIoError);
+            }
+            self.inner.set_kv_slice(key, val)
+        }
+
+        fn get_kv_slice(&self, key: &str, val: &mut Vec<u8>) -> Result<usize, Error> {
+            self.inner.get_kv_slice(key, val)
+        }
+
+        fn remove_kv(&self, key: &str) -> Result<(), Error> {
+            self.inner.remove_kv(key)
+        }
+    }
+
+    #[test]
+    fn test_global_ctr_persist_failure() {
+        let storage = Arc::new(FlakyStorage {
+            inner: MemKvStorage::new(),
+            fail: AtomicBool::new(false),
+        });
+        // The epoch wraps around
+        storage
+            .set_kv_u64(GROUP_DATA_MSG_CTR_KEY, (u32::MAX - 10) as u64)
+            .unwrap();
+        let mut ctr = GlobalCtr::new_persisted(storage.clone(), GROUP_DATA_MSG_CTR_KEY).unwrap();
+        for i in 0..MSG_CTR_EPOCH {
+            assert_eq!(ctr.get_msg_ctr(), Ok((u32::MAX - 10).wrapping_add(i)));
+        }
+
+        // No counter goes beyond the persisted value, until the next epoch is persisted
+        storage.fail.store(true, Ordering::Relaxed);
+        assert_eq!(ctr.get_msg_ctr(), Err(Error::StdIoError));
+        assert_eq!(ctr.get_msg_ctr(), Err(Error::StdIoError));
+        storage.fail.store(false, Ordering::Relaxed);
+        let next = (u32::MAX - 10).wrapping_add(MSG_CTR_EPOCH);
+        assert_eq!(ctr.get_msg_ctr(), Ok(next));
+        let mut stored = 0;
+        storage
+            .get_kv_u64(GROUP_DATA_MSG_CTR_KEY, &mut stored)
+            .unwrap();
+        assert_eq!(stored, next.wrapping_add(MSG_CTR_EPOCH) as u64);
+    }
+}
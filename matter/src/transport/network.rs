@@ -16,20 +16,58 @@
  */
 
 use std::{
+    ffi::CString,
     fmt::{Debug, Display},
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    future::Future,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    pin::Pin,
+    time::Duration,
 };
 
+use log::error;
+use smol::future;
+
 use crate::error::Error;
 
+use super::{
+    tcp::MAX_TCP_MSG_SIZE,
+    udp::{MATTER_PORT, MAX_RX_BUF_SIZE},
+};
+
+// The max time we block waiting for a packet, this allows the transport to periodically
+// service other activities (like subscription reports) even if the network is quiet
+pub const RECV_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum Address {
     Udp(SocketAddr),
+    /// The peer of a TCP connection. Each connection has a peer address of its own, so the
+    /// sessions established over a connection are bound to it.
+    Tcp(SocketAddr),
+}
+
+impl Address {
+    /// Whether the transport to this address takes care of the delivery of the messages,
+    /// in which case the Message Reliability Protocol isn't used
+    pub fn is_reliable(&self) -> bool {
+        matches!(self, Address::Tcp(_))
+    }
+
+    /// The largest message that can be exchanged with this address
+    pub fn max_msg_size(&self) -> usize {
+        match self {
+            Address::Udp(_) => MAX_RX_BUF_SIZE,
+            Address::Tcp(_) => MAX_TCP_MSG_SIZE,
+        }
+    }
 }
 
 impl Default for Address {
     fn default() -> Self {
-        Address::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080))
+        Address::Udp(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            MATTER_PORT,
+        ))
     }
 }
 
@@ -37,6 +75,7 @@ impl Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Address::Udp(addr) => writeln!(f, "{}", addr),
+            Address::Tcp(addr) => writeln!(f, "tcp:{}", addr),
         }
     }
 }
@@ -45,11 +84,166 @@ impl Debug for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Address::Udp(addr) => writeln!(f, "{}", addr),
+            Address::Tcp(addr) => writeln!(f, "tcp:{}", addr),
         }
     }
 }
 
+/// The future returned by [NetworkInterface::wait_recv]
+pub type RecvReady<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+
 pub trait NetworkInterface {
+    /// Receive a message, this blocks for at most [RECV_POLL_TIMEOUT], after which it
+    /// fails with [Error::Timeout]
     fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error>;
+    /// Receive a message that has already arrived, without blocking. If there is none, this
+    /// fails with [Error::Timeout] straight away.
+    fn try_recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error>;
     fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error>;
+    /// Wait until a packet can be received with [try_recv](NetworkInterface::try_recv)
+    fn wait_recv(&self) -> RecvReady<'_>;
+    /// Whether messages to this address are sent through this interface
+    fn is_for(&self, _addr: &Address) -> bool {
+        true
+    }
+    /// The largest message that can be received through this interface
+    fn max_msg_size(&self) -> usize {
+        MAX_RX_BUF_SIZE
+    }
+    /// The peers whose connections were closed since the last call. The sessions with these
+    /// peers are gone along with the connections.
+    fn take_closed(&self) -> Vec<Address> {
+        Vec::new()
+    }
+    /// Receive the messages to this multicast address, like the ones to a group. Only the
+    /// interfaces that can receive multicast messages take care of this.
+    fn join_multicast(&self, _addr: &Ipv6Addr) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Stop receiving the messages to this multicast address
+    fn leave_multicast(&self, _addr: &Ipv6Addr) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Wait until any of the futures is ready
+pub fn wait_any<'a, T: 'a>(
+    waits: impl IntoIterator<Item = Pin<Box<dyn Future<Output = T> + 'a>>>,
+) -> Pin<Box<dyn Future<Output = T> + 'a>> {
+    waits
+        .into_iter()
+        .reduce(|a, b| Box::pin(future::or(a, b)))
+        .unwrap_or_else(|| Box::pin(future::pending()))
+}
+
+/// The configuration of the network transport
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// The local address to bind to. The default, the IPv6 unspecified address, listens on
+    /// all the interfaces.
+    pub bind_addr: IpAddr,
+    /// The port to listen on. A port of 0 picks any free port.
+    pub port: u16,
+    /// Restrict the transport to the network interface with this name, like "eth0". This
+    /// is also the interface whose scope id qualifies an IPv6 link-local bind address.
+    pub interface: Option<String>,
+    /// With an IPv6 bind address, also accept IPv4 peers, as IPv4-mapped addresses
+    ///
+    /// On the hosts that have IPv6 disabled, the default IPv6 unspecified bind address falls
+    /// back to the IPv4 unspecified address, so only the IPv4 peers are reached.
+    pub dual_stack: bool,
+    /// Also accept TCP connections on the same port, for the messages that are too large
+    /// for UDP
+    pub tcp: bool,
+    /// Close the sessions that have seen no traffic for this long, like
+    /// [SESSION_IDLE_TIMEOUT](super::session::SESSION_IDLE_TIMEOUT). None, the default,
+    /// keeps them until they are evicted or closed by the peer.
+    ///
+    /// A session with a subscription only sees traffic with the subscription's reports, so
+    /// this has to be longer than the max interval of the subscriptions.
+    pub session_idle_timeout: Option<Duration>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: MATTER_PORT,
+            interface: None,
+            dual_stack: true,
+            tcp: false,
+            session_idle_timeout: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// The socket address to bind to
+    pub fn socket_addr(&self) -> Result<SocketAddr, Error> {
+        match self.bind_addr {
+            IpAddr::V4(ip) => Ok(SocketAddr::new(IpAddr::V4(ip), self.port)),
+            IpAddr::V6(ip) => {
+                // A link-local address is only unique on its link, so it needs the interface
+                let scope_id = if is_link_local(&ip) {
+                    let interface = self.interface.as_ref().ok_or_else(|| {
+                        error!("The link-local address {} needs an interface", ip);
+                        Error::Invalid
+                    })?;
+                    get_interface_index(interface)?
+                } else {
+                    0
+                };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip, self.port, 0, scope_id,
+                )))
+            }
+        }
+    }
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// The index of the network interface with the given name
+pub fn get_interface_index(name: &str) -> Result<u32, Error> {
+    let c_name = CString::new(name).map_err(|_| Error::Invalid)?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => {
+            error!("No network interface named {}", name);
+            Err(Error::Invalid)
+        }
+        index => Ok(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_local() {
+        let mut config = TransportConfig {
+            bind_addr: "fe80::1".parse().unwrap(),
+            port: 5541,
+            ..Default::default()
+        };
+        // A link-local address is meaningless without an interface
+        assert_eq!(config.socket_addr(), Err(Error::Invalid));
+
+        config.interface = Some("lo".to_string());
+        match config.socket_addr() {
+            Ok(SocketAddr::V6(a)) => {
+                assert_eq!(a.scope_id(), get_interface_index("lo").unwrap());
+                assert_eq!(a.port(), 5541);
+            }
+            a => panic!("Unexpected address {:?}", a),
+        }
+
+        config.bind_addr = "2001:db8::1".parse().unwrap();
+        match config.socket_addr() {
+            Ok(SocketAddr::V6(a)) => assert_eq!(a.scope_id(), 0),
+            a => panic!("Unexpected address {:?}", a),
+        }
+    }
 }
@@ -16,9 +16,7 @@
  */
 
 use log::{error, trace};
-use std::sync::Mutex;
-
-use boxslab::box_slab;
+use std::sync::{Arc, Mutex};
 
 use crate::{
     error::Error,
@@ -30,55 +28,87 @@ use super::{
     network::Address,
     plain_hdr::{self, PlainHdr},
     proto_hdr::{self, ProtoHdr},
+    session::GroupDetails,
 };
 
 pub const MAX_RX_BUF_SIZE: usize = 1583;
-type Buffer = [u8; MAX_RX_BUF_SIZE];
 
-// TODO: I am not very happy with this construction, need to find another way to do this
-pub struct BufferPool {
-    buffers: [Option<Buffer>; MAX_PACKET_POOL_SIZE],
+struct BufferPoolInner {
+    // A buffer is allocated on its first use, and then reused. It grows as needed, for the
+    // larger messages of the stream transports.
+    buffers: [Option<Vec<u8>>; MAX_PACKET_POOL_SIZE],
+    in_use: [bool; MAX_PACKET_POOL_SIZE],
 }
 
-impl BufferPool {
-    const INIT: Option<Buffer> = None;
-    fn get() -> &'static Mutex<BufferPool> {
-        static mut BUFFER_HOLDER: Option<Mutex<BufferPool>> = None;
-        static ONCE: Once = Once::new();
-        unsafe {
-            ONCE.call_once(|| {
-                BUFFER_HOLDER = Some(Mutex::new(BufferPool {
-                    buffers: [BufferPool::INIT; MAX_PACKET_POOL_SIZE],
-                }));
-            });
-            BUFFER_HOLDER.as_ref().unwrap()
-        }
+/// The pool of the buffers for the packets
+///
+/// Each transport has a pool of its own, so that the Matter instances in a process don't
+/// compete for the packets. Cloning the pool gives another handle to the same pool.
+#[derive(Clone)]
+pub struct PacketPool(Arc<Mutex<BufferPoolInner>>);
+
+impl PacketPool {
+    const INIT: Option<Vec<u8>> = None;
+
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(BufferPoolInner {
+            buffers: [Self::INIT; MAX_PACKET_POOL_SIZE],
+            in_use: [false; MAX_PACKET_POOL_SIZE],
+        })))
+    }
+
+    /// Allocate a packet for receiving
+    pub fn alloc_rx(&self) -> Result<Box<Packet<'static>>, Error> {
+        self.alloc_rx_with_size(MAX_RX_BUF_SIZE)
+    }
+
+    /// Allocate a packet for sending
+    pub fn alloc_tx(&self) -> Result<Box<Packet<'static>>, Error> {
+        self.alloc_tx_with_size(MAX_RX_BUF_SIZE)
+    }
+
+    /// Allocate a packet for receiving messages of up to the given size
+    pub fn alloc_rx_with_size(&self, size: usize) -> Result<Box<Packet<'static>>, Error> {
+        Ok(Box::new(Packet::new_rx(self, size)?))
     }
 
-    pub fn alloc() -> Option<(usize, &'static mut Buffer)> {
+    /// Allocate a packet for sending messages of up to the given size
+    pub fn alloc_tx_with_size(&self, size: usize) -> Result<Box<Packet<'static>>, Error> {
+        Ok(Box::new(Packet::new_tx(self, size)?))
+    }
+
+    fn alloc(&self, size: usize) -> Option<(usize, &'static mut [u8])> {
         trace!("Buffer Alloc called\n");
 
-        let mut pool = BufferPool::get().lock().unwrap();
-        for i in 0..MAX_PACKET_POOL_SIZE {
-            if pool.buffers[i].is_none() {
-                pool.buffers[i] = Some([0; MAX_RX_BUF_SIZE]);
-                // Sigh! to by-pass the borrow-checker telling us we are stealing a mutable reference
-                // from under the lock
-                // In this case the lock only protects against the setting of Some/None,
-                // the objects then are independently accessed in a unique way
-                let buffer = unsafe { &mut *(pool.buffers[i].as_mut().unwrap() as *mut Buffer) };
-                return Some((i, buffer));
-            }
+        let mut pool = self.0.lock().unwrap();
+        let i = pool.in_use.iter().position(|u| !u)?;
+        pool.in_use[i] = true;
+        let buffer = pool.buffers[i].get_or_insert_with(Vec::new);
+        if buffer.len() < size {
+            buffer.resize(size, 0);
         }
-        None
+        let buffer = &mut buffer[..size];
+        buffer.fill(0);
+        // Sigh! to by-pass the borrow-checker telling us we are stealing a mutable reference
+        // from under the lock
+        // In this case the lock only protects against the setting of in_use, the buffers
+        // then are independently accessed in a unique way. The buffer lives on the heap, it
+        // is only resized while it isn't in use, and is only freed along with the pool, which
+        // the packet holds on to.
+        let buffer = unsafe { &mut *(buffer as *mut [u8]) };
+        Some((i, buffer))
     }
 
-    pub fn free(index: usize) {
+    fn free(&self, index: usize) {
         trace!("Buffer Free called\n");
-        let mut pool = BufferPool::get().lock().unwrap();
-        if pool.buffers[index].is_some() {
-            pool.buffers[index] = None;
-        }
+        let mut pool = self.0.lock().unwrap();
+        pool.in_use[index] = false;
+    }
+}
+
+impl Default for PacketPool {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -98,37 +128,44 @@ pub struct Packet<'a> {
     pub plain: PlainHdr,
     pub proto: ProtoHdr,
     pub peer: Address,
+    // The group, and its fabric, of a group message that was decrypted
+    pub group: Option<GroupDetails>,
     data: Direction<'a>,
     buffer_index: usize,
+    pool: PacketPool,
 }
 
 impl<'a> Packet<'a> {
     const HDR_RESERVE: usize = plain_hdr::max_plain_hdr_len() + proto_hdr::max_proto_hdr_len();
 
-    pub fn new_rx() -> Result<Self, Error> {
-        let (buffer_index, buffer) = BufferPool::alloc().ok_or(Error::NoSpace)?;
+    fn new_rx(pool: &PacketPool, size: usize) -> Result<Packet<'static>, Error> {
+        let (buffer_index, buffer) = pool.alloc(size).ok_or(Error::PacketPoolExhaust)?;
         let buf_len = buffer.len();
-        Ok(Self {
+        Ok(Packet {
             plain: Default::default(),
             proto: Default::default(),
             buffer_index,
+            pool: pool.clone(),
             peer: Address::default(),
+            group: None,
             data: Direction::Rx(ParseBuf::new(buffer, buf_len), RxState::Uninit),
         })
     }
 
-    pub fn new_tx() -> Result<Self, Error> {
-        let (buffer_index, buffer) = BufferPool::alloc().ok_or(Error::NoSpace)?;
+    fn new_tx(pool: &PacketPool, size: usize) -> Result<Packet<'static>, Error> {
+        let (buffer_index, buffer) = pool.alloc(size).ok_or(Error::PacketPoolExhaust)?;
         let buf_len = buffer.len();
 
         let mut wb = WriteBuf::new(buffer, buf_len);
         wb.reserve(Packet::HDR_RESERVE)?;
 
-        let mut p = Self {
+        let mut p = Packet {
             plain: Default::default(),
             proto: Default::default(),
             buffer_index,
+            pool: pool.clone(),
             peer: Address::default(),
+            group: None,
             data: Direction::Tx(wb),
         };
         // Reliability on by default
@@ -203,6 +240,34 @@ impl<'a> Packet<'a> {
         }
     }
 
+    /// Decrypt and decode a group message with the first of the keys that it is secured
+    /// with, returns the fabric index of that key
+    pub fn proto_decode_group(
+        &mut self,
+        src_nodeid: u64,
+        keys: &[(u8, [u8; 16])],
+    ) -> Result<u8, Error> {
+        match &mut self.data {
+            Direction::Rx(pb, state) => {
+                if *state == RxState::PlainDecode {
+                    *state = RxState::ProtoDecode;
+                    let key_refs: Vec<&[u8]> = keys.iter().map(|(_, k)| &k[..]).collect();
+                    let index = self.proto.decrypt_any_and_decode(
+                        &self.plain,
+                        pb,
+                        src_nodeid,
+                        &key_refs,
+                    )?;
+                    Ok(keys[index].0)
+                } else {
+                    error!("Invalid state for proto_decode");
+                    Err(Error::InvalidState)
+                }
+            }
+            _ => Err(Error::InvalidState),
+        }
+    }
+
     pub fn is_plain_hdr_decoded(&self) -> Result<bool, Error> {
         match &self.data {
             Direction::Rx(_, state) => match state {
@@ -231,9 +296,36 @@ impl<'a> Packet<'a> {
 
 impl<'a> Drop for Packet<'a> {
     fn drop(&mut self) {
-        BufferPool::free(self.buffer_index);
+        self.pool.free(self.buffer_index);
         trace!("Dropping Packet......");
     }
 }
 
-box_slab!(PacketPool, Packet<'static>, MAX_PACKET_POOL_SIZE);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_per_instance() {
+        let pool1 = PacketPool::new();
+        let pool2 = PacketPool::new();
+        let mut packets = Vec::new();
+        for _ in 0..MAX_PACKET_POOL_SIZE {
+            packets.push(pool1.alloc_tx().unwrap());
+        }
+        assert!(pool1.alloc_rx().is_err());
+        // The other pool is unaffected
+        assert!(pool2.alloc_rx().is_ok());
+
+        // A packet that is dropped is back in its pool
+        packets.pop();
+        let mut large = pool1.alloc_rx_with_size(MAX_RX_BUF_SIZE * 4).unwrap();
+        assert_eq!(large.as_borrow_slice().len(), MAX_RX_BUF_SIZE * 4);
+        drop(large);
+        // The buffer that grew still gives a packet of the default size
+        assert_eq!(
+            pool1.alloc_rx().unwrap().as_borrow_slice().len(),
+            MAX_RX_BUF_SIZE
+        );
+    }
+}
@@ -19,12 +19,13 @@ use crate::error::*;
 use crate::utils::parsebuf::ParseBuf;
 use crate::utils::writebuf::WriteBuf;
 use bitflags::bitflags;
-use log::info;
+use log::{error, info};
 
 #[derive(Debug, PartialEq)]
 pub enum SessionType {
     None,
     Encrypted,
+    Group,
 }
 
 impl Default for SessionType {
@@ -42,6 +43,11 @@ bitflags! {
     }
 }
 
+// The security flags
+const SEC_FLAG_PRIVACY: u8 = 0x80;
+const SEC_FLAG_SESS_TYPE_MASK: u8 = 0x03;
+const SEC_FLAG_SESS_TYPE_GROUP: u8 = 0x01;
+
 // This is the unencrypted message
 #[derive(Debug, Default)]
 pub struct PlainHdr {
@@ -50,6 +56,7 @@ pub struct PlainHdr {
     pub sess_id: u16,
     pub ctr: u32,
     peer_nodeid: Option<u64>,
+    dest_group: Option<u16>,
 }
 
 impl PlainHdr {
@@ -58,6 +65,23 @@ impl PlainHdr {
         self.peer_nodeid = Some(id);
     }
 
+    /// Set the source node id, this is for the group messages, that have a group as the
+    /// destination instead of a node
+    pub fn set_src_u64(&mut self, id: u64) {
+        self.flags |= MsgFlags::SRC_ADDR_PRESENT;
+        self.peer_nodeid = Some(id);
+    }
+
+    pub fn set_dest_group(&mut self, group_id: u16) {
+        self.flags |= MsgFlags::DSIZ_GROUPCAST_NODEID;
+        self.sess_type = SessionType::Group;
+        self.dest_group = Some(group_id);
+    }
+
+    pub fn get_dest_group(&self) -> Option<u16> {
+        self.dest_group
+    }
+
     pub fn get_src_u64(&self) -> Option<u64> {
         if self.flags.contains(MsgFlags::SRC_ADDR_PRESENT) {
             self.peer_nodeid
@@ -72,8 +96,15 @@ impl PlainHdr {
     pub fn decode(&mut self, msg: &mut ParseBuf) -> Result<(), Error> {
         self.flags = MsgFlags::from_bits(msg.le_u8()?).ok_or(Error::Invalid)?;
         self.sess_id = msg.le_u16()?;
-        let _sec_flags = msg.le_u8()?;
-        self.sess_type = if self.sess_id != 0 {
+        let sec_flags = msg.le_u8()?;
+        if sec_flags & SEC_FLAG_PRIVACY != 0 {
+            // The obfuscation of the headers isn't supported
+            error!("Dropping a message with privacy enabled");
+            return Err(Error::Invalid);
+        }
+        self.sess_type = if sec_flags & SEC_FLAG_SESS_TYPE_MASK == SEC_FLAG_SESS_TYPE_GROUP {
+            SessionType::Group
+        } else if self.sess_id != 0 {
             SessionType::Encrypted
         } else {
             SessionType::None
@@ -83,6 +114,18 @@ impl PlainHdr {
         if self.flags.contains(MsgFlags::SRC_ADDR_PRESENT) {
             self.peer_nodeid = Some(msg.le_u64()?);
         }
+        if self.flags.contains(MsgFlags::DSIZ_UNICAST_NODEID) {
+            // This is our node id
+            msg.le_u64()?;
+        } else if self.flags.contains(MsgFlags::DSIZ_GROUPCAST_NODEID) {
+            self.dest_group = Some(msg.le_u16()?);
+        }
+        if self.sess_type == SessionType::Group
+            && (self.dest_group.is_none() || self.peer_nodeid.is_none())
+        {
+            // A group message has to be from a node, to a group
+            return Err(Error::Invalid);
+        }
 
         info!(
             "[decode] flags: {:?}, session type: {:#?}, sess_id: {}, ctr: {}",
@@ -94,17 +137,33 @@ impl PlainHdr {
     pub fn encode(&mut self, resp_buf: &mut WriteBuf) -> Result<(), Error> {
         resp_buf.le_u8(self.flags.bits())?;
         resp_buf.le_u16(self.sess_id)?;
-        resp_buf.le_u8(0)?;
+        resp_buf.le_u8(self.get_sec_flags())?;
         resp_buf.le_u32(self.ctr)?;
         if let Some(d) = self.peer_nodeid {
             resp_buf.le_u64(d)?;
         }
+        if let Some(g) = self.dest_group {
+            resp_buf.le_u16(g)?;
+        }
         Ok(())
     }
 
+    /// The security flags, these are a part of the nonce of the encrypted messages
+    pub fn get_sec_flags(&self) -> u8 {
+        if self.sess_type == SessionType::Group {
+            SEC_FLAG_SESS_TYPE_GROUP
+        } else {
+            0
+        }
+    }
+
     pub fn is_encrypted(&self) -> bool {
         self.sess_type == SessionType::Encrypted
     }
+
+    pub fn is_group(&self) -> bool {
+        self.sess_type == SessionType::Group
+    }
 }
 
 pub const fn max_plain_hdr_len() -> usize {
@@ -123,3 +182,56 @@ pub const fn max_plain_hdr_len() -> usize {
     // [optional] destination node ID
         8
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_hdr() {
+        let mut buf = [0u8; max_plain_hdr_len()];
+        let buf_len = buf.len();
+        let mut wb = WriteBuf::new(&mut buf, buf_len);
+        let mut hdr = PlainHdr {
+            sess_id: 0x1234,
+            ctr: 10,
+            ..Default::default()
+        };
+        hdr.set_src_u64(0x1122_3344_5566_7788);
+        hdr.set_dest_group(0x0101);
+        hdr.encode(&mut wb).unwrap();
+        assert_eq!(
+            wb.as_borrow_slice(),
+            [
+                0x06, 0x34, 0x12, 0x01, 0x0a, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33,
+                0x22, 0x11, 0x01, 0x01
+            ]
+        );
+
+        let mut input = wb.as_borrow_slice().to_vec();
+        let input_len = input.len();
+        let mut pb = ParseBuf::new(&mut input, input_len);
+        let mut hdr = PlainHdr::default();
+        hdr.decode(&mut pb).unwrap();
+        assert!(hdr.is_group());
+        assert_eq!(hdr.sess_id, 0x1234);
+        assert_eq!(hdr.get_src_u64(), Some(0x1122_3344_5566_7788));
+        assert_eq!(hdr.get_dest_group(), Some(0x0101));
+        assert!(pb.as_borrow_slice().is_empty());
+    }
+
+    #[test]
+    fn test_invalid_group_hdr() {
+        // No source node id
+        let mut input = [0x02, 0x34, 0x12, 0x01, 0x0a, 0x00, 0x00, 0x00, 0x01, 0x01];
+        let input_len = input.len();
+        let mut pb = ParseBuf::new(&mut input, input_len);
+        assert_eq!(PlainHdr::default().decode(&mut pb), Err(Error::Invalid));
+
+        // Privacy
+        let mut input = [0x00, 0x34, 0x12, 0x80, 0x0a, 0x00, 0x00, 0x00];
+        let input_len = input.len();
+        let mut pb = ParseBuf::new(&mut input, input_len);
+        assert_eq!(PlainHdr::default().decode(&mut pb), Err(Error::Invalid));
+    }
+}
@@ -15,12 +15,12 @@
  *    limitations under the License.
  */
 
-use boxslab::BoxSlab;
+use log::error;
 
 use crate::error::*;
 
-use super::exchange::ExchangeCtx;
-use super::packet::PacketPool;
+use super::exchange::{Exchange, ExchangeCtx, ExchangeMgr};
+use super::packet::Packet;
 
 const MAX_PROTOCOLS: usize = 4;
 
@@ -38,16 +38,16 @@ pub struct ProtoCtx<'a> {
     /// This is the exchange context, that includes the exchange and the session
     pub exch_ctx: ExchangeCtx<'a>,
     /// This is the received buffer for this transaction
-    pub rx: BoxSlab<PacketPool>,
+    pub rx: Box<Packet<'static>>,
     /// This is the transmit buffer for this transaction
-    pub tx: BoxSlab<PacketPool>,
+    pub tx: Box<Packet<'static>>,
 }
 
 impl<'a> ProtoCtx<'a> {
     pub fn new(
         exch_ctx: ExchangeCtx<'a>,
-        rx: BoxSlab<PacketPool>,
-        tx: BoxSlab<PacketPool>,
+        rx: Box<Packet<'static>>,
+        tx: Box<Packet<'static>>,
     ) -> Self {
         Self { exch_ctx, rx, tx }
     }
@@ -61,6 +61,17 @@ pub trait HandleProto {
     fn handle_session_event(&self) -> Result<(), Error> {
         Ok(())
     }
+
+    /// Called periodically from the transport's main loop. Protocols that have to originate
+    /// messages of their own (instead of responding to a received message) can initiate
+    /// exchanges and send them out here
+    fn handle_periodic(&mut self, _exch_mgr: &mut ExchangeMgr) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when a message that this protocol sent wasn't acknowledged, even after all
+    /// the retransmissions. The exchange is closed once this returns.
+    fn handle_exchange_failure(&mut self, _exch: &mut Exchange) {}
 }
 
 impl Default for ProtoDemux {
@@ -92,4 +103,22 @@ impl ProtoDemux {
             .ok_or(Error::NoHandler)?
             .handle_proto_id(proto_ctx);
     }
+
+    pub fn exchange_failed(&mut self, proto_id: u16, exch: &mut Exchange) {
+        if let Some(Some(handler)) = self.proto_id_handlers.get_mut(proto_id as usize) {
+            handler.handle_exchange_failure(exch);
+        }
+    }
+
+    pub fn periodic(&mut self, exch_mgr: &mut ExchangeMgr) {
+        for handler in self.proto_id_handlers.iter_mut().flatten() {
+            if let Err(e) = handler.handle_periodic(exch_mgr) {
+                error!(
+                    "Error in periodic handling of proto id {}: {:?}",
+                    handler.get_proto_id(),
+                    e
+                );
+            }
+        }
+    }
 }
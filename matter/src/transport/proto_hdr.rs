@@ -102,9 +102,50 @@ impl ProtoHdr {
     ) -> Result<(), Error> {
         if let Some(d) = dec_key {
             // We decrypt only if the decryption key is valid
-            decrypt_in_place(plain_hdr.ctr, peer_nodeid, parsebuf, d)?;
+            decrypt_in_place(
+                plain_hdr.get_sec_flags(),
+                plain_hdr.ctr,
+                peer_nodeid,
+                parsebuf,
+                d,
+            )?;
         }
+        self.decode(parsebuf)
+    }
+
+    /// Decrypt the message with the first of the keys that it is secured with, and decode
+    /// it. Returns the index of that key.
+    ///
+    /// The group messages may be secured with any of the keys that have the group session id
+    /// of the message.
+    pub fn decrypt_any_and_decode(
+        &mut self,
+        plain_hdr: &plain_hdr::PlainHdr,
+        parsebuf: &mut ParseBuf,
+        peer_nodeid: u64,
+        keys: &[&[u8]],
+    ) -> Result<usize, Error> {
+        let cipher_text = parsebuf.as_borrow_slice().to_vec();
+        let mut result = Err(Error::NoSession);
+        for (i, key) in keys.iter().enumerate() {
+            result = decrypt_in_place(
+                plain_hdr.get_sec_flags(),
+                plain_hdr.ctr,
+                peer_nodeid,
+                parsebuf,
+                key,
+            );
+            if result.is_ok() {
+                self.decode(parsebuf)?;
+                return Ok(i);
+            }
+            // Restore the cipher text for the next key
+            parsebuf.as_borrow_slice().copy_from_slice(&cipher_text);
+        }
+        result.map(|_| 0)
+    }
 
+    fn decode(&mut self, parsebuf: &mut ParseBuf) -> Result<(), Error> {
         self.exch_flags = ExchFlags::from_bits(parsebuf.le_u8()?).ok_or(Error::Invalid)?;
         self.proto_opcode = parsebuf.le_u8()?;
         self.exch_id = parsebuf.le_u16()?;
@@ -163,17 +204,18 @@ impl fmt::Display for ProtoHdr {
     }
 }
 
-fn get_iv(recvd_ctr: u32, peer_nodeid: u64, iv: &mut [u8]) -> Result<(), Error> {
-    // The IV is the source address (64-bit) followed by the message counter (32-bit)
+fn get_iv(sec_flags: u8, recvd_ctr: u32, peer_nodeid: u64, iv: &mut [u8]) -> Result<(), Error> {
+    // The IV is the security flags, followed by the message counter (32-bit) and the source
+    // address (64-bit)
     let mut write_buf = WriteBuf::new(iv, iv.len());
-    // For some reason, this is 0 in the 'bypass' mode
-    write_buf.le_u8(0)?;
+    write_buf.le_u8(sec_flags)?;
     write_buf.le_u32(recvd_ctr)?;
     write_buf.le_u64(peer_nodeid)?;
     Ok(())
 }
 
 pub fn encrypt_in_place(
+    sec_flags: u8,
     send_ctr: u32,
     peer_nodeid: u64,
     plain_hdr: &[u8],
@@ -182,7 +224,7 @@ pub fn encrypt_in_place(
 ) -> Result<(), Error> {
     // IV
     let mut iv = [0_u8; crypto::AEAD_NONCE_LEN_BYTES];
-    get_iv(send_ctr, peer_nodeid, &mut iv)?;
+    get_iv(sec_flags, send_ctr, peer_nodeid, &mut iv)?;
 
     // Cipher Text
     let tag_space = [0u8; crypto::AEAD_MIC_LEN_BYTES];
@@ -202,27 +244,26 @@ pub fn encrypt_in_place(
 }
 
 fn decrypt_in_place(
+    sec_flags: u8,
     recvd_ctr: u32,
     peer_nodeid: u64,
     parsebuf: &mut ParseBuf,
     key: &[u8],
 ) -> Result<(), Error> {
     // AAD:
-    //    the unencrypted header of this packet
-    let mut aad = [0_u8; crypto::AEAD_AAD_LEN_BYTES];
+    //    the unencrypted header of this packet, this is variable sized in length
+    let mut aad = [0_u8; plain_hdr::max_plain_hdr_len()];
     let parsed_slice = parsebuf.parsed_as_slice();
-    if parsed_slice.len() == aad.len() {
-        // The plain_header is variable sized in length, I wonder if the AAD is fixed at 8, or the variable size.
-        // If so, we need to handle it cleanly here.
-        aad.copy_from_slice(parsed_slice);
-    } else {
+    if parsed_slice.len() < crypto::AEAD_AAD_LEN_BYTES || parsed_slice.len() > aad.len() {
         return Err(Error::InvalidAAD);
     }
+    let aad = &mut aad[..parsed_slice.len()];
+    aad.copy_from_slice(parsed_slice);
 
     // IV:
     //   the specific way for creating IV is in get_iv
     let mut iv = [0_u8; crypto::AEAD_NONCE_LEN_BYTES];
-    get_iv(recvd_ctr, peer_nodeid, &mut iv)?;
+    get_iv(sec_flags, recvd_ctr, peer_nodeid, &mut iv)?;
 
     let cipher_text = parsebuf.as_borrow_slice();
     //println!("AAD: {:x?}", aad);
@@ -230,7 +271,7 @@ fn decrypt_in_place(
     //println!("IV: {:x?}", iv);
     //println!("Key: {:x?}", key);
 
-    crypto::decrypt_in_place(key, &iv, &aad, cipher_text)?;
+    crypto::decrypt_in_place(key, &iv, aad, cipher_text)?;
     // println!("Plain Text: {:x?}", cipher_text);
     parsebuf.tail(crypto::AEAD_MIC_LEN_BYTES)?;
     Ok(())
@@ -277,7 +318,7 @@ mod tests {
         parsebuf.le_u32().unwrap();
         parsebuf.le_u32().unwrap();
 
-        decrypt_in_place(recvd_ctr, 0, &mut parsebuf, &key).unwrap();
+        decrypt_in_place(0, recvd_ctr, 0, &mut parsebuf, &key).unwrap();
         assert_eq!(
             parsebuf.as_slice(),
             [
@@ -311,7 +352,7 @@ mod tests {
             0x1b, 0x33,
         ];
 
-        encrypt_in_place(send_ctr, 0, &plain_hdr, &mut writebuf, &key).unwrap();
+        encrypt_in_place(0, send_ctr, 0, &plain_hdr, &mut writebuf, &key).unwrap();
         assert_eq!(
             writebuf.as_slice(),
             [
@@ -321,4 +362,38 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    pub fn test_group_decrypt() {
+        let mut main_buf = [0u8; 64];
+        // A group header: the source node id and the group id follow the message counter
+        let plain_hdr: [u8; 18] = [
+            0x6, 0x34, 0x12, 0x1, 0xa, 0x0, 0x0, 0x0, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22,
+            0x11, 0x1, 0x1,
+        ];
+        main_buf[..18].copy_from_slice(&plain_hdr);
+        let proto_hdr = [0x5, 0x8, 0x1, 0x0, 0x1, 0x0];
+        let mut payload = WriteBuf::new(&mut main_buf[18..], 46);
+        payload.append(&proto_hdr).unwrap();
+        let key = [0xaa; 16];
+        encrypt_in_place(1, 10, 0x1122334455667788, &plain_hdr, &mut payload, &key).unwrap();
+
+        let mut parsebuf = ParseBuf::new(&mut main_buf, 18 + 6 + crypto::AEAD_MIC_LEN_BYTES);
+        let mut plain = plain_hdr::PlainHdr::default();
+        plain.decode(&mut parsebuf).unwrap();
+        let mut proto = ProtoHdr::default();
+        // Only the second key is the right one
+        let wrong_key = [0xbb; 16];
+        assert_eq!(
+            proto.decrypt_any_and_decode(
+                &plain,
+                &mut parsebuf,
+                0x1122334455667788,
+                &[&wrong_key, &key]
+            ),
+            Ok(1)
+        );
+        assert_eq!(proto.proto_id, 1);
+        assert_eq!(proto.exch_id, 1);
+    }
 }
@@ -15,19 +15,20 @@
  *    limitations under the License.
  */
 
-use std::sync::Once;
-
 use async_channel::{bounded, Receiver, Sender};
 
 use crate::error::Error;
 
-use super::session::CloneData;
+use super::session::{CloneData, SessionInfo};
 
 #[derive(Debug)]
 pub enum Msg {
     Tx(),
     Rx(),
     NewSession(CloneData),
+    // Close the secure session with this local session id, and report back the result
+    CloseSession(u16, Sender<Result<(), Error>>),
+    ListSessions(Sender<Vec<SessionInfo>>),
 }
 
 #[derive(Clone)]
@@ -35,26 +36,11 @@ pub struct WorkQ {
     tx: Sender<Msg>,
 }
 
-static mut G_WQ: Option<WorkQ> = None;
-static INIT: Once = Once::new();
-
 impl WorkQ {
-    pub fn init() -> Result<Receiver<Msg>, Error> {
+    /// Create the work queue, along with the receiving end for the transport
+    pub fn new() -> (WorkQ, Receiver<Msg>) {
         let (tx, rx) = bounded::<Msg>(3);
-        WorkQ::configure(tx);
-        Ok(rx)
-    }
-
-    fn configure(tx: Sender<Msg>) {
-        unsafe {
-            INIT.call_once(|| {
-                G_WQ = Some(WorkQ { tx });
-            });
-        }
-    }
-
-    pub fn get() -> Result<WorkQ, Error> {
-        unsafe { G_WQ.as_ref().cloned().ok_or(Error::Invalid) }
+        (WorkQ { tx }, rx)
     }
 
     pub fn sync_send(&self, msg: Msg) -> Result<(), Error> {
@@ -18,22 +18,33 @@
 use core::fmt;
 use std::{
     any::Any,
+    future::Future,
+    net::Ipv6Addr,
     ops::{Deref, DerefMut},
-    time::SystemTime,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use crate::{
     error::*,
+    fabric::MAX_SUPPORTED_FABRICS,
+    group_keys::GroupMgr,
+    persist::KvStorage,
     transport::{plain_hdr, proto_hdr},
     utils::writebuf::WriteBuf,
 };
-use boxslab::{BoxSlab, Slab};
 use colored::*;
-use log::{info, trace};
-use rand::Rng;
+use log::{error, info, trace};
+use smol::{future, Timer};
 
 use super::{
-    network::{Address, NetworkInterface},
+    capture::{Capture, CapturedMsg, Direction},
+    mrp::MrpParams,
+    msg_ctr::{
+        random_msg_ctr, GlobalCtr, RxCtrState, GROUP_DATA_MSG_CTR_KEY, UNENCRYPTED_MSG_CTR_KEY,
+    },
+    network::{wait_any, Address, NetworkInterface, RECV_POLL_TIMEOUT},
     packet::{Packet, PacketPool},
 };
 
@@ -57,12 +68,22 @@ impl CaseDetails {
     }
 }
 
+/// The group of a group session, along with the fabric that the group is of
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct GroupDetails {
+    pub fab_idx: u8,
+    pub group_id: u16,
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum SessionMode {
     // The Case session will capture the local fabric index
     Case(CaseDetails),
     Pase,
     PlainText,
+    // The messages from a node to a group. The group is that of the last message, the node
+    // can send to any of the groups of the fabric.
+    Group(GroupDetails),
 }
 
 impl Default for SessionMode {
@@ -84,9 +105,15 @@ pub struct Session {
     local_sess_id: u16,
     peer_sess_id: u16,
     msg_ctr: u32,
+    // The message counters received from the peer
+    rx_ctr: RxCtrState,
     mode: SessionMode,
     data: Option<Box<dyn Any>>,
     last_use: SystemTime,
+    // The last time that we received a message from the peer
+    last_rx: SystemTime,
+    // The peer's MRP parameters
+    mrp_params: MrpParams,
 }
 
 #[derive(Debug)]
@@ -100,6 +127,7 @@ pub struct CloneData {
     peer_nodeid: u64,
     peer_addr: Address,
     mode: SessionMode,
+    pub peer_mrp: MrpParams,
 }
 impl CloneData {
     pub fn new(
@@ -120,11 +148,17 @@ impl CloneData {
             peer_sess_id,
             local_sess_id,
             mode,
+            peer_mrp: Default::default(),
         }
     }
-}
 
-const MATTER_MSG_CTR_RANGE: u32 = 0x0fffffff;
+    pub fn get_local_fabric_idx(&self) -> Option<u8> {
+        match self.mode {
+            SessionMode::Case(a) => Some(a.fab_idx),
+            _ => None,
+        }
+    }
+}
 
 impl Session {
     pub fn new(peer_addr: Address, peer_nodeid: Option<u64>) -> Session {
@@ -137,10 +171,13 @@ impl Session {
             att_challenge: [0; MATTER_AES128_KEY_SIZE],
             peer_sess_id: 0,
             local_sess_id: 0,
-            msg_ctr: rand::thread_rng().gen_range(0..MATTER_MSG_CTR_RANGE),
+            msg_ctr: random_msg_ctr(),
+            rx_ctr: Default::default(),
             mode: SessionMode::PlainText,
             data: None,
             last_use: SystemTime::now(),
+            last_rx: SystemTime::now(),
+            mrp_params: Default::default(),
         }
     }
 
@@ -155,10 +192,13 @@ impl Session {
             att_challenge: clone_from.att_challenge,
             local_sess_id: clone_from.local_sess_id,
             peer_sess_id: clone_from.peer_sess_id,
-            msg_ctr: rand::thread_rng().gen_range(0..MATTER_MSG_CTR_RANGE),
+            msg_ctr: random_msg_ctr(),
+            rx_ctr: Default::default(),
             mode: clone_from.mode,
             data: None,
             last_use: SystemTime::now(),
+            last_rx: SystemTime::now(),
+            mrp_params: clone_from.peer_mrp,
         }
     }
 
@@ -195,13 +235,19 @@ impl Session {
         self.peer_addr
     }
 
+    /// Whether this is a secure unicast session, the group sessions are secure too, but
+    /// they are only for receiving
     pub fn is_encrypted(&self) -> bool {
         match self.mode {
             SessionMode::Case(_) | SessionMode::Pase => true,
-            SessionMode::PlainText => false,
+            SessionMode::PlainText | SessionMode::Group(_) => false,
         }
     }
 
+    pub fn is_group(&self) -> bool {
+        matches!(self.mode, SessionMode::Group(_))
+    }
+
     pub fn get_peer_node_id(&self) -> Option<u64> {
         self.peer_nodeid
     }
@@ -216,6 +262,7 @@ impl Session {
     pub fn get_local_fabric_idx(&self) -> Option<u8> {
         match self.mode {
             SessionMode::Case(a) => Some(a.fab_idx),
+            SessionMode::Group(g) => Some(g.fab_idx),
             _ => None,
         }
     }
@@ -224,23 +271,33 @@ impl Session {
         self.mode
     }
 
-    pub fn get_msg_ctr(&mut self) -> u32 {
+    /// The counter for the next message on this secure session. The counter never rolls
+    /// over, once it is exhausted the session has to be closed.
+    pub fn get_msg_ctr(&mut self) -> Result<u32, Error> {
         let ctr = self.msg_ctr;
-        self.msg_ctr += 1;
-        ctr
+        self.msg_ctr = self.msg_ctr.checked_add(1).ok_or_else(|| {
+            error!(
+                "The message counters of session {} ran out",
+                self.local_sess_id
+            );
+            Error::InvalidState
+        })?;
+        Ok(ctr)
     }
 
+    // The group messages are decrypted with the keys of the group, before they get to their
+    // session
     pub fn get_dec_key(&self) -> Option<&[u8]> {
         match self.mode {
             SessionMode::Case(_) | SessionMode::Pase => Some(&self.dec_key),
-            SessionMode::PlainText => None,
+            SessionMode::PlainText | SessionMode::Group(_) => None,
         }
     }
 
     pub fn get_enc_key(&self) -> Option<&[u8]> {
         match self.mode {
             SessionMode::Case(_) | SessionMode::Pase => Some(&self.enc_key),
-            SessionMode::PlainText => None,
+            SessionMode::PlainText | SessionMode::Group(_) => None,
         }
     }
 
@@ -248,15 +305,54 @@ impl Session {
         &self.att_challenge
     }
 
+    pub fn set_mrp_params(&mut self, params: MrpParams) {
+        self.mrp_params = params;
+    }
+
+    pub fn get_mrp_params(&self) -> MrpParams {
+        self.mrp_params
+    }
+
+    /// The base interval for retransmissions to the peer. This depends on whether we have
+    /// heard from the peer recently, in which case it is likely to still be active.
+    pub fn get_mrp_interval(&self) -> Duration {
+        let since_rx = SystemTime::now()
+            .duration_since(self.last_rx)
+            .unwrap_or_default();
+        if since_rx < self.mrp_params.active_threshold() {
+            self.mrp_params.active_interval()
+        } else {
+            self.mrp_params.idle_interval()
+        }
+    }
+
+    /// Decrypt and decode the received message, returns [Error::Duplicate] if its message
+    /// counter was already received. The duplicate is still decoded, so that it can be
+    /// acknowledged.
     pub fn recv(&mut self, proto_rx: &mut Packet) -> Result<(), Error> {
+        if let Some(group) = proto_rx.group {
+            self.mode = SessionMode::Group(group);
+        } else {
+            proto_rx.proto_decode(self.peer_nodeid.unwrap_or_default(), self.get_dec_key())?;
+        }
+        // The counter is only trusted, once the message is authenticated
+        let ctr = proto_rx.plain.ctr;
+        if self.is_encrypted() {
+            self.rx_ctr.recv_encrypted(ctr)?;
+        } else if self.is_group() {
+            self.rx_ctr.recv_group(ctr)?;
+        } else {
+            self.rx_ctr.recv_unencrypted(ctr)?;
+        }
         self.last_use = SystemTime::now();
-        proto_rx.proto_decode(self.peer_nodeid.unwrap_or_default(), self.get_dec_key())
+        self.last_rx = self.last_use;
+        Ok(())
     }
 
     pub fn pre_send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
         proto_tx.plain.sess_id = self.get_peer_sess_id();
-        proto_tx.plain.ctr = self.get_msg_ctr();
         if self.is_encrypted() {
+            proto_tx.plain.ctr = self.get_msg_ctr()?;
             proto_tx.plain.sess_type = plain_hdr::SessionType::Encrypted;
         }
         Ok(())
@@ -289,6 +385,7 @@ impl Session {
         let enc_key = self.get_enc_key();
         if let Some(e) = enc_key {
             proto_hdr::encrypt_in_place(
+                proto_tx.plain.get_sec_flags(),
                 ctr,
                 self.local_nodeid,
                 plain_hdr_bytes,
@@ -319,11 +416,41 @@ impl fmt::Display for Session {
     }
 }
 
+/// Information about a secure session, for the application
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub local_sess_id: u16,
+    pub peer_sess_id: u16,
+    pub peer_addr: Address,
+    pub peer_nodeid: Option<u64>,
+    pub mode: SessionMode,
+    pub last_use: SystemTime,
+}
+
 pub const MAX_SESSIONS: usize = 16;
+/// The sessions that each fabric is entitled to, once the sessions are full. The PASE and
+/// the plaintext sessions are counted together, as if they were of one more fabric.
+pub const SESSIONS_PER_FABRIC: usize = MAX_SESSIONS / (MAX_SUPPORTED_FABRICS + 1);
+/// The group sessions, one for each node that sends us group messages, that are kept at a
+/// time. Beyond these, the least recently used group session makes way for a new one.
+pub const MAX_GROUP_SESSIONS: usize = MAX_SESSIONS / 4;
+/// A time after which a session that has seen no traffic can be closed. This has to be longer
+/// than the max interval of the subscriptions on the session, whose reports are the only
+/// traffic that the session sees.
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
 pub struct SessionMgr {
     next_sess_id: u16,
     sessions: [Option<Session>; MAX_SESSIONS],
-    network: Option<Box<dyn NetworkInterface>>,
+    idle_timeout: Option<Duration>,
+    unencrypted_ctr: GlobalCtr,
+    group_data_ctr: GlobalCtr,
+    networks: Vec<Box<dyn NetworkInterface>>,
+    packet_pool: PacketPool,
+    capture: Option<Box<dyn Capture>>,
+    group_mgr: Option<Arc<GroupMgr>>,
+    // The multicast addresses of the groups that the interfaces have joined
+    multicast: Vec<Ipv6Addr>,
 }
 
 impl Default for SessionMgr {
@@ -337,22 +464,132 @@ impl SessionMgr {
         SessionMgr {
             sessions: Default::default(),
             next_sess_id: 1,
-            network: None,
+            idle_timeout: None,
+            unencrypted_ctr: GlobalCtr::new(),
+            group_data_ctr: GlobalCtr::new(),
+            networks: Vec::new(),
+            packet_pool: PacketPool::new(),
+            capture: None,
+            group_mgr: None,
+            multicast: Vec::new(),
         }
     }
 
+    /// The pool that the packets for this session manager are allocated from
+    pub fn get_packet_pool(&self) -> &PacketPool {
+        &self.packet_pool
+    }
+
+    /// Add a network interface, the messages to a peer are sent through the first interface
+    /// that is [for](NetworkInterface::is_for) the peer's address
     pub fn add_network_interface(
         &mut self,
         interface: Box<dyn NetworkInterface>,
     ) -> Result<(), Error> {
-        if self.network.is_none() {
-            self.network = Some(interface);
-            Ok(())
-        } else {
-            Err(Error::Invalid)
+        self.networks.push(interface);
+        Ok(())
+    }
+
+    /// Capture all the messages in plaintext, for debugging
+    pub fn set_capture(&mut self, capture: Box<dyn Capture>) {
+        self.capture = Some(capture);
+    }
+
+    /// Persist the global message counters in the storage, so that they carry on from where
+    /// they were after a reboot
+    pub fn set_storage(&mut self, storage: Arc<dyn KvStorage>) -> Result<(), Error> {
+        self.unencrypted_ctr = GlobalCtr::new_persisted(storage.clone(), UNENCRYPTED_MSG_CTR_KEY)?;
+        self.group_data_ctr = GlobalCtr::new_persisted(storage, GROUP_DATA_MSG_CTR_KEY)?;
+        Ok(())
+    }
+
+    /// Receive the messages to the groups of the Group Manager
+    pub fn set_group_mgr(&mut self, group_mgr: Arc<GroupMgr>) {
+        self.group_mgr = Some(group_mgr);
+    }
+
+    /// Join the multicast groups of the groups that we are a member of, and leave the ones
+    /// of the groups that are gone
+    pub fn sync_multicast(&mut self) {
+        let addrs = match &self.group_mgr {
+            Some(group_mgr) => group_mgr.get_multicast_addrs(),
+            None => return,
+        };
+        // The interfaces log their failures, an address is taken as joined regardless, so
+        // that it isn't retried over and over
+        for addr in addrs.iter().filter(|a| !self.multicast.contains(a)) {
+            for network in self.networks.iter() {
+                let _ = network.join_multicast(addr);
+            }
+        }
+        for addr in self.multicast.iter().filter(|a| !addrs.contains(a)) {
+            for network in self.networks.iter() {
+                let _ = network.leave_multicast(addr);
+            }
+        }
+        self.multicast = addrs;
+    }
+
+    /// The counter for the next group data message
+    pub fn get_group_data_msg_ctr(&mut self) -> Result<u32, Error> {
+        self.group_data_ctr.get_msg_ctr()
+    }
+
+    /// Set the time after which an idle session is closed, or None to never close them
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    fn capture(&self, direction: Direction, peer: Address, packet: &mut Packet) {
+        if let Some(capture) = &self.capture {
+            let payload = packet.as_borrow_slice().to_vec();
+            capture.capture(&CapturedMsg {
+                direction,
+                peer,
+                plain: &packet.plain,
+                proto: &packet.proto,
+                payload: &payload,
+            });
+        }
+    }
+
+    fn get_network(&self, peer: &Address) -> Result<&dyn NetworkInterface, Error> {
+        self.networks
+            .iter()
+            .find(|n| n.is_for(peer))
+            .map(|n| n.as_ref())
+            .ok_or(Error::NoNetworkInterface)
+    }
+
+    // The interface to receive from, for the users that poll, like the controller. With
+    // several interfaces, this is one that is ready, and the poll timeout applies to the wait
+    // for any of them to be ready.
+    fn get_ready_network(&self) -> Result<&dyn NetworkInterface, Error> {
+        match self.networks.as_slice() {
+            [] => Err(Error::NoNetworkInterface),
+            // The interface applies the poll timeout itself
+            [network] => Ok(network.as_ref()),
+            networks => {
+                let ready = wait_any(networks.iter().map(|n| {
+                    Box::pin(async move {
+                        n.wait_recv().await?;
+                        Ok(n.as_ref())
+                    }) as Pin<Box<dyn Future<Output = _>>>
+                }));
+                let timeout = async {
+                    Timer::after(RECV_POLL_TIMEOUT).await;
+                    Err(Error::Timeout)
+                };
+                smol::block_on(future::or(ready, timeout))
+            }
         }
     }
 
+    /// The peers whose connections were closed, since the last call
+    pub fn take_closed(&self) -> Vec<Address> {
+        self.networks.iter().flat_map(|n| n.take_closed()).collect()
+    }
+
     pub fn mut_by_index(&mut self, index: usize) -> Option<&mut Session> {
         self.sessions[index].as_mut()
     }
@@ -380,18 +617,73 @@ impl SessionMgr {
         self.sessions.iter().position(|x| x.is_none())
     }
 
-    pub fn get_lru(&mut self) -> usize {
-        let mut lru_index = 0;
-        let mut lru_ts = SystemTime::now();
-        for i in 0..MAX_SESSIONS {
-            if let Some(s) = &self.sessions[i] {
-                if s.last_use < lru_ts {
-                    lru_ts = s.last_use;
-                    lru_index = i;
-                }
-            }
-        }
-        lru_index
+    /// The session to evict, to make space for a new session of the given fabric, or of no
+    /// fabric for the PASE and plaintext sessions
+    ///
+    /// A fabric that already holds its [quota](SESSIONS_PER_FABRIC) makes space out of its
+    /// own sessions. Otherwise, the sessions of the fabrics that are over their quota are
+    /// evicted first, so that one fabric can't starve the others. Within these, the least
+    /// recently used session goes.
+    pub fn get_evictable(&self, fab_idx: Option<u8>) -> Option<usize> {
+        let count = |fab_idx| {
+            self.sessions
+                .iter()
+                .flatten()
+                .filter(|s| s.get_local_fabric_idx() == fab_idx)
+                .count()
+        };
+        let over_quota: Vec<Option<u8>> = if count(fab_idx) >= SESSIONS_PER_FABRIC {
+            vec![fab_idx]
+        } else {
+            self.sessions
+                .iter()
+                .flatten()
+                .map(|s| s.get_local_fabric_idx())
+                .filter(|f| count(*f) > SESSIONS_PER_FABRIC)
+                .collect()
+        };
+        self.get_lru(|s| over_quota.is_empty() || over_quota.contains(&s.get_local_fabric_idx()))
+    }
+
+    fn get_lru(&self, f: impl Fn(&Session) -> bool) -> Option<usize> {
+        self.sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.as_ref().filter(|s| f(s)).map(|s| (i, s.last_use)))
+            .min_by_key(|(_, last_use)| *last_use)
+            .map(|(i, _)| i)
+    }
+
+    /// The sessions that have been idle for longer than the idle timeout
+    pub fn get_idle(&self, now: SystemTime) -> Vec<usize> {
+        let timeout = match self.idle_timeout {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+        self.sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.as_ref().map(|s| (i, s)))
+            .filter(|(_, s)| matches!(now.duration_since(s.last_use), Ok(idle) if idle > timeout))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The information about all the secure sessions
+    pub fn get_sessions_info(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .flatten()
+            .filter(|s| s.is_encrypted())
+            .map(|s| SessionInfo {
+                local_sess_id: s.local_sess_id,
+                peer_sess_id: s.peer_sess_id,
+                peer_addr: s.peer_addr,
+                peer_nodeid: s.peer_nodeid,
+                mode: s.mode,
+                last_use: s.last_use,
+            })
+            .collect()
     }
 
     pub fn add(&mut self, peer_addr: Address, peer_nodeid: Option<u64>) -> Result<usize, Error> {
@@ -430,7 +722,7 @@ impl SessionMgr {
         is_encrypted: bool,
     ) -> Option<usize> {
         self.sessions.iter().position(|x| {
-            if let Some(x) = x {
+            if let Some(x) = x.as_ref().filter(|x| !x.is_group()) {
                 let mut nodeid_matches = true;
                 if x.peer_nodeid.is_some() && peer_nodeid.is_some() && x.peer_nodeid != peer_nodeid
                 {
@@ -446,11 +738,20 @@ impl SessionMgr {
         })
     }
 
+    pub fn get_index_with_peer(&self, peer_addr: Address) -> Option<usize> {
+        self.sessions
+            .iter()
+            .position(|x| x.as_ref().map(|s| s.peer_addr) == Some(peer_addr))
+    }
+
+    pub fn get_index_with_id(&self, sess_id: u16) -> Option<usize> {
+        self.sessions
+            .iter()
+            .position(|x| x.as_ref().map(|s| s.local_sess_id) == Some(sess_id))
+    }
+
     pub fn get_with_id(&mut self, sess_id: u16) -> Option<SessionHandle> {
-        let index = self
-            .sessions
-            .iter_mut()
-            .position(|x| x.as_ref().map(|s| s.local_sess_id) == Some(sess_id))?;
+        let index = self.get_index_with_id(sess_id)?;
         Some(self.get_session_handle(index))
     }
 
@@ -472,15 +773,56 @@ impl SessionMgr {
         }
     }
 
+    // The group session of the node in the fabric, there is one for all the groups
+    fn get_or_add_group(
+        &mut self,
+        group: GroupDetails,
+        peer_addr: Address,
+        peer_nodeid: u64,
+    ) -> Result<usize, Error> {
+        let index = self.sessions.iter().position(|x| {
+            matches!(x, Some(x) if x.peer_nodeid == Some(peer_nodeid)
+                && matches!(x.mode, SessionMode::Group(g) if g.fab_idx == group.fab_idx))
+        });
+        if let Some(index) = index {
+            Ok(index)
+        } else {
+            info!("Creating new group session");
+            let groups = self
+                .sessions
+                .iter()
+                .flatten()
+                .filter(|s| s.is_group())
+                .count();
+            if groups >= MAX_GROUP_SESSIONS || self.get_empty_slot().is_none() {
+                // A group session only holds the message counters of the node, so the
+                // nodes that send group messages can't push out the unicast sessions
+                if let Some(lru) = self.get_lru(|s| s.is_group()) {
+                    info!("Group sessions full, vacating session with index: {}", lru);
+                    self.remove(lru);
+                }
+            }
+            let mut session = Session::new(peer_addr, Some(peer_nodeid));
+            session.mode = SessionMode::Group(group);
+            self.add_session(session)
+        }
+    }
+
     // We will try to get a session for this Packet. If no session exists, we will try to add one
     // If the session list is full we will return a None
     pub fn post_recv(&mut self, rx: &Packet) -> Result<Option<usize>, Error> {
-        let sess_index = match self.get_or_add(
-            rx.plain.sess_id,
-            rx.peer,
-            rx.plain.get_src_u64(),
-            rx.plain.is_encrypted(),
-        ) {
+        let result = match (rx.group, rx.plain.get_src_u64()) {
+            (Some(group), Some(src)) => self.get_or_add_group(group, rx.peer, src),
+            // The group message couldn't be decrypted
+            _ if rx.plain.is_group() => Err(Error::NoSession),
+            _ => self.get_or_add(
+                rx.plain.sess_id,
+                rx.peer,
+                rx.plain.get_src_u64(),
+                rx.plain.is_encrypted(),
+            ),
+        };
+        let sess_index = match result {
             Ok(s) => Some(s),
             Err(Error::NoSpace) => None,
             Err(e) => {
@@ -490,13 +832,42 @@ impl SessionMgr {
         Ok(sess_index)
     }
 
-    pub fn recv(&mut self) -> Result<(BoxSlab<PacketPool>, Option<usize>), Error> {
-        let mut rx =
-            Slab::<PacketPool>::try_new(Packet::new_rx()?).ok_or(Error::PacketPoolExhaust)?;
-
-        let network = self.network.as_ref().ok_or(Error::NoNetworkInterface)?;
+    /// Receive a packet, this blocks for at most [RECV_POLL_TIMEOUT], after which it fails
+    /// with [Error::Timeout]
+    pub fn recv(&mut self) -> Result<(Box<Packet<'static>>, Option<usize>), Error> {
+        let network = self.get_ready_network()?;
+        let mut rx = self
+            .packet_pool
+            .alloc_rx_with_size(network.max_msg_size())?;
 
         let (len, src) = network.recv(rx.as_borrow_slice())?;
+        self.decode_rx(rx, len, src)
+    }
+
+    /// Receive the packet that is ready on the network interface with the given index, as
+    /// returned by [wait_recv](SessionMgr::wait_recv), without blocking
+    pub fn try_recv(
+        &mut self,
+        network: usize,
+    ) -> Result<(Box<Packet<'static>>, Option<usize>), Error> {
+        let network = self
+            .networks
+            .get(network)
+            .ok_or(Error::NoNetworkInterface)?;
+        let mut rx = self
+            .packet_pool
+            .alloc_rx_with_size(network.max_msg_size())?;
+
+        let (len, src) = network.try_recv(rx.as_borrow_slice())?;
+        self.decode_rx(rx, len, src)
+    }
+
+    fn decode_rx(
+        &mut self,
+        mut rx: Box<Packet<'static>>,
+        len: usize,
+        src: Address,
+    ) -> Result<(Box<Packet<'static>>, Option<usize>), Error> {
         rx.get_parsebuf()?.set_len(len);
         rx.peer = src;
 
@@ -505,29 +876,73 @@ impl SessionMgr {
 
         // Read unencrypted packet header
         rx.plain_hdr_decode()?;
+        if rx.plain.is_group() {
+            self.group_decode(&mut rx)?;
+        }
 
         // Get session
         let sess_handle = self.post_recv(&rx)?;
         Ok((rx, sess_handle))
     }
 
-    pub fn send(
-        &mut self,
-        sess_idx: usize,
-        mut proto_tx: BoxSlab<PacketPool>,
-    ) -> Result<(), Error> {
+    // Decrypt a group message with the keys of its group, this also tells the fabric that
+    // it is for
+    fn group_decode(&self, rx: &mut Packet) -> Result<(), Error> {
+        let group_mgr = self.group_mgr.as_ref().ok_or(Error::NoSession)?;
+        let group_id = rx.plain.get_dest_group().ok_or(Error::Invalid)?;
+        let src = rx.plain.get_src_u64().ok_or(Error::Invalid)?;
+        let keys = group_mgr.get_op_keys(group_id, rx.plain.sess_id);
+        if keys.is_empty() {
+            info!(
+                "Dropping a message to the group {:#x}, it has no keys",
+                group_id
+            );
+            return Err(Error::NoSession);
+        }
+        let fab_idx = rx.proto_decode_group(src, &keys)?;
+        rx.group = Some(GroupDetails { fab_idx, group_id });
+        Ok(())
+    }
+
+    /// Wait until a packet can be received with [try_recv](SessionMgr::try_recv), returns the
+    /// index of the network interface that it is ready on
+    pub async fn wait_recv(&self) -> Result<usize, Error> {
+        if self.networks.is_empty() {
+            return Err(Error::NoNetworkInterface);
+        }
+        wait_any(self.networks.iter().enumerate().map(|(index, n)| {
+            Box::pin(async move {
+                n.wait_recv().await?;
+                Ok(index)
+            }) as Pin<Box<dyn Future<Output = _>>>
+        }))
+        .await
+    }
+
+    pub fn send(&mut self, sess_idx: usize, proto_tx: &mut Packet) -> Result<(), Error> {
+        let peer = self.sessions[sess_idx]
+            .as_ref()
+            .ok_or(Error::NoSession)?
+            .peer_addr;
+        self.capture(Direction::Tx, peer, proto_tx);
         self.sessions[sess_idx]
             .as_mut()
             .ok_or(Error::NoSession)?
-            .do_send(&mut proto_tx)?;
+            .do_send(proto_tx)?;
 
-        let network = self.network.as_ref().ok_or(Error::NoNetworkInterface)?;
         let peer = proto_tx.peer;
-        network.send(proto_tx.as_borrow_slice(), peer)?;
+        self.get_network(&peer)?
+            .send(proto_tx.as_borrow_slice(), peer)?;
         println!("Message Sent to {}", peer);
         Ok(())
     }
 
+    /// Send a message that has already been encoded, like an MRP retransmission
+    pub fn send_raw(&self, data: &[u8], peer: Address) -> Result<(), Error> {
+        self.get_network(&peer)?.send(data, peer)?;
+        Ok(())
+    }
+
     pub fn get_session_handle(&mut self, sess_idx: usize) -> SessionHandle {
         SessionHandle {
             sess_mgr: self,
@@ -557,9 +972,27 @@ impl<'a> SessionHandle<'a> {
         self.sess_mgr.get_next_sess_id()
     }
 
-    pub fn send(&mut self, proto_tx: BoxSlab<PacketPool>) -> Result<(), Error> {
+    pub fn send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
         self.sess_mgr.send(self.sess_idx, proto_tx)
     }
+
+    /// Fill in the message header, the unencrypted messages take their counter from the
+    /// global unencrypted message counter
+    pub fn pre_send(&mut self, proto_tx: &mut Packet) -> Result<(), Error> {
+        self.deref_mut().pre_send(proto_tx)?;
+        if !self.is_encrypted() {
+            proto_tx.plain.ctr = self.sess_mgr.unencrypted_ctr.get_msg_ctr()?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt and decode the received message
+    pub fn recv(&mut self, proto_rx: &mut Packet) -> Result<(), Error> {
+        self.deref_mut().recv(proto_rx)?;
+        let peer = self.get_peer_addr();
+        self.sess_mgr.capture(Direction::Rx, peer, proto_rx);
+        Ok(())
+    }
 }
 
 impl<'a> Deref for SessionHandle<'a> {
@@ -580,9 +1013,20 @@ impl<'a> DerefMut for SessionHandle<'a> {
 #[cfg(test)]
 mod tests {
 
+    use std::time::{Duration, SystemTime};
+
     use crate::transport::network::Address;
 
-    use super::SessionMgr;
+    use super::*;
+
+    fn add_session(sm: &mut SessionMgr, local_sess_id: u16, mode: SessionMode) -> usize {
+        let clone_data = CloneData::new(1, 2, 100, local_sess_id, Address::default(), mode);
+        sm.clone_session(&clone_data).unwrap()
+    }
+
+    fn case(fab_idx: u8) -> SessionMode {
+        SessionMode::Case(CaseDetails::new(fab_idx, &[0; MAX_CAT_IDS_PER_NOC]))
+    }
 
     #[test]
     fn test_next_sess_id_doesnt_reuse() {
@@ -610,4 +1054,122 @@ mod tests {
         assert_eq!(sm.get_next_sess_id(), 65535);
         assert_eq!(sm.get_next_sess_id(), 2);
     }
+
+    #[test]
+    fn test_evictable() {
+        let mut sm = SessionMgr::new();
+        assert_eq!(sm.get_evictable(None), None);
+
+        // Fabric 1 takes over all the sessions but one of fabric 2, the oldest first
+        let fab2 = add_session(&mut sm, 1, case(2));
+        let mut fab1 = Vec::new();
+        for i in 0..MAX_SESSIONS - 1 {
+            fab1.push(add_session(&mut sm, i as u16 + 2, case(1)));
+        }
+        assert_eq!(sm.add(Address::default(), None), Err(Error::NoSpace));
+
+        // Fabric 1 is over its quota, it loses its least recently used session, even for itself
+        sm.mut_by_index(fab1[0]).unwrap().last_use = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(sm.get_evictable(Some(1)), Some(fab1[1]));
+        assert_eq!(sm.get_evictable(Some(3)), Some(fab1[1]));
+        assert_eq!(sm.get_evictable(None), Some(fab1[1]));
+
+        // Fabric 2 is within its quota, the older session is spared
+        sm.mut_by_index(fab2).unwrap().last_use = SystemTime::UNIX_EPOCH;
+        assert_eq!(sm.get_evictable(Some(2)), Some(fab1[1]));
+
+        // Once fabric 2 holds its quota, a new session of it evicts one of its own
+        for (i, index) in fab1.iter().take(SESSIONS_PER_FABRIC - 1).enumerate() {
+            sm.remove(*index);
+            add_session(&mut sm, 100 + i as u16, case(2));
+        }
+        assert_eq!(sm.get_evictable(Some(2)), Some(fab2));
+        assert_eq!(
+            sm.get_evictable(Some(1)),
+            Some(fab1[SESSIONS_PER_FABRIC - 1])
+        );
+    }
+
+    #[test]
+    fn test_group_sessions() {
+        let mut sm = SessionMgr::new();
+        let group = GroupDetails {
+            fab_idx: 1,
+            group_id: 0x101,
+        };
+        let mut indices = Vec::new();
+        for nodeid in 0..MAX_GROUP_SESSIONS as u64 {
+            let index = sm
+                .get_or_add_group(group, Address::default(), nodeid)
+                .unwrap();
+            sm.mut_by_index(index).unwrap().last_use =
+                SystemTime::UNIX_EPOCH + Duration::from_secs(nodeid + 1);
+            indices.push(index);
+        }
+        assert_eq!(
+            sm.get_or_add_group(group, Address::default(), 1),
+            Ok(indices[1])
+        );
+
+        // One more node takes the place of the least recently used one
+        sm.get_or_add_group(group, Address::default(), 100).unwrap();
+        let groups: Vec<_> = sm
+            .sessions
+            .iter()
+            .flatten()
+            .filter(|s| s.is_group())
+            .map(|s| s.get_peer_node_id().unwrap())
+            .collect();
+        assert_eq!(groups.len(), MAX_GROUP_SESSIONS);
+        assert!(!groups.contains(&0));
+        assert!(groups.contains(&100));
+
+        // Once all the sessions are taken, a new node still only replaces a group session
+        let mut sess_id = 1;
+        while sm.get_empty_slot().is_some() {
+            add_session(&mut sm, sess_id, SessionMode::Pase);
+            sess_id += 1;
+        }
+        sm.get_or_add_group(group, Address::default(), 200).unwrap();
+        assert_eq!(
+            sm.sessions
+                .iter()
+                .flatten()
+                .filter(|s| !s.is_group())
+                .count(),
+            MAX_SESSIONS - MAX_GROUP_SESSIONS
+        );
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut sm = SessionMgr::new();
+        sm.set_idle_timeout(Some(SESSION_IDLE_TIMEOUT));
+        let old = add_session(&mut sm, 1, SessionMode::Pase);
+        let _new = add_session(&mut sm, 2, case(1));
+        sm.mut_by_index(old).unwrap().last_use = SystemTime::now() - SESSION_IDLE_TIMEOUT * 2;
+        assert_eq!(sm.get_idle(SystemTime::now()), vec![old]);
+
+        sm.set_idle_timeout(None);
+        assert!(sm.get_idle(SystemTime::now()).is_empty());
+        sm.set_idle_timeout(Some(Duration::from_secs(1)));
+        assert_eq!(
+            sm.get_idle(SystemTime::now() + Duration::from_secs(2))
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_sessions_info() {
+        let mut sm = SessionMgr::new();
+        sm.add(Address::default(), None).unwrap();
+        add_session(&mut sm, 5, case(1));
+        let info = sm.get_sessions_info();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].local_sess_id, 5);
+        assert_eq!(info[0].peer_sess_id, 100);
+        assert_eq!(info[0].peer_nodeid, Some(2));
+        assert_eq!(info[0].mode, case(1));
+    }
 }
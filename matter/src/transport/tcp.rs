@@ -0,0 +1,672 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Read, Write},
+    iter,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::error::*;
+use log::{error, info};
+use smol::{Async, Timer};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{
+    network::{wait_any, Address, NetworkInterface, RecvReady, TransportConfig},
+    udp::{open_socket, to_canonical},
+};
+
+// Currently matches with the large payload size in the connectedhomeip repo
+pub const MAX_TCP_MSG_SIZE: usize = 64000;
+
+pub const MAX_TCP_CONNECTIONS: usize = 8;
+
+/// A connection that carries no message for this long is closed, so that the silent peers
+/// don't hold on to the connections forever
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// A connection to a peer that isn't established in this long is given up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Each message on the stream is prefixed by its length, as a 32-bit little-endian
+const LEN_PREFIX_SIZE: usize = 4;
+
+// The most of our data that a connection holds on to, while the peer doesn't take it. The
+// sends fail beyond this, until the peer catches up.
+const MAX_PENDING_SIZE: usize = 2 * (LEN_PREFIX_SIZE + MAX_TCP_MSG_SIZE);
+
+/// Reassembles the length-prefixed messages out of a stream, as the stream's data trickles in
+#[derive(Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    // The number of bytes that are still missing from the current frame
+    fn missing(&self) -> Result<usize, Error> {
+        if self.buf.len() < LEN_PREFIX_SIZE {
+            return Ok(LEN_PREFIX_SIZE - self.buf.len());
+        }
+        let mut len = [0; LEN_PREFIX_SIZE];
+        len.copy_from_slice(&self.buf[..LEN_PREFIX_SIZE]);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_TCP_MSG_SIZE {
+            error!("Message of {} bytes is too large", len);
+            return Err(Error::NoSpace);
+        }
+        Ok(LEN_PREFIX_SIZE + len - self.buf.len())
+    }
+
+    /// Read whatever is available of the current frame, without blocking. Once the frame is
+    /// complete, its message is returned.
+    ///
+    /// This never reads beyond the current frame, so the next frame is left on the stream.
+    fn read(&mut self, src: &mut impl Read) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let missing = self.missing()?;
+            if missing == 0 {
+                let msg = self.buf.split_off(LEN_PREFIX_SIZE);
+                self.buf.clear();
+                return Ok(Some(msg));
+            }
+            let start = self.buf.len();
+            self.buf.resize(start + missing, 0);
+            let result = src.read(&mut self.buf[start..]);
+            self.buf.truncate(start + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => {
+                    info!("Connection closed by the peer");
+                    return Err(Error::Network);
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Error on the connection: {:?}", e);
+                    return Err(Error::Network);
+                }
+            }
+        }
+    }
+}
+
+fn encode_frame(msg: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(LEN_PREFIX_SIZE + msg.len());
+    frame.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+    frame.extend_from_slice(msg);
+    frame
+}
+
+struct Connection {
+    peer: SocketAddr,
+    // This is shared with the futures that wait on the connection
+    stream: Arc<Async<TcpStream>>,
+    frame: FrameReader,
+    // The frames that the stream hasn't taken yet
+    pending: Vec<u8>,
+    // Whether the connection is established, our own connections to the peers take a while
+    connected: bool,
+    // The last time that a message was received or sent on the connection
+    last_active: Instant,
+}
+
+impl Connection {
+    fn new(peer: SocketAddr, stream: Arc<Async<TcpStream>>, connected: bool) -> Self {
+        Self {
+            peer,
+            stream,
+            frame: Default::default(),
+            pending: Vec::new(),
+            connected,
+            last_active: Instant::now(),
+        }
+    }
+
+    // The time at which the connection is closed, if nothing happens on it until then
+    fn deadline(&self, idle_timeout: Duration) -> Instant {
+        if self.connected {
+            self.last_active + idle_timeout
+        } else {
+            self.last_active + CONNECT_TIMEOUT
+        }
+    }
+
+    // Whether the connection waits for the stream to be writable, to get connected or to
+    // write the pending frames
+    fn wants_write(&self) -> bool {
+        !self.connected || !self.pending.is_empty()
+    }
+
+    /// Complete the connection, and write as much of the pending frames as the stream takes,
+    /// without blocking
+    fn flush(&mut self) -> Result<(), Error> {
+        let stream = self.stream.get_ref();
+        if !self.connected {
+            match stream.take_error() {
+                Ok(None) => (),
+                Ok(Some(e)) | Err(e) => {
+                    error!("Error connecting to {}: {:?}", self.peer, e);
+                    return Err(Error::Network);
+                }
+            }
+            match stream.peer_addr() {
+                Ok(_) => {
+                    info!("Connected to {}", self.peer);
+                    self.connected = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return Ok(()),
+                Err(e) => {
+                    error!("Error connecting to {}: {:?}", self.peer, e);
+                    return Err(Error::Network);
+                }
+            }
+        }
+        while !self.pending.is_empty() {
+            match (&mut &*stream).write(&self.pending) {
+                Ok(0) => {
+                    info!("Connection closed by the peer");
+                    return Err(Error::Network);
+                }
+                Ok(len) => {
+                    self.pending.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Error sending to {}: {:?}", self.peer, e);
+                    return Err(Error::Network);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The TCP transport, for the messages that are too large for UDP
+///
+/// This accepts the connections from the peers, and starts connecting to a peer when sending
+/// to it for the first time. The sends to a peer fail until the connection is established.
+/// Every connection has a peer address of its own, so the sessions are bound to the
+/// connection that they were established on, and they go away when it closes.
+///
+/// Nothing blocks: the messages are reassembled out of the streams as the data arrives, and
+/// the sent messages are queued on their connection and written as the stream takes them.
+/// So this is meant to be driven by [NetworkInterface::wait_recv], which also wakes up for
+/// the connections to be established and for the streams to take the queued messages.
+pub struct TcpTransport {
+    listener: Async<TcpListener>,
+    conns: RefCell<Vec<Connection>>,
+    // The connection that the next receive starts from, so that all get their turn
+    next: Cell<usize>,
+    idle_timeout: Duration,
+    // The peers of the connections that were closed, for take_closed()
+    closed: RefCell<Vec<Address>>,
+}
+
+impl TcpTransport {
+    pub fn new_with_config(config: &TransportConfig) -> Result<TcpTransport, Error> {
+        let (socket, addr) = open_socket(config, Type::STREAM, Protocol::TCP)?;
+        // A restarted transport must be able to listen, even while the connections of the
+        // previous one linger
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(MAX_TCP_CONNECTIONS as i32)?;
+        let listener: TcpListener = socket.into();
+        info!(
+            "Listening for TCP connections on {}",
+            listener.local_addr()?
+        );
+        Ok(TcpTransport {
+            listener: Async::new(listener)?,
+            conns: RefCell::new(Vec::new()),
+            next: Cell::new(0),
+            idle_timeout: TCP_IDLE_TIMEOUT,
+            closed: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn get_local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.get_ref().local_addr()?)
+    }
+
+    fn accept(&self) {
+        loop {
+            let (stream, peer) = match self.listener.get_ref().accept() {
+                Ok(s) => s,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Error accepting a connection: {:?}", e);
+                    return;
+                }
+            };
+            let peer = to_canonical(peer);
+            let mut conns = self.conns.borrow_mut();
+            self.close_idle(&mut conns);
+            if conns.len() >= MAX_TCP_CONNECTIONS {
+                // Dropping the stream closes the connection
+                error!("Too many connections, refusing the one from {}", peer);
+                continue;
+            }
+            match Async::new(stream) {
+                Ok(stream) => {
+                    info!("Accepted a connection from {}", peer);
+                    conns.push(Connection::new(peer, Arc::new(stream), true));
+                }
+                Err(e) => error!("Error with the connection from {}: {:?}", peer, e),
+            }
+        }
+    }
+
+    // Start connecting to the peer, without waiting for the connection to be established,
+    // returns the index of the new connection
+    fn connect(&self, conns: &mut Vec<Connection>, peer: SocketAddr) -> Result<usize, Error> {
+        self.close_idle(conns);
+        if conns.len() >= MAX_TCP_CONNECTIONS {
+            error!("Too many connections, can't connect to {}", peer);
+            return Err(Error::NoSpace);
+        }
+        let socket = Socket::new(Domain::for_address(peer), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&peer.into()) {
+            Ok(()) => (),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => {
+                error!("Error connecting to {}: {:?}", peer, e);
+                return Err(Error::Network);
+            }
+        }
+        let stream: TcpStream = socket.into();
+        info!("Connecting to {}", peer);
+        conns.push(Connection::new(peer, Arc::new(Async::new(stream)?), false));
+        Ok(conns.len() - 1)
+    }
+
+    // Move all the connections along, closing the ones that fail
+    fn flush(&self, conns: &mut Vec<Connection>) {
+        let mut index = 0;
+        while index < conns.len() {
+            if conns[index].flush().is_ok() {
+                index += 1;
+            } else {
+                self.close(conns, index);
+            }
+        }
+    }
+
+    fn close(&self, conns: &mut Vec<Connection>, index: usize) {
+        let conn = conns.remove(index);
+        info!("Closing the connection with {}", conn.peer);
+        self.closed.borrow_mut().push(Address::Tcp(conn.peer));
+    }
+
+    fn close_idle(&self, conns: &mut Vec<Connection>) {
+        let now = Instant::now();
+        while let Some(index) = conns
+            .iter()
+            .position(|c| c.deadline(self.idle_timeout) <= now)
+        {
+            info!("The connection with {} is idle", conns[index].peer);
+            self.close(conns, index);
+        }
+    }
+
+    // Receive a message from any of the connections, without blocking
+    fn recv_any(&self, in_buf: &mut [u8]) -> Option<(usize, Address)> {
+        self.close_idle(&mut self.conns.borrow_mut());
+        self.accept();
+        self.flush(&mut self.conns.borrow_mut());
+
+        let mut conns = self.conns.borrow_mut();
+        let count = conns.len();
+        for i in 0..count {
+            let index = (self.next.get() + i) % count;
+            let conn = &mut conns[index];
+            if !conn.connected {
+                continue;
+            }
+            let peer = conn.peer;
+            match conn.frame.read(&mut conn.stream.get_ref()) {
+                Ok(None) => continue,
+                Ok(Some(msg)) if msg.len() <= in_buf.len() => {
+                    conn.last_active = Instant::now();
+                    self.next.set(index + 1);
+                    in_buf[..msg.len()].copy_from_slice(&msg);
+                    return Some((msg.len(), Address::Tcp(peer)));
+                }
+                Ok(Some(msg)) => {
+                    error!("Message of {} bytes from {} is too large", msg.len(), peer);
+                }
+                Err(_) => (),
+            }
+            // The stream can't be trusted beyond an error, so the connection is closed
+            self.close(&mut conns, index);
+            return None;
+        }
+        None
+    }
+}
+
+impl NetworkInterface for TcpTransport {
+    /// This doesn't block, if no message is complete yet, this returns [Error::Timeout]
+    /// straight away
+    fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        self.try_recv(in_buf)
+    }
+
+    fn try_recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        self.recv_any(in_buf).ok_or(Error::Timeout)
+    }
+
+    fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
+        let peer = match addr {
+            Address::Tcp(peer) => peer,
+            _ => return Err(Error::Invalid),
+        };
+        if out_buf.len() > MAX_TCP_MSG_SIZE {
+            return Err(Error::NoSpace);
+        }
+        let mut conns = self.conns.borrow_mut();
+        let index = match conns.iter().position(|c| c.peer == peer) {
+            Some(index) => index,
+            None => self.connect(&mut conns, peer)?,
+        };
+        let conn = &mut conns[index];
+        // Whatever was queued up may go out now, making space for this frame
+        if conn.flush().is_err() {
+            self.close(&mut conns, index);
+            return Err(Error::Network);
+        }
+        if !conn.connected {
+            info!("The connection to {} isn't established yet", peer);
+            return Err(Error::InvalidState);
+        }
+        if conn.pending.len() + LEN_PREFIX_SIZE + out_buf.len() > MAX_PENDING_SIZE {
+            error!("The connection to {} is congested", peer);
+            return Err(Error::NoSpace);
+        }
+        conn.pending.extend(encode_frame(out_buf));
+        conn.last_active = Instant::now();
+        if conn.flush().is_err() {
+            // The stream is out of sync with a partial frame on it
+            self.close(&mut conns, index);
+            return Err(Error::Network);
+        }
+        Ok(out_buf.len())
+    }
+
+    fn wait_recv(&self) -> RecvReady<'_> {
+        let conns = self.conns.borrow();
+        let readable = conns
+            .iter()
+            .filter(|c| c.connected)
+            .map(|c| {
+                let stream = c.stream.clone();
+                Box::pin(async move {
+                    // An error on the connection is for recv to find, and close it
+                    let _ = stream.readable().await;
+                    Ok(())
+                }) as RecvReady<'_>
+            })
+            .collect::<Vec<_>>();
+        let writable = conns
+            .iter()
+            .filter(|c| c.wants_write())
+            .map(|c| {
+                let stream = c.stream.clone();
+                Box::pin(async move {
+                    let _ = stream.writable().await;
+                    Ok(())
+                }) as RecvReady<'_>
+            })
+            .collect::<Vec<_>>();
+        // Wake up for the first connection to go idle, so that it's closed
+        let idle = conns
+            .iter()
+            .map(|c| c.deadline(self.idle_timeout))
+            .min()
+            .map(|deadline| {
+                Box::pin(async move {
+                    Timer::at(deadline).await;
+                    Ok(())
+                }) as RecvReady<'_>
+            });
+        let accept = Box::pin(async move {
+            self.listener.readable().await.map_err(|e| {
+                error!("Error on the network: {:?}", e);
+                Error::Network
+            })
+        });
+        wait_any(
+            readable
+                .into_iter()
+                .chain(writable)
+                .chain(idle)
+                .chain(iter::once(accept as RecvReady<'_>)),
+        )
+    }
+
+    fn is_for(&self, addr: &Address) -> bool {
+        matches!(addr, Address::Tcp(_))
+    }
+
+    fn max_msg_size(&self) -> usize {
+        MAX_TCP_MSG_SIZE
+    }
+
+    fn take_closed(&self) -> Vec<Address> {
+        self.closed.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use smol::future;
+
+    use super::*;
+
+    // A stream whose data arrives in the given chunks
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = self
+                .0
+                .front_mut()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            if chunk.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(len)
+        }
+    }
+
+    fn transport() -> TcpTransport {
+        TcpTransport::new_with_config(&TransportConfig {
+            bind_addr: "127.0.0.1".parse().unwrap(),
+            port: 0,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    // Wait for a message, like the event loop does
+    fn recv_wait(t: &TcpTransport, buf: &mut [u8]) -> (usize, Address) {
+        let recv = async {
+            loop {
+                match t.recv(buf) {
+                    Ok(r) => return r,
+                    Err(Error::Timeout) => t.wait_recv().await.unwrap(),
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("No message received");
+        };
+        smol::block_on(future::or(recv, timeout))
+    }
+
+    // Send a message, waiting for the connection like the event loop does
+    fn send_wait(t: &TcpTransport, msg: &[u8], addr: Address) {
+        let send = async {
+            loop {
+                match t.send(msg, addr) {
+                    Ok(_) => return,
+                    Err(Error::InvalidState) => t.wait_recv().await.unwrap(),
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        };
+        let timeout = async {
+            Timer::after(Duration::from_secs(5)).await;
+            panic!("Not connected");
+        };
+        smol::block_on(future::or(send, timeout))
+    }
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let server = transport();
+        let server_addr = Address::Tcp(server.get_local_addr().unwrap());
+        let client = transport();
+
+        // The client connects on its first send, and the server replies on that connection
+        let mut buf = [0; MAX_TCP_MSG_SIZE];
+        send_wait(&client, &[1; 3000], server_addr);
+        let (len, peer) = recv_wait(&server, &mut buf);
+        assert_eq!(&buf[..len], &[1; 3000]);
+        server.send(&[2, 3], peer).unwrap();
+        assert_eq!(recv_wait(&client, &mut buf), (2, server_addr));
+        assert_eq!(&buf[..2], &[2, 3]);
+
+        // Once the client is gone, the server closes the connection
+        drop(client);
+        smol::block_on(server.wait_recv()).unwrap();
+        assert_eq!(server.recv(&mut buf).err(), Some(Error::Timeout));
+        assert_eq!(server.take_closed(), vec![peer]);
+        assert!(server.take_closed().is_empty());
+    }
+
+    #[test]
+    fn test_idle_connection() {
+        let mut server = transport();
+        server.idle_timeout = Duration::from_millis(200);
+        let server_addr = Address::Tcp(server.get_local_addr().unwrap());
+        let client = transport();
+
+        let mut buf = [0; MAX_TCP_MSG_SIZE];
+        send_wait(&client, &[1], server_addr);
+        let (_, peer) = recv_wait(&server, &mut buf);
+
+        // The server wakes up once the connection goes idle, and closes it
+        let start = Instant::now();
+        smol::block_on(server.wait_recv()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(server.recv(&mut buf).err(), Some(Error::Timeout));
+        assert_eq!(server.take_closed(), vec![peer]);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        let client = transport();
+        // Nobody listens on the port of a transport that is gone
+        let addr = Address::Tcp(transport().get_local_addr().unwrap());
+
+        // The send doesn't wait for the connection, which fails either straight away or in
+        // the background
+        match client.send(&[1], addr) {
+            Err(Error::InvalidState) => {
+                smol::block_on(client.wait_recv()).unwrap();
+                let mut buf = [0; 8];
+                assert_eq!(client.recv(&mut buf).err(), Some(Error::Timeout));
+            }
+            result => assert_eq!(result, Err(Error::Network)),
+        }
+        assert_eq!(client.take_closed(), vec![addr]);
+    }
+
+    #[test]
+    fn test_recv_fairness() {
+        let server = transport();
+        let server_addr = Address::Tcp(server.get_local_addr().unwrap());
+        let clients = [transport(), transport()];
+
+        let mut buf = [0; MAX_TCP_MSG_SIZE];
+        for client in clients.iter() {
+            send_wait(client, &[1], server_addr);
+        }
+        let mut peers = vec![
+            recv_wait(&server, &mut buf).1,
+            recv_wait(&server, &mut buf).1,
+        ];
+        // Both the connections always have a message ready, and they take turns
+        for client in clients.iter() {
+            for _ in 0..3 {
+                client.send(&[2], server_addr).unwrap();
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        for _ in 0..6 {
+            peers.push(recv_wait(&server, &mut buf).1);
+        }
+        assert_ne!(peers[0], peers[1]);
+        for pair in peers[2..].windows(2) {
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut stream = encode_frame(&[1, 2, 3]);
+        stream.extend(encode_frame(&[4; 2000]));
+        // The frames trickle in, split at arbitrary points
+        let mut chunks = Chunks(stream.chunks(3).map(|c| c.to_vec()).collect());
+
+        let mut reader = FrameReader::default();
+        let mut msgs = Vec::new();
+        while !chunks.0.is_empty() {
+            // Only a chunk at a time is available
+            let chunk = chunks.0.pop_front().unwrap();
+            let mut available = Chunks(VecDeque::from([chunk]));
+            while let Some(msg) = reader.read(&mut available).unwrap() {
+                msgs.push(msg);
+            }
+        }
+        assert_eq!(msgs, vec![vec![1, 2, 3], vec![4; 2000]]);
+
+        // A frame that is too large is an error, as is the end of the stream
+        let mut chunks = Chunks(VecDeque::from([encode_frame(&[0; MAX_TCP_MSG_SIZE + 1])]));
+        assert_eq!(
+            FrameReader::default().read(&mut chunks),
+            Err(Error::NoSpace)
+        );
+        let mut eof = &[][..];
+        assert_eq!(FrameReader::default().read(&mut eof), Err(Error::Network));
+    }
+}
@@ -15,15 +15,26 @@
  *    limitations under the License.
  */
 
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
 use crate::error::*;
-use smol::net::{Ipv6Addr, UdpSocket};
+use log::{error, info, warn};
+use smol::{future, Async, Timer};
+use socket2::{Domain, Protocol, Socket, Type};
 
-use super::network::{Address, NetworkInterface};
+use super::network::{Address, NetworkInterface, RecvReady, TransportConfig, RECV_POLL_TIMEOUT};
 
-// We could get rid of the smol here, but keeping it around in case we have to process
-// any other events in this thread's context
+// The socket is async, so that the transport can wait for packets in an event loop.
+// The blocking receive remains for the users that poll, like the controller.
 pub struct UdpListener {
-    socket: UdpSocket,
+    socket: Async<UdpSocket>,
+    // The peer addresses have to be of the socket's address family
+    ipv6: bool,
+    // The interface that the multicast groups are joined on, or 0 for the default one
+    interface_index: u32,
 }
 
 // Currently matches with the one in connectedhomeip repo
@@ -34,24 +45,245 @@ pub const MATTER_PORT: u16 = 5540;
 
 impl UdpListener {
     pub fn new() -> Result<UdpListener, Error> {
+        UdpListener::new_with_config(&Default::default())
+    }
+
+    /// Listen on the given port, instead of the Matter port. A port of 0 picks any free port,
+    /// which is what a controller would typically use.
+    pub fn new_with_port(port: u16) -> Result<UdpListener, Error> {
+        UdpListener::new_with_config(&TransportConfig {
+            port,
+            ..Default::default()
+        })
+    }
+
+    pub fn new_with_config(config: &TransportConfig) -> Result<UdpListener, Error> {
+        let (socket, addr) = open_socket(config, Type::DGRAM, Protocol::UDP)?;
+        let mut interface_index = 0;
+        if let Some(interface) = &config.interface {
+            interface_index = super::network::get_interface_index(interface)?;
+        }
+        socket.bind(&addr.into())?;
+        let socket: UdpSocket = socket.into();
+        info!("Listening on {}", socket.local_addr()?);
         Ok(UdpListener {
-            socket: smol::block_on(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, MATTER_PORT)))?,
+            socket: Async::new(socket)?,
+            ipv6: addr.is_ipv6(),
+            interface_index,
         })
     }
+
+    /// The address that we are listening on, this has the port that was picked, if the
+    /// configured port was 0
+    pub fn get_local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.get_ref().local_addr()?)
+    }
+
+    // An IPv4 peer is reached through its IPv4-mapped address on an IPv6 socket
+    fn to_socket_family(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(a) if self.ipv6 => {
+                SocketAddr::new(a.ip().to_ipv6_mapped().into(), a.port())
+            }
+            SocketAddr::V6(a) if !self.ipv6 => match a.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), a.port()),
+                None => addr,
+            },
+            _ => addr,
+        }
+    }
+}
+
+// The IPv4-mapped addresses of the peers on an IPv6 socket are reported as IPv4 addresses,
+// so that a peer has the same address irrespective of the socket that it reached us on
+pub(super) fn to_canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), a.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// Open a socket for the configured address, bound to the configured interface, but not yet
+/// to the address that is returned
+///
+/// The IPv6 unspecified address, the default, falls back to the IPv4 unspecified address on
+/// the hosts that have IPv6 disabled.
+pub(super) fn open_socket(
+    config: &TransportConfig,
+    ty: Type,
+    protocol: Protocol,
+) -> Result<(Socket, SocketAddr), Error> {
+    let mut addr = config.socket_addr()?;
+    let socket = match Socket::new(Domain::for_address(addr), ty, Some(protocol)) {
+        Err(e) if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            warn!("IPv6 isn't available ({:?}), only listening on IPv4", e);
+            addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+            Socket::new(Domain::IPV4, ty, Some(protocol))?
+        }
+        socket => socket?,
+    };
+    if addr.is_ipv6() {
+        socket.set_only_v6(!config.dual_stack)?;
+    }
+    if let Some(interface) = &config.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+    Ok((socket, addr))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+pub(super) fn bind_to_interface(socket: &Socket, interface: &str) -> Result<(), Error> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        error!("Couldn't bind to the interface {}: {:?}", interface, e);
+        Error::Network
+    })
+}
+
+#[cfg(target_vendor = "apple")]
+pub(super) fn bind_to_interface(socket: &Socket, interface: &str) -> Result<(), Error> {
+    let index = super::network::get_interface_index(interface)?;
+    socket
+        .bind_device_by_index(std::num::NonZeroU32::new(index))
+        .map_err(|e| {
+            error!("Couldn't bind to the interface {}: {:?}", interface, e);
+            Error::Network
+        })
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_vendor = "apple"
+)))]
+pub(super) fn bind_to_interface(_socket: &Socket, interface: &str) -> Result<(), Error> {
+    error!("Binding to the interface {} isn't supported", interface);
+    Err(Error::Invalid)
 }
 
 impl NetworkInterface for UdpListener {
     fn recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
-        let (size, addr) = smol::block_on(self.socket.recv_from(in_buf)).map_err(|e| {
-            println!("Error on the network: {:?}", e);
-            Error::Network
-        })?;
-        Ok((size, Address::Udp(addr)))
+        match self.try_recv(in_buf) {
+            Err(Error::Timeout) => {
+                let recv = async { Some(self.socket.recv_from(in_buf).await) };
+                let timeout = async {
+                    Timer::after(RECV_POLL_TIMEOUT).await;
+                    None
+                };
+                let (size, addr) = smol::block_on(future::or(recv, timeout))
+                    .ok_or(Error::Timeout)?
+                    .map_err(|e| {
+                        error!("Error on the network: {:?}", e);
+                        Error::Network
+                    })?;
+                Ok((size, Address::Udp(to_canonical(addr))))
+            }
+            result => result,
+        }
+    }
+
+    fn try_recv(&self, in_buf: &mut [u8]) -> Result<(usize, Address), Error> {
+        match self.socket.get_ref().recv_from(in_buf) {
+            Ok((size, addr)) => Ok((size, Address::Udp(to_canonical(addr)))),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(Error::Timeout),
+            Err(e) => {
+                error!("Error on the network: {:?}", e);
+                Err(Error::Network)
+            }
+        }
     }
 
     fn send(&self, out_buf: &[u8], addr: Address) -> Result<usize, Error> {
-        match addr {
-            Address::Udp(addr) => Ok(smol::block_on(self.socket.send_to(out_buf, addr))?),
+        let addr = match addr {
+            Address::Udp(addr) => self.to_socket_family(addr),
+            _ => return Err(Error::Invalid),
+        };
+        match self.socket.get_ref().send_to(out_buf, addr) {
+            // Like a packet that is lost on the way, the retransmission is up to MRP
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                warn!("The socket is busy, dropping the packet to {}", addr);
+                Ok(out_buf.len())
+            }
+            result => Ok(result?),
         }
     }
+
+    fn is_for(&self, addr: &Address) -> bool {
+        matches!(addr, Address::Udp(_))
+    }
+
+    fn wait_recv(&self) -> RecvReady<'_> {
+        Box::pin(async move {
+            self.socket.readable().await.map_err(|e| {
+                error!("Error on the network: {:?}", e);
+                Error::Network
+            })
+        })
+    }
+
+    fn join_multicast(&self, addr: &Ipv6Addr) -> Result<(), Error> {
+        if !self.ipv6 {
+            // The groups are only reached over IPv6
+            error!("Can't join the multicast group {} on an IPv4 socket", addr);
+            return Err(Error::Invalid);
+        }
+        self.socket
+            .get_ref()
+            .join_multicast_v6(addr, self.interface_index)
+            .map_err(|e| {
+                error!("Couldn't join the multicast group {}: {:?}", addr, e);
+                Error::Network
+            })
+    }
+
+    fn leave_multicast(&self, addr: &Ipv6Addr) -> Result<(), Error> {
+        if !self.ipv6 {
+            return Err(Error::Invalid);
+        }
+        self.socket
+            .get_ref()
+            .leave_multicast_v6(addr, self.interface_index)
+            .map_err(|e| {
+                error!("Couldn't leave the multicast group {}: {:?}", addr, e);
+                Error::Network
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn listener(bind_addr: IpAddr) -> UdpListener {
+        UdpListener::new_with_config(&TransportConfig {
+            bind_addr,
+            port: 0,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let v6 = listener(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let v4 = listener(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let v4_addr = v4.get_local_addr().unwrap();
+
+        // The IPv4 peer is reached from the IPv6 socket, and is seen by its IPv4 address
+        let mut buf = [0; 8];
+        v6.send(&[1, 2, 3], Address::Udp(v4_addr)).unwrap();
+        let (len, from) = v4.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        v4.send(&[4, 5], from).unwrap();
+        let (len, from) = v6.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[4, 5]);
+        assert_eq!(from, Address::Udp(v4_addr));
+        assert_eq!(v4.try_recv(&mut buf).err(), Some(Error::Timeout));
+        assert_eq!(v4.recv(&mut buf).err(), Some(Error::Timeout));
+    }
 }
@@ -0,0 +1,46 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The UTC time in Matter, which counts the seconds since the Matter epoch
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The start of the Matter epoch (2000-01-01 00:00:00 UTC) in UNIX time
+pub const MATTER_EPOCH_SECS: u64 = 946684800;
+
+/// A source of the current UTC time, that the validity periods of the certificates are
+/// checked against
+pub trait UtcTime {
+    /// The current time in seconds since the Matter epoch, or None if it isn't known, like
+    /// before the time has been synchronised
+    fn now(&self) -> Option<u32>;
+}
+
+/// The UTC time of the system clock
+///
+/// A clock that is still before the Matter epoch, like one that starts at the UNIX epoch
+/// on boot, is taken as not synchronised.
+pub struct SysUtcTime;
+
+impl UtcTime for SysUtcTime {
+    fn now(&self) -> Option<u32> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        now.checked_sub(MATTER_EPOCH_SECS)
+            .filter(|t| *t > 0)
+            .map(|t| t.min(u32::MAX as u64) as u32)
+    }
+}
@@ -15,5 +15,6 @@
  *    limitations under the License.
  */
 
+pub mod epoch;
 pub mod parsebuf;
 pub mod writebuf;
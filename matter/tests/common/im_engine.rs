@@ -16,7 +16,6 @@
  */
 
 use crate::common::echo_cluster;
-use boxslab::Slab;
 use matter::{
     acl::{AclEntry, AclMgr, AuthMode},
     data_model::{
@@ -28,16 +27,18 @@ use matter::{
     },
     error::Error,
     fabric::FabricMgr,
+    group_keys::GroupMgr,
     interaction_model::{core::OpCode, InteractionModel},
+    mdns::Mdns,
+    persist::MemKvStorage,
     secure_channel::pake::PaseMgr,
     tlv::{TLVWriter, TagType, ToTLV},
-    transport::packet::Packet,
     transport::{
         exchange::{self, Exchange, ExchangeCtx},
         network::Address,
         packet::PacketPool,
         proto_demux::ProtoCtx,
-        session::{CloneData, NocCatIds, SessionMgr, SessionMode},
+        session::{CloneData, GroupDetails, NocCatIds, SessionMgr, SessionMode},
     },
     transport::{proto_demux::HandleProto, session::CaseDetails},
     utils::writebuf::WriteBuf,
@@ -58,6 +59,7 @@ impl DevAttDataFetcher for DummyDevAtt {
 pub struct ImEngine {
     pub dm: DataModel,
     pub acl_mgr: Arc<AclMgr>,
+    pub group_mgr: Arc<GroupMgr>,
     pub im: Box<InteractionModel>,
     // By default, a new exchange is created for every run, if you wish to instead using a specific
     // exchange, set this variable. This is helpful in situations where you have to run multiple
@@ -70,6 +72,7 @@ pub struct ImInput<'a> {
     data: &'a dyn ToTLV,
     peer_id: u64,
     cat_ids: NocCatIds,
+    group_id: Option<u16>,
 }
 
 pub const IM_ENGINE_PEER_ID: u64 = 445566;
@@ -80,6 +83,7 @@ impl<'a> ImInput<'a> {
             data,
             peer_id: IM_ENGINE_PEER_ID,
             cat_ids: Default::default(),
+            group_id: None,
         }
     }
 
@@ -90,6 +94,11 @@ impl<'a> ImInput<'a> {
     pub fn set_cat_ids(&mut self, cat_ids: &NocCatIds) {
         self.cat_ids = *cat_ids;
     }
+
+    /// Send the input as a group message, to the group of the IM Engine's fabric
+    pub fn set_group(&mut self, group_id: u16) {
+        self.group_id = Some(group_id);
+    }
 }
 
 impl ImEngine {
@@ -106,15 +115,28 @@ impl ImEngine {
         };
 
         let dev_att = Box::new(DummyDevAtt {});
-        let fabric_mgr = Arc::new(FabricMgr::new().unwrap());
-        let acl_mgr = Arc::new(AclMgr::new_with(false).unwrap());
-        let pase_mgr = PaseMgr::new();
+        // Every engine has its own storage, so the tests don't interfere with each other
+        let storage = Arc::new(MemKvStorage::new());
+        let mdns = Arc::new(Mdns::new());
+        let fabric_mgr = Arc::new(FabricMgr::new(storage.clone(), mdns.clone()).unwrap());
+        let acl_mgr = Arc::new(AclMgr::new(storage.clone()).unwrap());
+        let group_mgr = Arc::new(GroupMgr::new(storage.clone(), fabric_mgr.clone()).unwrap());
+        let pase_mgr = PaseMgr::new(mdns);
         acl_mgr.erase_all();
         let mut default_acl = AclEntry::new(1, Privilege::ADMIN, AuthMode::Case);
         // Only allow the standard peer node id of the IM Engine
         default_acl.add_subject(IM_ENGINE_PEER_ID).unwrap();
         acl_mgr.add(default_acl).unwrap();
-        let dm = DataModel::new(dev_det, dev_att, fabric_mgr, acl_mgr.clone(), pase_mgr).unwrap();
+        let dm = DataModel::new(
+            dev_det,
+            dev_att,
+            fabric_mgr,
+            acl_mgr.clone(),
+            group_mgr.clone(),
+            pase_mgr,
+            storage,
+        )
+        .unwrap();
 
         {
             let mut d = dm.node.write().unwrap();
@@ -130,6 +152,7 @@ impl ImEngine {
         Self {
             dm,
             acl_mgr,
+            group_mgr,
             im,
             exch: None,
         }
@@ -142,6 +165,13 @@ impl ImEngine {
         let exch = self.exch.as_mut().unwrap_or(&mut new_exch);
 
         let mut sess_mgr: SessionMgr = Default::default();
+        let mode = match input.group_id {
+            Some(group_id) => SessionMode::Group(GroupDetails {
+                fab_idx: 1,
+                group_id,
+            }),
+            None => SessionMode::Case(CaseDetails::new(1, &input.cat_ids)),
+        };
 
         let clone_data = CloneData::new(
             123456,
@@ -152,13 +182,14 @@ impl ImEngine {
                 std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
                 5542,
             )),
-            SessionMode::Case(CaseDetails::new(1, &input.cat_ids)),
+            mode,
         );
         let sess_idx = sess_mgr.clone_session(&clone_data).unwrap();
         let sess = sess_mgr.get_session_handle(sess_idx);
         let exch_ctx = ExchangeCtx { exch, sess };
-        let mut rx = Slab::<PacketPool>::try_new(Packet::new_rx().unwrap()).unwrap();
-        let tx = Slab::<PacketPool>::try_new(Packet::new_tx().unwrap()).unwrap();
+        let pool = PacketPool::new();
+        let mut rx = pool.alloc_rx().unwrap();
+        let tx = pool.alloc_tx().unwrap();
         // Create fake rx packet
         rx.set_proto_id(0x01);
         rx.set_proto_opcode(input.action as u8);
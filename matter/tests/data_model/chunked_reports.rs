@@ -0,0 +1,154 @@
+/*
+ *
+ *    Copyright (c) 2023 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use matter::{
+    interaction_model::{
+        core::{IMStatusCode, OpCode},
+        messages::{
+            ib::{AttrPath, AttrResp},
+            msg::{ReadReq, ReportDataMsg, StatusResp, SubscribeReq, SubscribeResp},
+            GenericPath,
+        },
+    },
+    tlv::{self, FromTLV, ToTLV},
+    transport::exchange::{self, Exchange},
+};
+
+use crate::common::im_engine::{ImEngine, ImInput};
+
+fn process(im_engine: &mut ImEngine, action: OpCode, data: &dyn ToTLV) -> (OpCode, Vec<u8>) {
+    let mut out_buf = [0u8; 1500];
+    let input = ImInput::new(action, data);
+    let (opcode, out) = im_engine.process(&input, &mut out_buf);
+    (num::FromPrimitive::from_u8(opcode).unwrap(), out.to_vec())
+}
+
+/// Keep confirming the chunks of a report, till the last one. Returns the paths of all the
+/// attributes reported and the number of chunks
+fn collect_chunks(
+    im_engine: &mut ImEngine,
+    first_chunk: Vec<u8>,
+    subs_id: Option<u32>,
+) -> (Vec<(u16, u32, u16)>, usize) {
+    let mut paths = Vec::new();
+    let mut chunks = 0;
+    let mut out = first_chunk;
+    loop {
+        chunks += 1;
+        let root = tlv::get_root_node_struct(&out).unwrap();
+        let report = ReportDataMsg::from_tlv(&root).unwrap();
+        assert_eq!(report.subscription_id, subs_id);
+        for attr_resp in report.attr_reports.unwrap().iter() {
+            if let AttrResp::Data(d) = attr_resp {
+                let path = (
+                    d.path.endpoint.unwrap(),
+                    d.path.cluster.unwrap(),
+                    d.path.attr.unwrap(),
+                );
+                // No attribute should be reported twice
+                assert!(!paths.contains(&path));
+                paths.push(path);
+            }
+        }
+        if report.more_chunks != Some(true) {
+            return (paths, chunks);
+        }
+        // Every chunk, but the last one, must be acknowledged
+        assert_eq!(report.suppress_response, Some(false));
+
+        let status = StatusResp {
+            status: IMStatusCode::Sucess,
+        };
+        let (opcode, next) = process(im_engine, OpCode::StatusResponse, &status);
+        assert_eq!(opcode, OpCode::ReportData);
+        out = next;
+    }
+}
+
+#[test]
+fn test_read_chunked() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    // All the chunks are sent on the same exchange
+    im_engine.exch = Some(Exchange::new(1, 0, exchange::Role::Responder));
+
+    // A wildcard read of everything doesn't fit in a single message
+    let path = [AttrPath::new(&GenericPath::new(None, None, None))];
+    let read_req = ReadReq::new(true).set_attr_requests(&path);
+    let (opcode, out) = process(&mut im_engine, OpCode::ReadRequest, &read_req);
+    assert_eq!(opcode, OpCode::ReportData);
+
+    let (paths, chunks) = collect_chunks(&mut im_engine, out, None);
+    assert!(chunks > 1);
+    // Both the root and the light endpoints are covered
+    assert!(paths.iter().any(|p| p.0 == 0));
+    assert!(paths.iter().any(|p| p.0 == 1));
+}
+
+#[test]
+fn test_subscribe_chunked_priming() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    im_engine.exch = Some(Exchange::new(1, 0, exchange::Role::Responder));
+
+    let path = [AttrPath::new(&GenericPath::new(None, None, None))];
+    let subs_req = SubscribeReq::new(true, 5, 30).set_attr_requests(&path);
+    let (opcode, out) = process(&mut im_engine, OpCode::SubscribeRequest, &subs_req);
+    assert_eq!(opcode, OpCode::ReportData);
+    let root = tlv::get_root_node_struct(&out).unwrap();
+    let subs_id = ReportDataMsg::from_tlv(&root)
+        .unwrap()
+        .subscription_id
+        .unwrap();
+
+    let (_, chunks) = collect_chunks(&mut im_engine, out, Some(subs_id));
+    assert!(chunks > 1);
+
+    // The subscription is confirmed only after the last chunk of the priming report
+    let status = StatusResp {
+        status: IMStatusCode::Sucess,
+    };
+    let (opcode, out) = process(&mut im_engine, OpCode::StatusResponse, &status);
+    assert_eq!(opcode, OpCode::SubscriptResponse);
+    let root = tlv::get_root_node_struct(&out).unwrap();
+    let resp = SubscribeResp::from_tlv(&root).unwrap();
+    assert_eq!(resp.subs_id, subs_id);
+}
+
+#[test]
+fn test_read_chunked_aborted() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    im_engine.exch = Some(Exchange::new(1, 0, exchange::Role::Responder));
+
+    let path = [AttrPath::new(&GenericPath::new(None, None, None))];
+    let read_req = ReadReq::new(true).set_attr_requests(&path);
+    let (_, out) = process(&mut im_engine, OpCode::ReadRequest, &read_req);
+    let root = tlv::get_root_node_struct(&out).unwrap();
+    assert_eq!(
+        ReportDataMsg::from_tlv(&root).unwrap().more_chunks,
+        Some(true)
+    );
+
+    // The reader gives up, no more chunks are sent
+    let status = StatusResp {
+        status: IMStatusCode::Failure,
+    };
+    let (_, out) = process(&mut im_engine, OpCode::StatusResponse, &status);
+    assert!(out.is_empty());
+    assert!(im_engine.exch.as_ref().unwrap().is_data_none());
+}
@@ -17,21 +17,30 @@
 
 use crate::{
     cmd_data,
-    common::{commands::*, echo_cluster, im_engine::im_engine},
+    common::{
+        commands::*,
+        echo_cluster,
+        im_engine::{im_engine, ImEngine, ImInput},
+    },
     echo_req, echo_resp,
 };
 
 use matter::{
-    data_model::{cluster_on_off, objects::EncodeValue},
+    acl::{AclEntry, AuthMode},
+    data_model::{
+        cluster_groups, cluster_on_off,
+        objects::{AttrValue, EncodeValue, Privilege},
+        system_model::group_key_management,
+    },
     interaction_model::{
         core::{IMStatusCode, OpCode},
         messages::{
-            ib::{CmdData, CmdPath, CmdStatus},
+            ib::{self, CmdData, CmdPath, CmdStatus},
             msg,
             msg::InvReq,
         },
     },
-    tlv::{self, FromTLV, TLVArray},
+    tlv::{self, FromTLV, TLVArray, TLVElement, TLVWriter, TagType},
 };
 
 // Helper for handling Invoke Command sequences
@@ -156,3 +165,160 @@ fn test_invoke_cmd_wc_endpoint_only_1_has_cluster() {
     ))];
     handle_commands(input, expected);
 }
+
+#[test]
+fn test_invoke_cmd_groups() {
+    // - AddGroup for a group that has no key - UnsupportedAccess
+    // - RemoveGroup for a group that the endpoint isn't in - NotFound
+    // The responses carry the status in their first field
+    let _ = env_logger::try_init();
+
+    let add_group = |tag: TagType, tw: &mut TLVWriter| {
+        let _ = tw.start_struct(tag);
+        let _ = tw.u16(TagType::Context(0), 0x101);
+        let _ = tw.utf8(TagType::Context(1), b"Kitchen");
+        let _ = tw.end_container();
+    };
+    let remove_group = |tag: TagType, tw: &mut TLVWriter| {
+        let _ = tw.start_struct(tag);
+        let _ = tw.u16(TagType::Context(0), 0x101);
+        let _ = tw.end_container();
+    };
+    let add_path = CmdPath::new(
+        Some(1),
+        Some(cluster_groups::ID),
+        Some(cluster_groups::Commands::AddGroup as u16),
+    );
+    let remove_path = CmdPath::new(
+        Some(1),
+        Some(cluster_groups::ID),
+        Some(cluster_groups::Commands::RemoveGroup as u16),
+    );
+    let input = &[
+        CmdData::new(add_path, EncodeValue::Closure(&add_group)),
+        CmdData::new(remove_path, EncodeValue::Closure(&remove_group)),
+    ];
+    let expected = &[
+        ExpectedInvResp::Cmd(add_path, IMStatusCode::UnsupportedAccess as u8),
+        ExpectedInvResp::Cmd(remove_path, IMStatusCode::NotFound as u8),
+    ];
+    handle_commands(input, expected);
+}
+
+#[test]
+fn test_invoke_cmd_group_acl() {
+    // An On command to a group that endpoint 1 is a member of
+    // - without a Group ACL entry for the group - the command doesn't run
+    // - with a Group ACL entry granting Operate - the command runs
+    let _ = env_logger::try_init();
+
+    let mut im = ImEngine::new();
+    im.group_mgr.add_group(1, 0x101, 1, "Kitchen").unwrap();
+    let path = CmdPath::new(
+        None,
+        Some(cluster_on_off::ID),
+        Some(cluster_on_off::Commands::On as u16),
+    );
+    let input = &[cmd_data!(path, 1)];
+    let req = InvReq {
+        suppress_response: Some(true),
+        timed_request: Some(false),
+        inv_requests: Some(TLVArray::Slice(input)),
+    };
+    let mut input = ImInput::new(OpCode::InvokeRequest, &req);
+    input.set_group(0x101);
+    let on_off = |im: &ImEngine| {
+        im.dm
+            .read_attribute_raw(
+                1,
+                cluster_on_off::ID,
+                cluster_on_off::Attributes::OnOff as u16,
+            )
+            .unwrap()
+    };
+
+    let mut out_buf = [0u8; 400];
+    im.process(&input, &mut out_buf);
+    assert_eq!(on_off(&im), AttrValue::Bool(false));
+
+    let mut group_acl = AclEntry::new(1, Privilege::OPERATE, AuthMode::Group);
+    group_acl.add_subject(0x101).unwrap();
+    im.acl_mgr.add(group_acl).unwrap();
+    im.process(&input, &mut out_buf);
+    assert_eq!(on_off(&im), AttrValue::Bool(true));
+}
+
+// Invoke a single command through the IM Engine, the data of its response is passed to 'f'
+fn handle_command_resp(im: &mut ImEngine, input: &[CmdData], f: impl FnOnce(&TLVElement)) {
+    let mut out_buf = [0u8; 400];
+    let req = InvReq {
+        suppress_response: Some(false),
+        timed_request: Some(false),
+        inv_requests: Some(TLVArray::Slice(input)),
+    };
+    let input = ImInput::new(OpCode::InvokeRequest, &req);
+    let (_, out_buf) = im.process(&input, &mut out_buf);
+    tlv::print_tlv_list(out_buf);
+    let root = tlv::get_root_node_struct(out_buf).unwrap();
+    let resp = msg::InvResp::from_tlv(&root).unwrap();
+    match resp.inv_responses.unwrap().iter().next() {
+        Some(ib::InvResp::Cmd(c)) => match c.data {
+            EncodeValue::Tlv(t) => f(&t),
+            _ => panic!("Incorrect CmdDataType"),
+        },
+        _ => panic!("Invalid response, expected InvResponse::Cmd"),
+    }
+}
+
+#[test]
+fn test_invoke_cmd_key_set_read_all() {
+    // Every index that KeySetReadAllIndices reports can be read with KeySetRead, including
+    // the IPK, that is reported with a single epoch key and without the keys themselves
+    let _ = env_logger::try_init();
+
+    let mut im = ImEngine::new();
+    let read_all_path = CmdPath::new(
+        Some(0),
+        Some(group_key_management::ID),
+        Some(group_key_management::Commands::KeySetReadAllIndices as u16),
+    );
+    let read_all = |tag: TagType, tw: &mut TLVWriter| {
+        let _ = tw.start_struct(tag);
+        let _ = tw.end_container();
+    };
+    let mut ids = Vec::new();
+    handle_command_resp(
+        &mut im,
+        &[CmdData::new(read_all_path, EncodeValue::Closure(&read_all))],
+        |data| {
+            for id in data.find_tag(0).unwrap().enter().unwrap() {
+                ids.push(id.u16().unwrap());
+            }
+        },
+    );
+    assert_eq!(ids, vec![0]);
+
+    let read_path = CmdPath::new(
+        Some(0),
+        Some(group_key_management::ID),
+        Some(group_key_management::Commands::KeySetRead as u16),
+    );
+    for id in ids {
+        let read = |tag: TagType, tw: &mut TLVWriter| {
+            let _ = tw.start_struct(tag);
+            let _ = tw.u16(TagType::Context(0), id);
+            let _ = tw.end_container();
+        };
+        handle_command_resp(
+            &mut im,
+            &[CmdData::new(read_path, EncodeValue::Closure(&read))],
+            |data| {
+                let key_set = data.find_tag(0).unwrap();
+                assert_eq!(key_set.find_tag(0).unwrap().u16(), Ok(id));
+                assert!(key_set.find_tag(2).unwrap().null().is_ok());
+                assert_eq!(key_set.find_tag(3).unwrap().u64(), Ok(0));
+                assert!(key_set.find_tag(4).unwrap().null().is_ok());
+            },
+        );
+    }
+}
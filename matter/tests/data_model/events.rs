@@ -0,0 +1,161 @@
+/*
+ *
+ *    Copyright (c) 2023 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use matter::{
+    data_model::{cluster_on_off, objects::EventPriority},
+    interaction_model::{
+        core::{IMStatusCode, OpCode},
+        messages::{
+            ib::{EventFilter, EventPath, EventResp},
+            msg::{ReadReq, ReportDataMsg},
+            GenericPath,
+        },
+    },
+    tlv::{self, FromTLV},
+};
+
+use crate::common::{
+    echo_cluster,
+    im_engine::{ImEngine, ImInput},
+};
+
+const EVENT_ID: u32 = 1;
+
+fn emit_events(im_engine: &ImEngine, count: u8) {
+    for i in 0..count {
+        im_engine
+            .dm
+            .events
+            .emit(
+                1,
+                cluster_on_off::ID,
+                EVENT_ID,
+                EventPriority::Info,
+                |tw, tag| tw.u8(tag, i),
+            )
+            .unwrap();
+    }
+}
+
+fn read_events<'a>(
+    im_engine: &mut ImEngine,
+    paths: &[EventPath],
+    filters: &[EventFilter],
+    out_buf: &'a mut [u8],
+) -> ReportDataMsg<'a> {
+    let read_req = ReadReq::new(true)
+        .set_event_requests(paths)
+        .set_event_filters(filters);
+    let input = ImInput::new(OpCode::ReadRequest, &read_req);
+    let (opcode, out) = im_engine.process(&input, out_buf);
+    assert_eq!(
+        num::FromPrimitive::from_u8(opcode),
+        Some(OpCode::ReportData)
+    );
+    let root = tlv::get_root_node_struct(out).unwrap();
+    ReportDataMsg::from_tlv(&root).unwrap()
+}
+
+// The event numbers and payloads of all the events in a report
+fn event_data(report: &ReportDataMsg) -> Vec<(u64, u8)> {
+    report
+        .event_reports
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            EventResp::Data(d) => {
+                Some((d.event_number, d.data.unwrap_tlv().unwrap().u8().unwrap()))
+            }
+            EventResp::Status(_) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_read_events() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    emit_events(&im_engine, 3);
+
+    let paths = [EventPath::new(&GenericPath::new(
+        Some(1),
+        Some(cluster_on_off::ID),
+        None,
+    ))];
+    let mut out_buf = [0u8; 400];
+    let report = read_events(&mut im_engine, &paths, &[], &mut out_buf);
+    assert!(report.attr_reports.is_none());
+    assert_eq!(event_data(&report), vec![(0, 0), (1, 1), (2, 2)]);
+    if let EventResp::Data(d) = report.event_reports.unwrap().iter().next().unwrap() {
+        assert_eq!(d.path.endpoint, Some(1));
+        assert_eq!(d.path.cluster, Some(cluster_on_off::ID));
+        assert_eq!(d.path.event, Some(EVENT_ID));
+        assert_eq!(d.priority, EventPriority::Info as u8);
+        assert!(d.epoch_ts.is_some());
+    } else {
+        panic!("Expected event data");
+    }
+}
+
+#[test]
+fn test_read_events_filtered() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    emit_events(&im_engine, 3);
+
+    // Only the events from the given event number onwards
+    let paths = [EventPath::new(&GenericPath::new(None, None, None))];
+    let filters = [EventFilter {
+        node: None,
+        event_min: Some(2),
+    }];
+    let mut out_buf = [0u8; 400];
+    let report = read_events(&mut im_engine, &paths, &filters, &mut out_buf);
+    assert_eq!(event_data(&report), vec![(2, 2)]);
+
+    // Events of other clusters aren't reported
+    let paths = [EventPath::new(&GenericPath::new(
+        Some(0),
+        Some(echo_cluster::ID),
+        None,
+    ))];
+    let mut out_buf = [0u8; 400];
+    let report = read_events(&mut im_engine, &paths, &[], &mut out_buf);
+    assert_eq!(event_data(&report), vec![]);
+}
+
+#[test]
+fn test_read_events_unsupported_path() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+
+    let paths = [EventPath::new(&GenericPath::new(
+        Some(1),
+        Some(0x1234),
+        Some(EVENT_ID),
+    ))];
+    let mut out_buf = [0u8; 400];
+    let report = read_events(&mut im_engine, &paths, &[], &mut out_buf);
+    let mut reports = report.event_reports.unwrap().iter();
+    match reports.next() {
+        Some(EventResp::Status(s)) => {
+            assert_eq!(s.status.status, IMStatusCode::UnsupportedCluster)
+        }
+        _ => panic!("Expected an event status"),
+    }
+    assert!(reports.next().is_none());
+}
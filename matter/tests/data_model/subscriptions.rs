@@ -0,0 +1,77 @@
+/*
+ *
+ *    Copyright (c) 2023 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use matter::{
+    interaction_model::{
+        core::{IMStatusCode, OpCode},
+        messages::{
+            ib::AttrPath,
+            msg::{ReportDataMsg, StatusResp, SubscribeReq, SubscribeResp},
+            GenericPath,
+        },
+    },
+    tlv::{self, FromTLV},
+    transport::exchange::{self, Exchange},
+};
+
+use crate::common::{
+    echo_cluster,
+    im_engine::{ImEngine, ImInput},
+};
+
+#[test]
+fn test_subscribe_priming_and_confirm() {
+    let _ = env_logger::try_init();
+    let mut im_engine = ImEngine::new();
+    // The priming report and the confirmation happen on the same exchange
+    im_engine.exch = Some(Exchange::new(1, 0, exchange::Role::Responder));
+
+    let path = [AttrPath::new(&GenericPath::new(
+        Some(0),
+        Some(echo_cluster::ID),
+        Some(echo_cluster::Attributes::Att1 as u32),
+    ))];
+    let subs_req = SubscribeReq::new(true, 5, 30).set_attr_requests(&path);
+    let mut out_buf = [0u8; 400];
+    let input = ImInput::new(OpCode::SubscribeRequest, &subs_req);
+    let (opcode, out) = im_engine.process(&input, &mut out_buf);
+    assert_eq!(
+        num::FromPrimitive::from_u8(opcode),
+        Some(OpCode::ReportData)
+    );
+    let root = tlv::get_root_node_struct(out).unwrap();
+    let report = ReportDataMsg::from_tlv(&root).unwrap();
+    let subs_id = report.subscription_id.unwrap();
+    assert_eq!(report.suppress_response, Some(false));
+    assert_eq!(report.attr_reports.unwrap().iter().count(), 1);
+
+    // Confirm the priming report
+    let status = StatusResp {
+        status: IMStatusCode::Sucess,
+    };
+    let mut out_buf = [0u8; 400];
+    let input = ImInput::new(OpCode::StatusResponse, &status);
+    let (opcode, out) = im_engine.process(&input, &mut out_buf);
+    assert_eq!(
+        num::FromPrimitive::from_u8(opcode),
+        Some(OpCode::SubscriptResponse)
+    );
+    let root = tlv::get_root_node_struct(out).unwrap();
+    let resp = SubscribeResp::from_tlv(&root).unwrap();
+    assert_eq!(resp.subs_id, subs_id);
+    assert_eq!(resp.max_int, 30);
+}
@@ -21,6 +21,9 @@ mod data_model {
     mod acl_and_dataver;
     mod attribute_lists;
     mod attributes;
+    mod chunked_reports;
     mod commands;
+    mod events;
+    mod subscriptions;
     mod timed_requests;
 }
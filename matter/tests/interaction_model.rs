@@ -15,20 +15,18 @@
  *    limitations under the License.
  */
 
-use boxslab::Slab;
 use matter::error::Error;
 use matter::interaction_model::core::OpCode;
 use matter::interaction_model::messages::msg::InvReq;
 use matter::interaction_model::messages::msg::ReadReq;
 use matter::interaction_model::messages::msg::WriteReq;
-use matter::interaction_model::InteractionConsumer;
 use matter::interaction_model::InteractionModel;
 use matter::interaction_model::Transaction;
+use matter::interaction_model::{InteractionConsumer, ResumeCursor};
 use matter::tlv::TLVWriter;
 use matter::transport::exchange::Exchange;
 use matter::transport::exchange::ExchangeCtx;
 use matter::transport::network::Address;
-use matter::transport::packet::Packet;
 use matter::transport::packet::PacketPool;
 use matter::transport::proto_demux::HandleProto;
 use matter::transport::proto_demux::ProtoCtx;
@@ -96,8 +94,19 @@ impl InteractionConsumer for DataModel {
         _req: &ReadReq,
         _trans: &mut Transaction,
         _tlvwriter: &mut TLVWriter,
-    ) -> Result<(), Error> {
-        Ok(())
+        _resume_from: Option<ResumeCursor>,
+    ) -> Result<Option<ResumeCursor>, Error> {
+        Ok(None)
+    }
+
+    fn consume_read_event(
+        &self,
+        _req: &ReadReq,
+        _trans: &mut Transaction,
+        _tlvwriter: &mut TLVWriter,
+        _resume_from: Option<u64>,
+    ) -> Result<Option<u64>, Error> {
+        Ok(None)
     }
 
     fn consume_write_attr(
@@ -136,8 +145,9 @@ fn handle_data(action: OpCode, data_in: &[u8], data_out: &mut [u8]) -> (DataMode
         exch: &mut exch,
         sess,
     };
-    let mut rx = Slab::<PacketPool>::try_new(Packet::new_rx().unwrap()).unwrap();
-    let tx = Slab::<PacketPool>::try_new(Packet::new_tx().unwrap()).unwrap();
+    let pool = PacketPool::new();
+    let mut rx = pool.alloc_rx().unwrap();
+    let tx = pool.alloc_tx().unwrap();
     // Create fake rx packet
     rx.set_proto_id(0x01);
     rx.set_proto_opcode(action as u8);
@@ -0,0 +1,521 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use matter::{
+    cert::{Cert, CertSubject},
+    controller::{ca::CertAuthority, commissioner::Commissioner, Controller, ImResponse},
+    core::{CommissioningData, Matter},
+    crypto::{self, CryptoKeyPair, KeyPair},
+    data_model::{
+        cluster_basic_information::BasicInfoConfig, cluster_on_off,
+        device_types::device_type_add_on_off_light, objects::EncodeValue,
+    },
+    error::Error,
+    interaction_model::messages::{
+        ib::{AttrPath, CmdData, CmdPath},
+        msg::{InvReq, ReadReq, ReportDataMsg, SubscribeReq},
+        GenericPath,
+    },
+    persist::MemKvStorage,
+    secure_channel::spake2p::VerifierData,
+    tlv::{FromTLV, TLVArray},
+    transport::{
+        loopback::{LinkConditions, Loopback},
+        mgr::{SessionControl, StopHandle},
+        session::SessionMode,
+    },
+    utils::epoch::{SysUtcTime, UtcTime},
+};
+
+mod dev_att;
+
+const PASSCODE: u32 = 123456;
+
+// Run an On/Off Light on the given end of the link, until it is stopped
+fn start_device(network: Loopback) -> (StopHandle, SessionControl, thread::JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel();
+    let device = thread::spawn(move || {
+        let comm_data = CommissioningData {
+            verifier: VerifierData::new_with_pw(PASSCODE),
+            discriminator: 250,
+        };
+        let dev_info = BasicInfoConfig {
+            vid: 0xFFF1,
+            pid: 0x8000,
+            hw_ver: 2,
+            sw_ver: 1,
+            sw_ver_str: "1".to_string(),
+            serial_no: "aabbccdd".to_string(),
+            device_name: "OnOff Light".to_string(),
+        };
+        let mut matter = Matter::new_with_network(
+            dev_info,
+            Box::new(dev_att::TestDevAtt),
+            comm_data,
+            Arc::new(MemKvStorage::new()),
+            Box::new(network),
+        )
+        .unwrap();
+        device_type_add_on_off_light(&mut matter.get_data_model().node.write().unwrap()).unwrap();
+        tx.send((matter.get_stop_handle(), matter.get_session_control()))
+            .unwrap();
+        matter.start_daemon().unwrap();
+    });
+    let (stop, sessions) = rx.recv().unwrap();
+    (stop, sessions, device)
+}
+
+// Establish a PASE session with the device, and read its Vendor ID
+fn pase_and_read(conditions: LinkConditions) {
+    let (device_end, controller_end) = Loopback::pair_with(conditions);
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, _, device) = start_device(device_end);
+
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    let sess_id = controller.pase(device_addr, PASSCODE).unwrap();
+
+    let path = [AttrPath::new(&GenericPath::new(
+        Some(0),
+        Some(0x28),
+        Some(2),
+    ))];
+    let reports = controller
+        .read(sess_id, &ReadReq::new(false).set_attr_requests(&path))
+        .unwrap();
+    assert_eq!(reports.len(), 1);
+    let report = ReportDataMsg::from_tlv(&reports[0].root().unwrap()).unwrap();
+    assert_eq!(report.attr_reports.unwrap().iter().count(), 1);
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+#[test]
+fn test_loopback() {
+    pase_and_read(LinkConditions::default());
+}
+
+#[test]
+fn test_loopback_delayed() {
+    pase_and_read(LinkConditions {
+        delay: Duration::from_millis(20),
+        ..Default::default()
+    });
+}
+
+#[test]
+fn test_loopback_lossy() {
+    // The lost and reordered packets are recovered by the retransmissions, and the duplicate
+    // retransmissions are acknowledged, but not processed again
+    pase_and_read(LinkConditions {
+        drop_rate: 0.2,
+        delay: Duration::from_millis(5),
+        reorder_rate: 0.2,
+        reorder_delay: Duration::from_millis(20),
+        seed: 1,
+    });
+}
+
+#[test]
+fn test_loopback_wrong_passcode() {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, sessions, device) = start_device(device_end);
+
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    assert!(controller.pase(device_addr, PASSCODE + 1).is_err());
+    assert!(smol::block_on(sessions.list()).unwrap().is_empty());
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+// The attribute path of the Vendor ID
+fn vendor_id_path() -> [AttrPath; 1] {
+    [AttrPath::new(&GenericPath::new(
+        Some(0),
+        Some(0x28),
+        Some(2),
+    ))]
+}
+
+#[test]
+fn test_device_closes_session() {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, sessions, device) = start_device(device_end);
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    let sess_id = controller.pase(device_addr, PASSCODE).unwrap();
+
+    let list = smol::block_on(sessions.list()).unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].mode, SessionMode::Pase);
+    assert_eq!(list[0].peer_sess_id, sess_id);
+    let local_sess_id = list[0].local_sess_id;
+    assert_eq!(smol::block_on(sessions.close(local_sess_id)), Ok(()));
+    assert_eq!(
+        smol::block_on(sessions.close(local_sess_id)),
+        Err(Error::NoSession)
+    );
+    assert!(smol::block_on(sessions.list()).unwrap().is_empty());
+
+    // The controller tears its session down on the CloseSession
+    let path = vendor_id_path();
+    let req = ReadReq::new(false).set_attr_requests(&path);
+    assert!(controller.read(sess_id, &req).is_err());
+    assert_eq!(controller.read(sess_id, &req).err(), Some(Error::NoSession));
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+#[test]
+fn test_controller_closes_session() {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, sessions, device) = start_device(device_end);
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    let sess_id = controller.pase(device_addr, PASSCODE).unwrap();
+    assert_eq!(smol::block_on(sessions.list()).unwrap().len(), 1);
+
+    // The device tears its session down on the CloseSession
+    controller.close_session(sess_id).unwrap();
+    let mut closed = false;
+    for _ in 0..50 {
+        if smol::block_on(sessions.list()).unwrap().is_empty() {
+            closed = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(closed);
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+// The number of attribute reports, with data, in a report
+fn attr_data_count(report: &ImResponse) -> usize {
+    let report = ReportDataMsg::from_tlv(&report.root().unwrap()).unwrap();
+    report.attr_reports.map_or(0, |r| r.iter().count())
+}
+
+#[test]
+fn test_subscription_reports() {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, _, device) = start_device(device_end);
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    let sess_id = controller.pase(device_addr, PASSCODE).unwrap();
+
+    let path = [AttrPath::new(&GenericPath::new(
+        Some(1),
+        Some(cluster_on_off::ID),
+        Some(cluster_on_off::Attributes::OnOff as u32),
+    ))];
+    let req = SubscribeReq::new(false, 1, 4).set_attr_requests(&path);
+    let (reports, _) = controller.subscribe(sess_id, &req).unwrap();
+    assert_eq!(attr_data_count(&reports[0]), 1);
+
+    // Nothing is reported while nothing changes, until the max interval
+    assert!(controller
+        .recv_report(Duration::from_secs(2))
+        .unwrap()
+        .is_none());
+
+    // A change is reported once the min interval has lapsed
+    let toggle = [CmdData::new(
+        CmdPath::new(
+            Some(1),
+            Some(cluster_on_off::ID),
+            Some(cluster_on_off::Commands::Toggle as u16),
+        ),
+        EncodeValue::Value(&0u32),
+    )];
+    let inv_req = InvReq {
+        suppress_response: Some(false),
+        timed_request: Some(false),
+        inv_requests: Some(TLVArray::Slice(&toggle)),
+    };
+    controller.invoke(sess_id, &inv_req).unwrap();
+    let report = controller
+        .recv_report(Duration::from_secs(2))
+        .unwrap()
+        .unwrap();
+    assert_eq!(attr_data_count(&report), 1);
+
+    // The keep-alive report, with no data, follows at the max interval
+    assert!(controller
+        .recv_report(Duration::from_secs(2))
+        .unwrap()
+        .is_none());
+    let report = controller
+        .recv_report(Duration::from_secs(4))
+        .unwrap()
+        .unwrap();
+    assert_eq!(attr_data_count(&report), 0);
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+fn commissioner(controller_end: Loopback) -> Commissioner {
+    let controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    let ca = CertAuthority::new(1, 1, 0xFFF1).unwrap();
+    Commissioner::new(controller, ca, 0x1001).unwrap()
+}
+
+// Commission the device, establish another CASE session with it, and read its Vendor ID
+// over that session
+fn commission_and_read(conditions: LinkConditions) {
+    let (device_end, controller_end) = Loopback::pair_with(conditions);
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, sessions, device) = start_device(device_end);
+
+    // The device only has test certificates, for which we don't have the PAA
+    let mut commissioner = commissioner(controller_end);
+    commissioner.allow_untrusted_attestation();
+    let sess_id = commissioner
+        .commission(device_addr, PASSCODE, 0x2002)
+        .unwrap();
+    assert!(smol::block_on(sessions.list())
+        .unwrap()
+        .iter()
+        .any(|s| matches!(s.mode, SessionMode::Case(_))));
+
+    let path = vendor_id_path();
+    let req = ReadReq::new(false).set_attr_requests(&path);
+    assert_eq!(
+        commissioner.controller().read(sess_id, &req).unwrap().len(),
+        1
+    );
+
+    let case_sess_id = commissioner.case(device_addr, 0x2002).unwrap();
+    assert_ne!(case_sess_id, sess_id);
+    assert_eq!(
+        commissioner
+            .controller()
+            .read(case_sess_id, &req)
+            .unwrap()
+            .len(),
+        1
+    );
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+#[test]
+fn test_commissioner() {
+    commission_and_read(LinkConditions::default());
+}
+
+#[test]
+fn test_case_delayed() {
+    commission_and_read(LinkConditions {
+        delay: Duration::from_millis(20),
+        ..Default::default()
+    });
+}
+
+#[test]
+fn test_case_lossy() {
+    // The CASE messages that are lost or reordered are recovered by the retransmissions
+    commission_and_read(LinkConditions {
+        drop_rate: 0.2,
+        delay: Duration::from_millis(5),
+        reorder_rate: 0.2,
+        reorder_delay: Duration::from_millis(20),
+        seed: 2,
+    });
+}
+
+#[test]
+fn test_case_resumption() {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, sessions, device) = start_device(device_end);
+
+    let mut commissioner = commissioner(controller_end);
+    commissioner.allow_untrusted_attestation();
+    commissioner
+        .commission(device_addr, PASSCODE, 0x2002)
+        .unwrap();
+    let path = vendor_id_path();
+    let req = ReadReq::new(false).set_attr_requests(&path);
+
+    let mut record = commissioner.get_resumption_record(0x2002).unwrap().clone();
+    for _ in 0..2 {
+        // The Sigma1 offers the resumption, which the device accepts with a Sigma2_Resume.
+        // The session goes on with the shared secret of the full CASE, under a new
+        // resumption id.
+        let sess_id = commissioner.case(device_addr, 0x2002).unwrap();
+        let resumed = commissioner.get_resumption_record(0x2002).unwrap().clone();
+        assert_eq!(resumed.shared_secret, record.shared_secret);
+        assert_ne!(resumed.resumption_id, record.resumption_id);
+
+        // The device derived the same session keys as we did
+        assert_eq!(
+            commissioner.controller().read(sess_id, &req).unwrap().len(),
+            1
+        );
+        assert!(smol::block_on(sessions.list())
+            .unwrap()
+            .iter()
+            .any(|s| s.peer_sess_id == sess_id && matches!(s.mode, SessionMode::Case(_))));
+        record = resumed;
+    }
+
+    stop.stop();
+    device.join().unwrap();
+}
+
+// Commission the device, with the given PAA as the only trusted one
+fn commission_untrusted(paa: Option<&[u8]>) -> Result<u16, Error> {
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, _, device) = start_device(device_end);
+
+    let mut commissioner = commissioner(controller_end);
+    if let Some(paa) = paa {
+        commissioner.add_paa_cert(paa);
+    }
+    let result = commissioner.commission(device_addr, PASSCODE, 0x2002);
+
+    stop.stop();
+    device.join().unwrap();
+    result
+}
+
+#[test]
+fn test_commissioner_rejects_chain() {
+    // Without any trusted PAAs, nothing is trusted
+    assert_eq!(commission_untrusted(None), Err(Error::InvalidAuthKey));
+    // The DAC didn't sign the PAI
+    assert_eq!(
+        commission_untrusted(Some(&dev_att::DAC_CERT)),
+        Err(Error::InvalidAuthKey)
+    );
+}
+
+const HOUR: u32 = 60 * 60;
+const YEAR: u32 = 365 * 24 * HOUR;
+
+// A clock that is ahead of the system clock by the given number of seconds
+struct AheadTime(u32);
+
+impl UtcTime for AheadTime {
+    fn now(&self) -> Option<u32> {
+        SysUtcTime.now().map(|now| now + self.0)
+    }
+}
+
+// Commission the device into the fabric of the CA, with the certificates that 'issue' issues
+fn commission_with<F>(
+    ca: CertAuthority,
+    controller_time: Option<AheadTime>,
+    issue: F,
+) -> Result<u16, Error>
+where
+    F: FnOnce(&CertAuthority, &[u8]) -> Result<(Cert, Option<Cert>), Error>,
+{
+    let (device_end, controller_end) = Loopback::pair();
+    let device_addr = controller_end.get_peer_addr();
+    let (stop, _, device) = start_device(device_end);
+
+    let mut controller = Controller::new_with_network(Box::new(controller_end)).unwrap();
+    if let Some(time) = controller_time {
+        controller.set_utc_time(Box::new(time));
+    }
+    let mut commissioner = Commissioner::new(controller, ca, 0x1001).unwrap();
+    commissioner.allow_untrusted_attestation();
+    let result = commissioner.commission_with(device_addr, PASSCODE, 0x2002, issue);
+
+    stop.stop();
+    device.join().unwrap();
+    result
+}
+
+fn issue_noc(ca: &CertAuthority, pubkey: &[u8]) -> Result<(Cert, Option<Cert>), Error> {
+    Ok((ca.issue_noc(pubkey, 0x2002, &[])?, None))
+}
+
+#[test]
+fn test_add_noc_expired() {
+    let ca = CertAuthority::new(1, 1, 0xFFF1).unwrap();
+    let now = SysUtcTime.now().unwrap();
+    let result = commission_with(ca, None, |ca, pubkey| {
+        let noc = ca.issue_noc_with_validity(pubkey, 0x2002, &[], now - YEAR, now - HOUR)?;
+        Ok((noc, None))
+    });
+    assert_eq!(result, Err(Error::Invalid));
+}
+
+#[test]
+fn test_add_noc_misused() {
+    // The NOC is signed by another NOC, that isn't allowed to sign certificates
+    let ca = CertAuthority::new(1, 1, 0xFFF1).unwrap();
+    let result = commission_with(ca, None, |ca, pubkey| {
+        let key = KeyPair::new()?;
+        let mut issuer_pubkey = [0; crypto::EC_POINT_LEN_BYTES];
+        let len = key.get_public_key(&mut issuer_pubkey)?;
+        let issuer = ca.issue_noc(&issuer_pubkey[..len], 0x3003, &[])?;
+        let noc = Cert::issue(
+            &CertSubject::Noc {
+                node_id: 0x2002,
+                fabric_id: ca.get_fabric_id(),
+                cat_ids: &[],
+            },
+            pubkey,
+            Some(&issuer),
+            &key,
+            issuer.get_not_before(),
+            issuer.get_not_after(),
+        )?;
+        Ok((noc, Some(issuer)))
+    });
+    assert_eq!(result, Err(Error::Invalid));
+}
+
+#[test]
+fn test_commission_skewed_clock() {
+    // The certificates are valid from a little before they were issued, so the device
+    // accepts them, even though the commissioner's clock is somewhat ahead
+    let ca = CertAuthority::new_with_utc_time(1, 1, 0xFFF1, Box::new(AheadTime(HOUR / 2))).unwrap();
+    assert!(commission_with(ca, None, issue_noc).is_ok());
+
+    // But not once it is too far ahead, then the device rejects the root certificate
+    let ca = CertAuthority::new_with_utc_time(1, 1, 0xFFF1, Box::new(AheadTime(2 * HOUR))).unwrap();
+    assert_eq!(commission_with(ca, None, issue_noc), Err(Error::Invalid));
+}
+
+#[test]
+fn test_case_rejects_expired_noc() {
+    // By the controller's clock, the device's NOC has expired by the time of the CASE
+    // session, at the end of the commissioning
+    let ca = CertAuthority::new(1, 1, 0xFFF1).unwrap();
+    assert_eq!(
+        commission_with(ca, Some(AheadTime(11 * YEAR)), issue_noc),
+        Err(Error::Invalid)
+    );
+}
@@ -0,0 +1,140 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Generate the SPAKE2+ verifier of a device for its factory data
+//!
+//! The device then loads the verifier with `VerifierData::from_tlv()`, and the passcode
+//! itself is never on the device.
+
+extern crate clap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{App, Arg, ArgMatches};
+use matter::secure_channel::spake2p::{
+    generate_passcode, is_valid_passcode, VerifierData, VerifierOption, MAX_ITERATION_COUNT,
+    MAX_SALT_SIZE_BYTES, MIN_ITERATION_COUNT, MIN_SALT_SIZE_BYTES,
+};
+use rand::RngCore;
+use std::fs;
+use std::process;
+
+const MAX_FACTORY_DATA_SIZE: usize = 256;
+
+fn main() {
+    let m = App::new("spake2p")
+        .about("Generate the SPAKE2+ verifier, salt and iteration count for the factory data")
+        .arg(
+            Arg::with_name("passcode")
+                .short("p")
+                .long("passcode")
+                .takes_value(true)
+                .help("The passcode of the device (Default: a random valid passcode)"),
+        )
+        .arg(
+            Arg::with_name("salt")
+                .short("s")
+                .long("salt")
+                .takes_value(true)
+                .conflicts_with("salt-len")
+                .help("The salt in base64 (Default: a random salt)"),
+        )
+        .arg(
+            Arg::with_name("salt-len")
+                .long("salt-len")
+                .takes_value(true)
+                .help("The length of the random salt, from 16 to 32 bytes (Default: 32)"),
+        )
+        .arg(
+            Arg::with_name("iterations")
+                .short("i")
+                .long("iterations")
+                .takes_value(true)
+                .help("The PBKDF2 iteration count, from 1000 to 100000 (Default: 2000)"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Write the verifier as TLV to this file, for the factory data"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&m) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(m: &ArgMatches) -> Result<(), String> {
+    let passcode = match m.value_of("passcode") {
+        Some(p) => {
+            let passcode = p
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid passcode: {}", p))?;
+            if !is_valid_passcode(passcode) {
+                return Err(format!("The passcode {} isn't allowed", p));
+            }
+            passcode
+        }
+        None => generate_passcode(),
+    };
+
+    let count = parse_num(m, "iterations", 2000)?;
+    if !(MIN_ITERATION_COUNT as usize..=MAX_ITERATION_COUNT as usize).contains(&count) {
+        return Err(format!("Invalid iteration count: {}", count));
+    }
+
+    let salt = match m.value_of("salt") {
+        Some(s) => STANDARD
+            .decode(s)
+            .map_err(|e| format!("Invalid base64: {}", e))?,
+        None => {
+            let mut salt = vec![0; parse_num(m, "salt-len", MAX_SALT_SIZE_BYTES)?];
+            rand::thread_rng().fill_bytes(&mut salt);
+            salt
+        }
+    };
+    if !(MIN_SALT_SIZE_BYTES..=MAX_SALT_SIZE_BYTES).contains(&salt.len()) {
+        return Err(format!("Invalid salt length: {}", salt.len()));
+    }
+
+    let verifier = VerifierData::compute(passcode, count as u32, &salt)
+        .map_err(|e| format!("Couldn't compute the verifier: {}", e))?;
+
+    println!("Passcode:   {:08}", passcode);
+    println!("Iterations: {}", verifier.count);
+    println!("Salt:       {}", STANDARD.encode(verifier.salt()));
+    if let VerifierOption::Verifier(v) = &verifier.data {
+        println!("Verifier:   {}", STANDARD.encode(v));
+    }
+
+    if let Some(path) = m.value_of("output") {
+        let mut buf = [0_u8; MAX_FACTORY_DATA_SIZE];
+        let len = verifier
+            .as_tlv(&mut buf)
+            .map_err(|e| format!("Couldn't encode the verifier: {}", e))?;
+        fs::write(path, &buf[..len]).map_err(|e| format!("Couldn't write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn parse_num(m: &ArgMatches, name: &str, default: usize) -> Result<usize, String> {
+    m.value_of(name).map_or(Ok(default), |v| {
+        v.parse()
+            .map_err(|_| format!("Invalid value for --{}: {}", name, v))
+    })
+}
@@ -0,0 +1,408 @@
+/*
+ *
+ *    Copyright (c) 2023 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! The conversion between the TLV and the JSON, as per chip-tool's TLV-JSON convention
+//!
+//! The root is an anonymous structure, and is a JSON object. The key of each member is its
+//! context tag and its type, as "1:UINT". The types are INT, UINT, BOOL, FLOAT, DOUBLE,
+//! STRING, BYTES (in base64), NULL, STRUCT and ARRAY-<type of the elements>, which is ARRAY-?
+//! for an empty array. The integers that a JSON number can't represent exactly are strings.
+//!
+//! Beyond that convention, a LIST is an array of objects of a single member each, where the
+//! anonymous members have only the type as the key. A root that isn't a structure is the
+//! single member of an object, with the "value" key, as "value:UINT".
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::error;
+use matter::error::Error;
+use matter::tlv::{self, ElementType, TLVElement, TLVWriter, TagType};
+use serde_json::{Map, Number, Value};
+
+// The integers beyond this lose their precision as JSON numbers
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+const ROOT_VALUE: &str = "value";
+
+pub fn tlv_to_json(tlv: &[u8]) -> Result<Value, Error> {
+    let root = tlv::get_root_node(tlv)?;
+    if root.get_tag() != TagType::Anonymous {
+        error!("The root element has to be anonymous");
+        return Err(Error::Invalid);
+    }
+    if let ElementType::Struct(_) = root.get_element_type() {
+        to_json(&root)
+    } else {
+        let mut object = Map::new();
+        object.insert(
+            format!("{}:{}", ROOT_VALUE, type_name(&root)?),
+            to_json(&root)?,
+        );
+        Ok(Value::Object(object))
+    }
+}
+
+pub fn json_to_tlv(json: &Value, tw: &mut TLVWriter) -> Result<(), Error> {
+    let object = json.as_object().ok_or(Error::Invalid)?;
+    if object.len() == 1 {
+        let (key, value) = object.iter().next().ok_or(Error::Invalid)?;
+        if let (Some(ROOT_VALUE), ty) = parse_key(key)? {
+            return write(tw, TagType::Anonymous, ty, value);
+        }
+    }
+    write_struct(tw, TagType::Anonymous, object)
+}
+
+fn type_name(e: &TLVElement) -> Result<String, Error> {
+    let name = match e.get_element_type() {
+        ElementType::S8(_) | ElementType::S16(_) | ElementType::S32(_) | ElementType::S64(_) => {
+            "INT"
+        }
+        ElementType::U8(_) | ElementType::U16(_) | ElementType::U32(_) | ElementType::U64(_) => {
+            "UINT"
+        }
+        ElementType::False | ElementType::True => "BOOL",
+        ElementType::F32(_) => "FLOAT",
+        ElementType::F64(_) => "DOUBLE",
+        ElementType::Utf8l(_) | ElementType::Utf16l(_) => "STRING",
+        ElementType::Str8l(_) | ElementType::Str16l(_) => "BYTES",
+        ElementType::Null => "NULL",
+        ElementType::Struct(_) => "STRUCT",
+        ElementType::List(_) => "LIST",
+        ElementType::Array(_) => {
+            let first = e.enter().ok_or(Error::Invalid)?.next();
+            let elements = match first {
+                Some(first) => type_name(&first)?,
+                None => "?".to_owned(),
+            };
+            return Ok(format!("ARRAY-{}", elements));
+        }
+        _ => {
+            error!("Unsupported element {:?}", e.get_element_type());
+            return Err(Error::Invalid);
+        }
+    };
+    Ok(name.to_owned())
+}
+
+fn to_json(e: &TLVElement) -> Result<Value, Error> {
+    let value = match e.get_element_type() {
+        ElementType::S8(v) => int_to_json(v.into()),
+        ElementType::S16(v) => int_to_json(v.into()),
+        ElementType::S32(v) => int_to_json(v.into()),
+        ElementType::S64(v) => int_to_json(v),
+        ElementType::U8(v) => uint_to_json(v.into()),
+        ElementType::U16(v) => uint_to_json(v.into()),
+        ElementType::U32(v) => uint_to_json(v.into()),
+        ElementType::U64(v) => uint_to_json(v),
+        ElementType::False => Value::Bool(false),
+        ElementType::True => Value::Bool(true),
+        // Through the shortest representation of the f32, so that 0.1 doesn't widen to
+        // 0.10000000149011612
+        ElementType::F32(v) => float_to_json(v.to_string().parse().unwrap_or(v.into())),
+        ElementType::F64(v) => float_to_json(v),
+        ElementType::Utf8l(s) | ElementType::Utf16l(s) => Value::String(
+            std::str::from_utf8(s)
+                .map_err(|_| Error::Utf8Fail)?
+                .to_owned(),
+        ),
+        ElementType::Str8l(s) | ElementType::Str16l(s) => Value::String(STANDARD.encode(s)),
+        ElementType::Null => Value::Null,
+        ElementType::Struct(_) => {
+            let mut object = Map::new();
+            for member in e.enter().ok_or(Error::Invalid)? {
+                let key = format!("{}:{}", ctx_tag(&member)?, type_name(&member)?);
+                object.insert(key, to_json(&member)?);
+            }
+            Value::Object(object)
+        }
+        ElementType::Array(_) => {
+            let ty = type_name(e)?;
+            let mut array = Vec::new();
+            for element in e.enter().ok_or(Error::Invalid)? {
+                if element.get_tag() != TagType::Anonymous
+                    || format!("ARRAY-{}", type_name(&element)?) != ty
+                {
+                    error!("The elements of an array have to be anonymous, and of the same type");
+                    return Err(Error::Invalid);
+                }
+                array.push(to_json(&element)?);
+            }
+            Value::Array(array)
+        }
+        ElementType::List(_) => {
+            let mut array = Vec::new();
+            for member in e.enter().ok_or(Error::Invalid)? {
+                let key = match member.get_tag() {
+                    TagType::Anonymous => type_name(&member)?,
+                    _ => format!("{}:{}", ctx_tag(&member)?, type_name(&member)?),
+                };
+                let mut object = Map::new();
+                object.insert(key, to_json(&member)?);
+                array.push(Value::Object(object));
+            }
+            Value::Array(array)
+        }
+        _ => {
+            error!("Unsupported element {:?}", e.get_element_type());
+            return Err(Error::Invalid);
+        }
+    };
+    Ok(value)
+}
+
+fn ctx_tag(e: &TLVElement) -> Result<u8, Error> {
+    match e.get_tag() {
+        TagType::Context(tag) => Ok(tag),
+        tag => {
+            error!("Only the context tags are supported, found {:?}", tag);
+            Err(Error::Invalid)
+        }
+    }
+}
+
+fn int_to_json(v: i64) -> Value {
+    if v.unsigned_abs() <= MAX_SAFE_INTEGER {
+        Value::Number(v.into())
+    } else {
+        Value::String(v.to_string())
+    }
+}
+
+fn uint_to_json(v: u64) -> Value {
+    if v <= MAX_SAFE_INTEGER {
+        Value::Number(v.into())
+    } else {
+        Value::String(v.to_string())
+    }
+}
+
+fn float_to_json(v: f64) -> Value {
+    match Number::from_f64(v) {
+        Some(n) => Value::Number(n),
+        // Not representable as a JSON number
+        None if v.is_nan() => Value::String("NaN".to_owned()),
+        None if v > 0.0 => Value::String("Infinity".to_owned()),
+        None => Value::String("-Infinity".to_owned()),
+    }
+}
+
+// The key is "<tag>:<type>", or only the type for the anonymous members of a list
+fn parse_key(key: &str) -> Result<(Option<&str>, &str), Error> {
+    match key.split_once(':') {
+        Some((tag, ty)) => Ok((Some(tag), ty)),
+        None => Ok((None, key)),
+    }
+}
+
+fn parse_tag(tag: Option<&str>) -> Result<TagType, Error> {
+    match tag {
+        Some(tag) => tag.parse().map(TagType::Context).map_err(|_| {
+            error!("Invalid tag {}, only the context tags are supported", tag);
+            Error::Invalid
+        }),
+        None => Ok(TagType::Anonymous),
+    }
+}
+
+fn write_struct(
+    tw: &mut TLVWriter,
+    tag: TagType,
+    object: &Map<String, Value>,
+) -> Result<(), Error> {
+    let mut members = Vec::new();
+    for (key, value) in object {
+        let (member_tag, ty) = parse_key(key)?;
+        let member_tag = match parse_tag(member_tag)? {
+            TagType::Context(t) => t,
+            _ => {
+                error!("The members of a structure have to be tagged: {}", key);
+                return Err(Error::Invalid);
+            }
+        };
+        members.push((member_tag, ty, value));
+    }
+    // The members of a structure are encoded in the order of their tags
+    members.sort_by_key(|(t, _, _)| *t);
+
+    tw.start_struct(tag)?;
+    for (member_tag, ty, value) in members {
+        write(tw, TagType::Context(member_tag), ty, value)?;
+    }
+    tw.end_container()
+}
+
+fn write(tw: &mut TLVWriter, tag: TagType, ty: &str, value: &Value) -> Result<(), Error> {
+    match ty {
+        "INT" => tw.i64(tag, json_to_i64(value)?),
+        "UINT" => tw.u64(tag, json_to_u64(value)?),
+        "BOOL" => tw.bool(tag, value.as_bool().ok_or(Error::Invalid)?),
+        "FLOAT" => tw.f32(tag, json_to_f64(value)? as f32),
+        "DOUBLE" => tw.f64(tag, json_to_f64(value)?),
+        "STRING" => tw.utf16(tag, value.as_str().ok_or(Error::Invalid)?.as_bytes()),
+        "BYTES" => {
+            let bytes = STANDARD
+                .decode(value.as_str().ok_or(Error::Invalid)?)
+                .map_err(|_| Error::Invalid)?;
+            tw.str16(tag, &bytes)
+        }
+        "NULL" => {
+            value.as_null().ok_or(Error::Invalid)?;
+            tw.null(tag)
+        }
+        "STRUCT" => write_struct(tw, tag, value.as_object().ok_or(Error::Invalid)?),
+        "LIST" => {
+            tw.start_list(tag)?;
+            for member in value.as_array().ok_or(Error::Invalid)? {
+                let member = member.as_object().ok_or(Error::Invalid)?;
+                let (key, value) = match member.iter().next() {
+                    Some(kv) if member.len() == 1 => kv,
+                    _ => return Err(Error::Invalid),
+                };
+                let (member_tag, ty) = parse_key(key)?;
+                write(tw, parse_tag(member_tag)?, ty, value)?;
+            }
+            tw.end_container()
+        }
+        _ => {
+            let element_ty = ty.strip_prefix("ARRAY-").ok_or_else(|| {
+                error!("Unknown type {}", ty);
+                Error::Invalid
+            })?;
+            let elements = value.as_array().ok_or(Error::Invalid)?;
+            if element_ty == "?" && !elements.is_empty() {
+                return Err(Error::Invalid);
+            }
+            tw.start_array(tag)?;
+            for element in elements {
+                write(tw, TagType::Anonymous, element_ty, element)?;
+            }
+            tw.end_container()
+        }
+    }
+}
+
+fn json_to_i64(value: &Value) -> Result<i64, Error> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or(Error::Invalid)
+}
+
+fn json_to_u64(value: &Value) -> Result<u64, Error> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or(Error::Invalid)
+}
+
+fn json_to_f64(value: &Value) -> Result<f64, Error> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => match s.as_str() {
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            "NaN" => Some(f64::NAN),
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or(Error::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matter::utils::writebuf::WriteBuf;
+
+    fn round_trip(tlv: &[u8], json: &str) {
+        let value = tlv_to_json(tlv).unwrap();
+        assert_eq!(value, serde_json::from_str::<Value>(json).unwrap());
+
+        let mut buf = [0; 256];
+        let buf_len = buf.len();
+        let mut wb = WriteBuf::new(&mut buf, buf_len);
+        let mut tw = TLVWriter::new(&mut wb);
+        json_to_tlv(&value, &mut tw).unwrap();
+        assert_eq!(wb.as_slice(), tlv);
+    }
+
+    #[test]
+    fn test_struct() {
+        round_trip(
+            &[
+                0x15, 0x24, 0x00, 0x05, 0x20, 0x01, 0xfe, 0x29, 0x02, 0x2c, 0x03, 0x02, 0x68, 0x69,
+                0x30, 0x04, 0x02, 0xca, 0xfe, 0x34, 0x05, 0x35, 0x06, 0x25, 0x01, 0x00, 0x01, 0x18,
+                0x18,
+            ],
+            r#"{
+                "0:UINT": 5,
+                "1:INT": -2,
+                "2:BOOL": true,
+                "3:STRING": "hi",
+                "4:BYTES": "yv4=",
+                "5:NULL": null,
+                "6:STRUCT": { "1:UINT": 256 }
+            }"#,
+        );
+    }
+
+    #[test]
+    fn test_containers() {
+        round_trip(
+            &[
+                0x15, 0x36, 0x00, 0x04, 0x01, 0x04, 0x02, 0x18, 0x36, 0x01, 0x18, 0x37, 0x02, 0x24,
+                0x01, 0x03, 0x04, 0x04, 0x18, 0x18,
+            ],
+            r#"{
+                "0:ARRAY-UINT": [1, 2],
+                "1:ARRAY-?": [],
+                "2:LIST": [{ "1:UINT": 3 }, { "UINT": 4 }]
+            }"#,
+        );
+    }
+
+    #[test]
+    fn test_large_and_float() {
+        round_trip(
+            &[
+                0x15, 0x27, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x2a, 0x01, 0xcd,
+                0xcc, 0xcc, 0x3d, 0x18,
+            ],
+            r#"{ "0:UINT": "18446744073709551615", "1:FLOAT": 0.1 }"#,
+        );
+        // A root that isn't a structure
+        round_trip(&[0x05, 0x34, 0x12], r#"{ "value:UINT": 4660 }"#);
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Mixed types in an array
+        assert!(tlv_to_json(&[0x16, 0x04, 0x01, 0x0c, 0x00, 0x18]).is_err());
+
+        let mut buf = [0; 16];
+        let buf_len = buf.len();
+        let mut wb = WriteBuf::new(&mut buf, buf_len);
+        let mut tw = TLVWriter::new(&mut wb);
+        let json = serde_json::json!({ "1:UINT": -1 });
+        assert_eq!(json_to_tlv(&json, &mut tw), Err(Error::Invalid));
+        let json = serde_json::json!({ "x:UINT": 1 });
+        assert_eq!(json_to_tlv(&json, &mut tw), Err(Error::Invalid));
+    }
+}
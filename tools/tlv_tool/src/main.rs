@@ -16,13 +16,24 @@
  */
 
 extern crate clap;
-use clap::{App, Arg};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{App, Arg, ArgMatches};
 use matter::cert;
-use matter::tlv;
+use matter::tlv::{self, TLVWriter};
+use matter::utils::writebuf::WriteBuf;
 use simple_logger::SimpleLogger;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::process;
 use std::u8;
 
+mod json;
+mod msg;
+
+const MAX_CERT_SIZE: usize = 1024;
+const MAX_TLV_SIZE: usize = 64 * 1024;
+const PEM_CERT_TAG: &str = "CERTIFICATE";
+
 fn main() {
     SimpleLogger::new()
         .with_level(log::LevelFilter::Trace)
@@ -44,6 +55,41 @@ fn main() {
                 .long("dec")
                 .help("The input is in Decimal"),
         )
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .long("file")
+                .takes_value(true)
+                .help("Read the input from a file, or from the standard input for '-'"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["list", "hex", "base64", "bin"])
+                .help(
+                    "The format of the input: a comma-separated list of bytes, a hex string, \
+                     base64 or binary (Default: list for the arguments, bin for the files)",
+                ),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["list", "hex", "base64", "bin"])
+                .help("The format of the encoded TLVs and certificates (Default: hex)"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Decode the TLVs as JSON, as per the TLV-JSON convention of chip-tool"),
+        )
+        .arg(
+            Arg::with_name("encode")
+                .long("encode")
+                .conflicts_with_all(&["json", "cert", "as-asn1", "convert-cert"])
+                .help("Encode the JSON input as TLVs"),
+        )
         .arg(
             Arg::with_name("cert")
                 .long("cert")
@@ -54,51 +100,215 @@ fn main() {
                 .long("as-asn1")
                 .help("Decode a Matter-encoded Certificate and encode as ASN1"),
         )
-        .arg(Arg::with_name("tlvs").help("List of TLVs").required(true))
+        .arg(
+            Arg::with_name("cert-format")
+                .long("cert-format")
+                .takes_value(true)
+                .possible_values(&["tlv", "der", "pem"])
+                .help("The format of the input certificate (Default: tlv)"),
+        )
+        .arg(
+            Arg::with_name("convert-cert")
+                .long("convert-cert")
+                .takes_value(true)
+                .possible_values(&["tlv", "der", "pem"])
+                .help("Convert the certificate to the Matter TLV, X.509 DER or X.509 PEM format"),
+        )
+        .arg(
+            Arg::with_name("msg")
+                .long("msg")
+                .conflicts_with_all(&["json", "encode", "cert", "as-asn1", "convert-cert"])
+                .help("Decode a Matter message: its headers and its payload"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .requires("msg")
+                .help("The session key in hex, to decrypt the Matter message with"),
+        )
+        .arg(
+            Arg::with_name("node-id")
+                .long("node-id")
+                .takes_value(true)
+                .requires("msg")
+                .help(
+                    "The node id of the sender for the nonce, in hex \
+                     (Default: the source node id of the message, or else 0)",
+                ),
+        )
+        .arg(Arg::with_name("tlvs").help("List of TLVs"))
         .get_matches();
 
-    // Assume hexadecimal by-default
-    let base = if m.is_present("hex") {
-        16
-    } else if m.is_present("dec") {
-        10
+    if let Err(e) = run(&m) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(m: &ArgMatches) -> Result<(), String> {
+    let input = read_input(m)?;
+
+    if m.is_present("encode") {
+        let json: serde_json::Value =
+            serde_json::from_slice(&input).map_err(|e| format!("Invalid JSON: {}", e))?;
+        let mut buf = vec![0; MAX_TLV_SIZE];
+        let mut wb = WriteBuf::new(&mut buf, MAX_TLV_SIZE);
+        let mut tw = TLVWriter::new(&mut wb);
+        json::json_to_tlv(&json, &mut tw).map_err(|e| format!("Couldn't encode: {}", e))?;
+        return write_output(m, wb.as_slice());
+    }
+
+    let is_cert = m.is_present("cert") || m.is_present("as-asn1") || m.is_present("convert-cert");
+    if is_cert && m.value_of("cert-format") == Some("pem") {
+        let pem = pem::parse(&input).map_err(|e| format!("Invalid PEM: {}", e))?;
+        return cert_output(m, &parse_cert(pem.contents(), "der")?);
+    }
+
+    let bytes = decode_input(m, &input)?;
+    if m.is_present("msg") {
+        let key = m.value_of("key").map(parse_hex).transpose()?;
+        let node_id = m
+            .value_of("node-id")
+            .map(|id| {
+                u64::from_str_radix(id.strip_prefix("0x").unwrap_or(id), 16)
+                    .map_err(|_| format!("Invalid node id: {}", id))
+            })
+            .transpose()?;
+        let msg = msg::decode(&bytes, key.as_deref(), node_id)
+            .map_err(|e| format!("Couldn't decode the message: {}", e))?;
+        print!("{}", msg);
+        Ok(())
+    } else if is_cert {
+        let cert = parse_cert(&bytes, m.value_of("cert-format").unwrap_or("tlv"))?;
+        cert_output(m, &cert)
+    } else if m.is_present("json") {
+        let json = json::tlv_to_json(&bytes).map_err(|e| format!("Couldn't decode: {}", e))?;
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+        Ok(())
     } else {
-        16
-    };
+        tlv::print_tlv_list(&bytes);
+        Ok(())
+    }
+}
 
-    let list: String = m
-        .value_of("tlvs")
-        .unwrap()
-        .chars()
-        .filter(|c| !c.is_whitespace())
-        .collect();
-    let list = list.split(',');
-    let mut tlv_list: [u8; 1024] = [0; 1024];
-    let mut index = 0;
-    for byte in list {
-        let byte = byte.strip_prefix("0x").unwrap_or(byte);
-        if let Ok(b) = u8::from_str_radix(byte, base) {
-            tlv_list[index] = b;
-            index += 1;
-        } else {
-            eprintln!("Skipping unknown byte: {}", byte);
+fn read_input(m: &ArgMatches) -> Result<Vec<u8>, String> {
+    match (m.value_of("tlvs"), m.value_of("file")) {
+        (Some(tlvs), None) => Ok(tlvs.as_bytes().to_vec()),
+        (None, Some(path)) if path != "-" => {
+            fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))
+        }
+        (None, _) => {
+            let mut input = Vec::new();
+            io::stdin()
+                .read_to_end(&mut input)
+                .map_err(|e| format!("Couldn't read the standard input: {}", e))?;
+            Ok(input)
         }
-        if index >= 1024 {
-            eprintln!("Input too long");
-            process::exit(1);
+        (Some(_), Some(_)) => Err("Either the TLVs or a file can be the input".to_owned()),
+    }
+}
+
+fn decode_input(m: &ArgMatches, input: &[u8]) -> Result<Vec<u8>, String> {
+    let default = if m.is_present("tlvs") { "list" } else { "bin" };
+    let text = || -> Result<String, String> {
+        let text = std::str::from_utf8(input).map_err(|_| "The input isn't text".to_owned())?;
+        Ok(text.chars().filter(|c| !c.is_whitespace()).collect())
+    };
+    match m.value_of("format").unwrap_or(default) {
+        "bin" => Ok(input.to_vec()),
+        "hex" => parse_hex(&text()?),
+        "base64" => STANDARD
+            .decode(text()?)
+            .map_err(|e| format!("Invalid base64: {}", e)),
+        _ => {
+            // Assume hexadecimal by-default
+            let base = if m.is_present("dec") { 10 } else { 16 };
+            let mut tlv_list = Vec::new();
+            for byte in text()?.split(',') {
+                let byte = byte.strip_prefix("0x").unwrap_or(byte);
+                if let Ok(b) = u8::from_str_radix(byte, base) {
+                    tlv_list.push(b);
+                } else {
+                    eprintln!("Skipping unknown byte: {}", byte);
+                }
+            }
+            Ok(tlv_list)
         }
     }
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.strip_prefix("0x").unwrap_or(text);
+    if text.len() % 2 == 1 {
+        return Err("The hex string has an odd length".to_owned());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16)
+                .map_err(|_| format!("Invalid hex: {}", &text[i..i + 2]))
+        })
+        .collect()
+}
 
-    //    println!("Decoding: {:x?}", &tlv_list[..index]);
-    if m.is_present("cert") {
-        let cert = cert::Cert::new(&tlv_list[..index]).unwrap();
-        println!("{}", cert);
-    } else if m.is_present("as-asn1") {
-        let mut asn1_cert = [0_u8; 1024];
-        let cert = cert::Cert::new(&tlv_list[..index]).unwrap();
-        let len = cert.as_asn1(&mut asn1_cert).unwrap();
-        println!("{:02x?}", &asn1_cert[..len]);
+fn parse_cert(bytes: &[u8], format: &str) -> Result<cert::Cert, String> {
+    let cert = if format == "tlv" {
+        cert::Cert::new(bytes)
     } else {
-        tlv::print_tlv_list(&tlv_list[..index]);
+        cert::Cert::from_x509(bytes)
+    };
+    cert.map_err(|e| format!("Invalid certificate: {}", e))
+}
+
+fn cert_output(m: &ArgMatches, cert: &cert::Cert) -> Result<(), String> {
+    let mut buf = [0_u8; MAX_CERT_SIZE];
+    let err = |e| format!("Couldn't encode the certificate: {}", e);
+    match m.value_of("convert-cert") {
+        Some("tlv") => {
+            let len = cert.as_tlv(&mut buf).map_err(err)?;
+            write_output(m, &buf[..len])
+        }
+        Some("der") => {
+            let len = cert.as_x509(&mut buf).map_err(err)?;
+            write_output(m, &buf[..len])
+        }
+        Some(_) => {
+            let len = cert.as_x509(&mut buf).map_err(err)?;
+            let pem = pem::Pem::new(PEM_CERT_TAG, &buf[..len]);
+            print!("{}", pem::encode(&pem));
+            Ok(())
+        }
+        None if m.is_present("as-asn1") => {
+            let len = cert.as_asn1(&mut buf).map_err(err)?;
+            println!("{:02x?}", &buf[..len]);
+            Ok(())
+        }
+        None => {
+            println!("{}", cert);
+            Ok(())
+        }
+    }
+}
+
+fn write_output(m: &ArgMatches, bytes: &[u8]) -> Result<(), String> {
+    match m.value_of("output").unwrap_or("hex") {
+        "bin" => io::stdout()
+            .write_all(bytes)
+            .map_err(|e| format!("Couldn't write the output: {}", e)),
+        "base64" => {
+            println!("{}", STANDARD.encode(bytes));
+            Ok(())
+        }
+        "list" => {
+            let list: Vec<String> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+            println!("{}", list.join(", "));
+            Ok(())
+        }
+        _ => {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{}", hex);
+            Ok(())
+        }
     }
 }
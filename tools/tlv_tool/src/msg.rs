@@ -0,0 +1,537 @@
+/*
+ *
+ *    Copyright (c) 2020-2022 Project CHIP Authors
+ *
+ *    Licensed under the Apache License, Version 2.0 (the "License");
+ *    you may not use this file except in compliance with the License.
+ *    You may obtain a copy of the License at
+ *
+ *        http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *    Unless required by applicable law or agreed to in writing, software
+ *    distributed under the License is distributed on an "AS IS" BASIS,
+ *    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *    See the License for the specific language governing permissions and
+ *    limitations under the License.
+ */
+
+//! Decoding of the Matter messages: the message and protocol headers, and the
+//! Interaction Model and Secure Channel payloads with their fields named
+
+use std::fmt::Write;
+
+use matter::error::Error;
+use matter::interaction_model::core::{
+    IMStatusCode, OpCode as ImOpCode, PROTO_ID_INTERACTION_MODEL,
+};
+use matter::secure_channel::common::{OpCode as ScOpCode, SCStatusCodes, PROTO_ID_SECURE_CHANNEL};
+use matter::secure_channel::status_report::GeneralCode;
+use matter::tlv::{ElementType, TLVList, TagType};
+use matter::transport::capture;
+use matter::transport::packet::PacketPool;
+use matter::utils::parsebuf::ParseBuf;
+use num::FromPrimitive;
+
+const INDENT: &str = "    ";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Fmt {
+    Plain,
+    /// An Interaction Model status code, printed with its name
+    ImStatus,
+}
+
+/// A context-tagged field of a TLV structure
+///
+/// The members are those of the field if it is a structure or a list, and those of the
+/// elements if it is an array.
+struct Field {
+    tag: u8,
+    name: &'static str,
+    members: &'static [Field],
+    fmt: Fmt,
+}
+
+const fn f(tag: u8, name: &'static str) -> Field {
+    s(tag, name, &[])
+}
+
+const fn s(tag: u8, name: &'static str, members: &'static [Field]) -> Field {
+    Field {
+        tag,
+        name,
+        members,
+        fmt: Fmt::Plain,
+    }
+}
+
+const IM_REVISION: Field = f(0xFF, "InteractionModelRevision");
+
+const ATTR_PATH: &[Field] = &[
+    f(0, "EnableTagCompression"),
+    f(1, "Node"),
+    f(2, "Endpoint"),
+    f(3, "Cluster"),
+    f(4, "Attribute"),
+    f(5, "ListIndex"),
+];
+const EVENT_PATH: &[Field] = &[
+    f(0, "Node"),
+    f(1, "Endpoint"),
+    f(2, "Cluster"),
+    f(3, "Event"),
+    f(4, "IsUrgent"),
+];
+const EVENT_FILTER: &[Field] = &[f(0, "Node"), f(1, "EventMin")];
+const CLUSTER_PATH: &[Field] = &[f(0, "Node"), f(1, "Endpoint"), f(2, "Cluster")];
+const DATA_VERSION_FILTER: &[Field] = &[s(0, "Path", CLUSTER_PATH), f(1, "DataVersion")];
+const CMD_PATH: &[Field] = &[f(0, "Endpoint"), f(1, "Cluster"), f(2, "Command")];
+const STATUS: &[Field] = &[
+    Field {
+        tag: 0,
+        name: "Status",
+        members: &[],
+        fmt: Fmt::ImStatus,
+    },
+    f(1, "ClusterStatus"),
+];
+const ATTR_STATUS: &[Field] = &[s(0, "Path", ATTR_PATH), s(1, "Status", STATUS)];
+const EVENT_STATUS: &[Field] = &[s(0, "Path", EVENT_PATH), s(1, "Status", STATUS)];
+const CMD_STATUS: &[Field] = &[s(0, "CommandPath", CMD_PATH), s(1, "Status", STATUS)];
+const ATTR_DATA: &[Field] = &[f(0, "DataVersion"), s(1, "Path", ATTR_PATH), f(2, "Data")];
+const ATTR_REPORT: &[Field] = &[
+    s(0, "AttributeStatus", ATTR_STATUS),
+    s(1, "AttributeData", ATTR_DATA),
+];
+const EVENT_DATA: &[Field] = &[
+    s(0, "Path", EVENT_PATH),
+    f(1, "EventNumber"),
+    f(2, "Priority"),
+    f(3, "EpochTimestamp"),
+    f(4, "SystemTimestamp"),
+    f(5, "DeltaEpochTimestamp"),
+    f(6, "DeltaSystemTimestamp"),
+    f(7, "Data"),
+];
+const EVENT_REPORT: &[Field] = &[
+    s(0, "EventStatus", EVENT_STATUS),
+    s(1, "EventData", EVENT_DATA),
+];
+const CMD_DATA: &[Field] = &[s(0, "CommandPath", CMD_PATH), f(1, "CommandFields")];
+const INVOKE_RESP: &[Field] = &[s(0, "Command", CMD_DATA), s(1, "Status", CMD_STATUS)];
+
+const STATUS_RESPONSE: &[Field] = &[s(0, "Status", STATUS), IM_REVISION];
+const READ_REQ: &[Field] = &[
+    s(0, "AttributeRequests", ATTR_PATH),
+    s(1, "EventRequests", EVENT_PATH),
+    s(2, "EventFilters", EVENT_FILTER),
+    f(3, "FabricFiltered"),
+    s(4, "DataVersionFilters", DATA_VERSION_FILTER),
+    IM_REVISION,
+];
+const SUBSCRIBE_REQ: &[Field] = &[
+    f(0, "KeepSubscriptions"),
+    f(1, "MinIntervalFloor"),
+    f(2, "MaxIntervalCeiling"),
+    s(3, "AttributeRequests", ATTR_PATH),
+    s(4, "EventRequests", EVENT_PATH),
+    s(5, "EventFilters", EVENT_FILTER),
+    f(7, "FabricFiltered"),
+    s(8, "DataVersionFilters", DATA_VERSION_FILTER),
+    IM_REVISION,
+];
+const SUBSCRIBE_RESP: &[Field] = &[f(0, "SubscriptionId"), f(2, "MaxInterval"), IM_REVISION];
+const REPORT_DATA: &[Field] = &[
+    f(0, "SubscriptionId"),
+    s(1, "AttributeReports", ATTR_REPORT),
+    s(2, "EventReports", EVENT_REPORT),
+    f(3, "MoreChunkedMessages"),
+    f(4, "SuppressResponse"),
+    IM_REVISION,
+];
+const WRITE_REQ: &[Field] = &[
+    f(0, "SuppressResponse"),
+    f(1, "TimedRequest"),
+    s(2, "WriteRequests", ATTR_DATA),
+    f(3, "MoreChunkedMessages"),
+    IM_REVISION,
+];
+const WRITE_RESP: &[Field] = &[s(0, "WriteResponses", ATTR_STATUS), IM_REVISION];
+const INVOKE_REQ: &[Field] = &[
+    f(0, "SuppressResponse"),
+    f(1, "TimedRequest"),
+    s(2, "InvokeRequests", CMD_DATA),
+    IM_REVISION,
+];
+const INVOKE_RESPONSE: &[Field] = &[
+    f(0, "SuppressResponse"),
+    s(1, "InvokeResponses", INVOKE_RESP),
+    f(2, "MoreChunkedMessages"),
+    IM_REVISION,
+];
+const TIMED_REQ: &[Field] = &[f(0, "Timeout"), IM_REVISION];
+
+const SESSION_PARAMS: &[Field] = &[
+    f(1, "SessionIdleInterval"),
+    f(2, "SessionActiveInterval"),
+    f(3, "SessionActiveThreshold"),
+    f(4, "DataModelRevision"),
+    f(5, "InteractionModelRevision"),
+    f(6, "SpecificationVersion"),
+    f(7, "MaxPathsPerInvoke"),
+];
+const PBKDF_PARAMS: &[Field] = &[f(1, "Iterations"), f(2, "Salt")];
+const PBKDF_REQ: &[Field] = &[
+    f(1, "InitiatorRandom"),
+    f(2, "InitiatorSessionId"),
+    f(3, "PasscodeId"),
+    f(4, "HasPBKDFParameters"),
+    s(5, "InitiatorSessionParams", SESSION_PARAMS),
+];
+const PBKDF_RESP: &[Field] = &[
+    f(1, "InitiatorRandom"),
+    f(2, "ResponderRandom"),
+    f(3, "ResponderSessionId"),
+    s(4, "PBKDFParameters", PBKDF_PARAMS),
+    s(5, "ResponderSessionParams", SESSION_PARAMS),
+];
+const PAKE1: &[Field] = &[f(1, "pA")];
+const PAKE2: &[Field] = &[f(1, "pB"), f(2, "cB")];
+const PAKE3: &[Field] = &[f(1, "cA")];
+const SIGMA1: &[Field] = &[
+    f(1, "InitiatorRandom"),
+    f(2, "InitiatorSessionId"),
+    f(3, "DestinationId"),
+    f(4, "InitiatorEphPubKey"),
+    s(5, "InitiatorSessionParams", SESSION_PARAMS),
+    f(6, "ResumptionId"),
+    f(7, "InitiatorResumeMIC"),
+];
+const SIGMA2: &[Field] = &[
+    f(1, "ResponderRandom"),
+    f(2, "ResponderSessionId"),
+    f(3, "ResponderEphPubKey"),
+    f(4, "Encrypted2"),
+    s(5, "ResponderSessionParams", SESSION_PARAMS),
+];
+const SIGMA3: &[Field] = &[f(1, "Encrypted3")];
+const SIGMA2_RESUME: &[Field] = &[
+    f(1, "ResumptionId"),
+    f(2, "Sigma2ResumeMIC"),
+    f(3, "ResponderSessionId"),
+    s(4, "ResponderSessionParams", SESSION_PARAMS),
+];
+
+fn schema(proto_id: u16, opcode: u8) -> Option<&'static [Field]> {
+    match proto_id as usize {
+        PROTO_ID_INTERACTION_MODEL => match ImOpCode::from_u8(opcode)? {
+            ImOpCode::StatusResponse => Some(STATUS_RESPONSE),
+            ImOpCode::ReadRequest => Some(READ_REQ),
+            ImOpCode::SubscribeRequest => Some(SUBSCRIBE_REQ),
+            ImOpCode::SubscriptResponse => Some(SUBSCRIBE_RESP),
+            ImOpCode::ReportData => Some(REPORT_DATA),
+            ImOpCode::WriteRequest => Some(WRITE_REQ),
+            ImOpCode::WriteResponse => Some(WRITE_RESP),
+            ImOpCode::InvokeRequest => Some(INVOKE_REQ),
+            ImOpCode::InvokeResponse => Some(INVOKE_RESPONSE),
+            ImOpCode::TimedRequest => Some(TIMED_REQ),
+            ImOpCode::Reserved => None,
+        },
+        PROTO_ID_SECURE_CHANNEL => match ScOpCode::from_u8(opcode)? {
+            ScOpCode::PBKDFParamRequest => Some(PBKDF_REQ),
+            ScOpCode::PBKDFParamResponse => Some(PBKDF_RESP),
+            ScOpCode::PASEPake1 => Some(PAKE1),
+            ScOpCode::PASEPake2 => Some(PAKE2),
+            ScOpCode::PASEPake3 => Some(PAKE3),
+            ScOpCode::CASESigma1 => Some(SIGMA1),
+            ScOpCode::CASESigma2 => Some(SIGMA2),
+            ScOpCode::CASESigma3 => Some(SIGMA3),
+            ScOpCode::CASESigma2Resume => Some(SIGMA2_RESUME),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Decode a Matter message, and render it as text
+///
+/// The message is decrypted with the key, if it is encrypted. The nonce uses the given
+/// node id, or else the source node id of the message.
+pub fn decode(frame: &[u8], key: Option<&[u8]>, node_id: Option<u64>) -> Result<String, Error> {
+    let pool = PacketPool::new();
+    let mut rx = pool.alloc_rx_with_size(frame.len())?;
+    rx.as_borrow_slice()[..frame.len()].copy_from_slice(frame);
+    rx.get_parsebuf()?.set_len(frame.len());
+    rx.plain_hdr_decode()?;
+
+    let mut out = String::new();
+    let plain = &rx.plain;
+    let _ = writeln!(out, "Message Header:");
+    let _ = writeln!(out, "{}Flags: 0x{:02x}", INDENT, plain.flags.bits());
+    let _ = writeln!(out, "{}Session Id: {}", INDENT, plain.sess_id);
+    let _ = writeln!(out, "{}Encrypted: {}", INDENT, plain.is_encrypted());
+    let _ = writeln!(out, "{}Counter: {}", INDENT, plain.ctr);
+    let src = plain.get_src_u64();
+    if let Some(src) = src {
+        let _ = writeln!(out, "{}Source Node Id: 0x{:016X}", INDENT, src);
+    }
+
+    if rx.plain.is_encrypted() && key.is_none() {
+        let _ = writeln!(out, "Encrypted Payload:");
+        let _ = writeln!(out, "{}{}", INDENT, hex(rx.as_borrow_slice()));
+        return Ok(out);
+    }
+    rx.proto_decode(node_id.or(src).unwrap_or(0), key)?;
+
+    let proto = &rx.proto;
+    let _ = writeln!(out, "Protocol Header:");
+    let _ = writeln!(out, "{}Exchange Id: {}", INDENT, proto.exch_id);
+    let _ = writeln!(
+        out,
+        "{}Exchange Flags: {}",
+        INDENT,
+        capture::flags_str(proto)
+    );
+    let proto_name = match proto.proto_id as usize {
+        PROTO_ID_SECURE_CHANNEL => "Secure Channel",
+        PROTO_ID_INTERACTION_MODEL => "Interaction Model",
+        _ => "Unknown",
+    };
+    let _ = writeln!(
+        out,
+        "{}Protocol: {} ({})",
+        INDENT, proto_name, proto.proto_id
+    );
+    if let Some(vendor_id) = proto.proto_vendor_id {
+        let _ = writeln!(out, "{}Vendor Id: 0x{:04X}", INDENT, vendor_id);
+    }
+    let opcode_name = capture::opcode_name(proto.proto_id, proto.proto_opcode);
+    let _ = writeln!(
+        out,
+        "{}Opcode: {} (0x{:02x})",
+        INDENT,
+        opcode_name.as_deref().unwrap_or("Unknown"),
+        proto.proto_opcode
+    );
+    if let Some(ack) = proto.ack_msg_ctr {
+        let _ = writeln!(out, "{}Ack Counter: {}", INDENT, ack);
+    }
+
+    let (proto_id, opcode) = (proto.proto_id, proto.proto_opcode);
+    let payload = rx.as_borrow_slice();
+    if payload.is_empty() {
+        return Ok(out);
+    }
+    let _ = writeln!(out, "Payload:");
+    if capture::is_tlv(proto_id, opcode) {
+        write_tlv(&mut out, payload, schema(proto_id, opcode).unwrap_or(&[]));
+    } else if proto_id as usize == PROTO_ID_SECURE_CHANNEL
+        && matches!(ScOpCode::from_u8(opcode), Some(ScOpCode::StatusReport))
+    {
+        write_status_report(&mut out, payload)?;
+    } else {
+        let _ = writeln!(out, "{}{}", INDENT, hex(payload));
+    }
+    Ok(out)
+}
+
+fn write_status_report(out: &mut String, payload: &[u8]) -> Result<(), Error> {
+    let len = payload.len();
+    let mut buf = payload.to_vec();
+    let mut pb = ParseBuf::new(&mut buf, len);
+    let general_code = pb.le_u16()?;
+    let proto_id = pb.le_u32()?;
+    let proto_code = pb.le_u16()?;
+
+    let general_name = GeneralCode::from_u16(general_code).map(|c| format!("{:?}", c));
+    let proto_name = if proto_id as usize == PROTO_ID_SECURE_CHANNEL {
+        SCStatusCodes::from_u16(proto_code).map(|c| format!("{:?}", c))
+    } else {
+        None
+    };
+    let _ = writeln!(out, "{}StatusReport:", INDENT);
+    let _ = writeln!(
+        out,
+        "{0}{0}GeneralCode: {1} ({2})",
+        INDENT,
+        general_name.as_deref().unwrap_or("Unknown"),
+        general_code
+    );
+    let _ = writeln!(out, "{0}{0}ProtocolId: {1}", INDENT, proto_id);
+    let _ = writeln!(
+        out,
+        "{0}{0}ProtocolCode: {1} ({2})",
+        INDENT,
+        proto_name.as_deref().unwrap_or("Unknown"),
+        proto_code
+    );
+    let data = pb.as_slice();
+    if !data.is_empty() {
+        let _ = writeln!(out, "{0}{0}ProtocolData: {1}", INDENT, hex(data));
+    }
+    Ok(())
+}
+
+/// Write the TLV elements as an indented tree, with the context tags named as per the
+/// schema
+fn write_tlv(out: &mut String, payload: &[u8], schema: &'static [Field]) {
+    // The members of the containers that we are in, and their closing brackets
+    let mut stack: Vec<(&'static [Field], char)> = Vec::new();
+    let mut members = schema;
+    for element in TLVList::new(payload).iter() {
+        let element_type = element.get_element_type();
+        if element_type == ElementType::EndCnt {
+            if let Some((m, closing)) = stack.pop() {
+                let _ = writeln!(out, "{}{}", INDENT.repeat(stack.len() + 1), closing);
+                members = m;
+            }
+            continue;
+        }
+
+        let _ = write!(out, "{}", INDENT.repeat(stack.len() + 1));
+        let field = match element.get_tag() {
+            TagType::Context(tag) => {
+                let field = members.iter().find(|f| f.tag == tag);
+                match field {
+                    Some(field) => {
+                        let _ = write!(out, "{}: ", field.name);
+                    }
+                    None => {
+                        let _ = write!(out, "{}: ", tag);
+                    }
+                }
+                field
+            }
+            TagType::Anonymous => None,
+            tag => {
+                let _ = write!(out, "{:?}: ", tag);
+                None
+            }
+        };
+
+        let closing = match element_type {
+            ElementType::Struct(_) => Some('}'),
+            ElementType::Array(_) | ElementType::List(_) => Some(']'),
+            _ => None,
+        };
+        if let Some(closing) = closing {
+            let _ = writeln!(out, "{}", if closing == '}' { '{' } else { '[' });
+            stack.push((members, closing));
+            // The anonymous elements, like those of an array, have the members of their
+            // container
+            if element.get_tag() != TagType::Anonymous {
+                members = field.map_or(&[], |f| f.members);
+            }
+            continue;
+        }
+
+        let _ = write!(out, "{}", value_str(element_type));
+        if let Some(Field {
+            fmt: Fmt::ImStatus, ..
+        }) = field
+        {
+            let status = element.u16().ok().and_then(IMStatusCode::from_u16);
+            if let Some(status) = status {
+                let _ = write!(out, " ({:?})", status);
+            }
+        }
+        out.push('\n');
+    }
+}
+
+fn value_str(element_type: ElementType) -> String {
+    match element_type {
+        ElementType::S8(v) => v.to_string(),
+        ElementType::S16(v) => v.to_string(),
+        ElementType::S32(v) => v.to_string(),
+        ElementType::S64(v) => v.to_string(),
+        ElementType::U8(v) => v.to_string(),
+        ElementType::U16(v) => v.to_string(),
+        ElementType::U32(v) => v.to_string(),
+        ElementType::U64(v) => v.to_string(),
+        ElementType::True => "true".to_owned(),
+        ElementType::False => "false".to_owned(),
+        ElementType::F32(v) => v.to_string(),
+        ElementType::F64(v) => v.to_string(),
+        ElementType::Null => "null".to_owned(),
+        ElementType::Utf8l(s) | ElementType::Utf16l(s) => {
+            format!("\"{}\"", String::from_utf8_lossy(s))
+        }
+        ElementType::Str8l(b) | ElementType::Str16l(b) => {
+            format!("[{}] {}", b.len(), hex(b))
+        }
+        e => format!("{:?}", e),
+    }
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decrypt() {
+        // The frame of test_decrypt_success() in the proto_hdr module
+        let frame = [
+            0x0, 0x2, 0x0, 0x0, 0xf2, 0x43, 0xe9, 0x0, 0x31, 0xb5, 0x66, 0xec, 0x8b, 0x5b, 0xf4,
+            0x17, 0xe4, 0x80, 0xf3, 0xd5, 0x11, 0x59, 0x19, 0xb5, 0x23, 0x91, 0x35, 0x37, 0xb,
+            0xf9, 0xbf, 0x69, 0x55, 0x11, 0x75, 0x87, 0x77, 0x19, 0xfc, 0xf3, 0x5d, 0x4b, 0x47,
+            0x1f, 0xb0, 0x5e, 0xbe, 0xb5, 0x10, 0xad, 0xc6, 0x78, 0x94, 0x50, 0xe5, 0xd2, 0xe0,
+            0x80, 0xef, 0xa8, 0x3a, 0xf0, 0xa6, 0xaf, 0x1b, 0x2, 0x35, 0xa7, 0xd1, 0xc6, 0x32,
+        ];
+        let key = [
+            0x66, 0x63, 0x31, 0x97, 0x43, 0x9c, 0x17, 0xb9, 0x7e, 0x10, 0xee, 0x47, 0xc8, 0x8,
+            0x80, 0x4a,
+        ];
+
+        let out = decode(&frame, Some(&key), None).unwrap();
+        assert!(out.contains("Counter: 15287282"));
+        assert!(out.contains("Opcode: InvokeRequest (0x08)"));
+        assert!(out.contains("\n                CommandPath: [\n"));
+        assert!(out.contains("Cluster: 48\n"));
+        assert!(out.contains("Command: 2\n"));
+        assert!(out.contains("CommandFields: {\n"));
+
+        // Without the key, only the message header can be decoded
+        let out = decode(&frame, None, None).unwrap();
+        assert!(out.contains("Encrypted Payload:"));
+        assert!(!out.contains("Protocol Header:"));
+
+        // With the wrong key, the decryption fails
+        assert!(decode(&frame, Some(&[0; 16]), None).is_err());
+    }
+
+    #[test]
+    fn test_unencrypted() {
+        let mut frame = vec![
+            // Message header: the source node id is present, session 0, counter 1
+            0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66,
+            0x77, 0x88, //
+            // Protocol header: initiator and reliable, exchange 5, PBKDFParamRequest
+            0x05, 0x20, 0x05, 0x00, 0x00, 0x00,
+        ];
+        // {1: [4] 01020304, 2: 0x1234, 3: 0, 4: false, 5: {1: 500}}
+        frame.extend_from_slice(&[
+            0x15, 0x30, 0x01, 0x04, 0x01, 0x02, 0x03, 0x04, 0x25, 0x02, 0x34, 0x12, 0x24, 0x03,
+            0x00, 0x28, 0x04, 0x35, 0x05, 0x25, 0x01, 0xf4, 0x01, 0x18, 0x18,
+        ]);
+        let out = decode(&frame, None, None).unwrap();
+        assert!(out.contains("Source Node Id: 0x8877665544332211"));
+        assert!(out.contains("Opcode: PBKDFParamRequest (0x20)"));
+        assert!(out.contains("InitiatorRandom: [4] 01020304\n"));
+        assert!(out.contains("InitiatorSessionId: 4660\n"));
+        assert!(out.contains("HasPBKDFParameters: false\n"));
+        assert!(out.contains("InitiatorSessionParams: {\n            SessionIdleInterval: 500\n"));
+
+        // A status report: Failure, Secure Channel, InvalidParameter
+        let mut frame = frame[..16].to_vec();
+        frame.extend_from_slice(&[0x04, 0x40, 0x05, 0x00, 0x00, 0x00]);
+        frame.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+        let out = decode(&frame, None, None).unwrap();
+        assert!(out.contains("GeneralCode: Failure (1)"));
+        assert!(out.contains("ProtocolCode: InvalidParameter (2)"));
+    }
+}